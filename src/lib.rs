@@ -6,8 +6,10 @@
 #![feature(unique)]
 #![feature(allocator_api)]
 #![feature(const_atomic_usize_new)]
+#![feature(const_atomic_bool_new)]
 #![feature(global_allocator)]
 #![feature(alloc)]
+#![feature(asm)]
 #![no_std]                  //prevent automatic linking of standard library
 
 #[macro_use]
@@ -120,14 +122,28 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) {
     let boot_info = unsafe {
         multiboot2::load(multiboot_information_address)
     };
-    enable_nxe_bit();
     enable_write_protect_bit();
+    set_up_pat();
 
     // set up guard page and map the heap pages
-    memory::init(boot_info);
+    let frame_allocator = memory::init(boot_info);
 
     unsafe {
-    HEAP_ALLOCATOR.lock().init(HEAP_START, HEAP_START + HEAP_SIZE);
+    HEAP_ALLOCATOR.init(HEAP_START, HEAP_SIZE);
+    }
+
+    // `make test`: check the new page table and the paging code (which needs the heap)
+    // and report to QEMU, a failed assertion in test_paging exits through panic_fmt
+    #[cfg(feature = "remap_self_test")]
+    {
+        let mut frame_allocator = frame_allocator;
+        let passed = memory::test_remap(boot_info, rust_main as usize);
+        memory::test_paging(&mut frame_allocator, boot_info);
+        exit_qemu(passed);
+    }
+    #[cfg(not(feature = "remap_self_test"))]
+    {
+        drop(frame_allocator);
     }
 
     use alloc::boxed::Box;
@@ -151,15 +167,23 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) {
     loop {}
 }
 
-//enable NXE bit
-fn enable_nxe_bit() {
-    use x86_64::registers::msr::{IA32_EFER, rdmsr, wrmsr};
+// leave QEMU through the isa-debug-exit device at port 0xf4 (see `make test`)
+// QEMU exits with status (value << 1) | 1, so 33 for a pass and 35 for a failure
+#[cfg(feature = "remap_self_test")]
+fn exit_qemu(passed: bool) -> ! {
+    use x86_64::instructions::port::outl;
 
-    let nxe_bit = 1 << 11;
-    unsafe {
-        let efer = rdmsr(IA32_EFER);
-        wrmsr(IA32_EFER, efer | nxe_bit);
-    }
+    let value = if passed { 0x10 } else { 0x11 };
+    unsafe { outl(0xf4, value) };
+    loop {}
+}
+
+// load the page attribute table the CacheType flags are built for
+fn set_up_pat() {
+    use x86_64::registers::msr::wrmsr;
+
+    const IA32_PAT: u32 = 0x277;
+    unsafe { wrmsr(IA32_PAT, memory::PAT_LAYOUT) };
 }
 
 //enable write protection for kernel
@@ -178,6 +202,11 @@ pub extern fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str,
 {
     println!("\n\nPANIC in {} at line {}:", file, line);
     println!("    {}", fmt);
+    // under `make test` a panic fails the self test
+    #[cfg(feature = "remap_self_test")]
+    {
+        exit_qemu(false);
+    }
     loop{}
 }
 
@@ -187,13 +216,13 @@ pub extern fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str,
 #[lang = "eh_personality"] extern fn eh_personality() {}       //used for Rust unwinding on panic!
 //#[lang = "panic_fmt"] #[no_mangle] pub extern fn panic_fmt() -> ! {loop{}}      //doesn't return (required by ! return type), put in loop
 
-use memory::heap_allocator::BumpAllocator;
-use linked_list_allocator::LockedHeap;
+use memory::heap_allocator::{BumpAllocator, CheckedHeap};
 
+// the heap is [HEAP_START, HEAP_START + HEAP_SIZE), surrounded by unmapped guard pages
 pub const HEAP_START: usize = 0o_000_001_000_000_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: CheckedHeap = CheckedHeap::empty();
 //#[global_allocator]
 //static HEAP_ALLOCATOR: BumpAllocator = BumpAllocator::new(HEAP_START, HEAP_START + HEAP_SIZE);
@@ -1,7 +1,14 @@
 // the frame allocator
 
-use memory::{Frame, FrameAllocator};
-use multiboot2::{MemoryAreaIter, MemoryArea};
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use memory::bitmap_frame_allocator::BitmapFrameAllocator;
+use memory::reservation::{ReservationRegistry, RESERVATIONS};
+use multiboot2::{MemoryAreaIter, MemoryArea, BootInformation};
+use core::cmp;
+
+// the area allocator only ever moves forward, it is the bump allocator used during
+// early boot (remap_the_kernel) until the bitmap allocator can take over
+pub type BumpFrameAllocator = AreaFrameAllocator;
 
 pub struct AreaFrameAllocator {
     next_free_frame: Frame,     // counter that is increased every time we return a frame
@@ -59,6 +66,12 @@ impl FrameAllocator for AreaFrameAllocator {
                 number: self.multiboot_end.number + 1
             };
         }
+        // `frame` is reserved for something else, e.g. a multiboot module
+        else if let Some(reservation) = RESERVATIONS.lock().phys_reservation(&frame).cloned() {
+            self.next_free_frame = Frame {
+                number: Frame::containing_address(reservation.end).number + 1
+            };
+        }
         // frame is unused, increment `next_free_frame` and return it
         else {
             self.next_free_frame.number += 1;
@@ -72,6 +85,28 @@ impl FrameAllocator for AreaFrameAllocator {
     }
     }
 
+    // the frames from next_free_frame to the end of the areas, without the reserved
+    // ranges (assumed not to overlap each other)
+    fn frames_available(&self) -> Option<usize> {
+        let reservations = RESERVATIONS.lock();
+        let next = self.next_free_frame.number;
+        let available = self.areas.clone().map(|area| {
+            let area_first = Frame::containing_address(area.base_addr as usize).number;
+            let first = cmp::max(area_first, next);
+            let end = (area.base_addr + area.length) as usize / PAGE_SIZE;
+            if first >= end {
+                return 0;
+            }
+            let reserved: usize = reservations.phys_ranges().map(|reservation| {
+                let reserved_first = cmp::max(reservation.start / PAGE_SIZE, first);
+                let reserved_end = cmp::min(reservation.end / PAGE_SIZE + 1, end);
+                reserved_end.saturating_sub(reserved_first)
+            }).sum();
+            end - first - cmp::min(reserved, end - first)
+        }).sum();
+        Some(available)
+    }
+
     fn deallocate_frame(&mut self, frame: Frame) {
         // TODO (see below)
     }
@@ -121,3 +156,51 @@ impl AreaFrameAllocator {
     }
 }
 }
+
+// handoff from the early allocator to the runtime allocator
+impl AreaFrameAllocator {
+
+    // every frame handed out so far lies below this frame
+    pub fn high_water_mark(&self) -> Frame {
+        self.next_free_frame.clone()
+    }
+
+    // create the bitmap allocator: the available memory areas are free, except the
+    // reserved physical ranges and everything below the high-water mark
+    // (that covers all frames allocated during early boot)
+    pub fn into_bitmap(self, boot_info: &BootInformation, reservations: &ReservationRegistry)
+        -> BitmapFrameAllocator
+    {
+        self.into_bitmap_in(BitmapFrameAllocator::from_static(), boot_info, reservations)
+    }
+
+    // into_bitmap with a fresh (all used) bitmap allocator instead of the static one
+    pub fn into_bitmap_in(self, mut bitmap: BitmapFrameAllocator, boot_info: &BootInformation,
+        reservations: &ReservationRegistry) -> BitmapFrameAllocator
+    {
+
+        let memory_map_tag = boot_info.memory_map_tag().expect(
+            "Memory map tag required");
+        for area in memory_map_tag.memory_areas() {
+            // only frames that lie completely inside the area
+            let start = (area.base_addr as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+            let end = (area.base_addr + area.length) as usize / PAGE_SIZE;
+            if end > start {
+                bitmap.mark_range_free(Frame::from_number(start),
+                    Frame::from_number(end - 1));
+            }
+        }
+
+        if self.next_free_frame.number > 0 {
+            bitmap.mark_range_used(Frame::from_number(0),
+                Frame::from_number(self.next_free_frame.number - 1));
+        }
+        // kernel, multiboot structure and everything else registered at boot
+        for reservation in reservations.phys_ranges() {
+            bitmap.mark_range_used(Frame::containing_address(reservation.start),
+                Frame::containing_address(reservation.end));
+        }
+
+        bitmap
+    }
+}
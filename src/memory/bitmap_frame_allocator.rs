@@ -0,0 +1,318 @@
+// the runtime frame allocator
+// one bit per frame, a set bit means the frame is used
+
+use memory::{PAGE_SIZE, Frame, FrameAllocator, Zone, ReservationRegistry, RESERVATIONS,
+    frozen_reservations};
+use memory::paging::{self, ActivePageTable, PhysicalAddress, IDENTITY_REGION_END, WRITABLE,
+    NO_EXECUTE};
+use multiboot2::BootInformation;
+use core::cmp::{self, min};
+use core::slice;
+
+// number of frames the static bitmap can describe (4GiB of physical memory)
+pub const MAX_FRAMES: usize = 1 << 20;
+
+// storage for the bitmap, lives in .bss so it is mapped by remap_the_kernel
+static mut BITMAP: [u64; MAX_FRAMES / 64] = [0; MAX_FRAMES / 64];
+
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    next_word: usize,   // first word that may contain a free bit
+    frozen: bool,       // see FrameAllocator::freeze
+}
+
+impl BitmapFrameAllocator {
+
+    // all frames start out as used, free memory is released with mark_range_free
+    pub fn new(bitmap: &'static mut [u64]) -> BitmapFrameAllocator {
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        BitmapFrameAllocator {
+            bitmap: bitmap,
+            next_word: 0,
+            frozen: false,
+        }
+    }
+
+    // allocator over the static bitmap, may only be created once
+    pub fn from_static() -> BitmapFrameAllocator {
+        assert_has_not_been_called!("the static frame bitmap can only be used once");
+        BitmapFrameAllocator::new(unsafe { &mut BITMAP })
+    }
+
+    // number of frames described by the bitmap
+    pub fn frame_count(&self) -> usize {
+        self.bitmap.len() * 64
+    }
+
+    pub fn is_used(&self, frame: &Frame) -> bool {
+        if frame.number >= self.frame_count() {
+            return true;    // not managed by us, so never free
+        }
+        self.bitmap[frame.number / 64] & (1 << (frame.number % 64)) != 0
+    }
+
+    // number of frames that are currently free
+    pub fn free_frames(&self) -> usize {
+        self.bitmap.iter().map(|word| word.count_zeros() as usize).sum()
+    }
+
+    // every free frame in ascending order, read lazily from the current bitmap
+    pub fn free_frames_iter(&self) -> FreeFrameIter {
+        FreeFrameIter { bitmap: self.bitmap, next: 0 }
+    }
+
+    // the free frames as (first frame, number of frames) runs in ascending order
+    pub fn free_ranges_iter(&self) -> FreeRangeIter {
+        FreeRangeIter { frames: self.free_frames_iter(), pending: None }
+    }
+
+    // first free frame in the words [next_word, end_word)
+    fn allocate_below(&mut self, end_word: usize) -> Option<Frame> {
+        if self.frozen {
+            return None;
+        }
+        // skip full words, everything below next_word is known to be used
+        while self.next_word < end_word {
+            let word = self.bitmap[self.next_word];
+            if word != !0 {
+                let bit = (!word).trailing_zeros() as usize;
+                self.bitmap[self.next_word] |= 1 << bit;
+                return Some(Frame::from_number(self.next_word * 64 + bit));
+            }
+            self.next_word += 1;
+        }
+        None // no free frames left
+    }
+
+    // length in frames of the longest run of free frames
+    pub fn largest_contiguous_free(&self) -> usize {
+        let mut largest = 0;
+        let mut run = 0;
+        for &word in self.bitmap.iter() {
+            match word {
+                0 => run += 64,
+                _ if word == !0 => run = 0,
+                _ => for bit in 0..64 {
+                    if word & (1 << bit) == 0 {
+                        run += 1;
+                        largest = cmp::max(largest, run);
+                    } else {
+                        run = 0;
+                    }
+                },
+            }
+            largest = cmp::max(largest, run);
+        }
+        largest
+    }
+
+    // how scattered the free memory is, in percent: 0 if all free frames form one run,
+    // close to 100 if they are spread out in small runs (0 as well if nothing is free)
+    pub fn fragmentation_percent(&self) -> usize {
+        let free = self.free_frames();
+        if free == 0 {
+            return 0;
+        }
+        100 - self.largest_contiguous_free() * 100 / free
+    }
+
+    // mark all frames in [start, end] as used (frames outside the bitmap are ignored)
+    pub fn mark_range_used(&mut self, start: Frame, end: Frame) {
+        for frame in Frame::range_inclusive(start, end) {
+            if frame.number >= self.frame_count() {
+                break;
+            }
+            self.bitmap[frame.number / 64] |= 1 << (frame.number % 64);
+        }
+    }
+
+    // mark all frames in [start, end] as free (frames outside the bitmap are ignored)
+    pub fn mark_range_free(&mut self, start: Frame, end: Frame) {
+        if start.number / 64 < self.next_word {
+            self.next_word = start.number / 64;
+        }
+        for frame in Frame::range_inclusive(start, end) {
+            if frame.number >= self.frame_count() {
+                break;
+            }
+            self.bitmap[frame.number / 64] &= !(1 << (frame.number % 64));
+        }
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        let words = self.bitmap.len();
+        self.allocate_below(words)
+    }
+
+    // only the zone's part of the bitmap is searched
+    fn allocate_frame_in_zone(&mut self, zone: Zone) -> Option<Frame> {
+        let words = match zone.end_frame() {
+            Some(end) => min(end / 64, self.bitmap.len()),
+            None => self.bitmap.len(),
+        };
+        self.allocate_below(words)
+    }
+
+    fn frames_available(&self) -> Option<usize> {
+        Some(self.free_frames())
+    }
+
+    fn freeze(&mut self) -> bool {
+        self.frozen = true;
+        true
+    }
+
+    fn thaw(&mut self) {
+        self.frozen = false;
+    }
+
+    // sets the frame's bit if it is clear, frames outside the bitmap are never free
+    fn allocate_specific_frame(&mut self, frame: &Frame) -> bool {
+        if self.frozen || self.is_used(frame) {
+            return false;
+        }
+        self.bitmap[frame.number / 64] |= 1 << (frame.number % 64);
+        true
+    }
+
+    // leaves the bitmap alone while frozen, the frame stays used
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if self.frozen {
+            return;
+        }
+        assert!(self.is_used(&frame), "double free of {:?}", frame);
+        debug_assert!(!frozen_reservations()
+            .map_or(false, |reservations| reservations.contains_phys(&frame)),
+            "freeing reserved frame {:?}", frame);
+        // stale readers of the frame see POISON_PATTERN from now on, see poison_frame
+        #[cfg(debug_assertions)]
+        paging::poison_frame(&frame);
+        let word = frame.number / 64;
+        self.bitmap[word] &= !(1 << (frame.number % 64));
+        if word < self.next_word {
+            self.next_word = word;
+        }
+    }
+}
+
+// bytes of bitmap for `total_frames` frames, rounded up to whole u64 words
+pub fn bitmap_storage_size(total_frames: usize) -> usize {
+    (total_frames + 63) / 64 * 8
+}
+
+// the lowest page aligned start of `size` bytes inside one of the areas (start, size)
+// that touches no reserved physical range and ends at or below `limit`
+pub fn find_bitmap_region<I>(areas: I, size: usize, reservations: &ReservationRegistry,
+    limit: PhysicalAddress) -> Option<PhysicalAddress>
+    where I: Iterator<Item = (PhysicalAddress, usize)>
+{
+    let align_up = |address: PhysicalAddress| {
+        (address + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+    };
+    let mut best = None;
+    for (area_start, area_size) in areas {
+        let area_end = cmp::min(area_start.saturating_add(area_size), limit);
+        let mut candidate = align_up(area_start);
+        while candidate.saturating_add(size) <= area_end {
+            match reservations.phys_overlap(candidate, candidate + size - 1) {
+                Some(reservation) => candidate = align_up(reservation.end + 1),
+                None => {
+                    if best.map_or(true, |best| candidate < best) {
+                        best = Some(candidate);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    best
+}
+
+// storage for a bitmap covering all memory of the memory map, instead of the fixed
+// static one: placed in available memory below IDENTITY_REGION_END that holds
+// nothing reserved, identity mapped and reserved as "frame bitmap" so neither the
+// early allocator nor the bitmap itself hands the frames out
+// returns the storage for BitmapFrameAllocator::new and its first and last frame
+pub fn bootstrap_bitmap_storage<A>(boot_info: &BootInformation,
+    active_table: &mut ActivePageTable, allocator: &mut A)
+    -> (&'static mut [u64], Frame, Frame)
+    where A: FrameAllocator
+{
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+    let areas = || memory_map_tag.memory_areas()
+        .map(|area| (area.base_addr as PhysicalAddress, area.length as usize));
+    let memory_end = areas().map(|(start, size)| start.saturating_add(size)).max()
+        .unwrap_or(0);
+    let size = bitmap_storage_size((memory_end + PAGE_SIZE - 1) / PAGE_SIZE);
+
+    let start = find_bitmap_region(areas(), size, &RESERVATIONS.lock(), IDENTITY_REGION_END)
+        .expect("no available memory for the frame bitmap");
+    RESERVATIONS.lock().reserve_phys("frame bitmap", start, size);
+    active_table.identity_map_phys_range(start, size, WRITABLE | NO_EXECUTE, allocator);
+
+    let storage = unsafe { slice::from_raw_parts_mut(start as *mut u64, size / 8) };
+    (storage, Frame::containing_address(start), Frame::containing_address(start + size - 1))
+}
+
+fn bit_is_free(bitmap: &[u64], number: usize) -> bool {
+    bitmap[number / 64] & (1 << (number % 64)) == 0
+}
+
+pub struct FreeFrameIter<'a> {
+    bitmap: &'a [u64],
+    next: usize,        // next frame number to look at
+}
+
+impl<'a> Iterator for FreeFrameIter<'a> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        let frames = self.bitmap.len() * 64;
+        while self.next < frames {
+            // skip full words at once
+            if self.next % 64 == 0 && self.bitmap[self.next / 64] == !0 {
+                self.next += 64;
+                continue;
+            }
+            let number = self.next;
+            self.next += 1;
+            if bit_is_free(self.bitmap, number) {
+                return Some(Frame::from_number(number));
+            }
+        }
+        None
+    }
+}
+
+pub struct FreeRangeIter<'a> {
+    frames: FreeFrameIter<'a>,
+    pending: Option<usize>,     // first frame of the next run, already taken from `frames`
+}
+
+impl<'a> Iterator for FreeRangeIter<'a> {
+    type Item = (Frame, usize);
+
+    fn next(&mut self) -> Option<(Frame, usize)> {
+        let start = match self.pending.take() {
+            Some(number) => number,
+            None => match self.frames.next() {
+                Some(frame) => frame.number,
+                None => return None,
+            },
+        };
+        let mut count = 1;
+        while let Some(frame) = self.frames.next() {
+            if frame.number != start + count {
+                self.pending = Some(frame.number);
+                break;
+            }
+            count += 1;
+        }
+        Some((Frame::from_number(start), count))
+    }
+}
@@ -0,0 +1,63 @@
+// map counts of frames that are shared between several pages
+// only frames mapped through Mapper::map_shared / clone_mapping are counted,
+// a frame without an entry is not shared; needs the heap
+// also records which frames are pinned: they must stay where they are (DMA buffers,
+// page tables), code that moves frames (paging::swap_frame) skips them
+
+use memory::Frame;
+use alloc::btree_map::BTreeMap;
+use alloc::btree_set::BTreeSet;
+use spin::Mutex;
+
+// frame number -> number of pages mapping the frame
+static REFCOUNTS: Mutex<Option<BTreeMap<usize, usize>>> = Mutex::new(None);
+
+// numbers of the pinned frames
+static PINNED: Mutex<Option<BTreeSet<usize>>> = Mutex::new(None);
+
+// one more page maps the frame, returns the new count
+pub fn increment(frame: &Frame) -> usize {
+    let mut refcounts = REFCOUNTS.lock();
+    let count = refcounts.get_or_insert_with(BTreeMap::new)
+        .entry(frame.number).or_insert(0);
+    *count += 1;
+    *count
+}
+
+// one page less maps the frame, returns the new count
+// at 0 the entry is removed and the frame may be freed
+pub fn decrement(frame: &Frame) -> usize {
+    let mut refcounts = REFCOUNTS.lock();
+    let map = refcounts.get_or_insert_with(BTreeMap::new);
+    let count = {
+        let count = map.get_mut(&frame.number)
+            .expect("decrementing the map count of an unshared frame");
+        *count -= 1;
+        *count
+    };
+    if count == 0 {
+        map.remove(&frame.number);
+    }
+    count
+}
+
+// number of pages mapping the frame, 0 if it is not shared
+pub fn count(frame: &Frame) -> usize {
+    REFCOUNTS.lock().as_ref()
+        .and_then(|map| map.get(&frame.number).cloned())
+        .unwrap_or(0)
+}
+
+// the frame must not be moved until unpin_frame, pinning twice is a no-op
+pub fn pin_frame(frame: &Frame) {
+    PINNED.lock().get_or_insert_with(BTreeSet::new).insert(frame.number);
+}
+
+pub fn unpin_frame(frame: &Frame) {
+    let removed = PINNED.lock().as_mut().map_or(false, |pinned| pinned.remove(&frame.number));
+    assert!(removed, "unpinning {:?}, which is not pinned", frame);
+}
+
+pub fn is_pinned(frame: &Frame) -> bool {
+    PINNED.lock().as_ref().map_or(false, |pinned| pinned.contains(&frame.number))
+}
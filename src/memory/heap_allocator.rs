@@ -1,7 +1,8 @@
 // memory allocator
 
 use alloc::heap::{Alloc, AllocErr, Layout};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use linked_list_allocator::LockedHeap;
 
 #[derive(Debug)]
 
@@ -54,6 +55,50 @@ loop {
 }
 
 
+// linked list heap that refuses to be used before memory::init mapped the heap pages
+// an early allocation would otherwise touch unmapped memory and triple fault
+pub struct CheckedHeap {
+    heap: LockedHeap,
+    initialized: AtomicBool,
+}
+
+impl CheckedHeap {
+    pub const fn empty() -> Self {
+        Self { heap: LockedHeap::empty(), initialized: AtomicBool::new(false) }
+    }
+
+    // must only be called once the heap pages are mapped
+    pub unsafe fn init(&self, heap_bottom: usize, heap_size: usize) {
+        self.heap.lock().init(heap_bottom, heap_size);
+        self.initialized.store(true, Ordering::SeqCst);
+    }
+
+    // the check alloc panics on: Err with the panic message until init ran
+    pub fn check_initialized(&self) -> Result<(), &'static str> {
+        if self.initialized.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err("heap used before initialization")
+        }
+    }
+}
+
+unsafe impl<'a> Alloc for &'a CheckedHeap {
+
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        if let Err(message) = self.check_initialized() {
+            panic!("{}", message);
+        }
+        let mut heap = &self.heap;
+        heap.alloc(layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let mut heap = &self.heap;
+        heap.dealloc(ptr, layout)
+    }
+}
+
 /// Align downwards. Returns the greatest x with alignment `align`
 /// so that x <= addr. The alignment must be a power of 2.
 pub fn align_down(addr: usize, align: usize) -> usize {
@@ -0,0 +1,147 @@
+// frame allocator wrapper that records every allocation and free, for chasing leaks
+// the last LOG_SIZE events are kept in a ring buffer (no heap needed, so it can wrap
+// the boot allocators too), with `print` each event is printed as well
+
+use memory::{Frame, FrameAllocator, Zone};
+use core::cmp;
+
+// number of events the ring buffer keeps
+pub const LOG_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameEvent {
+    Allocated(usize),   // frame number
+    Failed,             // the allocator had no frame
+    Freed(usize),       // frame number
+}
+
+// an event and its position in the sequence of all events (starting at 0)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogEntry {
+    pub sequence: usize,
+    pub event: FrameEvent,
+}
+
+pub struct LoggingFrameAllocator<A: FrameAllocator> {
+    inner: A,
+    print: bool,
+    log: [Option<LogEntry>; LOG_SIZE],
+    next_sequence: usize,
+    allocated: usize,
+    freed: usize,
+}
+
+impl<A> LoggingFrameAllocator<A> where A: FrameAllocator {
+
+    pub fn new(inner: A, print: bool) -> LoggingFrameAllocator<A> {
+        LoggingFrameAllocator {
+            inner: inner,
+            print: print,
+            log: [None; LOG_SIZE],
+            next_sequence: 0,
+            allocated: 0,
+            freed: 0,
+        }
+    }
+
+    // the wrapped allocator, logging ends
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+
+    // frames allocated through the wrapper and not freed through it yet
+    // negative if more frames were freed than allocated (e.g. frames from before)
+    pub fn outstanding(&self) -> isize {
+        self.allocated as isize - self.freed as isize
+    }
+
+    // the recorded events, oldest first (at most LOG_SIZE)
+    pub fn log(&self) -> LogIter {
+        let recorded = cmp::min(self.next_sequence, LOG_SIZE);
+        LogIter {
+            log: &self.log,
+            sequence: self.next_sequence - recorded,
+            end: self.next_sequence,
+        }
+    }
+
+    fn record(&mut self, event: FrameEvent) {
+        let entry = LogEntry { sequence: self.next_sequence, event: event };
+        if self.print {
+            println!("frame log {}: {:?}", entry.sequence, entry.event);
+        }
+        self.log[self.next_sequence % LOG_SIZE] = Some(entry);
+        self.next_sequence += 1;
+    }
+
+    fn record_allocation(&mut self, frame: &Option<Frame>) {
+        match *frame {
+            Some(ref frame) => {
+                self.allocated += 1;
+                self.record(FrameEvent::Allocated(frame.number));
+            }
+            None => self.record(FrameEvent::Failed),
+        }
+    }
+}
+
+impl<A> FrameAllocator for LoggingFrameAllocator<A> where A: FrameAllocator {
+
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        let frame = self.inner.allocate_frame();
+        self.record_allocation(&frame);
+        frame
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        self.freed += 1;
+        self.record(FrameEvent::Freed(frame.number));
+        self.inner.deallocate_frame(frame)
+    }
+
+    fn frames_available(&self) -> Option<usize> {
+        self.inner.frames_available()
+    }
+
+    fn freeze(&mut self) -> bool {
+        self.inner.freeze()
+    }
+
+    fn thaw(&mut self) {
+        self.inner.thaw()
+    }
+
+    fn allocate_frame_in_zone(&mut self, zone: Zone) -> Option<Frame> {
+        let frame = self.inner.allocate_frame_in_zone(zone);
+        self.record_allocation(&frame);
+        frame
+    }
+
+    // a frame in use is not a failure of the allocator, only claims are recorded
+    fn allocate_specific_frame(&mut self, frame: &Frame) -> bool {
+        let claimed = self.inner.allocate_specific_frame(frame);
+        if claimed {
+            self.allocated += 1;
+            self.record(FrameEvent::Allocated(frame.number));
+        }
+        claimed
+    }
+}
+
+pub struct LogIter<'a> {
+    log: &'a [Option<LogEntry>; LOG_SIZE],
+    sequence: usize,    // next sequence number to return
+    end: usize,
+}
+
+impl<'a> Iterator for LogIter<'a> {
+    type Item = LogEntry;
+
+    fn next(&mut self) -> Option<LogEntry> {
+        if self.sequence == self.end {
+            return None;
+        }
+        self.sequence += 1;
+        self.log[(self.sequence - 1) % LOG_SIZE]
+    }
+}
@@ -1,32 +1,48 @@
 // memory module
 pub use self::paging::test_paging;
 
-pub use self::area_frame_allocator::AreaFrameAllocator;
-pub use self::paging::remap_the_kernel;
-use self::paging::PhysicalAddress;
-use multiboot2::BootInformation;
+pub use self::area_frame_allocator::{AreaFrameAllocator, BumpFrameAllocator};
+pub use self::bitmap_frame_allocator::BitmapFrameAllocator;
+pub use self::test_frame_allocator::TestFrameAllocator;
+pub use self::logging_frame_allocator::{LoggingFrameAllocator, FrameEvent, LogEntry};
+pub use self::reservation::{ReservationRegistry, RESERVATIONS, freeze_reservations,
+    frozen_reservations};
+pub use self::stack_allocator::{Stack, StackAllocator, grow_stack_on_fault};
+pub use self::paging::{remap_the_kernel, test_remap};
+pub use self::paging::{CacheType, PAT_LAYOUT, ENTRIES_PER_TABLE};
+use self::paging::{PhysicalAddress, PhysAddr, SectionMapping};
+use multiboot2::{BootInformation, ModuleTag};
+use core::{cmp, slice};
+use alloc::vec::Vec;
 
 mod area_frame_allocator;
+mod bitmap_frame_allocator;
+mod frame_refcount;
+mod logging_frame_allocator;
 mod paging;
+mod reservation;
+mod stack_allocator;
+mod test_frame_allocator;
 pub mod heap_allocator;
 
 // size of a physical page / frame
 pub const PAGE_SIZE: usize = 4096;
 
+// highest frame number reachable with 52 bit physical addresses
+pub const MAX_FRAME_NUMBER: usize = 0x000f_ffff_ffff_f000 / PAGE_SIZE;
+
 //map a page to a frame
-pub fn init(boot_info: &BootInformation) {
+// returns the runtime frame allocator, e.g. for the self test in `make test`
+pub fn init(boot_info: &BootInformation) -> BitmapFrameAllocator {
     assert_has_not_been_called!("memory::init must be called only once");
 
     let memory_map_tag = boot_info.memory_map_tag().expect(
         "Memory map tag required");
-    let elf_sections_tag = boot_info.elf_sections_tag().expect(
-        "Elf sections tag required");
 
-    let kernel_start = elf_sections_tag.sections()
-        .filter(|s| s.is_allocated()).map(|s| s.addr).min().unwrap();
-    let kernel_end = elf_sections_tag.sections()
-        .filter(|s| s.is_allocated()).map(|s| s.addr + s.size).max()
-        .unwrap();
+    // [kernel_start, kernel_end) covers the whole frames of the kernel image
+    let (kernel_start_frame, kernel_end_frame) = kernel_physical_range(boot_info);
+    let kernel_start = kernel_start_frame.start_address();
+    let kernel_end = kernel_end_frame.start_address() + PAGE_SIZE;
 
     println!("kernel start: {:#x}, kernel end: {:#x}",
              kernel_start,
@@ -35,13 +51,42 @@ pub fn init(boot_info: &BootInformation) {
              boot_info.start_address(),
              boot_info.end_address());
 
-    let mut frame_allocator = AreaFrameAllocator::new(
+    reserve_boot_regions(kernel_start, kernel_end, boot_info);
+    // nothing identity mapped from here on may lie beyond the installed memory
+    let physical_end = paging::physical_memory_end(boot_info);
+    paging::set_max_physical_frame(Some(Frame::containing_address(physical_end - 1)));
+
+    // the bump allocator is only used while remapping the kernel
+    let mut early_allocator = BumpFrameAllocator::new(
         kernel_start as usize, kernel_end as usize,
         boot_info.start_address(), boot_info.end_address(),
         memory_map_tag.memory_areas());
 
-    let mut active_table = paging::remap_the_kernel(&mut frame_allocator,
-        boot_info);
+    let mut active_table = match paging::remap_the_kernel(&mut early_allocator, boot_info) {
+        Ok(active_table) => active_table,
+        // still on the boot table, so the message gets out
+        Err(error) => panic!("cannot remap the kernel: {}", error),
+    };
+    if let Err(error) = paging::verify_kernel_sections(&active_table, boot_info) {
+        panic!("kernel sections are mapped wrongly after the remap: {}", error);
+    }
+    paging::harden_kernel_mappings(&mut active_table, boot_info);
+    // W^X: after hardening no kernel page may be writable and executable
+    active_table.for_each_wx_violation(|page| {
+        panic!("page {:#x} is writable and executable after the remap", page.start_address())
+    });
+    paging::install_active_table(active_table);
+
+    let mut frame_allocator = early_allocator.into_bitmap(boot_info, &RESERVATIONS.lock());
+    // everything is reserved now, the mappers check against this snapshot from here on
+    freeze_reservations();
+    paging::with_active_table(|active_table| {
+        active_table.install_p4_alias(&mut frame_allocator)
+    });
+    #[cfg(debug_assertions)]
+    paging::with_active_table(|active_table| {
+        active_table.install_poison_window(&mut frame_allocator)
+    });
 
     use self::paging::Page;
     use {HEAP_START, HEAP_SIZE};
@@ -49,8 +94,313 @@ pub fn init(boot_info: &BootInformation) {
     let heap_start_page = Page::containing_address(HEAP_START);
     let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE-1);
 
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        active_table.map(page, paging::WRITABLE, &mut frame_allocator);
+    // the pages directly below and above the heap stay unmapped as guard pages
+    let (lower_guard, upper_guard) = heap_guard_pages();
+    paging::with_active_table(|active_table| {
+        for page in Page::range_inclusive(heap_start_page, heap_end_page) {
+            active_table.map(page, paging::WRITABLE | paging::NO_EXECUTE,
+                &mut frame_allocator);
+        }
+        assert!(active_table.translate_page(lower_guard).is_none() &&
+                active_table.translate_page(upper_guard).is_none(),
+                "heap guard pages must not be mapped");
+    });
+
+    // bring-up mode: everything available is reachable at its physical address
+    #[cfg(feature = "identity_map_all")]
+    {
+        let frames = paging::with_active_table(|active_table| {
+            paging::identity_map_all_available(active_table, boot_info, &mut frame_allocator)
+        });
+        println!("identity mapped {} available frames", frames);
+    }
+
+    // the console moves to the higher half mapping of the VGA buffer
+    let vga_buffer = map_vga_buffer(&mut frame_allocator);
+    unsafe { ::vga_buffer::set_buffer_address(vga_buffer) };
+
+    frame_allocator
+}
+
+// higher half address of the VGA text buffer, see map_vga_buffer
+pub const VGA_BUFFER_VIRTUAL: usize = paging::KERNEL_VIRTUAL_BASE + ::vga_buffer::VGA_BUFFER_PHYSICAL;
+
+// map the VGA frame to VGA_BUFFER_VIRTUAL and return that address
+// the identity mapping stays, the console can be pointed at the returned address
+// with vga_buffer::set_buffer_address
+pub fn map_vga_buffer<A>(allocator: &mut A) -> usize
+    where A: FrameAllocator
+{
+    use self::paging::{Page, WRITABLE, NO_EXECUTE};
+    use vga_buffer::VGA_BUFFER_PHYSICAL;
+
+    paging::with_active_table(|active_table| {
+        let page = Page::containing_address(VGA_BUFFER_VIRTUAL);
+        match active_table.translate_page(page) {
+            // already mapped by an earlier call
+            Some(frame) => assert!(frame == Frame::containing_address(VGA_BUFFER_PHYSICAL),
+                "VGA_BUFFER_VIRTUAL maps another frame"),
+            None => active_table.map_to(page, Frame::containing_address(VGA_BUFFER_PHYSICAL),
+                WRITABLE | NO_EXECUTE, allocator),
+        }
+        // both addresses must reach the same frame
+        assert!(active_table.translate(VGA_BUFFER_VIRTUAL) ==
+                active_table.translate(VGA_BUFFER_PHYSICAL));
+    });
+    VGA_BUFFER_VIRTUAL
+}
+
+// identity map a multiboot module read-only and return its contents
+// the module's frames are reserved at boot, so nothing else can have reused them
+pub fn map_module<A>(module: &ModuleTag, allocator: &mut A) -> &'static [u8]
+    where A: FrameAllocator
+{
+    use self::paging::{Page, PRESENT, NO_EXECUTE};
+
+    let start = module.start_address() as usize;
+    let size = (module.end_address() - module.start_address()) as usize;
+    if let Some(range) = AddressRange::new(start, size) {
+        paging::with_active_table(|active_table| {
+            for frame in range.frames() {
+                if active_table.translate_page(Page::from_frame_identity(&frame)).is_none() {
+                    active_table.identity_map(frame, PRESENT | NO_EXECUTE, allocator);
+                }
+            }
+        });
+    }
+    unsafe { slice::from_raw_parts(start as *const u8, size) }
+}
+
+// record the memory that must never be allocated or mapped over
+fn reserve_boot_regions(kernel_start: usize, kernel_end: usize, boot_info: &BootInformation) {
+    use self::paging::{RECURSIVE_REGION_START, HUGE_PAGE_SIZE_1GIB, ENTRIES_PER_TABLE};
+
+    let mut reservations = RESERVATIONS.lock();
+    reservations.reserve_phys("kernel", kernel_start, kernel_end - kernel_start);
+    reservations.reserve_phys("multiboot", boot_info.start_address(),
+        boot_info.end_address() - boot_info.start_address());
+    reservations.reserve_phys("vga buffer", 0xb8000, PAGE_SIZE);
+    // modules (e.g. an initrd) are loaded somewhere in available memory
+    for module in boot_info.module_tags() {
+        reserve_module(&mut reservations, module);
+    }
+
+    let (lower_guard, upper_guard) = heap_guard_pages();
+    reservations.reserve_virt("heap guard", lower_guard.start_address(), PAGE_SIZE);
+    reservations.reserve_virt("heap guard", upper_guard.start_address(), PAGE_SIZE);
+    reservations.reserve_virt("recursive mapping", RECURSIVE_REGION_START,
+        ENTRIES_PER_TABLE * HUGE_PAGE_SIZE_1GIB);
+    reservations.reserve_virt("secondary recursive mapping",
+        RECURSIVE_REGION_START - ENTRIES_PER_TABLE * HUGE_PAGE_SIZE_1GIB,
+        ENTRIES_PER_TABLE * HUGE_PAGE_SIZE_1GIB);
+}
+
+// reserve the frames a multiboot module was loaded to
+fn reserve_module(reservations: &mut ReservationRegistry, module: &ModuleTag) {
+    reservations.reserve_phys("multiboot module", module.start_address() as usize,
+        (module.end_address() - module.start_address()) as usize);
+}
+
+// bytes of available RAM in the multiboot memory map
+// the area fields are 64 bit even on a 32 bit bootloader, sums that would overflow saturate
+pub fn total_ram(boot_info: &BootInformation) -> usize {
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+    let total = memory_map_tag.memory_areas()
+        .fold(0u64, |total, area| total.saturating_add(area.length));
+    cmp::min(total, usize::max_value() as u64) as usize
+}
+
+// start and size of the largest available area of the memory map, (0, 0) without any
+// an area reaching past the end of the address space is cut at 2^64 - 1
+pub fn largest_region(boot_info: &BootInformation) -> (PhysicalAddress, usize) {
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+    memory_map_tag.memory_areas()
+        .map(|area| {
+            let length = cmp::min(area.length, u64::max_value() - area.base_addr);
+            (area.base_addr as PhysicalAddress, length as usize)
+        })
+        .max_by_key(|&(_, size)| size)
+        .unwrap_or((0, 0))
+}
+
+// the first MiB holds the BIOS data, the VGA buffer and the ROMs, it is never tested
+const LOW_MEMORY_END: PhysicalAddress = 0x10_0000;
+
+// the available RAM a boot time memory test may write and verify: the memory map's
+// areas without the first MiB, the kernel, the multiboot info and every physical
+// reservation (modules, the frame bitmap), sorted by address
+// frames the allocator has handed out are still in there, a test has to restore what
+// it overwrites or run before they are in use
+pub fn testable_memory_ranges(boot_info: &BootInformation, kernel_range: AddressRange)
+    -> Vec<AddressRange>
+{
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+    let areas = memory_map_tag.memory_areas().map(|area| {
+        let length = cmp::min(area.length, u64::max_value() - area.base_addr);
+        (area.base_addr as PhysicalAddress, length as usize)
+    });
+    let mut excluded = vec![kernel_range];
+    excluded.extend(AddressRange::between(boot_info.start_address(), boot_info.end_address()));
+    excluded.extend(RESERVATIONS.lock().phys_ranges().map(|reservation| reservation.range()));
+    testable_ranges(areas, &excluded)
+}
+
+// the parts of the areas (start, size) outside the first MiB and the excluded ranges
+fn testable_ranges<I>(areas: I, excluded: &[AddressRange]) -> Vec<AddressRange>
+    where I: Iterator<Item = (PhysicalAddress, usize)>
+{
+    let low_memory = AddressRange { start: 0, end: LOW_MEMORY_END - 1 };
+    let mut ranges: Vec<AddressRange> = areas
+        .filter_map(|(start, size)| AddressRange::new(start, size))
+        .collect();
+    for hole in Some(low_memory).iter().chain(excluded.iter()) {
+        let mut remaining = Vec::new();
+        for range in ranges {
+            if !range.overlaps(hole) {
+                remaining.push(range);
+                continue;
+            }
+            if range.start < hole.start {
+                remaining.push(AddressRange { start: range.start, end: hole.start - 1 });
+            }
+            if hole.end < range.end {
+                remaining.push(AddressRange { start: hole.end + 1, end: range.end });
+            }
+        }
+        ranges = remaining;
+    }
+    ranges.sort_by_key(|range| range.start);
+    ranges
+}
+
+// first and last frame of the loaded kernel image: the frames the allocated, non-empty
+// ELF sections were loaded to (physical load addresses, not link addresses), or the
+// linker script's ranges if the bootloader gave no ELF sections tag
+pub fn kernel_physical_range(boot_info: &BootInformation) -> (Frame, Frame) {
+    let mut range = None;
+    paging::for_each_kernel_range(boot_info, |start, end, _| {
+        range = extend_load_range(range, start, end);
+    });
+    let (start, end) = range.expect("kernel has no allocated sections");
+
+    (Frame::containing_address(start), Frame::containing_address(end))
+}
+
+// the first and last physical address of `range` and the image range [start, end)
+// together, for kernel_physical_range
+fn extend_load_range(range: Option<(PhysicalAddress, PhysicalAddress)>, start: usize,
+    end: usize) -> Option<(PhysicalAddress, PhysicalAddress)>
+{
+    let load_address = |addr: usize| SectionMapping::detect(addr).physical_address(addr);
+    let (first, last) = (load_address(start), load_address(end - 1));
+    Some(match range {
+        Some((start, end)) => (cmp::min(start, first), cmp::max(end, last)),
+        None => (first, last),
+    })
+}
+
+// the unmapped pages around the heap, an overrun or underrun faults on them
+// usable heap memory is exactly [HEAP_START, HEAP_START + HEAP_SIZE)
+pub fn heap_guard_pages() -> (paging::Page, paging::Page) {
+    use self::paging::Page;
+    use {HEAP_START, HEAP_SIZE};
+
+    (Page::containing_address(HEAP_START - PAGE_SIZE),
+     Page::containing_address(HEAP_START + HEAP_SIZE))
+}
+
+// label for a page fault address, for the page fault handler's report
+pub fn describe_fault_address(address: usize) -> Option<&'static str> {
+    use self::paging::Page;
+
+    let page = Page::containing_address(address);
+    let (lower_guard, upper_guard) = heap_guard_pages();
+    if page == lower_guard || page == upper_guard {
+        Some("heap guard violation")
+    } else {
+        None
+    }
+}
+
+// address of the last byte of [start, start + size)
+// None for an empty range or if the range wraps around the address space
+pub fn checked_last_address(start: usize, size: usize) -> Option<usize> {
+    if size == 0 {
+        return None;
+    }
+    start.checked_add(size - 1)
+}
+
+// is the inclusive range of frame or page numbers [start, end] inverted? a bug the
+// range constructors debug-assert against; the empty range `end + 1 == start` is not
+pub fn is_inverted_range(start: usize, end: usize) -> bool {
+    start > end && start - end != 1
+}
+
+// a non-empty range of addresses [start, end], physical or virtual
+// end is the last address of the range, not the one after it, so a range can reach
+// the top of the address space (the recursive region, the last frame)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl AddressRange {
+
+    // [start, start + size), None if it is empty or wraps around
+    pub fn new(start: usize, size: usize) -> Option<AddressRange> {
+        checked_last_address(start, size).map(|end| AddressRange { start: start, end: end })
+    }
+
+    // [start, end), None if it is empty
+    pub fn between(start: usize, end: usize) -> Option<AddressRange> {
+        if start < end { Some(AddressRange { start: start, end: end - 1 }) } else { None }
+    }
+
+    // saturates at usize::max_value() for the whole address space
+    pub fn size(&self) -> usize {
+        (self.end - self.start).saturating_add(1)
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        self.start <= address && address <= self.end
+    }
+
+    // do the ranges share an address? adjacent ranges don't
+    pub fn overlaps(&self, other: &AddressRange) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    // the addresses in both ranges, None if they don't overlap
+    pub fn intersection(&self, other: &AddressRange) -> Option<AddressRange> {
+        if !self.overlaps(other) {
+            return None;
+        }
+        Some(AddressRange {
+            start: cmp::max(self.start, other.start),
+            end: cmp::min(self.end, other.end),
+        })
+    }
+
+    // the range grown to whole pages, two ranges that touch a common page overlap then
+    pub fn page_bounds(&self) -> AddressRange {
+        AddressRange {
+            start: self.start & !(PAGE_SIZE - 1),
+            end: self.end | (PAGE_SIZE - 1),
+        }
+    }
+
+    // the pages touched by the range
+    pub fn pages(&self) -> paging::PageIter {
+        paging::Page::range_inclusive(paging::Page::containing_address(self.start),
+            paging::Page::containing_address(self.end))
+    }
+
+    // the frames touched by the range
+    fn frames(&self) -> FrameIter {
+        Frame::range_inclusive(Frame::containing_address(self.start),
+            Frame::containing_address(self.end))
     }
 }
 
@@ -66,22 +416,56 @@ pub struct Frame {
 impl Frame {
 
     //iterate frames
+    // an inverted range is a bug, only the empty range `end + 1 == start` is allowed
     fn range_inclusive(start: Frame, end: Frame) -> FrameIter {
+    debug_assert!(!is_inverted_range(start.number, end.number),
+        "inverted frame range: {:?} > {:?}", start, end);
     FrameIter {
         start: start,
         end: end,
     }
 }
 
+    // containing_address for a typed physical address, e.g. the result of translate
+    pub fn containing_phys(address: PhysAddr) -> Frame {
+        Frame::containing_address(address.as_usize())
+    }
+
+    // the frames touched by the bytes [start_address, start_address + size)
+    // empty for size 0, panics if the range wraps around
+    fn range_for_bytes(start_address: PhysicalAddress, size: usize) -> FrameIter {
+        let start = Frame::containing_address(start_address);
+        match checked_last_address(start_address, size) {
+            Some(last_address) => Frame::range_inclusive(start,
+                Frame::containing_address(last_address)),
+            None if size == 0 => FrameIter {
+                start: Frame { number: start.number + 1 },
+                end: start,
+            },
+            None => panic!("physical range {:#x} + {:#x} overflows", start_address, size),
+        }
+    }
+
     // clone a frame
     fn clone(&self) -> Frame {
     Frame { number: self.number }
     }
 
+    // the frame the address lies in
+    // the address doesn't need to be aligned, it is rounded down to the frame start,
+    // so containing_address(start_address() + x) is the same frame for x < PAGE_SIZE
     fn containing_address(address: usize) -> Frame {
         Frame{ number: address / PAGE_SIZE }
     }
 
+    // frame with a known frame number, for allocators that count in frames
+    // the frame must lie in the 52 bit physical address space
+    pub fn from_number(number: usize) -> Frame {
+        debug_assert!(number <= MAX_FRAME_NUMBER,
+            "frame number {:#x} is outside the physical address space", number);
+        Frame { number: number }
+    }
+
     // return the physical address for the frame
     fn start_address(&self) -> PhysicalAddress {
         self.number * PAGE_SIZE
@@ -113,4 +497,55 @@ impl Iterator for FrameIter {
 pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
     fn deallocate_frame(&mut self, frame: Frame);
+
+    // how many frames allocate_frame can still hand out, None if the allocator can't tell
+    fn frames_available(&self) -> Option<usize> {
+        None
+    }
+
+    // claim exactly this frame, e.g. one a device needs or one the bootloader used
+    // false if it is in use (or the allocator can't hand out frames by address)
+    fn allocate_specific_frame(&mut self, _frame: &Frame) -> bool {
+        false
+    }
+
+    // stop all changes so a crash handler can walk the allocator's state and the page
+    // tables without them moving: allocations fail and frees are dropped until thaw
+    // false if the allocator can't be frozen
+    fn freeze(&mut self) -> bool {
+        false
+    }
+
+    fn thaw(&mut self) {}
+
+    // a frame inside the zone, None if the zone has no free frame left
+    // allocators that can't search by address only serve Zone::Any: a frame taken and
+    // given back could leak (AreaFrameAllocator drops freed frames)
+    fn allocate_frame_in_zone(&mut self, zone: Zone) -> Option<Frame> {
+        match zone.end_frame() {
+            Some(_) => None,
+            None => self.allocate_frame(),
+        }
+    }
+}
+
+// physical memory zones for frame allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    Low,    // below 4GiB, for devices with 32 bit physical addresses
+    Any,
+}
+
+impl Zone {
+    // first frame number above the zone, None if it has no upper limit
+    pub fn end_frame(&self) -> Option<usize> {
+        match *self {
+            Zone::Low => Some(0x1_0000_0000 / PAGE_SIZE),
+            Zone::Any => None,
+        }
+    }
+
+    pub fn contains(&self, frame: &Frame) -> bool {
+        self.end_frame().map(|end| frame.number < end).unwrap_or(true)
+    }
 }
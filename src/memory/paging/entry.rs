@@ -2,6 +2,7 @@
 
 use memory::Frame; // needed later
 use multiboot2::ElfSection;
+use core::fmt;
 
 pub struct Entry(u64);
 
@@ -27,6 +28,33 @@ impl EntryFlags {
 
         flags
     }
+
+    // the PROTECTION_KEY bits for the key (0-15)
+    pub fn from_protection_key(key: u8) -> EntryFlags {
+        assert!(key < 16, "protection keys have 4 bits: {}", key);
+        EntryFlags::from_bits_truncate((key as u64) << PROTECTION_KEY_SHIFT)
+    }
+
+    // the key in the PROTECTION_KEY bits
+    pub fn protection_key(&self) -> u8 {
+        ((*self & PROTECTION_KEY).bits() >> PROTECTION_KEY_SHIFT) as u8
+    }
+
+    // the flags of a raw entry value, e.g. one captured for a dump; the address bits
+    // are masked off first, so a frame address never turns into flags
+    pub fn from_raw_entry(raw: u64) -> EntryFlags {
+        EntryFlags::from_bits_truncate(raw & !ADDRESS_MASK)
+    }
+
+    // check if all of the given flags are set (superset test)
+    pub fn contains_all(&self, other: EntryFlags) -> bool {
+        *self & other == other
+    }
+
+    // these flags with the given flags removed, e.g. `flags.minus(WRITABLE)` for read-only
+    pub fn minus(&self, other: EntryFlags) -> EntryFlags {
+        *self & !other
+    }
 }
 
 impl Entry {
@@ -41,18 +69,29 @@ impl Entry {
         self.0 = 0;
     }
 
+    // the whole 64 bit value: address, flags and the bits neither of them covers
+    pub fn raw(&self) -> u64 {
+        self.0
+    }
+
+    // an entry with exactly this value, e.g. one saved with raw() earlier
+    pub fn from_raw(raw: u64) -> Entry {
+        Entry(raw)
+    }
+
     // extract flags from entry
     pub fn flags(&self) -> EntryFlags {
         EntryFlags::from_bits_truncate(self.0)  // drop bits that do not correspond to flag
     }
 
     // extract physical address
+    // None for guard entries, they map nothing
     pub fn pointed_frame(&self) -> Option<Frame> {
         //if entry is present
-        if self.flags().contains(PRESENT) {
+        if self.flags().contains(PRESENT) && !self.is_guard() {
             // return corresponding frame
             Some(Frame::containing_address(
-                self.0 as usize & 0x000fffff_fffff000   //mask bits 12-51 which is the physical address
+                (self.0 & ADDRESS_MASK) as usize   //mask bits 12-51 which is the physical address
             ))
         } else {
             None
@@ -60,14 +99,85 @@ impl Entry {
     }
     // modify entries
     // update flags
+    // the flags are stored exactly as given, PRESENT is not implied: an entry without it
+    // is ignored by the CPU, so other flags without PRESENT are almost certainly a bug
+    // (Mapper::map_to always adds PRESENT)
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         // check if entry is valid
-        assert!(frame.start_address() & !0x000fffff_fffff000 == 0);
+        assert!(frame.start_address() as u64 & !ADDRESS_MASK == 0);
+        debug_assert!(flags.is_empty() || flags.contains(PRESENT),
+            "entry flags {:?} without PRESENT", flags);
         // sets the needed flags from the start address
         self.0 = (frame.start_address() as u64) | flags.bits();
     }
+
+    // replace the flags of a present entry, the frame and the software bits
+    // (SOFTWARE_BITS) stay as they are unless `flags` changes them: COPY_ON_WRITE is only
+    // added, the protection key only replaced by a non-zero key; set_avl and
+    // set_protection_key clear them
+    pub fn update_flags(&mut self, flags: EntryFlags) {
+        debug_assert!(flags.contains(PRESENT), "entry flags {:?} without PRESENT", flags);
+        let mut kept = self.0 & (ADDRESS_MASK | SOFTWARE_BITS);
+        if flags.protection_key() != 0 {
+            kept &= !PROTECTION_KEY.bits();
+        }
+        self.0 = kept | flags.bits();
+    }
+
+    // the three bits (9-11) the CPU ignores and software may use for bookkeeping
+    // bit 0 of the result is bit 9, i.e. COPY_ON_WRITE
+    pub fn get_avl(&self) -> u8 {
+        ((self.0 >> AVL_SHIFT) & AVL_MASK) as u8
+    }
+
+    // replace the available bits, frame and hardware flags stay as they are
+    // only the low three bits of `bits` are used
+    pub fn set_avl(&mut self, bits: u8) {
+        debug_assert!(bits as u64 & !AVL_MASK == 0, "only 3 available bits: {:#b}", bits);
+        self.0 = (self.0 & !(AVL_MASK << AVL_SHIFT)) | ((bits as u64 & AVL_MASK) << AVL_SHIFT);
+    }
+
+    // turn the entry into a guard entry: not present, so any access faults, but tagged
+    // with GUARD_AVL_TAG (the CPU ignores every other bit of a non-present entry) so a
+    // fault handler can tell a guard page hit from an unmapped address
+    pub fn set_guard(&mut self) {
+        self.0 = (GUARD_AVL_TAG as u64) << AVL_SHIFT;
+    }
+
+    pub fn is_guard(&self) -> bool {
+        self.0 == (GUARD_AVL_TAG as u64) << AVL_SHIFT
+    }
+
+    // the protection key of the page (bits 59-62), ignored unless CR4.PKE is set
+    pub fn get_protection_key(&self) -> u8 {
+        self.flags().protection_key()
+    }
+
+    // replace the protection key, frame and the other flags stay as they are
+    pub fn set_protection_key(&mut self, key: u8) {
+        let key_bits = EntryFlags::from_protection_key(key).bits();
+        self.0 = (self.0 & !PROTECTION_KEY.bits()) | key_bits;
+    }
 }
 
+// bits 12-51 of an entry, the 40 bit frame number
+pub const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// position and width of the available bits 9-11
+const AVL_SHIFT: u64 = 9;
+const AVL_MASK: u64 = 0b111;
+
+// available bits value of guard entries, COPY_ON_WRITE (bit 9) stays clear
+pub const GUARD_AVL_TAG: u8 = 0b110;
+
+// position of the protection key bits 59-62
+const PROTECTION_KEY_SHIFT: u64 = 59;
+
+// the bits the CPU ignores and software keeps its bookkeeping in, e.g. COPY_ON_WRITE and
+// the guard tag: the available bits 9-11 and 52-58, plus the protection key (59-62)
+pub const SOFTWARE_BITS: u64 = (AVL_MASK << AVL_SHIFT) | (0x7f << 52) |
+    (0b1111 << PROTECTION_KEY_SHIFT);
+
 // flags of the physical address
 bitflags! {
     pub struct EntryFlags: u64 {
@@ -80,6 +190,96 @@ bitflags! {
         const DIRTY =           1 << 6;
         const HUGE_PAGE =       1 << 7;
         const GLOBAL =          1 << 8;
+        const COPY_ON_WRITE =   1 << 9;     // software bit: read-only shared page, copy on first write
+        const PROTECTION_KEY =  0b1111 << 59;   // 4 bit key, only used with CR4.PKE
         const NO_EXECUTE =      1 << 63;
     }
 }
+
+// compact form for page table dumps, e.g. "P|W|U|NX", "-" without flags
+// the protection key is printed as PK<n> if it isn't 0
+impl fmt::Display for EntryFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = [
+            (PRESENT, "P"), (WRITABLE, "W"), (USER_ACCESSIBLE, "U"), (WRITE_THROUGH, "WT"),
+            (NO_CACHE, "NC"), (ACCESSED, "A"), (DIRTY, "D"), (HUGE_PAGE, "H"),
+            (GLOBAL, "G"), (COPY_ON_WRITE, "COW"), (NO_EXECUTE, "NX"),
+        ];
+        let mut first = true;
+        for &(flag, name) in names.iter() {
+            if self.contains(flag) {
+                write!(f, "{}{}", if first { "" } else { "|" }, name)?;
+                first = false;
+            }
+        }
+        if self.protection_key() != 0 {
+            write!(f, "{}PK{}", if first { "" } else { "|" }, self.protection_key())?;
+            first = false;
+        }
+        if first {
+            write!(f, "-")?;
+        }
+        Ok(())
+    }
+}
+
+// in a P1 entry bit 7 selects the upper half of the PAT instead of a huge page
+pub const PAT_4KIB: EntryFlags = HUGE_PAGE;
+
+// in a 2MiB or 1GiB entry the PAT bit is bit 12, the lowest address bit (a huge page
+// frame is aligned, so the bit is free); not part of EntryFlags since it overlaps
+// the address
+pub const PAT_HUGE_PAGE: u64 = 1 << 12;
+
+// the PAT entries the cache types rely on: the power-on layout, except that
+// PA4 is write-combining instead of write-back (written by the boot code)
+// PA0 WB, PA1 WT, PA2 UC-, PA3 UC, PA4 WC, PA5 WT, PA6 UC-, PA7 UC
+pub const PAT_LAYOUT: u64 = 0x0007_0401_0007_0406;
+
+// memory type of a 4KiB mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,          // PA0: no bits
+    WriteThrough,       // PA1: PWT
+    Uncacheable,        // PA3: PCD | PWT
+    WriteCombining,     // PA4: PAT
+}
+
+impl CacheType {
+    // the PWT, PCD and PAT bits of a P1 entry that select the type in PAT_LAYOUT
+    pub fn flags(&self) -> EntryFlags {
+        match *self {
+            CacheType::WriteBack => EntryFlags::empty(),
+            CacheType::WriteThrough => WRITE_THROUGH,
+            CacheType::Uncacheable => NO_CACHE | WRITE_THROUGH,
+            CacheType::WriteCombining => PAT_4KIB,
+        }
+    }
+}
+
+// what a region of virtual memory is used for, fixes the W^X and cache policy in one
+// place instead of every caller choosing flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    KernelCode,     // read-only, executable
+    KernelData,     // writable, NX
+    Heap,           // writable, NX
+    Stack,          // writable, NX
+    Mmio,           // writable, NX, uncacheable
+    UserCode,       // user, read-only, executable
+    UserData,       // user, writable, NX
+}
+
+impl RegionKind {
+    // the default flags of a mapping of this kind (PRESENT is added by map_to)
+    pub fn flags(&self) -> EntryFlags {
+        match *self {
+            RegionKind::KernelCode => EntryFlags::empty(),
+            RegionKind::KernelData | RegionKind::Heap | RegionKind::Stack =>
+                WRITABLE | NO_EXECUTE,
+            RegionKind::Mmio => WRITABLE | NO_EXECUTE | CacheType::Uncacheable.flags(),
+            RegionKind::UserCode => USER_ACCESSIBLE,
+            RegionKind::UserData => USER_ACCESSIBLE | WRITABLE | NO_EXECUTE,
+        }
+    }
+}
@@ -1,26 +1,163 @@
 //mapping code from ActivePageTable
 //prohibits the closure to call with again and create a second inactive P4 table
+// nothing in here may use println!, the page fault handler maps through this code
+// and would deadlock on the VGA lock if the fault hit while it was held
 
-use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT};
+use super::{VirtualAddress, PhysicalAddress, PhysAddr, Page, PageIter};
+use super::{ENTRIES_PER_TABLE, RECURSIVE_INDEX};
+use super::{HUGE_PAGE_SIZE_2MIB, HUGE_PAGE_SIZE_1GIB};
 use super::entry::*;
-use super::table::{self, Table, Level4, Level1};
-use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use super::table::{self, Table, Level4, Level3, Level2, Level1, PageTableWalker,
+    RecursiveWalker};
+use super::{USER_P4_START, USER_P4_END, RECURSIVE_REGION_START};
+use super::tlb;
+use super::table_pool::TablePool;
+use super::snapshot::{MappingSnapshot, MappedLeaf, VmRegion};
+use memory::{PAGE_SIZE, Frame, FrameAllocator, frozen_reservations, frame_refcount};
 use core::ptr::Unique;
+use core::{cmp, fmt, mem};
+use alloc::vec::Vec;
+use alloc::btree_set::BTreeSet;
 
-pub struct Mapper {
+// why a paging operation failed, shared by all fallible mapping functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    FrameAllocationFailed,          // no frame was available for a page or table
+    AlreadyMapped(Page),            // the page is mapped already
+    NotMapped(Page),                // the page has no mapping to modify
+    InvalidAddress(VirtualAddress), // the address is not canonical
+    Misaligned,                     // a page or frame lacks the alignment of the page size
+    UnsupportedHugePage,            // the mapping has a page size the operation can't handle
+    Pinned(Page),                   // the page's frame is pinned and must not move
+    StackOverflow(Page),            // a stack would grow past its maximum size
+    WrongFlags(Page),               // the page is mapped with other flags than expected
+    BeyondPhysicalMemory(PhysicalAddress),  // the frame is above the installed memory
+    OutOfFrames(Page),              // no frame was left for the page or its tables
+    UnsupportedPagingMode,          // 5-level paging is enabled, see check_four_level_paging
+    AlreadyRemapped,                // remap_the_kernel ran before
+    UnknownFrameCount,              // the allocator can't tell how many frames it has
+    IncompleteKernelTable(VirtualAddress),  // the new kernel table misses this address
+    MissingElfSections,             // the bootloader gave no ELF sections tag
+    WalkTooDeep(VirtualAddress),    // the table at the address was walked as a lower level
+    ActiveTableUnavailable,         // lock taken or no table, see fault_with_active_table
+    RecursiveEntryInUse,            // P4[RECURSIVE_INDEX] maps something else
+    ReservedPage(Page),             // the page is reserved, see ReservationRegistry
+}
+
+impl fmt::Display for PagingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PagingError::FrameAllocationFailed => write!(f, "frame allocation failed"),
+            PagingError::AlreadyMapped(page) =>
+                write!(f, "page 0x{:x} is already mapped", page.start_address()),
+            PagingError::NotMapped(page) =>
+                write!(f, "page 0x{:x} is not mapped", page.start_address()),
+            PagingError::InvalidAddress(address) =>
+                write!(f, "invalid virtual address 0x{:x}", address),
+            PagingError::Misaligned => write!(f, "page or frame is misaligned"),
+            PagingError::UnsupportedHugePage => write!(f, "unsupported huge page mapping"),
+            PagingError::Pinned(page) =>
+                write!(f, "the frame of page 0x{:x} is pinned", page.start_address()),
+            PagingError::StackOverflow(page) =>
+                write!(f, "stack overflow at page 0x{:x}", page.start_address()),
+            PagingError::WrongFlags(page) =>
+                write!(f, "page 0x{:x} has the wrong flags", page.start_address()),
+            PagingError::BeyondPhysicalMemory(address) =>
+                write!(f, "frame 0x{:x} is beyond physical memory", address),
+            PagingError::OutOfFrames(page) =>
+                write!(f, "out of frames while mapping page 0x{:x}", page.start_address()),
+            PagingError::UnsupportedPagingMode =>
+                write!(f, "5-level paging is enabled, only 4 levels are supported"),
+            PagingError::AlreadyRemapped => write!(f, "kernel already remapped"),
+            PagingError::UnknownFrameCount =>
+                write!(f, "the frame allocator can't tell how many frames it has"),
+            PagingError::IncompleteKernelTable(address) =>
+                write!(f, "the new kernel table does not map 0x{:x}", address),
+            PagingError::MissingElfSections =>
+                write!(f, "the multiboot information has no ELF sections tag"),
+            PagingError::WalkTooDeep(address) =>
+                write!(f, "page table walk deeper than 4 levels at table 0x{:x}", address),
+            PagingError::ActiveTableUnavailable =>
+                write!(f, "the active page table is unavailable"),
+            PagingError::RecursiveEntryInUse =>
+                write!(f, "the recursive P4 entry is in use by another mapping"),
+            PagingError::ReservedPage(page) =>
+                write!(f, "page 0x{:x} is reserved", page.start_address()),
+        }
+    }
+}
+
+// the frame must not be above the installed memory, if that bound is set
+fn check_physical_frame(frame: &Frame) -> Result<(), PagingError> {
+    match super::max_physical_frame() {
+        Some(ref max) if frame > max =>
+            Err(PagingError::BeyondPhysicalMemory(frame.start_address())),
+        _ => Ok(()),
+    }
+}
+
+// a table frame validate_table_acyclic found a second time
+#[derive(Debug)]
+pub struct TableCycle {
+    pub frame: Frame,
+    pub level: usize,   // level of the table the second reference expects (3 = P3)
+}
+
+// the frame of the table the entry points to, None for unused and huge page entries
+fn table_frame_of(entry: &Entry) -> Option<Frame> {
+    if entry.flags().contains(HUGE_PAGE) {
+        None
+    } else {
+        entry.pointed_frame()
+    }
+}
+
+// record the table frame of the entry, error if it was seen before
+fn visit(visited: &mut BTreeSet<Frame>, entry: &Entry, level: usize) -> Result<(), TableCycle> {
+    let frame = entry.pointed_frame().unwrap();
+    if visited.contains(&frame) {
+        return Err(TableCycle { frame: frame, level: level });
+    }
+    visited.insert(frame);
+    Ok(())
+}
+
+// a page that verify_translations resolved differently on the two walks
+#[derive(Debug)]
+pub struct MismatchReport {
+    pub page: Page,
+    pub translated: Option<Frame>,  // result of translate_page
+    pub walked: Option<Frame>,      // result of the direct walk
+}
+
+// the walker backend defaults to the recursive mapping, see table::PageTableWalker
+pub struct Mapper<W: PageTableWalker = RecursiveWalker> {
     p4: Unique<Table<Level4>>,
+    walker: W,
+    highest_mapped: Option<Page>,   // see highest_mapped_page
 }
 
-//mapping functions from ActivePageTable
-//with function is removed
-impl Mapper {
+// the core walk, translate and map go through the walker backend
+impl<W> Mapper<W> where W: PageTableWalker {
 
-    pub unsafe fn new() -> Mapper {
+    // mapper for the P4 table at the given virtual address
+    // the walker must be able to reach all tables below it
+    pub unsafe fn with_walker(p4: *mut Table<Level4>, walker: W) -> Mapper<W> {
         Mapper {
-            p4: Unique::new_unchecked(table::P4),
+            p4: Unique::new_unchecked(p4),
+            walker: walker,
+            highest_mapped: None,
         }
     }
 
+    // high-water mark: the highest 4KiB page map_to (and everything built on it) has
+    // mapped through this mapper, None before the first mapping
+    // unmapping never lowers it, it tells how far up the address space was used, not
+    // what is mapped right now; huge page mappings are not counted
+    pub fn highest_mapped_page(&self) -> Option<Page> {
+        self.highest_mapped
+    }
+
     // methods for references to the P4 table
     pub fn p4(&self) -> &Table<Level4> {
         unsafe { self.p4.as_ref() }
@@ -31,74 +168,370 @@ impl Mapper {
 
     // translates virtual address to physical address
     /// Returns `None` if the address is not mapped.
-    pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
-        let offset = virtual_address % PAGE_SIZE;
+    // for huge pages the offset inside the whole 2MiB/1GiB page is added
+    pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysAddr> {
+        self.translate_with_flags(virtual_address)
+            .map(|(address, _)| PhysAddr::new(address))
+    }
+
+    // like translate, but also returns the effective flags of the mapping:
+    // WRITABLE and USER_ACCESSIBLE only if every level allows it,
+    // NO_EXECUTE if any level forbids execution
+    pub fn translate_with_flags(&self, virtual_address: VirtualAddress)
+        -> Option<(PhysicalAddress, EntryFlags)>
+    {
+        let page = Page::containing_address(virtual_address);
+
+        self.translate_leaf(page).map(|(start_frame, size, flags)| {
+            (start_frame.start_address() + virtual_address % size, flags)
+        })
+    }
+
+    // would a read from the address succeed?
+    // only the page tables are consulted: a stale TLB entry or a fault handler that
+    // would fix up the access (copy-on-write, demand paging) is not taken into account,
+    // so this is a statement about the current mapping, not a fault-free guarantee
+    pub fn probe_read(&self, virtual_address: VirtualAddress) -> bool {
+        self.translate_with_flags(virtual_address).is_some()
+    }
+
+    // would a write to the address succeed? same limitations as probe_read
+    // a COPY_ON_WRITE page is reported as not writable
+    pub fn probe_write(&self, virtual_address: VirtualAddress) -> bool {
+        self.effective_writable(virtual_address)
+    }
+
+    // is WRITABLE set at every level from the P4 entry down to the leaf?
+    // what the CPU enforces for user accesses, and for the kernel with CR0.WP (which
+    // rust_main sets); a writable leaf below a read-only table is still read-only
+    // false for unmapped addresses
+    pub fn effective_writable(&self, virtual_address: VirtualAddress) -> bool {
+        self.translate_with_flags(virtual_address)
+            .map(|(_, flags)| flags.contains(WRITABLE))
+            .unwrap_or(false)
+    }
+
+    // is NO_EXECUTE clear at every level? one NX entry on the way makes the whole
+    // region below it non-executable, whatever the leaf says
+    // false for unmapped addresses
+    pub fn effective_executable(&self, virtual_address: VirtualAddress) -> bool {
+        self.translate_with_flags(virtual_address)
+            .map(|(_, flags)| !flags.contains(NO_EXECUTE))
+            .unwrap_or(false)
+    }
+
+    // has the CPU (or map_to_with_state) set DIRTY on the leaf entry, i.e. was the page
+    // written since? false for unmapped addresses
+    pub fn is_dirty(&self, virtual_address: VirtualAddress) -> bool {
+        self.translate_with_flags(virtual_address)
+            .map(|(_, flags)| flags.contains(DIRTY))
+            .unwrap_or(false)
+    }
+
+    // ACCESSED on the leaf entry, the page was read or written since; false for
+    // unmapped addresses
+    pub fn is_accessed(&self, virtual_address: VirtualAddress) -> bool {
+        self.translate_with_flags(virtual_address)
+            .map(|(_, flags)| flags.contains(ACCESSED))
+            .unwrap_or(false)
+    }
+
+    // is USER_ACCESSIBLE set at every level, so ring 3 may access the address?
+    // false for unmapped addresses
+    pub fn effective_user_accessible(&self, virtual_address: VirtualAddress) -> bool {
+        self.translate_with_flags(virtual_address)
+            .map(|(_, flags)| flags.contains(USER_ACCESSIBLE))
+            .unwrap_or(false)
+    }
+
+    // translate many addresses, out[i] is translate(addrs[i])
+    // the P1 table of the previous address is reused while the addresses stay in the same
+    // 2MiB region, so sorted or clustered addresses mostly skip the upper levels
+    pub fn translate_batch(&self, addrs: &[VirtualAddress], out: &mut [Option<PhysAddr>]) {
+        assert!(addrs.len() == out.len(), "translate_batch needs one output per address");
 
-        self.translate_page(Page::containing_address(virtual_address)).map(|frame| frame.number * PAGE_SIZE + offset)
+        let mut cached: Option<(usize, &Table<Level1>)> = None;
+        for (&address, result) in addrs.iter().zip(out.iter_mut()) {
+            let page = Page::containing_address(address);
+            let region = page.number / ENTRIES_PER_TABLE;
+            let hit = match cached {
+                Some((cached_region, p1)) if cached_region == region => Some(p1),
+                _ => None,
+            };
+            let p1 = if hit.is_some() {
+                hit
+            } else {
+                let p1 = self.p4().next_table_with(page.p4_index(), &self.walker)
+                    .and_then(|p3| p3.next_table_with(page.p3_index(), &self.walker))
+                    .and_then(|p2| p2.next_table_with(page.p2_index(), &self.walker));
+                cached = p1.map(|p1| (region, p1));
+                p1
+            };
+
+            *result = match p1 {
+                Some(p1) => p1[page.p1_index()].pointed_frame()
+                    .map(|frame| PhysAddr::new(frame.start_address() + address % PAGE_SIZE)),
+                // huge page or unmapped, take the full walk
+                None => self.translate(address),
+            };
+        }
+    }
+
+    // size of the mapping backing the address: PAGE_SIZE, HUGE_PAGE_SIZE_2MIB or
+    // HUGE_PAGE_SIZE_1GIB, None if the address is not mapped
+    pub fn mapping_page_size(&self, virtual_address: VirtualAddress) -> Option<usize> {
+        self.translate_leaf(Page::containing_address(virtual_address))
+            .map(|(_, size, _)| size)
     }
 
     // takes a page and returns the corresponding frame
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
+        self.translate_leaf(page).map(|(start_frame, size, _)| {
+            let frames_per_page = size / PAGE_SIZE;
+            Frame {
+                number: start_frame.number + page.number % frames_per_page,
+            }
+        })
+    }
 
-        // unsafe to convert the P4 pointer to a reference
-        let p3 = self.p4().next_table(page.p4_index());
-
-        // calculates corresponding frame if huge pages are used
-        let huge_page = || {
-                p3.and_then(|p3| {
-              let p3_entry = &p3[page.p3_index()];
-              // 1GiB page?
-              if let Some(start_frame) = p3_entry.pointed_frame() {
-                  if p3_entry.flags().contains(HUGE_PAGE) {
-                      // address must be 1GiB aligned
-                      assert!(start_frame.number % (ENTRY_COUNT * ENTRY_COUNT) == 0);
-                      return Some(Frame {
-                          number: start_frame.number + page.p2_index() *
-                                  ENTRY_COUNT + page.p1_index(),
-                      });
-                  }
-              }
-              if let Some(p2) = p3.next_table(page.p3_index()) {
-                  let p2_entry = &p2[page.p2_index()];
-                  // 2MiB page?
-                  if let Some(start_frame) = p2_entry.pointed_frame() {
-                      if p2_entry.flags().contains(HUGE_PAGE) {
-                          // address must be 2MiB aligned
-                          assert!(start_frame.number % ENTRY_COUNT == 0);
-                          return Some(Frame {
-                              number: start_frame.number + page.p1_index()
-                          });
-                      }
-                  }
-              }
-              None
-          })
+    // call f(page, its frame and effective flags) for every page of the range, in
+    // order, None for an unmapped page; every leaf is looked up only once, so a huge
+    // page costs one walk for all its pages
+    pub fn for_each_page_in<F>(&self, pages: PageIter, mut f: F)
+        where F: FnMut(Page, Option<(Frame, EntryFlags)>)
+    {
+        self.for_each_leaf_in(pages, |first, count, leaf| {
+            for number in first.number..first.number + count {
+                let page = Page { number: number };
+                match leaf {
+                    Some((ref start_frame, ref size, ref flags)) => {
+                        let frames_per_page = *size / PAGE_SIZE;
+                        let frame = Frame {
+                            number: start_frame.number + number % frames_per_page,
+                        };
+                        f(page, Some((frame, *flags)))
+                    }
+                    None => f(page, None),
+                }
+            }
+        });
+    }
+
+    // the physical memory behind the pages as (start, length) extents in page order,
+    // pages whose frames follow each other share one extent: the shortest
+    // scatter-gather list for a DMA transfer from the range
+    // NotMapped for the first unmapped page
+    pub fn translate_extents(&self, pages: PageIter)
+        -> Result<Vec<(PhysicalAddress, usize)>, PagingError>
+    {
+        let mut extents: Vec<(PhysicalAddress, usize)> = Vec::new();
+        let mut unmapped = None;
+        self.for_each_page_in(pages, |page, mapping| {
+            let frame = match mapping {
+                Some((frame, _)) => frame,
+                None => {
+                    unmapped = unmapped.or(Some(page));
+                    return;
+                }
+            };
+            if let Some(extent) = extents.last_mut() {
+                if extent.0 + extent.1 == frame.start_address() {
+                    extent.1 += PAGE_SIZE;
+                    return;
+                }
+            }
+            extents.push((frame.start_address(), PAGE_SIZE));
+        });
+        match unmapped {
+            Some(page) => Err(PagingError::NotMapped(page)),
+            None => Ok(extents),
+        }
+    }
+
+    // call f(first page, page count, leaf) for the part of every leaf mapping inside
+    // the range, in order; the leaf is (its first frame, its size, the effective
+    // flags) as in translate_leaf, None for a single unmapped page
+    // first page and count are clipped to the range, also for a huge page that is
+    // only partly inside
+    pub fn for_each_leaf_in<F>(&self, pages: PageIter, mut f: F)
+        where F: FnMut(Page, usize, Option<(Frame, usize, EntryFlags)>)
+    {
+        let mut number = pages.start.number;
+        while number <= pages.end.number {
+            let leaf = self.translate_leaf(Page { number: number });
+            let pages_per_leaf = leaf.as_ref().map_or(1, |&(_, size, _)| size / PAGE_SIZE);
+            let next = cmp::min((number / pages_per_leaf + 1) * pages_per_leaf,
+                pages.end.number + 1);
+            f(Page { number: number }, next - number, leaf);
+            number = next;
+        }
+    }
+
+    // walk to the leaf entry that maps the page
+    // returns the first frame of the leaf mapping, its size in bytes
+    // (PAGE_SIZE, HUGE_PAGE_SIZE_2MIB or HUGE_PAGE_SIZE_1GIB) and the effective flags
+    fn translate_leaf(&self, page: Page) -> Option<(Frame, usize, EntryFlags)> {
+        let p4_flags = self.p4()[page.p4_index()].flags();
+        let p3 = match self.p4().next_table_with(page.p4_index(), &self.walker) {
+            Some(p3) => p3,
+            None => return None,
         };
 
-        // use the and_then function to go through the four table levels to find the frame
-        // if some entry is None, we check if the page is a huge page
-        p3.and_then(|p3| p3.next_table(page.p3_index()))
-          .and_then(|p2| p2.next_table(page.p2_index()))
-          .and_then(|p1| p1[page.p1_index()].pointed_frame())
-          .or_else(huge_page)
+        let p3_entry = &p3[page.p3_index()];
+        let p3_flags = effective_flags(p4_flags, p3_entry.flags());
+        // 1GiB page?
+        if let Some(start_frame) = p3_entry.pointed_frame() {
+            if p3_entry.flags().contains(HUGE_PAGE) {
+                // address must be 1GiB aligned
+                assert!(start_frame.number % (ENTRIES_PER_TABLE * ENTRIES_PER_TABLE) == 0);
+                return Some((start_frame, HUGE_PAGE_SIZE_1GIB, p3_flags));
+            }
+        }
+
+        let p2 = match p3.next_table_with(page.p3_index(), &self.walker) {
+            Some(p2) => p2,
+            None => return None,
+        };
+
+        let p2_entry = &p2[page.p2_index()];
+        let p2_flags = effective_flags(p3_flags, p2_entry.flags());
+        // 2MiB page?
+        if let Some(start_frame) = p2_entry.pointed_frame() {
+            if p2_entry.flags().contains(HUGE_PAGE) {
+                // address must be 2MiB aligned
+                assert!(start_frame.number % ENTRIES_PER_TABLE == 0);
+                return Some((start_frame, HUGE_PAGE_SIZE_2MIB, p2_flags));
+            }
+        }
+
+        p2.next_table_with(page.p2_index(), &self.walker)
+          .and_then(|p1| {
+              let entry = &p1[page.p1_index()];
+              entry.pointed_frame()
+                  .map(|frame| (frame, PAGE_SIZE, effective_flags(p2_flags, entry.flags())))
+          })
     }
 
     // map a page to a frame
     /// The `PRESENT` flag is added by default. Needs a
     /// `FrameAllocator` as it might need to create new page tables
+    // panics for pages in the recursive window, use map_to_unchecked to override
     pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
         where A: FrameAllocator
     {
+        assert_outside_recursive_window(page);
+        unsafe { self.map_to_unchecked(page, frame, flags, allocator) }
+    }
+
+    // map_to without the recursive window check
+    // unsafe: a page in [RECURSIVE_REGION_START, 2^64) overwrites a page table entry
+    pub unsafe fn map_to_unchecked<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        allocator: &mut A)
+        where A: FrameAllocator
+    {
+        match self.try_map_to_unchecked(page, frame, flags, allocator) {
+            Ok(()) => {}
+            Err(error @ PagingError::AlreadyMapped(_)) =>
+                panic!("map_to refuses to overwrite a mapping: {} (use update_flags to \
+                        change it, unmap to replace it)", error),
+            Err(error) => panic!("map_to: {}", error),
+        }
+    }
+
+    // the P1 entry is only written if it is unused: a present entry (whatever frame it
+    // points to) or a guard entry is never silently overwritten
+    // OutOfFrames if a table can't be allocated, the tables created so far stay
+    // ReservedPage for a page of a virtual reservation, before any table is created
+    unsafe fn try_map_to_unchecked<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        allocator: &mut A) -> Result<(), PagingError>
+        where A: FrameAllocator
+    {
+        // the reserved pages are only checked in debug builds
+        let reserved = frozen_reservations()
+            .map_or(false, |reservations| reservations.contains_virt(page));
+        if cfg!(debug_assertions) && reserved {
+            return Err(PagingError::ReservedPage(page));
+        }
 
         // return next table if it exist or create a new one
-        let p4 = self.p4_mut();
-        let mut p3 = p4.next_table_create(page.p4_index(), allocator);
-        let mut p2 = p3.next_table_create(page.p3_index(), allocator);
-        let mut p1 = p2.next_table_create(page.p2_index(), allocator);
+        let walker = &self.walker;
+        let p4 = self.p4.as_mut();
+        let mut p3 = p4.try_next_table_create_with(page.p4_index(), allocator, walker)
+            .map_err(|_| PagingError::OutOfFrames(page))?;
+        let mut p2 = p3.try_next_table_create_with(page.p3_index(), allocator, walker)
+            .map_err(|_| PagingError::OutOfFrames(page))?;
+        let mut p1 = p2.try_next_table_create_with(page.p2_index(), allocator, walker)
+            .map_err(|_| PagingError::OutOfFrames(page))?;
 
-        // assert that the page is unmapped and set the present flag
-        assert!(p1[page.p1_index()].is_unused());
+        // refuse to overwrite, then set the present flag
+        if !p1[page.p1_index()].is_unused() {
+            return Err(PagingError::AlreadyMapped(page));
+        }
         p1[page.p1_index()].set(frame, flags | PRESENT);
+        if self.highest_mapped.map_or(true, |highest| page > highest) {
+            self.highest_mapped = Some(page);
+        }
+        Ok(())
+    }
+
+    // map_to that reports a page that is mapped already (as a 4KiB page or as part of a
+    // huge page) instead of panicking
+    pub fn try_map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        allocator: &mut A) -> Result<(), PagingError>
+        where A: FrameAllocator
+    {
+        assert_outside_recursive_window(page);
+        if self.translate_page(page).is_some() {
+            return Err(PagingError::AlreadyMapped(page));
+        }
+        unsafe { self.try_map_to_unchecked(page, frame, flags, allocator) }
+    }
+
+    // map_to with the initial ACCESSED and DIRTY state of the leaf entry given explicitly,
+    // whatever `flags` says about them: a page that is written right away (e.g. zeroed)
+    // saves the CPU the update of the bits, a page mapped clean starts with both clear
+    pub fn map_to_with_state<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        accessed: bool, dirty: bool, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let mut flags = flags.minus(ACCESSED | DIRTY);
+        if accessed {
+            flags = flags | ACCESSED;
+        }
+        if dirty {
+            flags = flags | DIRTY;
+        }
+        self.map_to(page, frame, flags, allocator)
+    }
+
+    // map_to with the protection key `pkey` in the entry
+    // the key only takes effect once CR4.PKE is enabled
+    pub fn map_to_with_pkey<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        pkey: u8, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let flags = flags.minus(PROTECTION_KEY) | EntryFlags::from_protection_key(pkey);
+        self.map_to(page, frame, flags, allocator)
+    }
+
+    // map code that can be executed but, where possible, not read
+    // x86_64 has no execute-only bit: the nearest approximation is a protection key
+    // whose PKRU access disable bit blocks data reads, and keys only apply to user pages
+    // without that (no PKU, CR4.PKE off or a kernel page) the page is mapped read+execute
+    // NO_EXECUTE and WRITABLE are always removed from `flags`
+    // returns whether the page is really execute-only
+    pub fn map_to_exec_only<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        allocator: &mut A) -> bool
+        where A: FrameAllocator
+    {
+        let flags = flags.minus(NO_EXECUTE | WRITABLE);
+        if flags.contains(USER_ACCESSIBLE) && super::execute_only_supported() {
+            self.map_to_with_pkey(page, frame, flags, super::EXEC_ONLY_PKEY, allocator);
+            true
+        } else {
+            self.map_to(page, frame, flags.minus(PROTECTION_KEY), allocator);
+            false
+        }
     }
 
     // method that just picks a free frame for us
@@ -107,30 +540,659 @@ impl Mapper {
     pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
     where A: FrameAllocator
     {
-    let frame = allocator.allocate_frame().expect("out of memory");
-    self.map_to(page, frame, flags, allocator)
+    if let Err(error) = self.try_map(page, flags, allocator) {
+        panic!("map: {}", error);
+    }
+    }
+
+    // map returning the error instead of panicking, OutOfFrames names the page whose
+    // frame or tables couldn't be allocated; the frame goes back to the allocator if
+    // the page can't be mapped
+    pub fn try_map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), PagingError>
+        where A: FrameAllocator
+    {
+        let frame = match allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return Err(PagingError::OutOfFrames(page)),
+        };
+        match self.try_map_to(page, frame.clone(), flags, allocator) {
+            Ok(()) => Ok(()),
+            Err(error) => {
+                allocator.deallocate_frame(frame);
+                Err(error)
+            }
+        }
+    }
+}
+
+// the top 512GiB are the page tables seen through P4[RECURSIVE_INDEX], a mapping
+// there would silently replace a table entry
+fn assert_outside_recursive_window(page: Page) {
+    assert!(outside_recursive_window(page),
+        "refusing to map {:?} inside the recursive mapping window", page);
+}
+
+// the condition of assert_outside_recursive_window
+pub fn outside_recursive_window(page: Page) -> bool {
+    page.start_address() < RECURSIVE_REGION_START
+}
+
+// can the block of `block_pages` pages (512 for 2MiB, 512 * 512 for 1GiB) starting at
+// `page` be mapped with one huge page?
+fn huge_block_fits<F>(page: Page, last: Page, block_pages: usize, frame_for: &F) -> bool
+    where F: Fn(Page) -> Option<Frame>
+{
+    if page.number % block_pages != 0 || page.number + block_pages - 1 > last.number {
+        return false;
+    }
+    let first_frame = match frame_for(page) {
+        Some(frame) => frame.number,
+        None => return false,
+    };
+    first_frame % block_pages == 0 && (1..block_pages).all(|offset| {
+        frame_for(Page { number: page.number + offset })
+            .map_or(false, |frame| frame.number == first_frame + offset)
+    })
+}
+
+// how map_optimal mapped a range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingLayout {
+    pub pages_4kib: usize,
+    pub pages_2mib: usize,
+    pub pages_1gib: usize,
+}
+
+// combine the flags of a table entry with those of the entry below it:
+// the lower entry's flags, restricted by what the upper level allows
+fn effective_flags(upper: EntryFlags, lower: EntryFlags) -> EntryFlags {
+    let restricting = WRITABLE | USER_ACCESSIBLE;
+    let mut flags = lower.minus(restricting) | (lower & upper & restricting);
+    if upper.contains(NO_EXECUTE) {
+        flags = flags | NO_EXECUTE;
+    }
+    flags
+}
+
+//mapping functions from ActivePageTable
+//with function is removed
+impl Mapper {
+
+    pub unsafe fn new() -> Mapper {
+        Mapper {
+            p4: Unique::new_unchecked(table::P4),
+            walker: RecursiveWalker,
+            highest_mapped: None,
+        }
+    }
+
+    // the P1 entry of a 4KiB page
+    // None if a table on the way is missing or the page is part of a huge page
+    pub fn p1_entry(&self, page: Page) -> Option<&Entry> {
+        self.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))
+            .map(|p1| &p1[page.p1_index()])
+    }
+    pub fn p1_entry_mut(&mut self, page: Page) -> Option<&mut Entry> {
+        self.p4_mut().next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .map(|p1| &mut p1[page.p1_index()])
+    }
+
+    // map a 2MiB huge page, page and frame must be 2MiB aligned
+    pub fn map_to_2mib<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        allocator: &mut A)
+        where A: FrameAllocator
+    {
+        assert_outside_recursive_window(page);
+        assert!(page.number % ENTRIES_PER_TABLE == 0, "page is not 2MiB aligned");
+        assert!(frame.number % ENTRIES_PER_TABLE == 0, "frame is not 2MiB aligned");
+
+        let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
+        let p2 = p3.next_table_create(page.p3_index(), allocator);
+
+        assert!(p2[page.p2_index()].is_unused());
+        p2[page.p2_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+    }
+
+    // map a 1GiB huge page, page and frame must be 1GiB aligned
+    // the CPU must support 1GiB pages (CPUID.80000001H:EDX bit 26), otherwise the
+    // entry has a reserved bit set and any access to it faults
+    pub fn map_to_1gib<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        allocator: &mut A)
+        where A: FrameAllocator
+    {
+        const PAGES_PER_1GIB: usize = ENTRIES_PER_TABLE * ENTRIES_PER_TABLE;
+
+        assert_outside_recursive_window(page);
+        assert!(page.number % PAGES_PER_1GIB == 0, "page is not 1GiB aligned");
+        assert!(frame.number % PAGES_PER_1GIB == 0, "frame is not 1GiB aligned");
+
+        let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
+
+        assert!(p3[page.p3_index()].is_unused());
+        p3[page.p3_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+    }
+
+    // map_to_2mib that reports misaligned or already mapped pages instead of panicking
+    pub fn try_map_to_2mib<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        allocator: &mut A) -> Result<(), PagingError>
+        where A: FrameAllocator
+    {
+        if page.number % ENTRIES_PER_TABLE != 0 || frame.number % ENTRIES_PER_TABLE != 0 {
+            return Err(PagingError::Misaligned);
+        }
+        let mapped = (page.number..page.number + ENTRIES_PER_TABLE)
+            .map(|number| Page { number: number })
+            .find(|&page| self.translate_page(page).is_some());
+        if let Some(page) = mapped {
+            return Err(PagingError::AlreadyMapped(page));
+        }
+        self.map_to_2mib(page, frame, flags, allocator);
+        Ok(())
+    }
+
+    // create every P3, P2 and P1 table the range needs without mapping any page, so a
+    // later map_to into the range only writes the leaf entry and never allocates
+    // (e.g. for regions that are mapped from a fault handler)
+    // huge pages in the range are not supported
+    pub fn ensure_tables<A>(&mut self, pages: PageIter, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        if pages.start > pages.end {
+            return;
+        }
+        assert_outside_recursive_window(pages.end);
+        // one P1 table covers ENTRIES_PER_TABLE pages
+        let first = pages.start.number / ENTRIES_PER_TABLE;
+        let last = pages.end.number / ENTRIES_PER_TABLE;
+        for p1_number in first..last + 1 {
+            let page = Page { number: p1_number * ENTRIES_PER_TABLE };
+            let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
+            let p2 = p3.next_table_create(page.p3_index(), allocator);
+            p2.next_table_create(page.p2_index(), allocator);
+        }
+    }
+
+    // number of frames that mapping every unmapped page of the range with map_to would
+    // take: one per page plus the P3, P2 and P1 tables that don't exist yet
+    // lets a caller check up front that the allocator has enough frames
+    pub fn frames_needed_for(&self, pages: PageIter) -> usize {
+        let mut needed = 0;
+        // the last region whose missing table was counted, by pages >> 27/18/9
+        let (mut counted_p3, mut counted_p2, mut counted_p1) = (None, None, None);
+        for page in pages {
+            if self.translate_page(page).is_some() {
+                continue;   // mapped, also as part of a huge page
+            }
+            needed += 1;
+
+            let p3 = self.p4().next_table(page.p4_index());
+            let p2 = p3.and_then(|p3| p3.next_table(page.p3_index()));
+            let p1 = p2.and_then(|p2| p2.next_table(page.p2_index()));
+            if p3.is_none() && counted_p3 != Some(page.number >> 27) {
+                counted_p3 = Some(page.number >> 27);
+                needed += 1;
+            }
+            if p2.is_none() && counted_p2 != Some(page.number >> 18) {
+                counted_p2 = Some(page.number >> 18);
+                needed += 1;
+            }
+            if p1.is_none() && counted_p1 != Some(page.number >> 9) {
+                counted_p1 = Some(page.number >> 9);
+                needed += 1;
+            }
+        }
+        needed
+    }
+
+    // map a 4KiB page with the given memory type, e.g. Uncacheable for MMIO registers or
+    // WriteCombining for a frame buffer
+    // cache bits already in `flags` are replaced, relies on PAT_LAYOUT being loaded
+    pub fn map_to_cached<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        cache: CacheType, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let flags = flags.minus(WRITE_THROUGH | NO_CACHE | PAT_4KIB) | cache.flags();
+        self.map_to(page, frame, flags, allocator)
+    }
+
+    // demote a 2MiB huge page to 512 4KiB pages with the same frames and flags (the PAT
+    // bit moves from bit 12 to bit 7, PAT_4KIB) so per page operations (update_flags,
+    // unmap) work on parts of it afterwards
+    // the region is briefly unmapped while the new P1 is filled, so it must not
+    // contain the running code or stack
+    pub fn split_huge_page<A>(&mut self, page: Page, allocator: &mut A)
+        -> Result<(), PagingError>
+        where A: FrameAllocator
+    {
+        match self.translate_leaf(page) {
+            Some((_, HUGE_PAGE_SIZE_2MIB, _)) => {}
+            Some(_) => return Err(PagingError::UnsupportedHugePage),
+            None => return Err(PagingError::NotMapped(page)),
+        }
+        let table_frame = allocator.allocate_frame()
+            .ok_or(PagingError::FrameAllocationFailed)?;
+
+        let p2 = self.p4_mut().next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .unwrap();
+        let (start_frame, flags) = {
+            let raw = p2[page.p2_index()].raw();
+            let start_frame = Frame::containing_address(
+                (raw & ADDRESS_MASK & !PAT_HUGE_PAGE) as usize);
+            let flags = EntryFlags::from_raw_entry(raw).minus(HUGE_PAGE);
+            (start_frame, if raw & PAT_HUGE_PAGE != 0 { flags | PAT_4KIB } else { flags })
+        };
+
+        // the new P1 allows everything, the leaf entries carry the restrictions
+        p2[page.p2_index()].set(table_frame, PRESENT | WRITABLE | (flags & USER_ACCESSIBLE));
+        let p1 = p2.next_table_mut(page.p2_index()).unwrap();
+        // the recursive address of the P1 mapped the huge page before
+        tlb::flush_address(&*p1 as *const _ as usize);
+
+        for index in 0..ENTRIES_PER_TABLE {
+            p1[index].set(Frame { number: start_frame.number + index }, flags);
+        }
+        // the huge page may be GLOBAL (a kernel page), flush_all would keep it cached
+        tlb::flush_global();
+        Ok(())
+    }
+
+    // inverse of split_huge_page: if all 512 P1 entries of the page's 2MiB region map
+    // contiguous frames starting at a 2MiB aligned frame with identical flags, replace
+    // them by one huge P2 entry and free the P1 table
+    // ACCESSED and DIRTY are ignored when comparing, returns false if not mergeable
+    pub fn try_merge_huge_page<A>(&mut self, page: Page, allocator: &mut A) -> bool
+        where A: FrameAllocator
+    {
+        let ignored = ACCESSED | DIRTY;
+        let (start_frame, flags) = {
+            let p1 = match self.p4().next_table(page.p4_index())
+                .and_then(|p3| p3.next_table(page.p3_index()))
+                .and_then(|p2| p2.next_table(page.p2_index())) {
+                Some(p1) => p1,
+                None => return false,
+            };
+            let start_frame = match p1[0].pointed_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            // bit 7 is PAT in a P1 entry, it would become HUGE_PAGE in the P2 entry
+            let flags = p1[0].flags().minus(ignored);
+            if start_frame.number % ENTRIES_PER_TABLE != 0 || flags.contains(HUGE_PAGE) {
+                return false;
+            }
+            for index in 1..ENTRIES_PER_TABLE {
+                let contiguous = p1[index].pointed_frame()
+                    .map(|frame| frame.number == start_frame.number + index)
+                    .unwrap_or(false);
+                if !contiguous || p1[index].flags().minus(ignored) != flags {
+                    return false;
+                }
+            }
+            (start_frame, flags)
+        };
+
+        let p2 = self.p4_mut().next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .unwrap();
+        let table_frame = p2[page.p2_index()].pointed_frame().unwrap();
+        p2[page.p2_index()].set(start_frame, flags | HUGE_PAGE);
+        // the 4KiB pages may be GLOBAL, flush_all would keep them cached
+        tlb::flush_global();
+
+        allocator.deallocate_frame(table_frame);
+        true
+    }
+
+    // map a demand-zero page
+    // all such pages share the read-only zero frame and are tagged COPY_ON_WRITE,
+    // so the first write fault gives the page its own frame (see handle_cow_fault)
+    // `flags` are the flags the page should have, WRITABLE is dropped until the copy
+    pub fn map_zero_page<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let flags = if flags.contains(WRITABLE) {
+            flags.minus(WRITABLE) | COPY_ON_WRITE
+        } else {
+            flags
+        };
+        self.map_to(page, super::zero_frame(), flags, allocator)
+    }
+
+    // map every page of the range to the frame returned by `frame_provider`
+    // lets special regions take their frames from a reserved pool instead of the allocator
+    // (which is still used for new page tables)
+    // if the provider runs dry the pages mapped so far are unmapped again (their frames
+    // are not given back, they belong to the provider) and an error is returned
+    pub fn map_region_with<F, A>(&mut self, pages: PageIter, flags: EntryFlags,
+        mut frame_provider: F, allocator: &mut A) -> Result<(), PagingError>
+        where F: FnMut() -> Option<Frame>, A: FrameAllocator
+    {
+        let first_page = pages.start;
+        let mut mapped = 0;
+        for page in pages {
+            match frame_provider() {
+                Some(frame) => {
+                    self.map_to(page, frame, flags, allocator);
+                    mapped += 1;
+                }
+                None => {
+                    // roll back
+                    for number in first_page.number..first_page.number + mapped {
+                        self.unmap(Page { number: number }, allocator);
+                    }
+                    return Err(PagingError::FrameAllocationFailed);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // map every page of the range to a new frame from the allocator, the page tables come
+    // from the pool, which is refilled from the allocator whenever it could run dry
+    // (a page needs at most three new tables); on failure the pages mapped so far are
+    // unmapped and their frames freed, the pool keeps its frames for the next call
+    pub fn map_region_bulk<A>(&mut self, pages: PageIter, flags: EntryFlags,
+        pool: &mut TablePool, allocator: &mut A) -> Result<(), PagingError>
+        where A: FrameAllocator
+    {
+        let first_page = pages.start;
+        let mut mapped = 0;
+        for page in pages {
+            if pool.len() < 3 {
+                pool.refill(allocator);
+            }
+            let frame = if pool.len() < 3 { None } else { allocator.allocate_frame() };
+            match frame {
+                Some(frame) => {
+                    self.map_to(page, frame, flags, pool);
+                    mapped += 1;
+                }
+                None => {
+                    for number in first_page.number..first_page.number + mapped {
+                        let page = Page { number: number };
+                        let frame = self.translate_page(page).unwrap();
+                        self.unmap(page, allocator);
+                        allocator.deallocate_frame(frame);
+                    }
+                    return Err(PagingError::FrameAllocationFailed);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // map_region_with with the default flags of the region kind, `flags` replaces them
+    // for the rare mapping that needs something else
+    pub fn map_region_typed<F, A>(&mut self, pages: PageIter, kind: RegionKind,
+        flags: Option<EntryFlags>, frame_provider: F, allocator: &mut A)
+        -> Result<(), PagingError>
+        where F: FnMut() -> Option<Frame>, A: FrameAllocator
+    {
+        let flags = flags.unwrap_or(kind.flags());
+        self.map_region_with(pages, flags, frame_provider, allocator)
+    }
+
+    // map the range with as many 2MiB pages as possible and 4KiB pages for the rest
+    // `frame_for` returns the frame that backs a page and must give the same answer when
+    // asked again; a 2MiB block of the range becomes a huge page if it is 2MiB aligned,
+    // completely inside the range and its frames are contiguous and start 2MiB aligned
+    // so a range starting between two 2MiB boundaries gets a 4KiB prefix, a huge page
+    // body and a 4KiB suffix; returns the number of huge pages used
+    // fails before mapping anything if a page has no frame
+    pub fn map_region_best_fit<F, A>(&mut self, pages: PageIter, frame_for: F,
+        flags: EntryFlags, allocator: &mut A) -> Result<usize, PagingError>
+        where F: Fn(Page) -> Option<Frame>, A: FrameAllocator
+    {
+        if (pages.start.number..pages.end.number + 1)
+            .any(|number| frame_for(Page { number: number }).is_none())
+        {
+            return Err(PagingError::FrameAllocationFailed);
+        }
+
+        let mut huge_pages = 0;
+        let mut number = pages.start.number;
+        while number <= pages.end.number {
+            let page = Page { number: number };
+            if huge_block_fits(page, pages.end, ENTRIES_PER_TABLE, &frame_for) {
+                self.map_to_2mib(page, frame_for(page).unwrap(), flags, allocator);
+                huge_pages += 1;
+                number += ENTRIES_PER_TABLE;
+            } else {
+                self.map_to(page, frame_for(page).unwrap(), flags, allocator);
+                number += 1;
+            }
+        }
+        Ok(huge_pages)
+    }
+
+    // map_region_best_fit with 1GiB pages as well: each block goes into the largest page
+    // that fits (1GiB, then 2MiB, then 4KiB), the returned layout counts the pages of
+    // each size, e.g. to check a large linear map
+    // 1GiB pages need CPU support (CPUID.80000001H:EDX bit 26), see map_to_1gib
+    // fails before mapping anything if a page has no frame
+    pub fn map_optimal<F, A>(&mut self, pages: PageIter, frame_for: F, flags: EntryFlags,
+        allocator: &mut A) -> Result<MappingLayout, PagingError>
+        where F: Fn(Page) -> Option<Frame>, A: FrameAllocator
+    {
+        const PAGES_PER_1GIB: usize = ENTRIES_PER_TABLE * ENTRIES_PER_TABLE;
+
+        if (pages.start.number..pages.end.number + 1)
+            .any(|number| frame_for(Page { number: number }).is_none())
+        {
+            return Err(PagingError::FrameAllocationFailed);
+        }
+
+        let mut layout = MappingLayout { pages_4kib: 0, pages_2mib: 0, pages_1gib: 0 };
+        let mut number = pages.start.number;
+        while number <= pages.end.number {
+            let page = Page { number: number };
+            let frame = frame_for(page).unwrap();
+            if huge_block_fits(page, pages.end, PAGES_PER_1GIB, &frame_for) {
+                self.map_to_1gib(page, frame, flags, allocator);
+                layout.pages_1gib += 1;
+                number += PAGES_PER_1GIB;
+            } else if huge_block_fits(page, pages.end, ENTRIES_PER_TABLE, &frame_for) {
+                self.map_to_2mib(page, frame, flags, allocator);
+                layout.pages_2mib += 1;
+                number += ENTRIES_PER_TABLE;
+            } else {
+                self.map_to(page, frame, flags, allocator);
+                layout.pages_4kib += 1;
+                number += 1;
+            }
+        }
+        Ok(layout)
     }
 
     // identity mapping to make it easier to remap the kernel
     /// Identity map the the given frame with the provided flags.
     /// The `FrameAllocator` is used to create new page tables if needed.
+    // panics for a frame above max_physical_frame, see try_identity_map
     pub fn identity_map<A>(&mut self, frame: Frame, flags: EntryFlags, allocator: &mut A)
         where A: FrameAllocator
     {
-        let page = Page::containing_address(frame.start_address());
+        if let Err(error) = check_physical_frame(&frame) {
+            panic!("identity_map: {}", error);
+        }
+        let page = Page::from_frame_identity(&frame);
         self.map_to(page, frame, flags, allocator)
     }
 
-    // to unmap a page we set the corresponding P1 entry to unused
-    /// Unmaps the given page and adds all freed frames to the given
-    /// `FrameAllocator`.
-    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+    // identity_map returning BeyondPhysicalMemory for a frame above max_physical_frame
+    // (a mapping of memory that isn't there faults on first access) and AlreadyMapped
+    // instead of panicking
+    pub fn try_identity_map<A>(&mut self, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), PagingError>
+        where A: FrameAllocator
+    {
+        check_physical_frame(&frame)?;
+        let page = Page::from_frame_identity(&frame);
+        self.try_map_to(page, frame, flags, allocator)
+    }
+
+    /// Identity map all frames touched by the physical range `[start, start + size)`.
+    // start doesn't need to be frame aligned, the last frame is the one containing
+    // the last byte (start + size - 1), an empty range maps nothing
+    pub fn identity_map_phys_range<A>(&mut self, start: PhysicalAddress, size: usize,
+        flags: EntryFlags, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        for frame in Frame::range_for_bytes(start, size) {
+            self.identity_map(frame, flags, allocator);
+        }
+    }
+
+    // identity_map_phys_range for a range that may share frames with earlier identity
+    // mappings, e.g. boot data next to a kernel section: a frame that is already
+    // identity mapped keeps its mapping and flags; returns how many were skipped
+    // a page that maps another frame is AlreadyMapped, the frames before stay mapped
+    pub fn identity_map_phys_range_shared<A>(&mut self, start: PhysicalAddress, size: usize,
+        flags: EntryFlags, allocator: &mut A) -> Result<usize, PagingError>
+        where A: FrameAllocator
+    {
+        let mut skipped = 0;
+        for frame in Frame::range_for_bytes(start, size) {
+            let page = Page::containing_address(frame.start_address());
+            match self.translate_page(page) {
+                Some(ref mapped) if *mapped == frame => skipped += 1,
+                Some(_) => return Err(PagingError::AlreadyMapped(page)),
+                None => self.map_to(page, frame, flags, allocator),
+            }
+        }
+        Ok(skipped)
+    }
+
+    // the leaf entry that maps the page (P1 entry, or a huge P2/P3 entry)
+    // and the size of the memory it maps
+    fn leaf_entry_mut(&mut self, page: Page) -> Option<(&mut Entry, usize)> {
+        let size = match self.translate_leaf(page) {
+            Some((_, size, _)) => size,
+            None => return None,
+        };
+
+        let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+        if size == HUGE_PAGE_SIZE_1GIB {
+            Some((&mut p3[page.p3_index()], size))
+        } else {
+            let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+            if size == HUGE_PAGE_SIZE_2MIB {
+                Some((&mut p2[page.p2_index()], size))
+            } else {
+                let p1 = p2.next_table_mut(page.p2_index()).unwrap();
+                Some((&mut p1[page.p1_index()], size))
+            }
+        }
+    }
+
+    // change the flags of an existing mapping, the frame stays the same
+    // a huge page keeps HUGE_PAGE and the new flags apply to the whole huge page
+    // the software bits (COPY_ON_WRITE, the available bits, the protection key) are kept
+    // unless `flags` sets them, see Entry::update_flags
+    pub fn update_flags(&mut self, page: Page, flags: EntryFlags)
+        -> Result<(), PagingError>
+    {
+        {
+            let (entry, size) = match self.leaf_entry_mut(page) {
+                Some(leaf) => leaf,
+                None => return Err(PagingError::NotMapped(page)),
+            };
+            let flags = if size == PAGE_SIZE { flags | PRESENT }
+                        else { flags | PRESENT | HUGE_PAGE };
+            entry.update_flags(flags);
+        }
+        tlb::flush_page(page);
+        Ok(())
+    }
+
+    // add flags to the leaf entry of a mapped page, frame and other flags stay the same
+    // the TLB is not flushed: for batch updates that end with a single tlb::flush_all
+    pub fn add_flags_unflushed(&mut self, page: Page, flags: EntryFlags)
+        -> Result<(), PagingError>
+    {
+        let entry = match self.leaf_entry_mut(page) {
+            Some((entry, _)) => entry,
+            None => return Err(PagingError::NotMapped(page)),
+        };
+        let flags = entry.flags() | flags;
+        entry.update_flags(flags);
+        Ok(())
+    }
+
+    // update_flags for every mapped page of the range
+    // a huge page is updated once as a whole, even if the range only covers part of it
+    // (split_huge_page first to protect only a part)
+    pub fn protect_range(&mut self, pages: PageIter, flags: EntryFlags)
+        -> Result<(), PagingError>
+    {
+        let mut number = pages.start.number;
+        while number <= pages.end.number {
+            let page = Page { number: number };
+            let size = match self.translate_leaf(page) {
+                Some((_, size, _)) => size,
+                None => return Err(PagingError::NotMapped(page)),
+            };
+            self.update_flags(page, flags)?;
+
+            // continue after the end of the leaf mapping
+            let pages_per_leaf = size / PAGE_SIZE;
+            number = (number / pages_per_leaf + 1) * pages_per_leaf;
+        }
+        Ok(())
+    }
+
+    // make the unmapped page a guard page: accesses fault like on an unmapped page, but
+    // the entry is tagged (see Entry::set_guard) and is_guard_page recognizes the address
+    // translate reports the page as unmapped, map_to panics on it until
+    // remove_guard_page
+    pub fn install_guard_page<A>(&mut self, page: Page, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        assert_outside_recursive_window(page);
+        let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
+        let p2 = p3.next_table_create(page.p3_index(), allocator);
+        let p1 = p2.next_table_create(page.p2_index(), allocator);
+        assert!(p1[page.p1_index()].is_unused(), "guard page {:?} is in use", page);
+        p1[page.p1_index()].set_guard();
+        tlb::flush_page(page);
+    }
+
+    pub fn remove_guard_page(&mut self, page: Page) {
+        let entry = self.p1_entry_mut(page)
+            .expect("no guard page installed at this page");
+        assert!(entry.is_guard(), "no guard page installed at this page");
+        entry.set_unused();
+    }
+
+    // for the page fault handler: is the address in a page installed as a guard page?
+    pub fn is_guard_page(&self, virtual_address: VirtualAddress) -> bool {
+        self.p1_entry(Page::containing_address(virtual_address))
+            .map_or(false, |entry| entry.is_guard())
+    }
+
+    // unmap the page if it is mapped and return its frame, the caller decides whether
+    // to free it; an unmapped page (or a guard page) is left alone and gives None, so
+    // cleanup code may run twice, e.g. while unwinding from an error
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Option<Frame>
         where A: FrameAllocator
     {
-        use x86_64::instructions::tlb;
-        use x86_64::VirtualAddress;
+        if self.translate_page(page).is_none() {
+            return None;
+        }
+        Some(self.unmap_strict(page))
+    }
 
-        assert!(self.translate(page.start_address()).is_some());
+    // to unmap a page we set the corresponding P1 entry to unused
+    // for callers that know the page is mapped: an unmapped page panics, a huge page too
+    // returns the page's frame, neither the frame nor emptied tables are freed
+    pub fn unmap_strict(&mut self, page: Page) -> Frame {
+        assert!(self.translate(page.start_address()).is_some(),
+            "unmap_strict: {:?} is not mapped", page);
 
         let p1 = self.p4_mut()
                     .next_table_mut(page.p4_index())
@@ -141,9 +1203,469 @@ impl Mapper {
         let frame = p1[page.p1_index()].pointed_frame().unwrap();
         p1[page.p1_index()].set_unused();
 
-        tlb::flush(VirtualAddress(page.start_address()));
-        // TODO free p(1,2,3) table if empty
-        //allocator.deallocate_frame(frame);
+        tlb::flush_page(page);
+        frame
     }
 
+    // map every page of the range to a fresh frame like map, returns the number of pages
+    // mapped; an empty range maps nothing
+    pub fn map_range<A>(&mut self, pages: PageIter, flags: EntryFlags, allocator: &mut A)
+        -> usize
+        where A: FrameAllocator
+    {
+        let mut mapped = 0;
+        for page in pages {
+            self.map(page, flags, allocator);
+            mapped += 1;
+        }
+        mapped
+    }
+
+    // undo map_range: unmap every page of the range and free its frame, pages that are
+    // not mapped are skipped; returns the number of pages unmapped
+    pub fn unmap_range<A>(&mut self, pages: PageIter, allocator: &mut A) -> usize
+        where A: FrameAllocator
+    {
+        let mut unmapped = 0;
+        for page in pages {
+            if let Some(frame) = self.unmap(page, allocator) {
+                allocator.deallocate_frame(frame);
+                unmapped += 1;
+            }
+        }
+        unmapped
+    }
+
+    // map a frame the mapping owns from now on
+    // the frame is moved in, the caller must not keep a clone of it to free or map again;
+    // unmap hands the owned frame back and the caller frees it then
+    pub fn map_owned<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        allocator: &mut A)
+        where A: FrameAllocator
+    {
+        self.map_to(page, frame, flags, allocator)
+    }
+
+    // map a frame that other pages may map too, the frame's map count is incremented
+    pub fn map_shared<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+        allocator: &mut A)
+        where A: FrameAllocator
+    {
+        frame_refcount::increment(&frame);
+        self.map_to(page, frame, flags, allocator)
+    }
+
+    // map `to` to the frame of the 4KiB page `from` with the same flags, both pages are
+    // counted afterwards (an uncounted `from` becomes a shared mapping)
+    pub fn clone_mapping<A>(&mut self, from: Page, to: Page, allocator: &mut A)
+        -> Result<(), PagingError>
+        where A: FrameAllocator
+    {
+        let (frame, flags) = match self.p1_entry(from) {
+            Some(entry) if entry.flags().contains(PRESENT) =>
+                (entry.pointed_frame().unwrap(), entry.flags().minus(ACCESSED | DIRTY)),
+            _ => return Err(PagingError::NotMapped(from)),
+        };
+        if frame_refcount::count(&frame) == 0 {
+            frame_refcount::increment(&frame);
+        }
+        self.map_shared(to, frame, flags, allocator);
+        Ok(())
+    }
+
+    // unmap a page mapped with map_shared or clone_mapping
+    // the frame goes back to the allocator when the last page mapping it is gone,
+    // returns whether it was freed
+    pub fn unmap_shared<A>(&mut self, page: Page, allocator: &mut A) -> bool
+        where A: FrameAllocator
+    {
+        let frame = self.translate_page(page).expect("unmapping a page that is not mapped");
+        self.unmap(page, allocator);
+        if frame_refcount::decrement(&frame) == 0 {
+            allocator.deallocate_frame(frame);
+            true
+        } else {
+            false
+        }
+    }
+
+    // unmap for idempotent teardown: does nothing and returns None if the page is
+    // not mapped (or is a guard page, those go through remove_guard_page), otherwise
+    // unmaps it like unmap and returns its frame; UnsupportedHugePage for a page that
+    // is part of a huge page, which stays mapped
+    // only walks the tables once for the common 4KiB case
+    pub fn unmap_if_mapped<A>(&mut self, page: Page, _allocator: &mut A)
+        -> Result<Option<Frame>, PagingError>
+        where A: FrameAllocator
+    {
+        let unmapped = match self.p1_entry_mut(page) {
+            Some(entry) => {
+                if entry.flags().contains(PRESENT) && !entry.is_guard() {
+                    let frame = entry.pointed_frame();
+                    entry.set_unused();
+                    frame
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+
+        if unmapped.is_some() {
+            tlb::flush_page(page);
+        } else if self.translate_page(page).is_some() {
+            // no P1 table, but the page is part of a huge page
+            return Err(PagingError::UnsupportedHugePage);
+        }
+        Ok(unmapped)
+    }
+
+    // all present 4KiB mappings in the user part of the address space
+    // huge pages are not used for user memory
+    pub fn user_mappings(&self) -> Vec<(Page, Frame, EntryFlags)> {
+        let mut mappings = Vec::new();
+        self.walk_mappings(USER_P4_START, USER_P4_END, |page, frame, flags, size| {
+            if size == PAGE_SIZE {
+                mappings.push((page, frame, flags));
+            }
+        });
+        mappings
+    }
+
+    // call f(first page, first frame, flags, size in bytes) for every present leaf
+    // entry (4KiB, 2MiB or 1GiB), the recursive entry is skipped
+    pub fn for_each_mapping<F>(&self, f: F)
+        where F: FnMut(Page, Frame, EntryFlags, usize)
+    {
+        self.walk_mappings(0, RECURSIVE_INDEX, f)
+    }
+
+    // reverse lookup: every page that maps the frame, in address order
+    // walks the whole table (except the recursive window), a diagnostic tool for
+    // aliasing and shared frames, not for regular use
+    // a frame inside a huge page yields the page of the huge page that maps it
+    pub fn find_pages_for_frame(&self, frame: &Frame) -> Vec<Page> {
+        let mut pages = Vec::new();
+        self.for_each_mapping(|page, start_frame, _, size| {
+            let frames = size / PAGE_SIZE;
+            if start_frame.number <= frame.number && frame.number < start_frame.number + frames {
+                pages.push(Page { number: page.number + frame.number - start_frame.number });
+            }
+        });
+        pages
+    }
+
+    // record every leaf mapping for a later snapshot::diff
+    pub fn snapshot_mappings(&self) -> MappingSnapshot {
+        let mut leaves = Vec::new();
+        self.for_each_mapping(|page, frame, flags, size| {
+            leaves.push(MappedLeaf {
+                page: page,
+                frame_number: frame.number,
+                flags: flags,
+                size: size,
+            });
+        });
+        MappingSnapshot::new(leaves)
+    }
+
+    // the mapped address ranges in vmmap form, see MappingSnapshot::regions
+    pub fn vm_regions(&self) -> Vec<VmRegion> {
+        self.snapshot_mappings().regions()
+    }
+
+    // W^X check: call f with the first page of every leaf mapping that is writable and
+    // executable once the flags of the tables above it are applied, in address order
+    // a hardened kernel table has none; needs no heap, so it can run right after the remap
+    pub fn for_each_wx_violation<F>(&self, mut f: F)
+        where F: FnMut(Page)
+    {
+        self.for_each_mapping(|page, _, _, _| {
+            let flags = self.translate_leaf(page).unwrap().2;
+            if flags.contains(WRITABLE) && !flags.contains(NO_EXECUTE) {
+                f(page);
+            }
+        });
+    }
+
+    // the pages for_each_wx_violation reports
+    pub fn scan_wx_violations(&self) -> Vec<Page> {
+        let mut pages = Vec::new();
+        self.for_each_wx_violation(|page| pages.push(page));
+        pages
+    }
+
+    // sum of the sizes of all present leaf mappings
+    // the leaf counterpart to counting the page table frames
+    pub fn mapped_virtual_bytes(&self) -> usize {
+        let mut bytes = 0;
+        self.for_each_mapping(|_, _, _, size| bytes += size);
+        bytes
+    }
+
+    // walk the leaf entries below the P4 entries [p4_start, p4_end)
+    fn walk_mappings<F>(&self, p4_start: usize, p4_end: usize, mut f: F)
+        where F: FnMut(Page, Frame, EntryFlags, usize)
+    {
+        for p4_index in p4_start..p4_end {
+            let p3 = match self.p4().next_table(p4_index) {
+                Some(p3) => p3,
+                None => continue,
+            };
+            for p3_index in 0..ENTRIES_PER_TABLE {
+                let p3_entry = &p3[p3_index];
+                if p3_entry.flags().contains(PRESENT | HUGE_PAGE) {
+                    let page = Page::from_table_indices(p4_index, p3_index, 0, 0);
+                    f(page, p3_entry.pointed_frame().unwrap(), p3_entry.flags(),
+                      HUGE_PAGE_SIZE_1GIB);
+                    continue;
+                }
+                let p2 = match p3.next_table(p3_index) {
+                    Some(p2) => p2,
+                    None => continue,
+                };
+                for p2_index in 0..ENTRIES_PER_TABLE {
+                    let p2_entry = &p2[p2_index];
+                    if p2_entry.flags().contains(PRESENT | HUGE_PAGE) {
+                        let page = Page::from_table_indices(p4_index, p3_index, p2_index, 0);
+                        f(page, p2_entry.pointed_frame().unwrap(), p2_entry.flags(),
+                          HUGE_PAGE_SIZE_2MIB);
+                        continue;
+                    }
+                    let p1 = match p2.next_table(p2_index) {
+                        Some(p1) => p1,
+                        None => continue,
+                    };
+                    for p1_index in 0..ENTRIES_PER_TABLE {
+                        if let Some(frame) = p1[p1_index].pointed_frame() {
+                            let page = Page::from_table_indices(p4_index, p3_index,
+                                p2_index, p1_index);
+                            f(page, frame, p1[p1_index].flags(), PAGE_SIZE);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // check that no table frame is reachable twice, e.g. a P2 entry pointing back at a
+    // P3 table, which would make walks over the table loop or alias tables
+    // meant for tables built from untrusted input, run it inside `with` before switching
+    pub fn validate_table_acyclic(&self) -> Result<(), TableCycle> {
+        let mut visited = BTreeSet::new();
+        if let Some(p4_frame) = self.p4()[RECURSIVE_INDEX].pointed_frame() {
+            visited.insert(p4_frame);
+        }
+
+        for p4_index in 0..RECURSIVE_INDEX {
+            let p3 = match self.p4().next_table(p4_index) {
+                Some(p3) => p3,
+                None => continue,
+            };
+            visit(&mut visited, &self.p4()[p4_index], 3)?;
+            for p3_index in 0..ENTRIES_PER_TABLE {
+                let p2 = match p3.next_table(p3_index) {
+                    Some(p2) => p2,
+                    None => continue,
+                };
+                visit(&mut visited, &p3[p3_index], 2)?;
+                for p2_index in 0..ENTRIES_PER_TABLE {
+                    if p2.next_table(p2_index).is_some() {
+                        visit(&mut visited, &p2[p2_index], 1)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // physical address of the entry indices[4 - level] in the table of the given level
+    // (4 = the P4 entry indices[0], 1 = the P1 entry indices[3]), the lower indices are
+    // ignored; None if an intermediate table is missing or a huge page
+    pub fn entry_physical_address(&self, level: u8, indices: [usize; 4])
+        -> Option<PhysicalAddress>
+    {
+        assert!(level >= 1 && level <= 4, "invalid table level {}", level);
+        assert!(indices.iter().all(|&index| index < ENTRIES_PER_TABLE),
+            "table index out of range: {:?}", indices);
+
+        let entry_size = mem::size_of::<Entry>();
+        let table_frame = match level {
+            4 => self.p4()[RECURSIVE_INDEX].pointed_frame(),
+            3 => table_frame_of(&self.p4()[indices[0]]),
+            2 => self.p4().next_table(indices[0])
+                .and_then(|p3| table_frame_of(&p3[indices[1]])),
+            _ => self.p4().next_table(indices[0])
+                .and_then(|p3| p3.next_table(indices[1]))
+                .and_then(|p2| table_frame_of(&p2[indices[2]])),
+        };
+        let index = indices[4 - level as usize];
+        table_frame.map(|frame| frame.start_address() + index * entry_size)
+    }
+
+    // compare translate_page with an independent walk for every page of the range
+    // the independent walk computes the recursive addresses of the tables directly from
+    // the page's indices instead of following next_table, returns the first mismatch
+    pub fn verify_translations(&self, pages: PageIter) -> Result<(), MismatchReport> {
+        for page in pages {
+            let translated = self.translate_page(page);
+            let walked = self.walk_direct(page);
+            if translated != walked {
+                return Err(MismatchReport {
+                    page: page,
+                    translated: translated,
+                    walked: walked,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // frame of the page, reading the tables at their recursive addresses
+    // (table::recursive_address) instead of walking through next_table
+    fn walk_direct(&self, page: Page) -> Option<Frame> {
+        let (p4, p3, p2, p1) = (page.p4_index(), page.p3_index(), page.p2_index(),
+            page.p1_index());
+
+        if !self.p4()[p4].flags().contains(PRESENT) {
+            return None;
+        }
+        let p3_table = unsafe {
+            &*(table::recursive_address(&[p4]) as *const Table<Level3>)
+        };
+        let p3_entry = &p3_table[p3];
+        let p3_frame = match p3_entry.pointed_frame() {
+            Some(frame) => frame,
+            None => return None,
+        };
+        if p3_entry.flags().contains(HUGE_PAGE) {
+            return Some(Frame { number: p3_frame.number + (p2 << 9 | p1) });
+        }
+
+        let p2_table = unsafe {
+            &*(table::recursive_address(&[p4, p3]) as *const Table<Level2>)
+        };
+        let p2_entry = &p2_table[p2];
+        let p2_frame = match p2_entry.pointed_frame() {
+            Some(frame) => frame,
+            None => return None,
+        };
+        if p2_entry.flags().contains(HUGE_PAGE) {
+            return Some(Frame { number: p2_frame.number + p1 });
+        }
+
+        let p1_table = unsafe {
+            &*(table::recursive_address(&[p4, p3, p2]) as *const Table<Level1>)
+        };
+        p1_table[p1].pointed_frame()
+    }
+
+    // the present P4 entries outside of the user part, except the recursive entry
+    pub fn kernel_p4_entries(&self) -> Vec<(usize, Frame, EntryFlags)> {
+        let mut entries = Vec::new();
+        for index in 0..RECURSIVE_INDEX {
+            if index >= USER_P4_START && index < USER_P4_END {
+                continue;
+            }
+            let entry = &self.p4()[index];
+            if let Some(frame) = entry.pointed_frame() {
+                entries.push((index, frame, entry.flags()));
+            }
+        }
+        entries
+    }
+
+    // write protect a writable page and tag it COPY_ON_WRITE
+    // the caller has to flush the TLB
+    pub fn mark_cow(&mut self, page: Page) {
+        let entry = self.p1_entry_mut(page).expect("page is not mapped");
+        let flags = entry.flags();
+        if flags.contains(WRITABLE) {
+            let frame = entry.pointed_frame().unwrap();
+            entry.set(frame, flags.minus(WRITABLE) | COPY_ON_WRITE);
+        }
+    }
+
+    // free the P3/P2/P1 tables below a P4 entry and clear the entry
+    // with free_leaves the mapped frames (including huge pages) are freed as well,
+    // the caller has to make sure they are not mapped anywhere else
+    // returns the number of freed frames
+    pub fn free_subtree<A>(&mut self, p4_index: usize, free_leaves: bool, allocator: &mut A)
+        -> usize
+        where A: FrameAllocator
+    {
+        let p3_frame = match self.p4()[p4_index].pointed_frame() {
+            Some(frame) => frame,
+            None => return 0,
+        };
+        let mut freed = 0;
+        {
+            // frees the frames of a leaf mapping if requested, returns how many
+            let free_leaf = |frame: Frame, size: usize, allocator: &mut A| {
+                if !free_leaves {
+                    return 0;
+                }
+                for number in frame.number..frame.number + size / PAGE_SIZE {
+                    allocator.deallocate_frame(Frame { number: number });
+                }
+                size / PAGE_SIZE
+            };
+
+            let p3 = self.p4().next_table(p4_index).unwrap();
+            for p3_index in 0..ENTRIES_PER_TABLE {
+                let p2_frame = match p3[p3_index].pointed_frame() {
+                    Some(frame) => frame,
+                    None => continue,
+                };
+                if p3[p3_index].flags().contains(HUGE_PAGE) {
+                    freed += free_leaf(p2_frame, HUGE_PAGE_SIZE_1GIB, allocator);
+                    continue;
+                }
+                let p2 = p3.next_table(p3_index).unwrap();
+                for p2_index in 0..ENTRIES_PER_TABLE {
+                    let p1_frame = match p2[p2_index].pointed_frame() {
+                        Some(frame) => frame,
+                        None => continue,
+                    };
+                    if p2[p2_index].flags().contains(HUGE_PAGE) {
+                        freed += free_leaf(p1_frame, HUGE_PAGE_SIZE_2MIB, allocator);
+                        continue;
+                    }
+                    let p1 = p2.next_table(p2_index).unwrap();
+                    for p1_index in 0..ENTRIES_PER_TABLE {
+                        if let Some(frame) = p1[p1_index].pointed_frame() {
+                            freed += free_leaf(frame, PAGE_SIZE, allocator);
+                        }
+                    }
+                    allocator.deallocate_frame(p1_frame);
+                    freed += 1;
+                }
+                allocator.deallocate_frame(p2_frame);
+                freed += 1;
+            }
+        }
+
+        self.p4_mut()[p4_index].set_unused();
+        allocator.deallocate_frame(p3_frame);
+        // GLOBAL pages below the entry survive a flush_all
+        tlb::flush_global();
+        freed + 1
+    }
+
+    // make the whole 512GiB of a P4 entry absent at once
+    // with `free` the tables and mapped frames are freed like free_subtree(p4_index, true)
+    // and the number of freed frames is returned; without it only the P4 entry is
+    // cleared, which is fast but leaks the tables and frames below it (returns 0)
+    pub fn clear_p4_entry<A>(&mut self, p4_index: usize, free: bool, allocator: &mut A)
+        -> usize
+        where A: FrameAllocator
+    {
+        assert!(p4_index != RECURSIVE_INDEX, "refusing to clear the recursive entry");
+        if free {
+            return self.free_subtree(p4_index, true, allocator);
+        }
+        self.p4_mut()[p4_index].set_unused();
+        tlb::flush_global();
+        0
+    }
 }
@@ -0,0 +1,42 @@
+// mappings of memory mapped device registers
+// registers are identity mapped (they all lie below 4GiB, inside the identity region)
+// uncacheable, writable and never executable
+
+use super::{ActivePageTable, Page, PhysicalAddress, VirtualAddress, CacheType};
+use super::{WRITABLE, NO_EXECUTE};
+use memory::{Frame, FrameAllocator};
+
+// default physical base of the local APIC registers (IA32_APIC_BASE after reset)
+pub const LOCAL_APIC_DEFAULT_BASE: PhysicalAddress = 0xfee0_0000;
+
+// map the register page containing `phys` and return the virtual address of `phys`
+// a page that is already mapped is left alone, it must map the same frame
+pub fn map_mmio<A>(phys: PhysicalAddress, active_table: &mut ActivePageTable,
+    allocator: &mut A) -> VirtualAddress
+    where A: FrameAllocator
+{
+    let frame = Frame::containing_address(phys);
+    let page = Page::from_frame_identity(&frame);
+    match active_table.translate_page(page) {
+        Some(mapped) => assert!(mapped == frame, "MMIO page {:?} maps another frame", page),
+        None => active_table.map_to_cached(page, frame, WRITABLE | NO_EXECUTE,
+            CacheType::Uncacheable, allocator),
+    }
+    phys
+}
+
+// the local APIC register page at its default address
+pub fn map_local_apic<A>(active_table: &mut ActivePageTable, allocator: &mut A)
+    -> VirtualAddress
+    where A: FrameAllocator
+{
+    map_mmio(LOCAL_APIC_DEFAULT_BASE, active_table, allocator)
+}
+
+// the HPET register page, `phys` comes from the ACPI HPET table
+pub fn map_hpet<A>(phys: PhysicalAddress, active_table: &mut ActivePageTable,
+    allocator: &mut A) -> VirtualAddress
+    where A: FrameAllocator
+{
+    map_mmio(phys, active_table, allocator)
+}
@@ -1,27 +1,230 @@
 // paging module that reads and modifies the hierarchicak page table through recursive mapping
 
 pub use self::entry::*;     //export for all entry types
-pub use self::mapper::Mapper;
-use core::ptr::Unique;
+pub use self::mapper::{Mapper, PagingError, MismatchReport, TableCycle, MappingLayout};
+pub use self::table::{PageTableWalker, RecursiveWalker, OffsetWalker, SecondaryRecursiveWalker,
+    recursive_address};
+pub use self::virtual_address_space::VirtualAddressSpace;
+pub use self::table_pool::{TablePool, TABLE_POOL_SIZE};
+pub use self::temporary_page::ScopedMap;
+pub use self::snapshot::{MappingSnapshot, MappedLeaf, MappingDiff, VmRegion, diff};
+pub use self::mmio::{map_mmio, map_local_apic, map_hpet, LOCAL_APIC_DEFAULT_BASE};
+use core::ptr::{self, Unique};
+use core::{cmp, mem, slice};
 use memory::FrameAllocator;
-use self::table::{Table, Level4};
-use memory::PAGE_SIZE;
+use self::table::{Table, Level4, Level1};
+use memory::{PAGE_SIZE, AddressRange, checked_last_address, is_inverted_range, frame_refcount};
 use memory::Frame;
 use self::temporary_page::TemporaryPage;
 use core::ops::{Deref, DerefMut};
-use multiboot2::BootInformation;
+use core::marker::PhantomData;
+use multiboot2::{BootInformation, ElfSection, ElfSectionsTag};
 use memory::paging::table::P4;
+use spin::{Mutex, Once};
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::vec::Vec;
 
 mod entry;
 mod table;
 mod temporary_page;
 mod mapper;
+mod virtual_address_space;
+mod mmio;
+mod snapshot;
+mod table_pool;
+pub mod tlb;
 
-const ENTRY_COUNT: usize = 512;     // number of entries per table
+pub const ENTRIES_PER_TABLE: usize = 512;   // number of entries per table
+
+// the P4 entry that points to the P4 itself
+pub const RECURSIVE_INDEX: usize = ENTRIES_PER_TABLE - 1;
+
+// P4 slot for editing an inactive table next to the active one, see
+// InactivePageTable::map_secondary_recursive
+pub const SECONDARY_RECURSIVE_INDEX: usize = RECURSIVE_INDEX - 1;
+
+// compile time check, the index arithmetic (9 bits per level) relies on 512
+#[allow(dead_code)]
+const ENTRIES_PER_TABLE_IS_512: [(); 0] = [(); ENTRIES_PER_TABLE - 512];
+
+// first address of the 512GiB reached through the recursive entry P4[RECURSIVE_INDEX]
+// nothing else may be mapped there, it holds the page tables themselves
+pub const RECURSIVE_REGION_START: VirtualAddress = 0xffff_ff80_0000_0000;
+
+// sizes of the memory mapped by a huge P2 and a huge P3 entry
+pub const HUGE_PAGE_SIZE_2MIB: usize = PAGE_SIZE * ENTRIES_PER_TABLE;
+pub const HUGE_PAGE_SIZE_1GIB: usize = HUGE_PAGE_SIZE_2MIB * ENTRIES_PER_TABLE;
 
 pub type PhysicalAddress = usize;
+
+// typed addresses for the public boundary: translate hands out a PhysAddr, which the
+// compiler won't accept where a virtual address is expected (e.g. in
+// Page::containing_address); the arithmetic inside paging stays on the usize aliases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PhysAddr(PhysicalAddress);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VirtAddr(VirtualAddress);
+
+impl PhysAddr {
+    pub fn new(address: PhysicalAddress) -> PhysAddr {
+        PhysAddr(address)
+    }
+
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+impl VirtAddr {
+    pub fn new(address: VirtualAddress) -> VirtAddr {
+        VirtAddr(address)
+    }
+
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+// highest physical address with 52 address bits
+const MAX_PHYSICAL_ADDRESS: PhysicalAddress = 0x000f_ffff_ffff_ffff;
+
+// our PhysicalAddress as the x86_64 crate's type for the control register functions
+pub fn to_x86_phys(address: PhysicalAddress) -> ::x86_64::PhysicalAddress {
+    debug_assert!(address <= MAX_PHYSICAL_ADDRESS,
+        "physical address {:#x} has more than 52 bits", address);
+    ::x86_64::PhysicalAddress(address as u64)
+}
+
+// the x86_64 crate's physical address as ours
+pub fn from_x86_phys(address: ::x86_64::PhysicalAddress) -> PhysicalAddress {
+    debug_assert!(address.0 as usize <= MAX_PHYSICAL_ADDRESS,
+        "physical address {:#x} has more than 52 bits", address.0);
+    address.0 as PhysicalAddress
+}
 pub type VirtualAddress = usize;
 
+// a higher half kernel is linked at this virtual base but loaded at (address - base)
+pub const KERNEL_VIRTUAL_BASE: VirtualAddress = 0xffff_8000_0000_0000;
+
+// how the pages of a kernel section relate to the frames it was loaded to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionMapping {
+    Identity,       // linked at its load address
+    Relocated,      // linked at KERNEL_VIRTUAL_BASE + load address
+}
+
+impl SectionMapping {
+
+    // detect the mode from the section's virtual (link) address
+    pub fn detect(virtual_address: VirtualAddress) -> SectionMapping {
+        if virtual_address >= KERNEL_VIRTUAL_BASE {
+            SectionMapping::Relocated
+        } else {
+            SectionMapping::Identity
+        }
+    }
+
+    // the physical address the bootloader loaded the given virtual address to
+    pub fn physical_address(&self, virtual_address: VirtualAddress) -> PhysicalAddress {
+        match *self {
+            SectionMapping::Identity => virtual_address,
+            SectionMapping::Relocated => virtual_address - KERNEL_VIRTUAL_BASE,
+        }
+    }
+}
+
+// the kernel image as src/arch/x86_64/linker.ld lays it out, [start, end) per range
+// the fallback for the remap when the bootloader gives no ELF sections tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkerSymbols {
+    pub rodata: (VirtualAddress, VirtualAddress),   // starts with the multiboot header
+    pub text: (VirtualAddress, VirtualAddress),
+    pub data: (VirtualAddress, VirtualAddress),     // .data, .bss, .got and .got.plt
+    pub relro: (VirtualAddress, VirtualAddress),    // .data.rel.ro, .gcc_except_table
+}
+
+extern "C" {
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __kernel_end: u8;
+}
+
+impl LinkerSymbols {
+
+    // the addresses of the symbols the linker script defines
+    pub fn from_linker() -> LinkerSymbols {
+        let address = |symbol: &u8| symbol as *const u8 as VirtualAddress;
+        unsafe {
+            LinkerSymbols {
+                rodata: (address(&__rodata_start), address(&__rodata_end)),
+                text: (address(&__text_start), address(&__text_end)),
+                data: (address(&__data_start), address(&__data_end)),
+                relro: (address(&__data_end), address(&__kernel_end)),
+            }
+        }
+    }
+
+    // the ranges with the flags from_elf_section_flags would give their sections
+    pub fn ranges(&self) -> [(VirtualAddress, VirtualAddress, EntryFlags); 4] {
+        [
+            (self.rodata.0, self.rodata.1, PRESENT | NO_EXECUTE),
+            (self.text.0, self.text.1, PRESENT),
+            (self.data.0, self.data.1, PRESENT | WRITABLE | NO_EXECUTE),
+            (self.relro.0, self.relro.1, PRESENT | NO_EXECUTE),
+        ]
+    }
+}
+
+// the ELF sections tag, MissingElfSections if the bootloader gave none: some (e.g.
+// ones loading a flat binary) don't, the callers fall back to the linker symbols
+pub fn elf_sections_tag(boot_info: &BootInformation)
+    -> Result<&'static ElfSectionsTag, PagingError>
+{
+    boot_info.elf_sections_tag().ok_or(PagingError::MissingElfSections)
+}
+
+// call f(start, end, flags) for every non-empty range of the kernel image: the allocated
+// ELF sections, or the linker script's ranges if there is no ELF sections tag
+pub fn for_each_kernel_range<F>(boot_info: &BootInformation, mut f: F)
+    where F: FnMut(VirtualAddress, VirtualAddress, EntryFlags)
+{
+    match elf_sections_tag(boot_info) {
+        Ok(elf_sections_tag) => for_each_section_range(elf_sections_tag.sections(), f),
+        Err(_) => {
+            for &(start, end, flags) in LinkerSymbols::from_linker().ranges().iter() {
+                if start < end {
+                    f(start, end, flags);
+                }
+            }
+        }
+    }
+}
+
+// the ELF sections part of for_each_kernel_range: f for every allocated, non-empty section
+pub fn for_each_section_range<'a, I, F>(sections: I, mut f: F)
+    where I: Iterator<Item = &'a ElfSection>,
+          F: FnMut(VirtualAddress, VirtualAddress, EntryFlags)
+{
+    for section in sections.filter(|s| s.is_allocated() && s.size > 0) {
+        f(section.start_address(), section.end_address(),
+          EntryFlags::from_elf_section_flags(section));
+    }
+}
+
+// P4 entries that make up the user part of the address space (end exclusive)
+// P4[0] holds the identity mapped kernel and the heap, the higher half is kernel only
+pub const USER_P4_START: usize = 1;
+pub const USER_P4_END: usize = 256;
+
+// the identity mapped kernel lives in P4[0], only there a page and a frame
+// with the same number describe the same memory
+pub const IDENTITY_REGION_END: VirtualAddress = ENTRIES_PER_TABLE * HUGE_PAGE_SIZE_1GIB;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
    number: usize,
@@ -30,6 +233,9 @@ pub struct Page {
 impl Page {
 
     // get the Page from the virtual address
+    // every canonical address works, up to 0xffff_ffff_ffff_ffff (the last page of
+    // P4[511]); for the end of a range use range_for_bytes instead of start + size,
+    // which overflows there
     pub fn containing_address(address: VirtualAddress) -> Page {
     // make sure we do not access a invalid virtual adress
     // address space is split up into two halves, one with sign extension adresses and one without
@@ -40,10 +246,57 @@ impl Page {
     Page { number: address / PAGE_SIZE }
     }
 
-    fn start_address(&self) -> usize {
+    // containing_address for a typed virtual address
+    pub fn containing_virt(address: VirtAddr) -> Page {
+        Page::containing_address(address.as_usize())
+    }
+
+    // containing_address for addresses that come from outside the kernel, a non
+    // canonical address is an error instead of a panic
+    pub fn try_containing_address(address: VirtualAddress) -> Result<Page, PagingError> {
+        if address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000 {
+            Ok(Page { number: address / PAGE_SIZE })
+        } else {
+            Err(PagingError::InvalidAddress(address))
+        }
+    }
+
+    pub fn start_address(&self) -> usize {
     self.number * PAGE_SIZE
     }
 
+    // the pages touched by the bytes [start_address, start_address + size)
+    // empty for size 0, panics if the range wraps around
+    pub fn range_for_bytes(start_address: VirtualAddress, size: usize) -> PageIter {
+        let start = Page::containing_address(start_address);
+        match checked_last_address(start_address, size) {
+            Some(last_address) => Page::range_inclusive(start,
+                Page::containing_address(last_address)),
+            None if size == 0 => PageIter {
+                start: Page { number: start.number + 1 },
+                end: start,
+            },
+            None => panic!("virtual range {:#x} + {:#x} overflows", start_address, size),
+        }
+    }
+
+    // the page reached through the given table indexes
+    // sign extends the number for pages in the higher half
+    pub fn from_table_indices(p4: usize, p3: usize, p2: usize, p1: usize) -> Page {
+        let mut number = (p4 << 27) | (p3 << 18) | (p2 << 9) | p1;
+        if p4 >= 256 {
+            number |= 0xffff << 36;    // bits 48-63 of the address
+        }
+        Page { number: number }
+    }
+
+    // the page with the same number as the frame, for identity mapped memory
+    pub fn from_frame_identity(frame: &Frame) -> Page {
+        debug_assert!(frame.start_address() < IDENTITY_REGION_END,
+            "{:?} is outside the identity mapped region", frame);
+        Page { number: frame.number }
+    }
+
     // returns the different table indexes
     fn p4_index(&self) -> usize {
     (self.number >> 27) & 0o777
@@ -57,7 +310,10 @@ impl Page {
     fn p1_index(&self) -> usize {
         (self.number >> 0) & 0o777
     }
+    // an inverted range is a bug, only the empty range `end + 1 == start` is allowed
     pub fn range_inclusive(start: Page, end: Page) -> PageIter {
+    debug_assert!(!is_inverted_range(start.number, end.number),
+        "inverted page range: {:?} > {:?}", start, end);
     PageIter {
         start: start,
         end: end,
@@ -73,17 +329,60 @@ pub struct PageIter {
 impl Iterator for PageIter {
     type Item = Page;
 
+    // the last page empties the range without incrementing past `end`, so a range
+    // ending at the last page can't wrap around and start over
     fn next(&mut self) -> Option<Page> {
-        if self.start <= self.end {
+        if self.start < self.end {
             let page = self.start;
             self.start.number += 1;
             Some(page)
+        } else if self.start == self.end {
+            let page = self.start;
+            self.start = Page { number: 1 };
+            self.end = Page { number: 0 };
+            Some(page)
+        } else {
+            None
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = if self.start > self.end { 0 }
+                  else { self.end.number - self.start.number + 1 };
+        (len, Some(len))
+    }
+}
+
+// next_back ends an empty range the same way as next, so both sides agree on exhaustion
+// and `end` never decrements below `start`
+impl DoubleEndedIterator for PageIter {
+    fn next_back(&mut self) -> Option<Page> {
+        if self.start < self.end {
+            let page = self.end;
+            self.end.number -= 1;
+            Some(page)
+        } else if self.start == self.end {
+            let page = self.end;
+            self.start = Page { number: 1 };
+            self.end = Page { number: 0 };
+            Some(page)
         } else {
             None
         }
     }
 }
 
+impl ExactSizeIterator for PageIter {}
+
+impl Frame {
+    // the frame with the same number as the page, for identity mapped memory
+    pub fn from_page_identity(page: Page) -> Frame {
+        debug_assert!(page.start_address() < IDENTITY_REGION_END,
+            "{:?} is outside the identity mapped region", page);
+        Frame { number: page.number }
+    }
+}
+
 // P4 table is owned by the ActivePageTable struct
 // use unique to indicate ownership
 pub struct ActivePageTable {
@@ -122,59 +421,418 @@ impl ActivePageTable {
                //closure gets a Mapper as argument instead of ActivePageTable
     where F: FnOnce(&mut Mapper)
     {
-        use x86_64::instructions::tlb;
-        use x86_64::registers::control_regs;
+    table.edited = true;
+    {
+        // the recursive mapping points to the inactive table until the guard is dropped
+        let _guard = RecursiveRemapGuard::new(self, table.p4_frame.clone(), temporary_page);
+
+        // execute f in the new context when the recursive mapping now points to an inactive table
+        f(self);
+    }
+
+        temporary_page.unmap(self);
+    }
 
+    // switch tables
+    // reload cr3 with the physical address of the new P4 frame
+    // the CR3 write drops every cached translation except the GLOBAL ones (with
+    // CR4.PGE); those are the kernel's and the same in every table, since the kernel
+    // tables are shared. A table edited while inactive may map a global page
+    // differently, so switching to it also flushes the global translations
+    // refuses (and gives the table back as Err) if the new P4[RECURSIVE_INDEX] doesn't point to the
+    // table itself, further page table edits would be impossible after the switch
+    pub fn switch(&mut self, new_table: InactivePageTable, temporary_page: &mut TemporaryPage)
+        -> Result<InactivePageTable, InactivePageTable>
     {
-        //create backup of the P4 entry by reading it from the CR3 control register
-        //to restore it after the closure has run
-        let backup = Frame::containing_address(
-            control_regs::cr3().0 as usize);
+    use x86_64::registers::control_regs;
+
+    let recursive_entry_ok = new_table.recursive_entry(self, temporary_page)
+        .map(|(frame, flags)| frame == new_table.p4_frame && flags.contains(PRESENT | WRITABLE))
+        .unwrap_or(false);
+    if !recursive_entry_ok {
+        return Err(new_table);
+    }
+
+    let old_table = InactivePageTable {
+        p4_frame: Frame::containing_address(from_x86_phys(control_regs::cr3())),
+        edited: false,
+    };
+    unsafe {
+        control_regs::cr3_write(to_x86_phys(new_table.p4_frame.start_address()));
+    }
+    if switch_needs_global_flush(new_table.edited, tlb::global_pages_enabled()) {
+        tlb::flush_global();
+    }
+    Ok(old_table)
+}
+}
+
+// must a switch to the table flush the global translations, which the CR3 write keeps?
+// only if the table was edited while inactive and global pages are on at all
+fn switch_needs_global_flush(edited: bool, global_pages_enabled: bool) -> bool {
+    edited && global_pages_enabled
+}
+
+// points the recursive entry of the active P4 to another P4 frame while it lives and
+// restores the original entry on drop, so no path out of `with` can forget it
+// (the kernel aborts on panic for now, a panicking closure never reaches the drop)
+// the active P4 is written through the temporary page: once the entry is swapped, the
+// recursive addresses reach the other table
+struct RecursiveRemapGuard<'a> {
+    active_p4: &'a mut Table<Level1>,   // the active P4, mapped at the temporary page
+    backup: Frame,                      // the active P4 frame, from CR3
+}
 
-        // map temporary_page to current p4 table
-        let p4_table = temporary_page.map_table_frame(backup.clone(), self);
+impl<'a> RecursiveRemapGuard<'a> {
 
-        // overwrite recursive mapping
-        // overwrite P4 entry and point it to the inactive table frame
-        self.p4_mut()[511].set(table.p4_frame.clone(), PRESENT | WRITABLE);
+    fn new(active_table: &mut ActivePageTable, target: Frame,
+        temporary_page: &'a mut TemporaryPage) -> RecursiveRemapGuard<'a>
+    {
+        use x86_64::registers::control_regs;
+
+        //create backup of the P4 entry by reading it from the CR3 control register
+        let backup = Frame::containing_address(from_x86_phys(control_regs::cr3()));
+        let active_p4 = temporary_page.map_table_frame(backup.clone(), active_table);
 
+        active_p4[RECURSIVE_INDEX].set(target, PRESENT | WRITABLE);
         //flush TLB so no old translations exist
         tlb::flush_all();
 
-        // execute f in the new context when the recursive mapping now points to an inactive table
-        f(self);
+        RecursiveRemapGuard {
+            active_p4: active_p4,
+            backup: backup,
+        }
+    }
+}
 
+impl<'a> Drop for RecursiveRemapGuard<'a> {
+    fn drop(&mut self) {
         // restore recursive mapping to original p4 table
-        p4_table[511].set(backup, PRESENT | WRITABLE);
+        self.active_p4[RECURSIVE_INDEX].set(self.backup.clone(), PRESENT | WRITABLE);
         tlb::flush_all();
     }
+}
 
-        temporary_page.unmap(self);
+// the page after the default temporary page keeps a mapping of the active P4, so the
+// P4 can be reached even when the recursive entry is broken (see install_p4_alias)
+const P4_ALIAS_PAGE_NUMBER: usize = temporary_page::TEMP_PAGE_NUMBER + 1;
+
+// the P4 entries as (index, flags, pointed frame), all 512 in index order
+pub struct P4EntryIter<'a> {
+    p4: &'a Table<Level4>,
+    index: usize,
+}
+
+impl<'a> Iterator for P4EntryIter<'a> {
+    type Item = (usize, EntryFlags, Option<Frame>);
+
+    fn next(&mut self) -> Option<(usize, EntryFlags, Option<Frame>)> {
+        if self.index == ENTRIES_PER_TABLE {
+            return None;
+        }
+        let entry = &self.p4[self.index];
+        self.index += 1;
+        Some((self.index - 1, entry.flags(), entry.pointed_frame()))
     }
+}
 
-    // switch tables
-    // reload cr3 with the physical address of the new P4 frame
-    pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
-    use x86_64::PhysicalAddress;
+impl ActivePageTable {
+
+    // read-only view of the active P4 through the recursive mapping, the top level of a
+    // page table browser; the entry at RECURSIVE_INDEX points to the P4 itself
+    pub fn p4_entries(&self) -> P4EntryIter {
+        P4EntryIter { p4: self.p4(), index: 0 }
+    }
+}
+
+// set once the alias maps the active P4
+static P4_ALIAS: Once<Frame> = Once::new();
+
+impl ActivePageTable {
+
+    // map the active P4 at P4_ALIAS_PAGE_NUMBER for ensure_recursive_mapping
+    // a permanent temporary page: it has to exist before the recursive entry breaks,
+    // afterwards nothing could be mapped; refers to the P4 active at the call, so it
+    // goes stale with a switch to another table
+    pub fn install_p4_alias<A>(&mut self, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        use x86_64::registers::control_regs;
+
+        let p4_frame = Frame::containing_address(from_x86_phys(control_regs::cr3()));
+        let page = Page { number: P4_ALIAS_PAGE_NUMBER };
+        self.map_to(page, p4_frame.clone(), WRITABLE | NO_EXECUTE, allocator);
+        P4_ALIAS.call_once(|| p4_frame);
+    }
+
+    // health check for the recursive entry: if P4[RECURSIVE_INDEX] doesn't point to the
+    // active P4 (cleared or overwritten), it is set again through the P4 alias, since
+    // the recursive addresses themselves are what's broken; returns whether it repaired
+    // panics if install_p4_alias wasn't called or another table is active
+    pub fn ensure_recursive_mapping(&mut self) -> bool {
+        use x86_64::registers::control_regs;
+
+        let p4_frame = Frame::containing_address(from_x86_phys(control_regs::cr3()));
+        let entry = &mut self.aliased_p4()[RECURSIVE_INDEX];
+        let intact = entry.pointed_frame().map_or(false, |frame| frame == p4_frame) &&
+            entry.flags().contains(PRESENT | WRITABLE);
+        if intact {
+            return false;
+        }
+        entry.set(p4_frame, PRESENT | WRITABLE);
+        tlb::flush_all();
+        true
+    }
+
+    // the recursive entry as it is now, for table surgery that repoints
+    // P4[RECURSIVE_INDEX] by hand where `with` doesn't fit
+    // every save must be paired with a restore_recursive_entry before the recursive
+    // addresses are used for the active table again
+    pub fn save_recursive_entry(&self) -> Entry {
+        Entry::from_raw(self.p4()[RECURSIVE_INDEX].raw())
+    }
+
+    // write a saved recursive entry back and flush the TLB, without the flush stale
+    // translations of the recursive region would still reach the other table
+    // the write goes through the P4 alias because the recursive addresses may point
+    // anywhere right now; panics if install_p4_alias wasn't called
+    pub fn restore_recursive_entry(&mut self, saved: Entry) {
+        self.aliased_p4()[RECURSIVE_INDEX] = saved;
+        tlb::flush_all();
+    }
+
+    // the active P4 through the P4 alias, independent of the recursive entry
+    // panics if install_p4_alias wasn't called or another table is active
+    fn aliased_p4(&mut self) -> &mut Table<Level4> {
+        use x86_64::registers::control_regs;
+
+        let alias_frame = P4_ALIAS.try().expect("no P4 alias installed");
+        let p4_frame = Frame::containing_address(from_x86_phys(control_regs::cr3()));
+        assert!(*alias_frame == p4_frame, "the P4 alias belongs to another table");
+
+        let page = Page { number: P4_ALIAS_PAGE_NUMBER };
+        unsafe { &mut *(page.start_address() as *mut Table<Level4>) }
+    }
+
+    // zero all pages of the range with one write per leaf mapping, so a region backed
+    // by huge pages is cleared in 2MiB/1GiB steps instead of page by page
+    // the whole range must be mapped writable, an unmapped page panics
+    pub fn clear_mapped_range(&mut self, pages: PageIter) {
+        self.for_each_leaf_in(pages, |first, count, leaf| {
+            assert!(leaf.is_some(), "clear_mapped_range: page is not mapped");
+            unsafe {
+                ptr::write_bytes(first.start_address() as *mut u8, 0, count * PAGE_SIZE);
+            }
+        });
+    }
+
+    // `count` values of T at `address`, None if the address is not aligned for T or any
+    // page the slice touches is unmapped, so reading the slice can't fault halfway
+    // the mapping must not change while the slice is borrowed
+    pub fn mapped_slice<T>(&self, address: VirtualAddress, count: usize) -> Option<&[T]> {
+        if address % mem::align_of::<T>() != 0 {
+            return None;
+        }
+        let size = match count.checked_mul(mem::size_of::<T>()) {
+            Some(size) => size,
+            None => return None,
+        };
+        if size == 0 {
+            return Some(&[]);
+        }
+        let last_address = match checked_last_address(address, size) {
+            Some(last_address) => last_address,
+            None => return None,    // wraps around the address space
+        };
+        let start_page = Page::containing_address(address);
+        let end_page = Page::containing_address(last_address);
+        let all_mapped = Page::range_inclusive(start_page, end_page)
+            .all(|page| self.translate_page(page).is_some());
+        if !all_mapped {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts(address as *const T, count) })
+    }
+
+    // map every page of the range to a fresh frame right away instead of on first access
+    // with `zero` the frames are cleared through the new mapping (read-only pages are
+    // mapped writable for that and then downgraded to `flags`)
+    // if the allocator runs dry or a page is mapped already, everything mapped so far is
+    // unmapped and freed again
+    // silent: every failure is returned instead of panicking, grow_stack_on_fault calls
+    // this from the page fault handler
+    pub fn prefault_range<A>(&mut self, pages: PageIter, flags: EntryFlags, zero: bool,
+        allocator: &mut A) -> Result<(), PagingError>
+        where A: FrameAllocator
+    {
+        let first_page = pages.start;
+        let mut mapped = 0;
+        let mut result = Ok(());
+        for page in pages {
+            let frame = match allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => {
+                    result = Err(PagingError::FrameAllocationFailed);
+                    break;
+                }
+            };
+
+            let map_flags = if zero { flags | WRITABLE } else { flags };
+            if let Err(error) = self.try_map_to(page, frame.clone(), map_flags, allocator) {
+                allocator.deallocate_frame(frame);
+                result = Err(error);
+                break;
+            }
+            mapped += 1;
+            if zero {
+                zero_page(page.start_address());
+                if !flags.contains(WRITABLE) {
+                    self.update_flags(page, flags)?;
+                }
+            }
+        }
+
+        if result.is_err() {
+            // roll back
+            for number in first_page.number..first_page.number + mapped {
+                let page = Page { number: number };
+                let frame = self.translate_page(page).unwrap();
+                self.unmap(page, allocator);
+                allocator.deallocate_frame(frame);
+            }
+        }
+        result
+    }
+}
+
+// the active table after remap_the_kernel, behind a spinlock so interrupt handlers
+// and the main flow can't race on CR3 or the recursive entry (with() is the worst case)
+// the lock must never be held across a context switch, the other thread would spin forever
+// it is not reentrant either, nothing that runs while it is held may take it again:
+// with_active_table keeps interrupts disabled for the whole critical section, and since
+// cli doesn't hold off a page fault, the fault paths (handle_cow_fault,
+// grow_stack_on_fault) reach the table through fault_with_active_table, which fails
+// instead of spinning on a lock the interrupted code holds
+static ACTIVE_TABLE: Mutex<Option<ActivePageTable>> = Mutex::new(None);
+
+// print every present leaf mapping as "page -> frame size flags" for debugging
+// the recursive window is left out
+pub fn dump_mappings(mapper: &Mapper) {
+    mapper.for_each_mapping(|page, frame, flags, size| {
+        println!("{:#x} -> {:#x} {}KiB {}", page.start_address(), frame.start_address(),
+            size / 1024, flags);
+    });
+}
+
+// for bootloaders that hand over a table without the recursive entry (our boot.asm
+// sets it up itself): point P4[RECURSIVE_INDEX] of the active P4 to the P4, after
+// that ActivePageTable::new is valid
+// the P4 is written at its physical address, so its frame must be identity mapped,
+// as it is in tables that identity map the low memory the kernel was loaded to
+// returns false if the entry was already correct, RecursiveEntryInUse if the slot is
+// used by something else
+// unsafe: writes to the P4 frame address without being able to check the mapping
+pub unsafe fn install_recursive_mapping() -> Result<bool, PagingError> {
+    let (p4, p4_frame) = identity_mapped_p4();
+    let installed = install_recursive_entry(&mut p4[RECURSIVE_INDEX], p4_frame)?;
+    if installed {
+        tlb::flush_all();
+    }
+    Ok(installed)
+}
+
+// does the active P4 lack the recursive entry? the check before install_recursive_mapping,
+// RecursiveEntryInUse if the slot is used by something else
+// unsafe: reads the P4 frame address like install_recursive_mapping
+pub unsafe fn recursive_mapping_missing() -> Result<bool, PagingError> {
+    let (p4, p4_frame) = identity_mapped_p4();
+    recursive_entry_intact(&p4[RECURSIVE_INDEX], &p4_frame).map(|intact| !intact)
+}
+
+// the active P4 at its physical address, and its frame
+unsafe fn identity_mapped_p4() -> (&'static mut Table<Level4>, Frame) {
     use x86_64::registers::control_regs;
 
-    let old_table = InactivePageTable {
-        p4_frame: Frame::containing_address(
-            control_regs::cr3().0 as usize
-        ),
+    let p4_frame = Frame::containing_address(from_x86_phys(control_regs::cr3()));
+    (&mut *(p4_frame.start_address() as *mut Table<Level4>), p4_frame)
+}
+
+// true if the recursive entry points to the P4's own frame, false if it is unused
+fn recursive_entry_intact(entry: &Entry, p4_frame: &Frame) -> Result<bool, PagingError> {
+    match entry.pointed_frame() {
+        Some(ref frame) if frame == p4_frame => Ok(true),
+        None if entry.is_unused() => Ok(false),
+        _ => Err(PagingError::RecursiveEntryInUse),
+    }
+}
+
+// point the recursive entry of a P4 to the P4's own frame, the part of
+// install_recursive_mapping that works on any P4 (e.g. one mapped at a temporary page)
+fn install_recursive_entry(entry: &mut Entry, p4_frame: Frame) -> Result<bool, PagingError> {
+    if recursive_entry_intact(entry, &p4_frame)? {
+        return Ok(false);
+    }
+    entry.set(p4_frame, PRESENT | WRITABLE);
+    Ok(true)
+}
+
+// hand the active table over to the global lock, done once after the remap
+pub fn install_active_table(active_table: ActivePageTable) {
+    let mut table = ACTIVE_TABLE.lock();
+    assert!(table.is_none(), "active table already installed");
+    *table = Some(active_table);
+}
+
+// run f with exclusive access to the active table
+// all mapping operations on the active table are serialized through here
+// interrupts are disabled while f runs (an interrupt handler taking the lock would spin
+// forever) and enabled again afterwards if they were enabled before
+pub fn with_active_table<F, R>(f: F) -> R
+    where F: FnOnce(&mut ActivePageTable) -> R
+{
+    use x86_64::instructions::interrupts;
+    use x86_64::registers::flags;
+
+    let interrupts_enabled = flags::flags().contains(flags::IF);
+    unsafe { interrupts::disable() };
+    let result = {
+        let mut table = ACTIVE_TABLE.lock();
+        f(table.as_mut().expect("active table not installed yet"))
     };
-    unsafe {
-        control_regs::cr3_write(PhysicalAddress(
-            new_table.p4_frame.start_address() as u64));
+    if interrupts_enabled {
+        unsafe { interrupts::enable() };
+    }
+    result
+}
+
+// with_active_table for the page fault handler: None instead of spinning if the lock is
+// taken (the fault may have interrupted its holder) or no table is installed yet
+pub fn try_with_active_table<F, R>(f: F) -> Option<R>
+    where F: FnOnce(&mut ActivePageTable) -> R
+{
+    match ACTIVE_TABLE.try_lock() {
+        Some(mut table) => table.as_mut().map(f),
+        None => None,
     }
-    old_table
 }
+
+// the way to the active table for the page fault paths: ActiveTableUnavailable if the
+// fault interrupted the holder of the lock (or no table is installed yet), the handler
+// has to treat that like a fault it can't resolve
+pub fn fault_with_active_table<F, R>(f: F) -> Result<R, PagingError>
+    where F: FnOnce(&mut ActivePageTable) -> Result<R, PagingError>
+{
+    match try_with_active_table(f) {
+        Some(result) => result,
+        None => Err(PagingError::ActiveTableUnavailable),
+    }
 }
 
 // used on inactie page tables
 // not used by CPU
 pub struct InactivePageTable {
     p4_frame: Frame,
+    edited: bool,   // changed through `with` or the secondary slot, see switch
 }
 
 impl InactivePageTable {
@@ -190,103 +848,4830 @@ impl InactivePageTable {
             // now we are able to zero the table
             table.zero();
             // set up recursive mapping for the table
-            table[511].set(frame.clone(), PRESENT | WRITABLE);
+            table[RECURSIVE_INDEX].set(frame.clone(), PRESENT | WRITABLE);
         }
         temporary_page.unmap(active_table);
 
-        InactivePageTable { p4_frame: frame }
+        InactivePageTable { p4_frame: frame, edited: false }
     }
-}
-
-// map kernel sections in new page table
-pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation)
-    -> ActivePageTable
-    where A: FrameAllocator
-{
-    let mut temporary_page = TemporaryPage::new(Page { number: 0xcafebabe },
-        allocator);
-
-    let mut active_table = unsafe { ActivePageTable::new() };
-    let mut new_table = {
-        let frame = allocator.allocate_frame().expect("no more frames");
-        InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
-    };
-
-    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
-        let elf_sections_tag = boot_info.elf_sections_tag()
-            .expect("Memory map tag required");
 
-        //identity map the kernel sections
-        for section in elf_sections_tag.sections() {
+    // the table a raw CR3 value points to, e.g. one captured from another context
+    // the flag bits (PCD, PWT) are masked off, None if the P4 frame is not in
+    // an available memory area of the memory map
+    pub fn from_cr3(cr3: u64, boot_info: &BootInformation) -> Option<InactivePageTable> {
+        let p4_address = (cr3 & 0x000f_ffff_ffff_f000) as PhysicalAddress;
+        let memory_map_tag = boot_info.memory_map_tag().expect(
+            "Memory map tag required");
+        let available = memory_map_tag.memory_areas().any(|area| {
+            let start = area.base_addr as usize;
+            let end = (area.base_addr + area.length) as usize;
+            start <= p4_address && p4_address + PAGE_SIZE <= end
+        });
 
-            use self::entry::WRITABLE;
+        if available {
+            Some(InactivePageTable {
+                p4_frame: Frame::containing_address(p4_address),
+                edited: false,
+            })
+        } else {
+            None
+        }
+    }
 
-            if !section.is_allocated() {
-                // section is not loaded to memory
-                continue;
-            }
-            assert!(section.start_address() % PAGE_SIZE == 0,
-                    "sections need to be page aligned");
+    // frame and flags of the recursive entry P4[RECURSIVE_INDEX], None if it is unused
+    // only reads the table through the temporary page
+    pub fn recursive_entry(&self, active_table: &mut ActivePageTable,
+        temporary_page: &mut TemporaryPage) -> Option<(Frame, EntryFlags)>
+    {
+        let entry = {
+            let table = temporary_page.map_table_frame(self.p4_frame.clone(), active_table);
+            table[RECURSIVE_INDEX].pointed_frame().map(|frame| (frame, table[RECURSIVE_INDEX].flags()))
+        };
+        temporary_page.unmap(active_table);
+        entry
+    }
+}
 
-            println!("mapping section at addr: {:#x}, size: {:#x}",
-                section.addr, section.size);
+impl InactivePageTable {
 
-            let flags = EntryFlags::from_elf_section_flags(section);
+    // install the table in the secondary recursive slot of the active P4, so it can be
+    // edited through its own window while the active mappings stay untouched (unlike
+    // `with`, nothing of the active table is hidden during the edit)
+    // the slot is cleared again when the editor is dropped
+    pub fn map_secondary_recursive<'a>(&'a mut self, active_table: &'a mut ActivePageTable)
+        -> SecondaryTableEditor<'a>
+    {
+        assert!(active_table.p4()[SECONDARY_RECURSIVE_INDEX].is_unused(),
+                "secondary recursive slot is already in use");
+        self.edited = true;
+        active_table.p4_mut()[SECONDARY_RECURSIVE_INDEX].set(self.p4_frame.clone(),
+            PRESENT | WRITABLE);
+        tlb::flush_all();
 
-            let start_frame = Frame::containing_address(section.start_address());
-            let end_frame = Frame::containing_address(section.end_address() - 1);
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
-                mapper.identity_map(frame, flags, allocator);
-            }
+        let p4 = SECONDARY_P4 as *mut Table<Level4>;
+        SecondaryTableEditor {
+            mapper: unsafe { Mapper::with_walker(p4, SecondaryRecursiveWalker) },
+            active_table: active_table,
+            _table: PhantomData,
         }
+    }
+}
 
-        // identity map the VGA text buffer
-        let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer_frame, WRITABLE, allocator);
+// virtual address of a P4 installed in the secondary recursive slot
+const SECONDARY_P4: usize = 0xffff_ffff_ffff_e000;
 
-        // identity map the multiboot info structure
-        let multiboot_start = Frame::containing_address(boot_info.start_address());
-        let multiboot_end = Frame::containing_address(boot_info.end_address() - 1);
-        for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-            mapper.identity_map(frame, PRESENT, allocator);
-        }
+// mapper for an inactive table in the secondary recursive slot, see
+// InactivePageTable::map_secondary_recursive
+pub struct SecondaryTableEditor<'a> {
+    mapper: Mapper<SecondaryRecursiveWalker>,
+    active_table: &'a mut ActivePageTable,
+    _table: PhantomData<&'a mut InactivePageTable>,
+}
 
-    });
+impl<'a> SecondaryTableEditor<'a> {
 
-    let old_table = active_table.switch(new_table);
-    println!("NEW TABLE!!!");
+    // the active table, still reached through P4[RECURSIVE_INDEX]; together with the
+    // editor's own walk through P4[SECONDARY_RECURSIVE_INDEX] both tables can be read
+    // side by side without switching CR3, e.g. to diff them
+    pub fn active(&self) -> &Mapper {
+        &*self.active_table
+    }
+}
 
-    // turn the old p4 page into a guard page
-    let old_p4_page = Page::containing_address(
-      old_table.p4_frame.start_address()
-    );
-    active_table.unmap(old_p4_page, allocator);
-    println!("guard page at {:#x}", old_p4_page.start_address());
+impl<'a> Deref for SecondaryTableEditor<'a> {
+    type Target = Mapper<SecondaryRecursiveWalker>;
+
+    fn deref(&self) -> &Mapper<SecondaryRecursiveWalker> {
+        &self.mapper
+    }
+}
+
+impl<'a> DerefMut for SecondaryTableEditor<'a> {
+    fn deref_mut(&mut self) -> &mut Mapper<SecondaryRecursiveWalker> {
+        &mut self.mapper
+    }
+}
+
+// teardown: clear the slot and forget the window's translations
+impl<'a> Drop for SecondaryTableEditor<'a> {
+    fn drop(&mut self) {
+        self.active_table.p4_mut()[SECONDARY_RECURSIVE_INDEX].set_unused();
+        tlb::flush_all();
+    }
+}
+
+// pattern written to freed frames in debug builds to make use-after-free visible
+pub const POISON_PATTERN: u64 = 0xdeadbeef_deadbeef;
+
+// the poison window: POISON_PAGE_NUMBER maps a freed frame while it is filled with
+// POISON_PATTERN, POISON_P1_PAGE_NUMBER maps the P1 table holding the window's entry
+// so the window is set without the recursive entry (which points to another table
+// while an inactive table is edited) and without the ACTIVE_TABLE lock
+// both pages lie below P4[508], a kernel entry every address space shares
+const POISON_PAGE_NUMBER: usize = temporary_page::TEMP_PAGE_NUMBER + 4;
+const POISON_P1_PAGE_NUMBER: usize = POISON_PAGE_NUMBER + 1;
+
+// true once the poison window is installed, held while a frame is poisoned
+static POISON_WINDOW: Mutex<bool> = Mutex::new(false);
+
+impl ActivePageTable {
+
+    // set up the poison window, from then on BitmapFrameAllocator::deallocate_frame
+    // poisons every frame it gets back; so every path that frees through it does:
+    // unmap_range, free_subtree, clear_p4_entry, InactivePageTable::free_all and the
+    // frames callers free after unmap
+    #[cfg(debug_assertions)]
+    pub fn install_poison_window<A>(&mut self, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let page = Page { number: POISON_PAGE_NUMBER };
+        // the window's entry stays unused between two poisonings, only its P1 is needed
+        self.ensure_tables(Page::range_inclusive(page, page), allocator);
+        let p1_frame = self.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2[page.p2_index()].pointed_frame())
+            .expect("the poison window has no P1 table");
+        self.map_to(Page { number: POISON_P1_PAGE_NUMBER }, p1_frame, WRITABLE | NO_EXECUTE,
+            allocator);
+        *POISON_WINDOW.lock() = true;
+    }
+}
+
+// fill the frame with POISON_PATTERN through the poison window, so stale readers of a
+// freed frame see garbage instead of old data
+// does nothing before install_poison_window and while the window is busy (a free from
+// an interrupt handler in the middle of another poisoning): poisoning is a debug aid
+// and deallocate_frame must not spin
+#[cfg(debug_assertions)]
+pub fn poison_frame(frame: &Frame) {
+    let installed = match POISON_WINDOW.try_lock() {
+        Some(installed) => installed,
+        None => return,
+    };
+    if !*installed {
+        return;
+    }
+    let page = Page { number: POISON_PAGE_NUMBER };
+    let p1 = unsafe {
+        &mut *(Page { number: POISON_P1_PAGE_NUMBER }.start_address() as *mut Table<Level1>)
+    };
+    p1[page.p1_index()].set(frame.clone(), PRESENT | WRITABLE | NO_EXECUTE);
+    tlb::flush_page(page);
+    let words = unsafe {
+        slice::from_raw_parts_mut(page.start_address() as *mut u64, PAGE_SIZE / 8)
+    };
+    for word in words.iter_mut() {
+        *word = POISON_PATTERN;
+    }
+    p1[page.p1_index()].set_unused();
+    tlb::flush_page(page);
+}
+
+// FNV-1a hash of the frame's contents, for finding unexpected writes to a frame by
+// comparing the checksum before and after an operation
+// the frame is read through the temporary page, which must not be mapped
+pub fn checksum_frame(frame: &Frame, active_table: &mut ActivePageTable,
+    temporary_page: &mut TemporaryPage) -> u64
+{
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let bytes = temporary_page.map_scoped(frame.clone(), active_table);
+    // bound first, the guard has to outlive the borrow of the fold
+    let hash = bytes.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    });
+    hash
+}
+
+// look at a frame that may be mapped writable elsewhere without any risk of changing
+// it: the temporary page maps it read-only, a write through the view faults
+// the view borrows the temporary page, so it is gone before unmap_readonly_view
+pub fn map_readonly_view<'a>(frame: Frame, active_table: &mut ActivePageTable,
+    temporary_page: &'a mut TemporaryPage) -> &'a [u8]
+{
+    let address = temporary_page.map_readonly(frame, active_table);
+    unsafe { slice::from_raw_parts(address as *const u8, PAGE_SIZE) }
+}
+
+// end the view of map_readonly_view
+pub fn unmap_readonly_view(active_table: &mut ActivePageTable,
+    temporary_page: &mut TemporaryPage)
+{
+    temporary_page.unmap(active_table);
+}
+
+impl InactivePageTable {
+
+    // create a new address space for a child process
+    // the kernel P4 entries are copied, so the kernel P3/P2/P1 tables are shared
+    // user pages are shared copy-on-write: both parent and child entries become
+    // read-only + COPY_ON_WRITE and the first write fault copies the frame
+    pub fn fork_from<A>(parent: &mut ActivePageTable, temporary_page: &mut TemporaryPage,
+        allocator: &mut A) -> InactivePageTable
+        where A: FrameAllocator
+    {
+        let user_mappings = parent.user_mappings();
+        for &(page, _, _) in &user_mappings {
+            parent.mark_cow(page);
+        }
+        tlb::flush_all();
+        let kernel_entries = parent.kernel_p4_entries();
+
+        let mut child = {
+            let frame = allocator.allocate_frame().expect("no more frames");
+            InactivePageTable::new(frame, parent, temporary_page)
+        };
+
+        parent.with(&mut child, temporary_page, |mapper| {
+            copy_kernel_mappings(mapper, &kernel_entries);
+            for (page, frame, flags) in user_mappings {
+                // clone the mapping with the same write protected flags as the parent
+                mapper.map_to(page, frame, cow_flags(flags), allocator);
+            }
+        });
+
+        child
+    }
+}
+
+impl InactivePageTable {
+
+    // free all page table frames of the table (and with free_leaves the mapped
+    // frames), the cleanup when a process exits
+    // with skip_kernel_entries only the user part is freed, the kernel tables are
+    // shared with the other address spaces (P4[0] and the higher half in this kernel)
+    // returns the number of freed frames, including the P4 frame
+    pub fn free_all<A>(self, active_table: &mut ActivePageTable,
+        temporary_page: &mut TemporaryPage, allocator: &mut A,
+        skip_kernel_entries: bool, free_leaves: bool) -> usize
+        where A: FrameAllocator
+    {
+        let mut table = self;
+        let mut freed = 0;
+        active_table.with(&mut table, temporary_page, |mapper| {
+            for p4_index in 0..RECURSIVE_INDEX {
+                let user_entry = p4_index >= USER_P4_START && p4_index < USER_P4_END;
+                if skip_kernel_entries && !user_entry {
+                    continue;
+                }
+                freed += mapper.free_subtree(p4_index, free_leaves, allocator);
+            }
+        });
+
+        allocator.deallocate_frame(table.p4_frame);
+        freed + 1
+    }
+}
+
+// point the P4 entries of the kernel at the same P3 tables as the parent
+// only the P4 entries are copied, the P3/P2/P1 tables below them are the parent's
+// frames: a kernel page mapped later below one of these entries is visible in every
+// address space at once, without touching their P4s (a new kernel P4 entry is not,
+// it has to be copied again)
+fn copy_kernel_mappings(mapper: &mut Mapper, entries: &[(usize, Frame, EntryFlags)]) {
+    for &(index, ref frame, flags) in entries {
+        mapper.p4_mut()[index].set(frame.clone(), flags);
+    }
+}
+
+// the flags of a shared copy-on-write page
+fn cow_flags(flags: EntryFlags) -> EntryFlags {
+    if flags.contains(WRITABLE) {
+        flags.minus(WRITABLE) | COPY_ON_WRITE
+    } else {
+        flags
+    }
+}
+
+// fixed address of the syscall/interrupt entry trampoline: the first page of P4[507],
+// a kernel P4 entry, so fork_from's copy_kernel_mappings takes it into every new
+// address space
+pub const TRAMPOLINE_ADDRESS: VirtualAddress = 0xffff_fd80_0000_0000;
+
+// map the frame holding the entry stub read-only, executable and GLOBAL (it stays in
+// the TLB across CR3 switches) at `virtual_address`, normally TRAMPOLINE_ADDRESS
+// the page must be in the kernel half, a user P4 entry would not be shared
+pub fn install_trampoline_page<A>(frame: Frame, virtual_address: VirtualAddress,
+    active_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let page = Page::containing_address(virtual_address);
+    assert!(virtual_address % PAGE_SIZE == 0 && page.p4_index() >= USER_P4_END,
+        "trampoline page {:#x} must be a page in the kernel half", virtual_address);
+    active_table.map_to(page, frame, GLOBAL, allocator);
+}
+
+// called by the page fault handler for a write fault at `address`, with the table from
+// fault_with_active_table (never with_active_table, see ACTIVE_TABLE)
+// gives a COPY_ON_WRITE page its own writable copy of the frame
+// Ok(false) if the page is not copy-on-write (a real protection fault), OutOfFrames if
+// no frame is left for the copy (the page stays copy-on-write then)
+pub fn handle_cow_fault<A>(active_table: &mut ActivePageTable, address: VirtualAddress,
+    temporary_page: &mut TemporaryPage, allocator: &mut A) -> Result<bool, PagingError>
+    where A: FrameAllocator
+{
+    let page = Page::containing_address(address);
+    let flags = match active_table.p1_entry(page) {
+        Some(entry) if entry.flags().contains(PRESENT | COPY_ON_WRITE) => entry.flags(),
+        _ => return Ok(false),
+    };
+
+    // copy the old content through the still mapped (read-only) page
+    let new_frame = match allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return Err(PagingError::OutOfFrames(page)),
+    };
+    {
+        let copy = temporary_page.map(new_frame.clone(), active_table);
+        unsafe {
+            core::ptr::copy_nonoverlapping(page.start_address() as *const u8,
+                copy as *mut u8, PAGE_SIZE);
+        }
+    }
+    temporary_page.unmap(active_table);
+
+    let new_flags = flags.minus(COPY_ON_WRITE) | WRITABLE;
+    active_table.p1_entry_mut(page).unwrap().set(new_frame, new_flags);
+    tlb::flush_page(page);
+    Ok(true)
+}
+
+// move a mapped 4KiB page to `new_frame`: the content is copied, the page keeps its
+// flags and the old frame is returned to the caller
+// refuses pinned frames (frame_refcount::pin_frame); the page must not be in use by
+// other code while it moves, e.g. by DMA
+pub fn swap_frame(active_table: &mut ActivePageTable, page: Page, new_frame: Frame,
+    temporary_page: &mut TemporaryPage) -> Result<Frame, PagingError>
+{
+    let (old_frame, flags) = match active_table.p1_entry(page) {
+        Some(entry) if entry.flags().contains(PRESENT) =>
+            (entry.pointed_frame().unwrap(), entry.flags()),
+        Some(_) => return Err(PagingError::NotMapped(page)),
+        None => match active_table.translate_page(page) {
+            Some(_) => return Err(PagingError::UnsupportedHugePage),
+            None => return Err(PagingError::NotMapped(page)),
+        },
+    };
+    if frame_refcount::is_pinned(&old_frame) {
+        return Err(PagingError::Pinned(page));
+    }
+
+    {
+        let copy = temporary_page.map(new_frame.clone(), active_table);
+        unsafe {
+            ptr::copy_nonoverlapping(page.start_address() as *const u8,
+                copy as *mut u8, PAGE_SIZE);
+        }
+    }
+    temporary_page.unmap(active_table);
+
+    active_table.p1_entry_mut(page).unwrap().set(new_frame, flags);
+    tlb::flush_page(page);
+    Ok(old_frame)
+}
+
+// clear the page at the page aligned `address` with u64 wide writes, exactly the 4096
+// bytes of that page; it must be mapped writable
+// shared by Table::zero and prefault_range
+pub fn zero_page(address: VirtualAddress) {
+    assert!(address % PAGE_SIZE == 0, "zero_page: {:#x} is not page aligned", address);
+    unsafe {
+        ptr::write_bytes(address as *mut u64, 0, PAGE_SIZE / mem::size_of::<u64>());
+    }
+}
+
+// a single zeroed frame shared read-only by all demand-zero pages
+static ZERO_FRAME: Once<Frame> = Once::new();
+
+// allocate and zero the shared zero frame, must run before map_zero_page
+pub fn init_zero_frame<A>(active_table: &mut ActivePageTable,
+    temporary_page: &mut TemporaryPage, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let frame = allocator.allocate_frame().expect("no more frames");
+    temporary_page.map_table_frame(frame.clone(), active_table).zero();
+    temporary_page.unmap(active_table);
+
+    ZERO_FRAME.call_once(|| frame);
+}
+
+// the shared zero frame
+pub fn zero_frame() -> Frame {
+    ZERO_FRAME.try().expect("zero frame not initialized").clone()
+}
+
+// progress and diagnostic output of the kernel remap, off by default
+// besides it only dump_mappings (a debug aid) and the self tests print in paging
+// println! takes the VGA lock, so nothing a page fault handler can reach may print
+// or panic (a panic prints too): a fault while the lock is held would deadlock. The
+// handler's path returns its failures instead: Mapper's try_ functions, prefault_range
+// and grow_stack_on_fault, handle_cow_fault and try_with_active_table
+const VERBOSE_REMAP: bool = false;
+
+// map kernel sections in new page table
+// convenience wrapper: build and validate the new table, then switch to it
+// fails with FrameAllocationFailed before touching anything if the allocator can't
+// provide kernel_table_frames_needed frames, the boot table stays active then
+pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation)
+    -> Result<ActivePageTable, PagingError>
+    where A: FrameAllocator
+{
+    // the remap starts from the bootstrap table, a second run would rebuild the kernel
+    // table on top of the active one
+    match remap_once(&KERNEL_REMAPPED, allocator, boot_info) {
+        Err(PagingError::AlreadyRemapped) => panic!("{}", PagingError::AlreadyRemapped),
+        result => result,
+    }
+}
+
+// remap_the_kernel with the one-shot flag as a parameter, so the guard can be tried
+// without a second real remap
+// the flag is only claimed once every check passed, a caller that handles a failed
+// check can retry; AlreadyRemapped if the flag was claimed before
+fn remap_once<A>(flag: &AtomicBool, allocator: &mut A, boot_info: &BootInformation)
+    -> Result<ActivePageTable, PagingError>
+    where A: FrameAllocator
+{
+    // every data mapping of the new table has NO_EXECUTE, bit 63 is reserved (and the
+    // first entry with it faults) until EFER.NXE is set
+    enable_nxe();
+
+    check_four_level_paging(la57_enabled())?;
+    check_kernel_sections(boot_info)?;
+
+    // an allocator that can't tell its frames could run dry halfway through the remap
+    let needed = kernel_table_frames_needed(boot_info);
+    let available = match allocator.frames_available() {
+        Some(available) => available,
+        None => return Err(PagingError::UnknownFrameCount),
+    };
+    if available < needed {
+        if VERBOSE_REMAP {
+            println!("remap needs up to {} frames, the allocator has {}", needed, available);
+        }
+        return Err(PagingError::FrameAllocationFailed);
+    }
+
+    if !claim_once(flag) {
+        return Err(PagingError::AlreadyRemapped);
+    }
+    // the frames of an incomplete table are lost, but the boot table stays active
+    let build = build_new_kernel_table(allocator, boot_info)?;
+    if let Some(missing) = build.report.missing {
+        return Err(PagingError::IncompleteKernelTable(missing));
+    }
+
+    Ok(commit_kernel_table(build, allocator))
+}
+
+// set by the first remap_the_kernel
+static KERNEL_REMAPPED: AtomicBool = AtomicBool::new(false);
+
+// true for the first call with the flag, false for every later one
+fn claim_once(flag: &AtomicBool) -> bool {
+    !flag.swap(true, Ordering::SeqCst)
+}
+
+// upper bound of the frames build_new_kernel_table allocates: the new P4, the
+// temporary page's three tables and the P3/P2/P1 tables of every mapped range,
+// counted per range as if no tables were shared (Mapper::frames_needed_for doesn't
+// fit here, the new table doesn't exist yet and the leaves need no frames)
+pub fn kernel_table_frames_needed(boot_info: &BootInformation) -> usize {
+    // tables for the pages touched by the range
+    fn tables_for(range: AddressRange) -> usize {
+        let (first, last) = (range.start / PAGE_SIZE, range.end / PAGE_SIZE);
+        ((last >> 27) - (first >> 27) + 1) + ((last >> 18) - (first >> 18) + 1) +
+            ((last >> 9) - (first >> 9) + 1)
+    }
+
+    let mut sections = 0;
+    for_each_kernel_range(boot_info, |start, end, _| {
+        sections += AddressRange::between(start, end).map_or(0, tables_for)
+    });
+    1 + 3 + sections + tables_for(vga_buffer_range()) + tables_for(multiboot_range(boot_info))
+}
+
+// the VGA text buffer, identity mapped in every kernel table
+fn vga_buffer_range() -> AddressRange {
+    AddressRange { start: 0xb8000, end: 0xb8000 + PAGE_SIZE - 1 }
+}
+
+// the multiboot information structure, identity mapped in every kernel table
+fn multiboot_range(boot_info: &BootInformation) -> AddressRange {
+    AddressRange::between(boot_info.start_address(), boot_info.end_address())
+        .expect("empty multiboot information")
+}
+
+// the result of build_new_kernel_table, everything commit_kernel_table needs
+pub struct KernelTableBuild {
+    pub active_table: ActivePageTable,
+    pub new_table: InactivePageTable,
+    pub temporary_page: TemporaryPage,
+    pub report: ValidationReport,
+}
+
+// result of the read-only walk over a new kernel table before switching to it
+#[derive(Debug)]
+pub struct ValidationReport {
+    pub checked_pages: usize,
+    pub missing: Option<VirtualAddress>,    // first essential page that is not mapped
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_none()
+    }
+}
+
+// phase one: create the new kernel table without touching CR3
+// the returned table can be inspected before commit_kernel_table switches to it
+pub fn build_new_kernel_table<A>(allocator: &mut A, boot_info: &BootInformation)
+    -> Result<KernelTableBuild, PagingError>
+    where A: FrameAllocator
+{
+    // without the ELF sections tag the kernel is mapped from the linker script's
+    // symbols, never from a guessed range that would have to be writable and executable
+    check_kernel_sections(boot_info)?;
+
+    // our boot.asm sets up the recursive entry, another bootloader's table may lack it
+    let mut active_table = unsafe {
+        if recursive_mapping_missing()? {
+            install_recursive_mapping()?;
+        }
+        ActivePageTable::new()
+    };
+    let mut temporary_page = TemporaryPage::new_default(&active_table, allocator);
+    let mut new_table = {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
+    };
+
+    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+        if let Ok(elf_sections_tag) = elf_sections_tag(boot_info) {
+            let kernel_end = elf_sections_tag.sections().filter(|s| s.is_allocated())
+                .map(|s| s.end_address()).max().unwrap();
+            assert_below_recursive_region(kernel_end);
+
+            if let Some((a, b)) = find_section_overlap(elf_sections_tag.sections()) {
+                panic!("kernel sections [{:#x}, {:#x}) and [{:#x}, {:#x}) share pages",
+                       a.0, a.1, b.0, b.1);
+            }
+        } else {
+            let symbols = LinkerSymbols::from_linker();
+            if VERBOSE_REMAP {
+                println!("no ELF sections tag, mapping the kernel from the linker symbols");
+            }
+            assert_below_recursive_region(symbols.relro.1);
+        }
+
+        //identity map the kernel sections (empty ones occupy no pages)
+        for_each_kernel_range(boot_info, |start, end, flags| {
+            assert!(start % PAGE_SIZE == 0, "sections need to be page aligned");
+
+            if VERBOSE_REMAP {
+                println!("mapping section at addr: {:#x}, size: {:#x}", start, end - start);
+            }
+
+            map_section(mapper, start, end, flags, allocator);
+        });
+
+        // identity map the VGA text buffer
+        let vga_buffer_frame = Frame::containing_address(vga_buffer_range().start);
+        mapper.identity_map(vga_buffer_frame, WRITABLE | NO_EXECUTE, allocator);
+
+        // identity map the multiboot info structure, some bootloaders put it right
+        // after the kernel, so it may share a frame with the last section
+        let multiboot = multiboot_range(boot_info);
+        let shared = mapper.identity_map_phys_range_shared(multiboot.start, multiboot.size(),
+            PRESENT | NO_EXECUTE, allocator);
+        match shared {
+            Ok(0) => {}
+            Ok(skipped) => if VERBOSE_REMAP {
+                println!("multiboot info shares {} frames with the kernel", skipped);
+            },
+            Err(error) => panic!("cannot map the multiboot info: {}", error),
+        }
+
+    });
+
+    let report = validate_kernel_table(&mut active_table, &mut new_table,
+        &mut temporary_page, boot_info);
+
+    Ok(KernelTableBuild {
+        active_table: active_table,
+        new_table: new_table,
+        temporary_page: temporary_page,
+        report: report,
+    })
+}
+
+// find two allocated sections [start, end) that touch a common page
+// runs before the heap exists, so it compares all pairs instead of sorting
+fn find_section_overlap<'a, I>(sections: I)
+    -> Option<((VirtualAddress, VirtualAddress), (VirtualAddress, VirtualAddress))>
+    where I: Iterator<Item = &'a ElfSection> + Clone
+{
+    for (i, a) in sections.clone().enumerate() {
+        for b in sections.clone().skip(i + 1) {
+            // empty sections don't occupy pages
+            if !a.is_allocated() || !b.is_allocated() || a.size == 0 || b.size == 0 {
+                continue;
+            }
+            let a_range = AddressRange::between(a.start_address(), a.end_address()).unwrap();
+            let b_range = AddressRange::between(b.start_address(), b.end_address()).unwrap();
+            if a_range.page_bounds().overlaps(&b_range.page_bounds()) {
+                return Some(((a.start_address(), a.end_address()),
+                             (b.start_address(), b.end_address())));
+            }
+        }
+    }
+    None
+}
+
+// end of the highest memory area in the memory map
+pub fn physical_memory_end(boot_info: &BootInformation) -> PhysicalAddress {
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+    memory_map_tag.memory_areas()
+        .map(|area| cmp::min(area.base_addr.saturating_add(area.length),
+            usize::max_value() as u64) as PhysicalAddress)
+        .max().unwrap_or(0)
+}
+
+// highest frame identity_map accepts, None accepts every frame
+// memory::init sets it from the memory map, so corrupt boot data can't make the kernel
+// map memory that isn't installed
+static MAX_PHYSICAL_FRAME: Mutex<Option<Frame>> = Mutex::new(None);
+
+// set the bound, returns the previous one
+pub fn set_max_physical_frame(frame: Option<Frame>) -> Option<Frame> {
+    mem::replace(&mut *MAX_PHYSICAL_FRAME.lock(), frame)
+}
+
+pub fn max_physical_frame() -> Option<Frame> {
+    MAX_PHYSICAL_FRAME.lock().as_ref().map(|frame| frame.clone())
+}
+
+// the allocated sections must have addresses map_section can handle, bogus ELF
+// metadata is caught here instead of in Page::containing_address halfway through
+// the remap; prints the first bad section
+fn check_kernel_sections(boot_info: &BootInformation) -> Result<(), PagingError> {
+    // the linker symbols are the kernel's own, nothing to check without the tag
+    let elf_sections_tag = match elf_sections_tag(boot_info) {
+        Ok(elf_sections_tag) => elf_sections_tag,
+        Err(_) => return Ok(()),
+    };
+    let physical_end = physical_memory_end(boot_info);
+    for section in elf_sections_tag.sections().filter(|s| s.is_allocated()) {
+        if let Err(error) = check_section_range(section.addr as VirtualAddress,
+            section.size as usize, physical_end)
+        {
+            if VERBOSE_REMAP {
+                println!("section at addr: {:#x}, size: {:#x} is invalid: {}",
+                    section.addr, section.size, error);
+            }
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+// [start, start + size) must be canonical without crossing the non canonical hole,
+// and the memory it was loaded to must end at or below `physical_end`
+// the error holds the first bad address
+fn check_section_range(start: VirtualAddress, size: usize, physical_end: PhysicalAddress)
+    -> Result<(), PagingError>
+{
+    const LOWER_HALF_END: VirtualAddress = 0x0000_8000_0000_0000;
+
+    let last = match checked_last_address(start, size) {
+        Some(last) => last,
+        None if size == 0 => return Ok(()),
+        None => return Err(PagingError::InvalidAddress(start)),     // wraps around
+    };
+    Page::try_containing_address(start)?;
+    if start < LOWER_HALF_END && last >= LOWER_HALF_END {
+        return Err(PagingError::InvalidAddress(LOWER_HALF_END));
+    }
+    let mode = SectionMapping::detect(start);
+    if mode.physical_address(last) >= physical_end {
+        return Err(PagingError::InvalidAddress(start));
+    }
+    Ok(())
+}
+
+// post-remap integrity check: every page of every kernel range is mapped, writable
+// exactly if its section is writable and executable exactly if its section is
+// executable; NotMapped or WrongFlags for the first page that isn't
+pub fn verify_kernel_sections(active_table: &ActivePageTable, boot_info: &BootInformation)
+    -> Result<(), PagingError>
+{
+    let mut result = Ok(());
+    for_each_kernel_range(boot_info, |start, end, flags| {
+        if result.is_ok() {
+            result = verify_kernel_range(active_table, start, end, flags);
+        }
+    });
+    result
+}
+
+// the pages of [start, end) against the flags from_elf_section_flags gave the section
+fn verify_kernel_range(mapper: &Mapper, start: VirtualAddress, end: VirtualAddress,
+    flags: EntryFlags) -> Result<(), PagingError>
+{
+    for page in Page::range_for_bytes(start, end - start) {
+        let mapped = match mapper.translate_with_flags(page.start_address()) {
+            Some((_, mapped)) => mapped,
+            None => return Err(PagingError::NotMapped(page)),
+        };
+        if mapped.contains(WRITABLE) != flags.contains(WRITABLE) ||
+            mapped.contains(NO_EXECUTE) != flags.contains(NO_EXECUTE)
+        {
+            return Err(PagingError::WrongFlags(page));
+        }
+    }
+    Ok(())
+}
+
+// mapping the kernel into the recursive region would clobber the page tables
+fn assert_below_recursive_region(kernel_end: VirtualAddress) {
+    assert!(below_recursive_region(kernel_end),
+            "kernel ends at {:#x}, inside the recursive mapping region starting at {:#x}",
+            kernel_end, RECURSIVE_REGION_START);
+}
+
+// the condition of assert_below_recursive_region, `kernel_end` is exclusive
+fn below_recursive_region(kernel_end: VirtualAddress) -> bool {
+    kernel_end <= RECURSIVE_REGION_START
+}
+
+// check that the kernel sections, the VGA buffer and the multiboot structure are
+// mapped in the new table, only reads the table
+fn validate_kernel_table(active_table: &mut ActivePageTable, new_table: &mut InactivePageTable,
+    temporary_page: &mut TemporaryPage, boot_info: &BootInformation) -> ValidationReport
+{
+    let mut report = ValidationReport { checked_pages: 0, missing: None };
+
+    active_table.with(new_table, temporary_page, |mapper| {
+        let mut check = |range: AddressRange| {
+            for page in range.pages() {
+                report.checked_pages += 1;
+                if report.missing.is_none() && mapper.translate_page(page).is_none() {
+                    report.missing = Some(page.start_address());
+                }
+            }
+        };
+
+        for_each_kernel_range(boot_info, |start, end, _| {
+            if let Some(range) = AddressRange::between(start, end) {
+                check(range);
+            }
+        });
+        check(vga_buffer_range());
+        check(multiboot_range(boot_info));
+    });
+
+    report
+}
+
+// the code running right now must stay executable after the switch, otherwise the
+// next instruction faults (NXE is already on) with nothing pointing at the cause
+fn assert_code_executable(active_table: &mut ActivePageTable, new_table: &mut InactivePageTable,
+    temporary_page: &mut TemporaryPage)
+{
+    let rip = instruction_pointer();
+    match executable_in(active_table, new_table, temporary_page, rip) {
+        Some(executable) => assert!(executable,
+            "running code at {:#x} is not executable in the new table", rip),
+        None => panic!("running code at {:#x} is not mapped in the new table", rip),
+    }
+}
+
+// the condition of assert_code_executable: whether the address is executable in the
+// table, None if it is not mapped there
+fn executable_in(active_table: &mut ActivePageTable, table: &mut InactivePageTable,
+    temporary_page: &mut TemporaryPage, address: VirtualAddress) -> Option<bool>
+{
+    let mut flags = None;
+    active_table.with(table, temporary_page, |mapper| {
+        flags = mapper.translate_with_flags(address).map(|(_, flags)| flags);
+    });
+    flags.map(|flags| !flags.contains(NO_EXECUTE))
+}
+
+// stack bytes below rsp the switch and the code right after it may push into
+const STACK_HEADROOM: usize = 1024;
+
+// the stack must stay mapped after the switch too, the first push would fault
+// otherwise, and with the stack gone that fault can't be handled
+fn assert_stack_mapped(active_table: &mut ActivePageTable, new_table: &mut InactivePageTable,
+    temporary_page: &mut TemporaryPage)
+{
+    let rsp = stack_pointer();
+    let mut result = Ok(());
+    active_table.with(new_table, temporary_page, |mapper| {
+        result = check_stack_mapped(mapper, rsp);
+    });
+    if let Err(error) = result {
+        panic!("stack at {:#x} is not mapped in the new table: {}", rsp, error);
+    }
+}
+
+// the pages from rsp - STACK_HEADROOM up to rsp must be mapped writable
+fn check_stack_mapped(mapper: &Mapper, rsp: VirtualAddress) -> Result<(), PagingError> {
+    let lowest = rsp.saturating_sub(STACK_HEADROOM);
+    for page in Page::range_inclusive(Page::containing_address(lowest),
+        Page::containing_address(rsp))
+    {
+        match mapper.translate_with_flags(page.start_address()) {
+            Some((_, flags)) if flags.contains(WRITABLE) => {}
+            Some(_) => return Err(PagingError::WrongFlags(page)),
+            None => return Err(PagingError::NotMapped(page)),
+        }
+    }
+    Ok(())
+}
+
+// the current stack pointer
+#[inline(always)]
+fn stack_pointer() -> VirtualAddress {
+    let rsp: usize;
+    unsafe { asm!("mov %rsp, $0" : "=r"(rsp) ::: "volatile") };
+    rsp
+}
+
+// address of an instruction in the calling code
+#[inline(always)]
+fn instruction_pointer() -> VirtualAddress {
+    let rip: usize;
+    unsafe { asm!("lea (%rip), $0" : "=r"(rip) ::: "volatile") };
+    rip
+}
+
+// the protection key map_to_exec_only puts on user code pages
+// whoever enables CR4.PKE must set the access disable bit of this key in PKRU
+pub const EXEC_ONLY_PKEY: u8 = 1;
+
+// can protection keys make pages execute-only right now?
+// needs PKU support (CPUID.(EAX=7,ECX=0):ECX bit 3) and CR4.PKE (bit 22) being set
+pub fn execute_only_supported() -> bool {
+    use x86_64::registers::control_regs::cr4;
+
+    let ecx: u32;
+    unsafe {
+        asm!("cpuid" : "={ecx}"(ecx) : "{eax}"(7), "{ecx}"(0) : "eax", "ebx", "edx")
+    };
+    let pke_enabled = cr4().bits() & (1 << 22) != 0;
+    ecx & (1 << 3) != 0 && pke_enabled
+}
+
+// does the CPU support 5-level paging? CPUID.(EAX=7,ECX=0):ECX bit 16
+pub fn la57_supported() -> bool {
+    let ecx: u32;
+    unsafe {
+        asm!("cpuid" : "={ecx}"(ecx) : "{eax}"(7), "{ecx}"(0) : "eax", "ebx", "edx")
+    };
+    ecx & (1 << 16) != 0
+}
+
+// let entries use NO_EXECUTE: sets EFER.NXE (bit 11), setting it twice does nothing
+pub fn enable_nxe() {
+    use x86_64::registers::msr::{IA32_EFER, rdmsr, wrmsr};
+
+    unsafe {
+        let efer = rdmsr(IA32_EFER);
+        wrmsr(IA32_EFER, efer | EFER_NXE);
+    }
+}
+
+pub fn nxe_enabled() -> bool {
+    use x86_64::registers::msr::{IA32_EFER, rdmsr};
+
+    unsafe { rdmsr(IA32_EFER) & EFER_NXE != 0 }
+}
+
+const EFER_NXE: u64 = 1 << 11;
+
+// is 5-level paging on? CR4.LA57 (bit 12), only the bootloader can have set it, it
+// can't be changed while paging is enabled
+pub fn la57_enabled() -> bool {
+    use x86_64::registers::control_regs::cr4;
+
+    cr4().bits() & (1 << 12) != 0
+}
+
+// the paging code knows 4 levels only: with LA57 the top table is a P5, the
+// recursive entry sits in the P5 and every recursive address (P4 at
+// 0xffff_ffff_ffff_f000, the secondary window, RECURSIVE_REGION_START) is wrong
+// takes the CR4 bit as a parameter so the guard can be tried with either value
+pub fn check_four_level_paging(la57_enabled: bool) -> Result<(), PagingError> {
+    if la57_enabled {
+        Err(PagingError::UnsupportedPagingMode)
+    } else {
+        Ok(())
+    }
+}
+
+// phase two: switch to the new table and turn the old P4 into a guard page
+pub fn commit_kernel_table<A>(build: KernelTableBuild, allocator: &mut A) -> ActivePageTable
+    where A: FrameAllocator
+{
+    let KernelTableBuild { mut active_table, mut new_table, mut temporary_page, .. } = build;
+
+    assert_code_executable(&mut active_table, &mut new_table, &mut temporary_page);
+    assert_stack_mapped(&mut active_table, &mut new_table, &mut temporary_page);
+    let old_table = match active_table.switch(new_table, &mut temporary_page) {
+        Ok(old_table) => old_table,
+        Err(_) => panic!("new kernel table has a broken recursive mapping"),
+    };
+    if VERBOSE_REMAP {
+        println!("NEW TABLE!!!");
+    }
+
+    // turn the old p4 page into a guard page
+    let old_p4_page = Page::from_frame_identity(&old_table.p4_frame);
+    active_table.unmap(old_p4_page, allocator);
+    REMAP_GUARD_PAGE.call_once(|| old_p4_page);
+    if VERBOSE_REMAP {
+        println!("guard page at {:#x}", old_p4_page.start_address());
+    }
+    temporary_page.release(allocator);
 
     active_table
 }
 
-// function to test the paging
-pub fn test_paging<A>(allocator: &mut A)
+// late hardening pass over the kernel sections of the active table: GLOBAL on every
+// kernel page, NO_EXECUTE on every page that holds no code; frames stay the same and
+// the TLB is flushed once at the end
+// GLOBAL only takes effect with CR4.PGE, afterwards a change to a kernel page needs
+// tlb::flush_page or tlb::flush_global, flush_all doesn't drop global translations
+// (split_huge_page, try_merge_huge_page, free_subtree and clear_p4_entry flush_global)
+// returns the number of updated pages
+pub fn harden_kernel_mappings(active_table: &mut ActivePageTable, boot_info: &BootInformation)
+    -> usize
+{
+    // a page shared with a code range must stay executable
+    // (runs before the heap exists, so the ranges are walked again for every page)
+    let holds_code = |page: Page| {
+        let mut code = false;
+        for_each_kernel_range(boot_info, |start, end, flags| {
+            if !flags.contains(NO_EXECUTE) {
+                code = code || Page::range_for_bytes(start, end - start)
+                    .any(|code_page| code_page == page);
+            }
+        });
+        code
+    };
+
+    let mut updated = 0;
+    // the ELF sections, or the linker script's ranges without the tag
+    for_each_kernel_range(boot_info, |start, end, _| {
+        for page in Page::range_for_bytes(start, end - start) {
+            let flags = if holds_code(page) { GLOBAL } else { GLOBAL | NO_EXECUTE };
+            active_table.add_flags_unflushed(page, flags)
+                .expect("kernel section is not mapped");
+            updated += 1;
+        }
+    });
+    tlb::flush_all();
+    updated
+}
+
+// bring-up aid: identity map every available frame of the memory map WRITABLE (and NX), with
+// 2MiB pages where a whole aligned block is available and unmapped, 4KiB pages else
+// frames that are mapped already (e.g. the kernel) are skipped, memory above the
+// identity region (IDENTITY_REGION_END) is left out
+// returns the number of frames that were mapped
+#[cfg(feature = "identity_map_all")]
+pub fn identity_map_all_available<A>(active_table: &mut ActivePageTable,
+    boot_info: &BootInformation, allocator: &mut A) -> usize
+    where A: FrameAllocator
+{
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+    let mut mapped = 0;
+    for area in memory_map_tag.memory_areas() {
+        let start = cmp::min(area.base_addr as usize, IDENTITY_REGION_END);
+        let end = cmp::min((area.base_addr + area.length) as usize, IDENTITY_REGION_END);
+        let mut number = (start + PAGE_SIZE - 1) / PAGE_SIZE;     // first whole frame
+        let end_number = end / PAGE_SIZE;                        // after the last one
+        while number < end_number {
+            let block_end = number + ENTRIES_PER_TABLE;
+            let huge = number % ENTRIES_PER_TABLE == 0 && block_end <= end_number &&
+                (number..block_end)
+                    .all(|n| active_table.translate_page(Page { number: n }).is_none());
+            if huge {
+                active_table.map_to_2mib(Page { number: number }, Frame { number: number },
+                    WRITABLE | NO_EXECUTE, allocator);
+                mapped += ENTRIES_PER_TABLE;
+                number = block_end;
+            } else {
+                if active_table.translate_page(Page { number: number }).is_none() {
+                    active_table.identity_map(Frame { number: number }, WRITABLE | NO_EXECUTE,
+                        allocator);
+                    mapped += 1;
+                }
+                number += 1;
+            }
+        }
+    }
+    mapped
+}
+
+// the page of the boot P4 table, left unmapped by commit_kernel_table
+static REMAP_GUARD_PAGE: Once<Page> = Once::new();
+
+// check the invariants the remap must establish, run on the active table after
+// memory::init: kernel code executable and read-only, read-only data not writable
+// and not executable, the VGA buffer writable, the old P4 unmapped and the kernel
+// entry point at its load address
+// prints every violation and returns false if there was one
+pub fn test_remap(boot_info: &BootInformation, entry_point: VirtualAddress) -> bool {
+    let mut ok = true;
+    {
+        let mut fail = |what: &str, address: VirtualAddress| {
+            println!("remap test failed: {} ({:#x})", what, address);
+            ok = false;
+        };
+
+        with_active_table(|active_table| {
+            // the ELF sections, or the linker script's ranges without the tag, like the
+            // remap itself
+            for_each_kernel_range(boot_info, |address, end, expected| {
+                let flags = match active_table.translate_with_flags(address) {
+                    Some((_, flags)) => flags,
+                    None => return fail("section not mapped", address),
+                };
+                let executable = !expected.contains(NO_EXECUTE);
+                let writable = expected.contains(WRITABLE);
+                if executable && (flags.contains(WRITABLE) || flags.contains(NO_EXECUTE)) {
+                    fail("code section is writable or not executable", address);
+                }
+                if !executable && !writable &&
+                    (flags.contains(WRITABLE) || !flags.contains(NO_EXECUTE)) {
+                    fail("read-only data section is writable or executable", address);
+                }
+                // every page of .rodata, not only the first one
+                if !writable {
+                    let end = end - 1;
+                    let writable_page = (address / PAGE_SIZE..end / PAGE_SIZE + 1)
+                        .map(|number| number * PAGE_SIZE)
+                        .find(|&page_address| active_table.effective_writable(page_address));
+                    if let Some(page_address) = writable_page {
+                        fail("read-only section is effectively writable", page_address);
+                    }
+                }
+            });
+
+            if !active_table.probe_write(0xb8000) {
+                fail("VGA buffer not writable", 0xb8000);
+            }
+
+            // pure data: the VGA buffer and the multiboot information are not executable
+            if !nxe_enabled() {
+                fail("EFER.NXE is off", 0);
+            }
+            for &address in [vga_buffer_range().start, boot_info.start_address()].iter() {
+                let executable = active_table.translate_with_flags(address)
+                    .map_or(false, |(_, flags)| !flags.contains(NO_EXECUTE));
+                if executable {
+                    fail("data mapping is executable", address);
+                }
+            }
+
+            active_table.for_each_wx_violation(|page| {
+                fail("page is writable and executable", page.start_address());
+            });
+
+            if let Some(guard) = REMAP_GUARD_PAGE.try() {
+                if active_table.translate_page(*guard).is_some() {
+                    fail("guard page is mapped", guard.start_address());
+                }
+            } else {
+                fail("no guard page recorded", 0);
+            }
+
+            let expected = SectionMapping::detect(entry_point).physical_address(entry_point);
+            if active_table.translate(entry_point) != Some(PhysAddr::new(expected)) {
+                fail("entry point not at its load address", entry_point);
+            }
+        });
+    }
+    ok
+}
+
+// map the pages of a section [start, end) to the frames it was loaded to
+// identity mapped if linked low, otherwise page -> (page - KERNEL_VIRTUAL_BASE)
+fn map_section<A>(mapper: &mut Mapper, start: VirtualAddress, end: VirtualAddress,
+    flags: EntryFlags, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mode = SectionMapping::detect(start);
+    // empty for an empty section
+    for page in Page::range_for_bytes(start, end - start) {
+        let frame = Frame::containing_address(
+            mode.physical_address(page.start_address()));
+        mapper.map_to(page, frame, flags, allocator);
+    }
+}
+
+// function to test the paging
+pub fn test_paging<A>(allocator: &mut A, boot_info: &BootInformation)
+    where A: FrameAllocator
+{
+    let mut page_table = unsafe { ActivePageTable::new() };
+
+    test_map_unmap(&mut page_table, allocator);
+    test_relocated_section(&mut page_table, allocator);
+    #[cfg(debug_assertions)]
+    test_unmap_poison(&mut page_table, allocator);
+    test_huge_translate_offset(&mut page_table, allocator);
+    test_active_table_lock(&mut page_table, allocator);
+    test_flag_arithmetic();
+    test_identity_map_phys_range(&mut page_table, allocator);
+    test_fork_copy_on_write(&mut page_table, allocator);
+    test_demand_zero(&mut page_table, allocator);
+    test_temporary_page_placement(&mut page_table, boot_info);
+    test_frame_from_address();
+    test_map_region_with(&mut page_table, allocator);
+    test_mapped_virtual_bytes(&mut page_table, allocator);
+    test_heap_before_init(&mut page_table, allocator);
+    test_protect_huge_page(&mut page_table, allocator);
+    test_kernel_below_recursive_region(boot_info);
+    test_unmap_if_mapped(&mut page_table, allocator);
+    test_mapping_page_size(&mut page_table, allocator);
+    test_split_huge_page(&mut page_table, allocator);
+    test_merge_huge_page(&mut page_table, allocator);
+    test_heap_guards(&mut page_table);
+    test_free_all(&mut page_table, allocator);
+    test_walkers(&mut page_table, allocator);
+    test_section_overlap();
+    test_switch_checks_recursive_entry(&mut page_table, allocator);
+    test_reservations();
+    test_frozen_reservations(&mut page_table, allocator);
+    test_table_from_cr3(&mut page_table, allocator, boot_info);
+    test_available_bits();
+    test_zone_allocation(allocator);
+    test_verify_translations(&mut page_table, allocator);
+    test_map_vga_buffer(allocator);
+    test_identity_conversions();
+    test_cache_types(&mut page_table, allocator);
+    test_zero_size_section(&mut page_table, allocator);
+    test_table_layout();
+    test_prefault_range(&mut page_table, allocator);
+    test_validate_table_acyclic(&mut page_table, allocator);
+    test_translate_batch(&mut page_table, allocator);
+    test_test_frame_allocator();
+    test_entry_physical_address(&mut page_table, allocator);
+    test_protection_keys(&mut page_table, allocator);
+    test_reserved_virtual_range(&mut page_table, allocator);
+    test_shared_frame(&mut page_table, allocator);
+    test_empty_flags(&mut page_table, allocator);
+    test_map_owned(&mut page_table, allocator);
+    test_mmio_mappings(&mut page_table, allocator);
+    test_mapped_slice(&mut page_table, allocator);
+    test_clear_mapped_range(&mut page_table, allocator);
+    test_largest_contiguous_free();
+    test_checksum_frame(&mut page_table, allocator);
+    test_secondary_editor(&mut page_table, allocator);
+    test_map_region_best_fit(&mut page_table, allocator);
+    test_paging_error_display();
+    test_free_frame_iterators();
+    test_exec_only(&mut page_table, allocator);
+    test_entry_flags_display();
+    test_tlb_flush_counter(&mut page_table, allocator);
+    test_memory_map_summary(boot_info);
+    test_snapshot_diff(&mut page_table, allocator);
+    test_temporary_page_aliasing(&mut page_table, allocator);
+    test_recursive_remap_guard(&mut page_table, allocator);
+    test_clear_p4_entry(&mut page_table, allocator);
+    test_effective_writable(&mut page_table, allocator);
+    test_effective_executable(&mut page_table, allocator);
+    test_logging_allocator();
+    test_teardown_guard(&mut page_table, allocator);
+    test_ensure_tables(&mut page_table, allocator);
+    test_x86_phys_conversion();
+    test_find_pages_for_frame(&mut page_table, allocator);
+    test_last_canonical_address();
+    test_harden_kernel_mappings(&mut page_table, boot_info);
+    test_typed_translation(&mut page_table);
+    #[cfg(feature = "identity_map_all")]
+    test_identity_map_all_available(&mut page_table, allocator);
+    test_map_overwrite(&mut page_table, allocator);
+    test_install_recursive_entry(&mut page_table, allocator);
+    test_frames_needed(&mut page_table, allocator);
+    test_region_kinds(&mut page_table, allocator);
+    test_ensure_recursive_mapping(&mut page_table);
+    test_p4_boundary(&mut page_table, allocator);
+    test_for_each_page_in(&mut page_table, allocator);
+    test_stack_growth(&mut page_table, allocator);
+    test_stack_allocator(&mut page_table, allocator);
+    test_translate_extents(&mut page_table, allocator);
+    test_wx_scanner(&mut page_table, allocator);
+    test_claim_frame(allocator);
+    test_pinned_frame(&mut page_table, allocator);
+    test_linker_sections(&mut page_table, allocator);
+    test_boot_data_overlap(&mut page_table, allocator);
+    test_raw_entry_round_trip();
+    test_p4_entries(&mut page_table);
+    test_range_at_address_space_end();
+    test_bitmap_placement();
+    test_corrupt_sections();
+    test_bulk_map(&mut page_table, allocator);
+    test_vm_regions(&mut page_table, allocator);
+    test_kernel_table_sharing(&mut page_table, allocator);
+    test_trampoline_page(&mut page_table, allocator);
+    test_section_flags_check(&mut page_table, allocator);
+    test_stack_check(&mut page_table, allocator);
+    test_recursive_address(&mut page_table);
+    test_identity_map_bound(&mut page_table, allocator);
+    test_unmap_flush_count(&mut page_table, allocator);
+    test_side_by_side(&mut page_table, allocator);
+    test_allocator_exhausted(&mut page_table);
+    test_address_ranges();
+    test_scoped_temporary_mapping(&mut page_table, allocator);
+    test_la57_guard();
+    test_restore_recursive_entry(&mut page_table, allocator);
+    test_testable_memory();
+    test_dirty_bit(&mut page_table, allocator);
+    test_remap_once_guard();
+    test_remap_frame_budget(allocator, boot_info);
+    test_missing_elf_sections();
+    test_remap_once_retry(allocator, boot_info);
+    test_readonly_view(&mut page_table, allocator);
+    test_high_water_mark(&mut page_table, allocator);
+    test_switch_global_flush();
+    test_page_size_selection(&mut page_table, allocator);
+    test_map_range(&mut page_table, allocator);
+    test_update_flags(&mut page_table, allocator);
+    test_page_iter();
+    test_temporary_page_table_leak(&mut page_table, allocator);
+    test_zero_page(&mut page_table, allocator);
+    test_frozen_allocator(allocator);
+    test_walk_depth_limit(&mut page_table, allocator);
+    test_range_last_address();
+    test_bump_allocator_handoff(boot_info);
+    test_recursive_window_refused();
+    test_module_mapping(&mut page_table, allocator);
+    test_temporary_page_state(&mut page_table, allocator);
+    test_executable_check(&mut page_table, allocator);
+    test_kernel_frames();
+    test_global_flush(&mut page_table, allocator);
+    test_split_pat_bit(&mut page_table, allocator);
+    test_software_bits(&mut page_table, allocator);
+    test_guard_entry(&mut page_table, allocator);
+    #[cfg(debug_assertions)]
+    test_reserved_page_refused(&mut page_table, allocator);
+}
+
+// a P4 entry lent to a test: it has to be unused when the test takes it, and the
+// subtree the test built below it is freed when the slot goes out of scope, so the
+// next test finds it empty again even if this one gave up halfway
+struct TestSlot<'a, A: 'a + FrameAllocator> {
+    index: usize,
+    page_table: &'a mut ActivePageTable,
+    allocator: &'a mut A,
+}
+
+impl<'a, A: 'a + FrameAllocator> TestSlot<'a, A> {
+    fn take(index: usize, page_table: &'a mut ActivePageTable, allocator: &'a mut A)
+        -> TestSlot<'a, A>
+    {
+        assert!(page_table.p4()[index].is_unused(), "P4[{}] is already in use", index);
+        TestSlot { index: index, page_table: page_table, allocator: allocator }
+    }
+
+    // the table and the allocator for the test to work with
+    fn parts(&mut self) -> (&mut ActivePageTable, &mut A) {
+        (&mut *self.page_table, &mut *self.allocator)
+    }
+}
+
+impl<'a, A: 'a + FrameAllocator> Drop for TestSlot<'a, A> {
+    fn drop(&mut self) {
+        self.page_table.free_subtree(self.index, false, self.allocator);
+    }
+}
+
+// map_to, update_flags, unmap and unmap_strict of a page in the 42nd P3 entry of P4[0]
+fn test_map_unmap<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let addr = 42 * 512 * 512 * 4096; // 42th P3 entry
+    let page = Page::containing_address(addr);
+    let frame = allocator.allocate_frame().expect("no more frames");
+
+    println!("None = {:?}, map to {:?}", page_table.translate(addr),frame);
+    assert!(!page_table.probe_read(addr) && !page_table.probe_write(addr));
+
+    // map_to adds PRESENT, so empty flags give a present read-only page
+    page_table.map_to(page, frame, EntryFlags::empty(), allocator);
+
+    println!("Some = {:?}", page_table.translate(addr));
+    // mapped read-only
+    assert!(page_table.probe_read(addr) && !page_table.probe_write(addr));
+    page_table.update_flags(page, WRITABLE).unwrap();
+    assert!(page_table.probe_write(addr));
+    println!("next free frame: {:?}", allocator.allocate_frame());
+
+    page_table.unmap(Page::containing_address(addr), allocator);
+    println!("None = {:?}", page_table.translate(addr));
+    // unmapping again is a no-op; unmap_strict would panic here (no way to catch that
+    // in the kernel), on a mapped page it hands out the frame like unmap
+    assert!(page_table.unmap(page, allocator).is_none());
+    {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        let number = frame.number;
+        page_table.map_to(page, frame, EntryFlags::empty(), allocator);
+        let unmapped = page_table.unmap_strict(page);
+        assert!(unmapped.number == number && page_table.unmap(page, allocator).is_none());
+        allocator.deallocate_frame(unmapped);
+    }
+
+    // the first test page is gone again, reading it would fault
+    assert!(page_table.translate(addr).is_none() && !page_table.probe_read(addr));
+}
+
+// a section linked in the higher half is mapped to the frames it was loaded to, a low
+// linked one is identity mapped: a synthetic relocated section, virtual address
+// KERNEL_VIRTUAL_BASE + its load address, loaded at 5 * 512GiB so it lands in the
+// empty P4[261] (P4[256] holds the VGA buffer; the frame is never accessed)
+fn test_relocated_section<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(261, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let physical = 5 * HUGE_PAGE_SIZE_1GIB * ENTRIES_PER_TABLE + 0x3000;
+    let virtual_address = KERNEL_VIRTUAL_BASE + physical;
+    assert!(SectionMapping::detect(virtual_address) == SectionMapping::Relocated &&
+        SectionMapping::detect(physical) == SectionMapping::Identity);
+    map_section(page_table, virtual_address, virtual_address + PAGE_SIZE,
+        NO_EXECUTE, allocator);
+    assert!(page_table.translate(virtual_address + 0x10) ==
+        Some(PhysAddr::new(physical + 0x10)), "the section is not at its load address");
+    page_table.unmap(Page::containing_address(virtual_address), allocator);
+}
+
+// in debug builds a page written and then unmapped by unmap_range has its frame
+// filled with POISON_PATTERN when the BitmapFrameAllocator gets it back, the frame
+// is freed into a bitmap of the first 1GiB and read through the temporary page
+#[cfg(debug_assertions)]
+fn test_unmap_poison<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use memory::BitmapFrameAllocator;
+    static mut POISON_BITMAP: [u64; 4096] = [0; 4096];
+
+    let installed = *POISON_WINDOW.lock();
+    if !installed {
+        page_table.install_poison_window(allocator);
+    }
+    let mut bitmap = BitmapFrameAllocator::new(unsafe { &mut POISON_BITMAP });
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let page = Page::from_table_indices(69, 0, 0, 0);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    assert!(frame.number < bitmap.frame_count(), "the frame is beyond the test bitmap");
+    let mut slot = TestSlot::take(69, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    page_table.map_to(page, frame.clone(), WRITABLE | NO_EXECUTE, allocator);
+    unsafe { ptr::write_bytes(page.start_address() as *mut u8, 0x42, PAGE_SIZE) };
+    let pages = Page::range_inclusive(page, page);
+    assert!(page_table.unmap_range(pages, &mut bitmap) == 1);
+    let poisoned = {
+        let address = temporary_page.map(frame.clone(), page_table);
+        let words = unsafe {
+            slice::from_raw_parts(address as *const u64, PAGE_SIZE / 8)
+        };
+        words.iter().all(|&word| word == POISON_PATTERN)
+    };
+    temporary_page.unmap(page_table);
+    assert!(poisoned, "the frame freed by unmap_range is not poisoned");
+    temporary_page.release(allocator);
+    allocator.deallocate_frame(frame);
+}
+
+// translate adds the offset inside the whole huge page: a 2MiB page in the empty
+// P4[26] on frames above 4GiB (never accessed, only the tables are walked)
+fn test_huge_translate_offset<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(26, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(26, 0, 1, 0);
+    let frame = Frame { number: 4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE + 3 * 512 };
+    let physical = frame.start_address();
+    page_table.map_to_2mib(page, frame, NO_EXECUTE, allocator);
+    for &offset in [0x1234, 0x10_1234, HUGE_PAGE_SIZE_2MIB - 1].iter() {
+        assert!(page_table.translate(page.start_address() + offset) ==
+            Some(PhysAddr::new(physical + offset)), "wrong translation of {:#x}", offset);
+    }
+}
+
+// the active table lock serializes mapping operations: while one runs, a second one
+// can't get the table (try_with_active_table gives up instead of spinning), after it
+// the second one maps its page in the empty P4[27] too
+fn test_active_table_lock<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(27, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let first = Page::from_table_indices(27, 0, 0, 0);
+    let second = Page { number: first.number + 1 };
+    let blocked = with_active_table(|active_table| {
+        active_table.map(first, NO_EXECUTE, allocator);
+        try_with_active_table(|_| ()).is_none()
+    });
+    assert!(blocked, "a second user got the table while the lock was held");
+    let mapped = try_with_active_table(|active_table| {
+        active_table.map(second, NO_EXECUTE, allocator);
+        active_table.translate_page(first).is_some()
+    });
+    assert!(mapped == Some(true), "the free lock was refused");
+    // the fault path fails instead of spinning while the lock is held, and interrupts
+    // are off inside with_active_table and as before after it
+    {
+        use x86_64::registers::flags;
+
+        let interrupts_before = flags::flags().contains(flags::IF);
+        let (refused, interrupts_inside) = with_active_table(|_| {
+            (fault_with_active_table(|_| Ok(())) ==
+                Err(PagingError::ActiveTableUnavailable),
+             flags::flags().contains(flags::IF))
+        });
+        assert!(refused, "the fault path got the table while the lock was held");
+        assert!(!interrupts_inside, "interrupts are enabled while the lock is held");
+        assert!(flags::flags().contains(flags::IF) == interrupts_before,
+            "with_active_table changed the interrupt flag");
+        assert!(fault_with_active_table(|_| Ok(())) == Ok(()),
+            "the fault path was refused the free lock");
+    }
+    for &page in [first, second].iter() {
+        let frame = page_table.unmap(page, allocator).unwrap();
+        allocator.deallocate_frame(frame);
+    }
+}
+
+// flag arithmetic: WRITABLE merged into PRESENT contains both, removing WRITABLE
+// again gives the read-only flags
+fn test_flag_arithmetic() {
+    let writable = PRESENT | WRITABLE;
+    assert!(writable.contains_all(PRESENT | WRITABLE) && !PRESENT.contains_all(writable));
+    let read_only = writable.minus(WRITABLE);
+    assert!(read_only == PRESENT && !read_only.contains(WRITABLE));
+    assert!(PRESENT.minus(WRITABLE) == PRESENT, "removing a clear flag changed the flags");
+}
+
+// a physical range not starting on a frame boundary is identity mapped from the frame
+// of its first byte to the frame of its last: 0x800 + 2 pages at the start of the
+// empty P4[28] touch three frames (identity mapped, the frames are never accessed)
+fn test_identity_map_phys_range<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(28, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let base = Page::from_table_indices(28, 0, 0, 0).start_address();
+    let previous = set_max_physical_frame(None);
+    page_table.identity_map_phys_range(base + 0x800, 2 * PAGE_SIZE, NO_EXECUTE, allocator);
+    set_max_physical_frame(previous);
+    let (first, last) = (Page::containing_address(base),
+        Page::containing_address(base + 2 * PAGE_SIZE));
+    for page in Page::range_inclusive(first, last) {
+        assert!(page_table.translate_page(page) ==
+            Some(Frame::containing_address(page.start_address())),
+            "{:?} of the range is not identity mapped", page);
+    }
+    assert!(page_table.translate(base + 3 * PAGE_SIZE).is_none(), "mapped past the range");
+    for page in Page::range_inclusive(first, last) {
+        page_table.unmap(page, allocator);
+    }
+}
+
+// fork shares a writable user page copy-on-write: parent and child map its frame
+// read-only with COPY_ON_WRITE, and a write fault gives the parent its own copy while
+// the child keeps the old frame (the only user page is the one in the empty P4[29])
+fn test_fork_copy_on_write<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(29, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(29, 0, 0, 0);
+    let address = page.start_address();
+    page_table.map(page, WRITABLE | USER_ACCESSIBLE | NO_EXECUTE, allocator);
+    unsafe { *(address as *mut u64) = 0x1234 };
+    let frame = page_table.translate_page(page).unwrap();
+    assert!(page_table.user_mappings().len() == 1,
+        "other user pages would become copy-on-write");
+
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let mut child = InactivePageTable::fork_from(page_table, &mut temporary_page,
+        allocator);
+    let cow = |flags: EntryFlags| {
+        flags.contains(COPY_ON_WRITE) && !flags.contains(WRITABLE)
+    };
+    let mut child_entry = None;
+    page_table.with(&mut child, &mut temporary_page, |mapper| {
+        child_entry = mapper.p1_entry(page).map(|e| (e.pointed_frame(), e.flags()));
+    });
+    assert!(page_table.p1_entry(page).map_or(false, |entry| cow(entry.flags())),
+        "the parent page is not copy-on-write");
+    assert!(child_entry.map_or(false, |(mapped, flags)| {
+        mapped == Some(frame.clone()) && cow(flags)
+    }), "the child page is not a copy-on-write mapping of the frame");
+
+    let copied = handle_cow_fault(page_table, address, &mut temporary_page,
+        allocator);
+    assert!(copied == Ok(true), "the write fault was not handled: {:?}", copied);
+    let new_frame = page_table.translate_page(page).unwrap();
+    assert!(new_frame != frame && page_table.probe_write(address) &&
+        unsafe { *(address as *const u64) } == 0x1234, "the copy is wrong");
+
+    child.free_all(page_table, &mut temporary_page, allocator, true, false);
+    temporary_page.release(allocator);
+    page_table.unmap(page, allocator);
+    allocator.deallocate_frame(new_frame);
+    allocator.deallocate_frame(frame);
+}
+
+// 100 demand-zero pages in the empty P4[30] share the zero frame until a write fault
+// gives the written page a zeroed frame of its own, the others stay on the zero frame
+fn test_demand_zero<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(30, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    if ZERO_FRAME.try().is_none() {
+        init_zero_frame(page_table, &mut temporary_page, allocator);
+    }
+    let zero = zero_frame();
+    let first = Page::from_table_indices(30, 0, 0, 0);
+    let page = |offset: usize| Page { number: first.number + offset };
+    for offset in 0..100 {
+        page_table.map_zero_page(page(offset), WRITABLE | NO_EXECUTE, allocator);
+    }
+    let zero_pages = |table: &ActivePageTable| (0..100).filter(|&offset| {
+        table.translate_page(page(offset)) == Some(zero.clone())
+    }).count();
+    assert!(zero_pages(page_table) == 100, "a demand-zero page has its own frame");
+    let written = page(7).start_address();
+    assert!(!page_table.probe_write(written));
+    assert!(handle_cow_fault(page_table, written, &mut temporary_page, allocator) ==
+        Ok(true), "the write fault on the zero page was not handled");
+    unsafe { *(written as *mut u64) = 0x5a };
+    let own = page_table.translate_page(page(7)).unwrap();
+    assert!(own != zero && unsafe { *(written as *const u64).offset(1) } == 0,
+        "the written page did not get a zeroed frame of its own");
+    assert!(zero_pages(page_table) == 99, "the other pages left the zero frame");
+    for offset in 0..100 {
+        page_table.unmap(page(offset), allocator);
+    }
+    allocator.deallocate_frame(own);
+    temporary_page.release(allocator);
+}
+
+// the default temporary page overlaps no kernel mapping: no page of a kernel range
+// shares its P4 entry, it is outside P4[0] and the higher half kernel's P4[256] and
+// unmapped while not in use
+fn test_temporary_page_placement(page_table: &mut ActivePageTable,
+    boot_info: &BootInformation)
+{
+    let page = Page { number: temporary_page::TEMP_PAGE_NUMBER };
+    let index = page.p4_index();
+    let mut overlaps = false;
+    for_each_kernel_range(boot_info, |start, end, _| {
+        let (first, last) = (Page::containing_address(start),
+            Page::containing_address(end - 1));
+        overlaps |= first.p4_index() <= index && index <= last.p4_index();
+    });
+    assert!(!overlaps, "the temporary page shares a P4 entry with the kernel");
+    assert!(index != 0 && index != Page::containing_address(KERNEL_VIRTUAL_BASE).p4_index()
+        && index != RECURSIVE_INDEX);
+    assert!(page_table.translate_page(page).is_none(), "the temporary page is mapped");
+}
+
+// from_number and containing_address agree for an aligned address, and for one
+// inside the frame (containing_address rounds down)
+fn test_frame_from_address() {
+    let address = 0x1234 * PAGE_SIZE;
+    assert!(Frame::from_number(0x1234) == Frame::containing_address(address) &&
+        Frame::from_number(0x1234) == Frame::containing_address(address + 0xfff));
+    assert!(Frame::from_number(0x1234).start_address() == address);
+}
+
+// map_region_with maps each page to the next frame of the provider, a provider that
+// runs dry unmaps the region again: three pages of the empty P4[31] on a fixed
+// sequence of frames (never accessed)
+fn test_map_region_with<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(31, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let first = Page::from_table_indices(31, 0, 0, 0);
+    let last = Page { number: first.number + 2 };
+    let numbers = [100, 57, 300];
+    let mut frames = numbers.iter().map(|&number| Frame::from_number(number));
+    page_table.map_region_with(Page::range_inclusive(first, last), NO_EXECUTE,
+        || frames.next(), allocator).expect("the provider had a frame for every page");
+    for (page, &number) in Page::range_inclusive(first, last).zip(numbers.iter()) {
+        assert!(page_table.translate_page(page) == Some(Frame::from_number(number)),
+            "{:?} is not on the provider's frame", page);
+        page_table.unmap(page, allocator);
+    }
+
+    let mut short = numbers[..2].iter().map(|&number| Frame::from_number(number));
+    assert!(page_table.map_region_with(Page::range_inclusive(first, last), NO_EXECUTE,
+        || short.next(), allocator) == Err(PagingError::FrameAllocationFailed));
+    assert!(Page::range_inclusive(first, last)
+        .all(|page| page_table.translate_page(page).is_none()), "no rollback");
+}
+
+// mapped_virtual_bytes counts each leaf with its size: a 4KiB, a 2MiB and a 1GiB
+// page in the empty P4[32] add exactly their sizes (the frames above 4GiB are never
+// accessed)
+fn test_mapped_virtual_bytes<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    const FRAMES_PER_1GIB: usize = ENTRIES_PER_TABLE * ENTRIES_PER_TABLE;
+
+    let mut slot = TestSlot::take(32, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let before = page_table.mapped_virtual_bytes();
+    page_table.map_to(Page::from_table_indices(32, 0, 0, 0), Frame::from_number(
+        4 * FRAMES_PER_1GIB), NO_EXECUTE, allocator);
+    page_table.map_to_2mib(Page::from_table_indices(32, 0, 1, 0), Frame::from_number(
+        4 * FRAMES_PER_1GIB + ENTRIES_PER_TABLE), NO_EXECUTE, allocator);
+    page_table.map_to_1gib(Page::from_table_indices(32, 1, 0, 0), Frame::from_number(
+        5 * FRAMES_PER_1GIB), NO_EXECUTE, allocator);
+    assert!(page_table.mapped_virtual_bytes() - before ==
+        PAGE_SIZE + HUGE_PAGE_SIZE_2MIB + HUGE_PAGE_SIZE_1GIB, "wrong mapped byte count");
+    page_table.free_subtree(32, false, allocator);
+    assert!(page_table.mapped_virtual_bytes() == before);
+}
+
+// a heap allocation before init is refused with the "heap used before initialization"
+// panic (checked without the panic, which can't be caught), after init on a page of
+// the empty P4[33] it is served from that page
+fn test_heap_before_init<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use alloc::heap::{Alloc, Layout};
+    use memory::heap_allocator::CheckedHeap;
+
+    let mut slot = TestSlot::take(33, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let heap = CheckedHeap::empty();
+    assert!(heap.check_initialized() == Err("heap used before initialization"),
+        "an uninitialized heap passes the check");
+    let page = Page::from_table_indices(33, 0, 0, 0);
+    page_table.map(page, WRITABLE | NO_EXECUTE, allocator);
+    unsafe { heap.init(page.start_address(), PAGE_SIZE) };
+    assert!(heap.check_initialized().is_ok());
+    let block = unsafe { (&heap).alloc(Layout::from_size_align(64, 8).unwrap()) };
+    assert!(block.map_or(false, |block| {
+        let address = block as usize;
+        address >= page.start_address() && address + 64 <= page.start_address() + PAGE_SIZE
+    }), "the initialized heap did not allocate from its page");
+    let frame = page_table.unmap(page, allocator).unwrap();
+    allocator.deallocate_frame(frame);
+}
+
+// protecting a 2MiB page read-only updates its P2 entry, which keeps HUGE_PAGE and
+// the frame: a writable huge page in the empty P4[34] (the frames are never accessed)
+fn test_protect_huge_page<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(34, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(34, 0, 0, 0);
+    let frame_number = 4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE;
+    page_table.map_to_2mib(page, Frame::from_number(frame_number), WRITABLE | NO_EXECUTE,
+        allocator);
+    let last = Page { number: page.number + ENTRIES_PER_TABLE - 1 };
+    page_table.protect_range(Page::range_inclusive(page, last), NO_EXECUTE)
+        .expect("the huge page could not be protected");
+    {
+        let (flags, frame) = page_table.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .map(|p2| (p2[page.p2_index()].flags(), p2[page.p2_index()].pointed_frame()))
+            .expect("the huge page has no P2 entry");
+        assert!(flags.contains(HUGE_PAGE | NO_EXECUTE) && !flags.contains(WRITABLE),
+            "wrong flags {:?} after protecting the huge page", flags);
+        assert!(frame == Some(Frame::from_number(frame_number)));
+    }
+    assert!(!page_table.probe_write(last.start_address()));
+}
+
+// the kernel ends below the recursive region, a synthetic section reaching into it
+// trips the remap assertion (its condition is checked, the panic can't be caught),
+// one ending right at the region's start doesn't
+fn test_kernel_below_recursive_region(boot_info: &BootInformation) {
+    let mut kernel_end = 0;
+    for_each_kernel_range(boot_info, |_, end, _| kernel_end = cmp::max(kernel_end, end));
+    assert!(below_recursive_region(kernel_end), "the kernel reaches the recursive region");
+    let section_start = RECURSIVE_REGION_START - PAGE_SIZE;
+    assert!(!below_recursive_region(section_start + 2 * PAGE_SIZE),
+        "a section inside the recursive region passes");
+    assert!(below_recursive_region(section_start + PAGE_SIZE));
+}
+
+// unmap_if_mapped on an unmapped page does nothing and returns None, on a mapped
+// page it unmaps it and returns its frame, a page of a 2MiB page (on frames above
+// 4GiB, never accessed) is refused and stays mapped (pages in the empty P4[35])
+fn test_unmap_if_mapped<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(35, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(35, 0, 0, 0);
+    assert!(page_table.unmap_if_mapped(page, allocator) == Ok(None),
+        "an unmapped page was unmapped");
+    let frame = allocator.allocate_frame().expect("no more frames");
+    page_table.map_to(page, frame.clone(), NO_EXECUTE, allocator);
+    assert!(page_table.unmap_if_mapped(page, allocator) == Ok(Some(frame.clone())),
+        "the mapped page stayed");
+    assert!(page_table.translate_page(page).is_none() &&
+        page_table.unmap_if_mapped(page, allocator) == Ok(None));
+    allocator.deallocate_frame(frame);
+
+    let huge_page = Page::from_table_indices(35, 0, 1, 0);
+    page_table.map_to_2mib(huge_page, Frame::from_number(4 * ENTRIES_PER_TABLE *
+        ENTRIES_PER_TABLE), NO_EXECUTE, allocator);
+    let inside = Page { number: huge_page.number + 3 };
+    assert!(page_table.unmap_if_mapped(inside, allocator) ==
+        Err(PagingError::UnsupportedHugePage), "a page of the huge page was unmapped");
+    assert!(page_table.translate_page(inside).is_some(), "the huge page is gone");
+}
+
+// mapping_page_size reports the size of the mapping behind an address anywhere in it:
+// a 4KiB and a 2MiB page in the empty P4[36] (the frames are never accessed)
+fn test_mapping_page_size<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(36, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let small = Page::from_table_indices(36, 0, 0, 0);
+    let huge = Page::from_table_indices(36, 0, 1, 0);
+    let frame_number = 4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE;
+    page_table.map_to(small, Frame::from_number(frame_number), NO_EXECUTE, allocator);
+    page_table.map_to_2mib(huge, Frame::from_number(frame_number + ENTRIES_PER_TABLE),
+        NO_EXECUTE, allocator);
+    assert!(page_table.mapping_page_size(small.start_address() + 0x123) == Some(PAGE_SIZE));
+    assert!(page_table.mapping_page_size(huge.start_address() + 0x12_3456) ==
+        Some(HUGE_PAGE_SIZE_2MIB), "wrong size inside the 2MiB page");
+    assert!(page_table.mapping_page_size(small.start_address() + PAGE_SIZE).is_none());
+}
+
+// after split_huge_page every address of the former 2MiB page translates to the same
+// physical address and reads the same bytes: a read-only alias of the first 2MiB of
+// physical memory in the empty P4[37], split into 4KiB pages
+fn test_split_huge_page<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(37, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(37, 0, 0, 0);
+    let offsets = [0x10, 0x7008, 0x10_0ff8, HUGE_PAGE_SIZE_2MIB - 8];
+    page_table.map_to_2mib(page, Frame::from_number(0), NO_EXECUTE, allocator);
+    let read = |offset: usize| unsafe {
+        ptr::read_volatile((page.start_address() + offset) as *const u64)
+    };
+    let before: Vec<(Option<PhysAddr>, u64)> = offsets.iter()
+        .map(|&offset| (page_table.translate(page.start_address() + offset), read(offset)))
+        .collect();
+    page_table.split_huge_page(page, allocator).expect("the huge page was not split");
+    assert!(page_table.mapping_page_size(page.start_address()) == Some(PAGE_SIZE));
+    for (&offset, expected) in offsets.iter().zip(before.iter()) {
+        assert!(page_table.translate(page.start_address() + offset) == expected.0 &&
+            read(offset) == expected.1, "offset {:#x} changed in the split", offset);
+    }
+}
+
+// 512 pages on contiguous frames from a 2MiB aligned one with the same flags merge
+// into one huge page and its P1 goes back to the allocator; with two frames swapped
+// the region is refused and stays on 4KiB pages (two regions of the empty P4[38],
+// the frames above 4GiB are never accessed)
+fn test_merge_huge_page<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(38, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let frame_base = 4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE;
+    let mergeable = Page::from_table_indices(38, 0, 1, 0);
+    let swapped = Page::from_table_indices(38, 0, 2, 0);
+    for index in 0..ENTRIES_PER_TABLE {
+        let swapped_index = match index { 5 => 6, 6 => 5, index => index };
+        page_table.map_to(Page { number: mergeable.number + index },
+            Frame::from_number(frame_base + index), NO_EXECUTE, allocator);
+        page_table.map_to(Page { number: swapped.number + index },
+            Frame::from_number(frame_base + ENTRIES_PER_TABLE + swapped_index),
+            NO_EXECUTE, allocator);
+    }
+
+    let available = allocator.frames_available();
+    assert!(page_table.try_merge_huge_page(mergeable, allocator), "no merge");
+    let inside = Page { number: mergeable.number + 77 };
+    assert!(page_table.mapping_page_size(mergeable.start_address()) ==
+        Some(HUGE_PAGE_SIZE_2MIB));
+    assert!(page_table.translate_page(inside) == Some(Frame::from_number(frame_base + 77)));
+    assert!(available.map(|count| count + 1) == allocator.frames_available() ||
+        available.is_none(), "the P1 of the merged region was not freed");
+
+    assert!(!page_table.try_merge_huge_page(swapped, allocator), "merged swapped frames");
+    assert!(page_table.mapping_page_size(swapped.start_address()) == Some(PAGE_SIZE));
+}
+
+// the pages right below and above the heap are unmapped guards, labelled as heap
+// guard violations for the fault handler, while the first and last heap pages are
+// mapped writable
+fn test_heap_guards(page_table: &mut ActivePageTable) {
+    use memory::{heap_guard_pages, describe_fault_address};
+    use {HEAP_START, HEAP_SIZE};
+
+    let (lower_guard, upper_guard) = heap_guard_pages();
+    assert!(page_table.translate_page(lower_guard).is_none() &&
+        page_table.translate_page(upper_guard).is_none(), "a heap guard page is mapped");
+    assert!(page_table.probe_write(HEAP_START) &&
+        page_table.probe_write(HEAP_START + HEAP_SIZE - 1), "the heap is not mapped");
+    assert!(describe_fault_address(lower_guard.start_address() + 8) ==
+        Some("heap guard violation") && describe_fault_address(upper_guard.start_address())
+        == Some("heap guard violation") && describe_fault_address(HEAP_START).is_none());
+}
+
+// free_all gives back every frame of a small address space: its P4, the P3, P2 and
+// P1 of one user page in P4[39] and, with free_leaves, the page's frame
+fn test_free_all<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let available = allocator.frames_available();
+    let mut table = {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        InactivePageTable::new(frame, page_table, &mut temporary_page)
+    };
+    page_table.with(&mut table, &mut temporary_page, |mapper| {
+        mapper.map(Page::from_table_indices(39, 0, 0, 0), WRITABLE | USER_ACCESSIBLE,
+            allocator);
+    });
+    let freed = table.free_all(page_table, &mut temporary_page, allocator, true, true);
+    assert!(freed == 5, "free_all freed {} frames instead of 5", freed);
+    assert!(allocator.frames_available() == available, "the address space leaked frames");
+    temporary_page.release(allocator);
+}
+
+// the same translate assertions hold for the recursive walker and for the offset
+// walker: page (41, 0, 0, 3) maps a frame above 4GiB (never accessed) in the active
+// table and in a mock table hierarchy whose four table frames are mapped at
+// offset + their physical address, the offset being the start of the empty P4[40]
+fn test_walkers<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    fn translations<W: PageTableWalker>(mapper: &Mapper<W>, page: Page) -> bool {
+        let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE + 9);
+        mapper.translate(page.start_address() + 0x10) ==
+            Some(PhysAddr::new(frame.start_address() + 0x10)) &&
+            mapper.translate_page(page) == Some(frame) &&
+            mapper.translate(page.start_address() + PAGE_SIZE).is_none()
+    }
+
+    let mut offset_slot = TestSlot::take(40, page_table, allocator);
+    let (page_table, allocator) = offset_slot.parts();
+    let mut slot = TestSlot::take(41, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(41, 0, 0, 3);
+    let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE + 9);
+    page_table.map_to(page, frame.clone(), NO_EXECUTE, allocator);
+    assert!(translations(&**page_table, page), "wrong translation by the recursive walker");
+
+    let offset = Page::from_table_indices(40, 0, 0, 0).start_address();
+    let walker = OffsetWalker { offset: offset };
+    let frames: Vec<Frame> = (0..4)
+        .map(|_| allocator.allocate_frame().expect("no more frames")).collect();
+    let table_at = |frame: &Frame| {
+        (walker.offset + frame.start_address()) as *mut Table<Level1>
+    };
+    for table_frame in &frames {
+        let alias = Page::containing_address(table_at(table_frame) as usize);
+        page_table.map_to(alias, table_frame.clone(), WRITABLE | NO_EXECUTE, allocator);
+        unsafe { (*table_at(table_frame)).zero() };
+    }
+    // P4 -> P3 -> P2 -> P1 -> frame
+    let indices = [page.p4_index(), page.p3_index(), page.p2_index()];
+    for level in 0..3 {
+        unsafe {
+            (*table_at(&frames[level]))[indices[level]].set(frames[level + 1].clone(),
+                PRESENT | WRITABLE);
+        }
+    }
+    unsafe { (*table_at(&frames[3]))[page.p1_index()].set(frame, PRESENT | NO_EXECUTE) };
+    {
+        let mock = unsafe {
+            Mapper::with_walker(walker.p4(&frames[0]), OffsetWalker { offset: offset })
+        };
+        assert!(translations(&mock, page), "wrong translation by the offset walker");
+    }
+
+    for table_frame in frames {
+        page_table.unmap(Page::containing_address(table_at(&table_frame) as usize),
+            allocator);
+        allocator.deallocate_frame(table_frame);
+    }
+    page_table.unmap(page, allocator);
+}
+
+// two synthetic sections sharing a page are reported as overlapping before anything
+// is mapped (remap_the_kernel panics with both ranges), a third one elsewhere isn't;
+// the sections are raw ElfSection entries: name and type, flags (allocated), address,
+// offset, size, link and info, alignment, entry size
+fn test_section_overlap() {
+    static SECTIONS: [[u64; 8]; 3] = [
+        [1 << 32, 0x2, 0x10_0000, 0, 0x1800, 0, 0x1000, 0],
+        [1 << 32, 0x2, 0x10_1800, 0, 0x1000, 0, 0x800, 0],
+        [1 << 32, 0x2, 0x20_0000, 0, 0x1000, 0, 0x1000, 0],
+    ];
+    let sections = unsafe { &*(&SECTIONS as *const _ as *const [ElfSection; 3]) };
+    assert!(find_section_overlap(sections[..2].iter()) ==
+        Some(((0x10_0000, 0x10_1800), (0x10_1800, 0x10_2800))), "the overlap was missed");
+    assert!(find_section_overlap([&sections[0], &sections[2]].iter().cloned()).is_none(),
+        "separate sections overlap");
+}
+
+// switch refuses a table whose recursive entry doesn't point at its own P4 and hands
+// it back, CR3 stays the same: a fresh table with the recursive entry cleared, and
+// one with it pointing at another frame
+fn test_switch_checks_recursive_entry<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use x86_64::registers::control_regs;
+
+    let cr3 = from_x86_phys(control_regs::cr3());
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let other = allocator.allocate_frame().expect("no more frames");
+    for &clear in [true, false].iter() {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        let table = InactivePageTable::new(frame, page_table, &mut temporary_page);
+        {
+            let p4_frame = table.p4_frame.clone();
+            let p4 = temporary_page.map_table_frame(p4_frame, page_table);
+            if clear {
+                p4[RECURSIVE_INDEX].set_unused();
+            } else {
+                p4[RECURSIVE_INDEX].set(other.clone(), PRESENT | WRITABLE);
+            }
+        }
+        temporary_page.unmap(page_table);
+        let refused = match page_table.switch(table, &mut temporary_page) {
+            Ok(_) => panic!("switched to a table with a broken recursive entry"),
+            Err(table) => table,
+        };
+        assert!(from_x86_phys(control_regs::cr3()) == cr3, "CR3 changed");
+        allocator.deallocate_frame(refused.p4_frame);
+    }
+    allocator.deallocate_frame(other);
+    temporary_page.release(allocator);
+}
+
+// a frame is reserved while any overlapping or adjacent range covers it: the first
+// and the last frame of a range are in, the frame after its end is out, two adjacent
+// ranges leave no gap and virtual reservations only answer for pages
+fn test_reservations() {
+    use memory::ReservationRegistry;
+
+    let mut reservations = ReservationRegistry::new();
+    reservations.reserve_phys("first", 0x10_0000, 0x3000);
+    reservations.reserve_phys("overlapping", 0x10_2000, 0x2000);
+    reservations.reserve_phys("adjacent", 0x10_4000, 0x1000);
+    reservations.reserve_virt("window", 0x4000_0000, 0x2000);
+    let frame = |address| Frame::containing_address(address);
+    assert!(!reservations.contains_phys(&frame(0xf_f000)), "the frame before is reserved");
+    for address in [0x10_0000, 0x10_2000, 0x10_3000, 0x10_4000].iter() {
+        assert!(reservations.contains_phys(&frame(*address)), "a reserved frame is free");
+    }
+    assert!(!reservations.contains_phys(&frame(0x10_5000)), "the frame after is reserved");
+    assert!(reservations.phys_reservation(&frame(0x10_3000)).unwrap().name ==
+        "overlapping", "the wrong reservation answered");
+    assert!(reservations.phys_overlap(0x10_4fff, 0x10_5fff).is_some() &&
+        reservations.phys_overlap(0x10_5000, 0x10_5fff).is_none());
+    assert!(reservations.contains_virt(Page::containing_address(0x4000_1fff)));
+    assert!(!reservations.contains_virt(Page::containing_address(0x4000_2000)));
+    assert!(!reservations.contains_virt(Page::containing_address(0x10_0000)),
+        "a physical reservation answered for a page");
+}
+
+// the mappers check the snapshot frozen at the end of boot, which holds the boot
+// reservations, and never take the RESERVATIONS lock: mapping and freeing a page in
+// the empty P4[70] while the lock is held doesn't deadlock (neither does the runtime
+// BitmapFrameAllocator)
+fn test_frozen_reservations<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use memory::{RESERVATIONS, frozen_reservations};
+
+    let mut slot = TestSlot::take(70, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let frozen = frozen_reservations().expect("the reservations were not frozen");
+    assert!(frozen.contains_virt(Page::containing_address(RECURSIVE_REGION_START)),
+        "the snapshot misses the boot reservations");
+    let page = Page::from_table_indices(70, 0, 0, 0);
+    let _held = RESERVATIONS.lock();
+    page_table.map(page, WRITABLE | NO_EXECUTE, allocator);
+    let frame = page_table.unmap(page, allocator).unwrap();
+    allocator.deallocate_frame(frame);
+}
+
+// a table built from the raw CR3 value of the active table, with PWT and PCD set,
+// is the active P4 and its recursive entry points at itself; a CR3 outside of the
+// available memory is refused
+fn test_table_from_cr3<A>(page_table: &mut ActivePageTable, allocator: &mut A,
+    boot_info: &BootInformation)
+    where A: FrameAllocator
+{
+    use x86_64::registers::control_regs;
+
+    let cr3 = control_regs::cr3().0;
+    let p4_frame = Frame::containing_address(cr3 as PhysicalAddress & !(PAGE_SIZE - 1));
+    let table = InactivePageTable::from_cr3(cr3 | 0x18, boot_info)
+        .expect("the active P4 is not in available memory");
+    assert!(table.p4_frame == p4_frame, "the flag bits were not masked off");
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let entry = table.recursive_entry(page_table, &mut temporary_page);
+    assert!(entry.map_or(false, |(frame, flags)|
+        frame == p4_frame && flags.contains(PRESENT | WRITABLE)),
+        "the recursive entry of the CR3 table doesn't point at itself");
+    temporary_page.release(allocator);
+    assert!(InactivePageTable::from_cr3(1 << 40, boot_info).is_none(),
+        "a CR3 outside of the available memory was accepted");
+}
+
+// the available bits round-trip through an entry without touching the frame or the
+// hardware flags, and setting them again replaces the old value
+fn test_available_bits() {
+    let frame = Frame::from_number(0x1234);
+    let mut entry = Entry::from_raw(0);
+    entry.set(frame.clone(), PRESENT | WRITABLE | NO_EXECUTE);
+    assert!(entry.get_avl() == 0, "a fresh entry has available bits set");
+    for bits in 0..8 {
+        entry.set_avl(bits);
+        assert!(entry.get_avl() == bits, "the available bits {:#b} were lost", bits);
+        assert!(entry.pointed_frame() == Some(frame.clone()), "set_avl moved the frame");
+        assert!(entry.flags().contains(PRESENT | WRITABLE | NO_EXECUTE),
+            "set_avl changed the hardware flags");
+    }
+    entry.set_avl(0);
+    assert!(entry.flags() == PRESENT | WRITABLE | NO_EXECUTE, "cleared bits are still set");
+}
+
+// a Low zone frame lies below 4GiB; allocators without a zone search refuse the
+// Low zone without taking a frame and serve Zone::Any as allocate_frame
+fn test_zone_allocation<A>(allocator: &mut A)
+    where A: FrameAllocator
+{
+    use memory::{TestFrameAllocator, Zone};
+
+    if let Some(frame) = allocator.allocate_frame_in_zone(Zone::Low) {
+        assert!(frame.start_address() < 0x1_0000_0000, "{:?} is not in Zone::Low", frame);
+        allocator.deallocate_frame(frame);
+    }
+
+    let frame = allocator.allocate_frame().expect("no more frames");
+    {
+        let mut plain = TestFrameAllocator::new(frame.clone(), 1);
+        assert!(plain.allocate_frame_in_zone(Zone::Low).is_none() &&
+            plain.available() == 1, "the default Low zone search took a frame");
+        let any = plain.allocate_frame_in_zone(Zone::Any);
+        assert!(any.as_ref().map(|f| f.number) == Some(frame.number));
+        plain.deallocate_frame(any.unwrap());
+    }
+    allocator.deallocate_frame(frame);
+}
+
+// verify_translations agrees with translate on an intact range and reports the first
+// page of a 1GiB page (frame above 4GiB, never accessed) once HUGE_PAGE is set on its
+// P4 entry: translate stops at the invalid P4 entry, the direct walk reads the P3 at
+// its recursive address anyway, where bit 7 of the P4 entry is only PAT
+fn test_verify_translations<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    const FRAMES_PER_1GIB: usize = ENTRIES_PER_TABLE * ENTRIES_PER_TABLE;
+
+    let mut slot = TestSlot::take(42, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(42, 0, 0, 0);
+    let frame = Frame::from_number(4 * FRAMES_PER_1GIB);
+    let pages = || Page::range_inclusive(page, Page::from_table_indices(42, 0, 0, 3));
+    page_table.map_to_1gib(page, frame.clone(), NO_EXECUTE, allocator);
+    assert!(page_table.verify_translations(pages()).is_ok(), "an intact range mismatched");
+
+    let flags = page_table.p4()[42].flags();
+    let p3_frame = page_table.p4()[42].pointed_frame().unwrap();
+    page_table.p4_mut()[42].set(p3_frame.clone(), flags | HUGE_PAGE);
+    tlb::flush_all();
+    let report = page_table.verify_translations(pages());
+    page_table.p4_mut()[42].set(p3_frame, flags);
+    tlb::flush_all();
+    match report {
+        Err(report) => assert!(report.page == page && report.translated.is_none() &&
+            report.walked == Some(frame), "wrong mismatch report: {:?}", report),
+        Ok(()) => panic!("the corrupted P4 entry went unnoticed"),
+    }
+}
+
+// map_vga_buffer hands out the higher half alias of the VGA buffer (already mapped by
+// memory::init, so this takes the "already mapped" path): a character written to the
+// last cell through VGA_BUFFER_VIRTUAL is read back through the identity mapped 0xb8000
+fn test_map_vga_buffer<A>(allocator: &mut A)
+    where A: FrameAllocator
+{
+    use memory::{map_vga_buffer, VGA_BUFFER_VIRTUAL};
+    use vga_buffer::VGA_BUFFER_PHYSICAL;
+
+    let virt = map_vga_buffer(allocator);
+    assert!(virt == VGA_BUFFER_VIRTUAL, "the VGA buffer moved to {:#x}", virt);
+    let last_cell = (80 * 25 - 1) * 2;
+    let alias = (virt + last_cell) as *mut u16;
+    let identity = (VGA_BUFFER_PHYSICAL + last_cell) as *mut u16;
+    unsafe {
+        let saved = ptr::read_volatile(identity);
+        ptr::write_volatile(alias, 0x0d21);   // pink '!'
+        let seen = ptr::read_volatile(identity);
+        ptr::write_volatile(identity, saved);
+        assert!(seen == 0x0d21, "the alias reached another frame: {:#x}", seen);
+    }
+}
+
+// the identity conversions round-trip inside the identity mapped region: a frame and
+// its page share the start address, and converting back gives the original
+fn test_identity_conversions() {
+    for &number in [0, 0xb8, 0x1234, IDENTITY_REGION_END / PAGE_SIZE - 1].iter() {
+        let frame = Frame::from_number(number);
+        let page = Page::from_frame_identity(&frame);
+        assert!(page.start_address() == frame.start_address(),
+            "{:?} and its identity page {:?} start at different addresses", frame, page);
+        assert!(Frame::from_page_identity(page) == frame, "{:?} didn't round-trip", frame);
+        assert!(Page::from_frame_identity(&Frame::from_page_identity(page)) == page);
+    }
+}
+
+// every cache type gets exactly its documented PWT/PCD/PAT bits from map_to_cached,
+// NO_CACHE passed in the flags is replaced, and those bits select the PAT_LAYOUT entry
+// of the type (memory type codes WB 6, WT 4, UC 0, WC 1); frames above 4GiB
+fn test_cache_types<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(43, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let cache_bits = WRITE_THROUGH | NO_CACHE | PAT_4KIB;
+    let types = [(CacheType::WriteBack, EntryFlags::empty(), 6),
+        (CacheType::WriteThrough, WRITE_THROUGH, 4),
+        (CacheType::Uncacheable, NO_CACHE | WRITE_THROUGH, 0),
+        (CacheType::WriteCombining, PAT_4KIB, 1)];
+    for (i, &(cache, bits, memory_type)) in types.iter().enumerate() {
+        let page = Page::from_table_indices(43, 0, 0, i);
+        let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE + i);
+        page_table.map_to_cached(page, frame, NO_CACHE | NO_EXECUTE, cache, allocator);
+        let flags = page_table.translate_with_flags(page.start_address())
+            .expect("the cached page is not mapped").1;
+        assert!(flags & cache_bits == bits, "{:?} got the cache bits {:?}", cache, flags);
+        let pat_index = (flags.contains(PAT_4KIB) as u64) << 2 |
+            (flags.contains(NO_CACHE) as u64) << 1 | flags.contains(WRITE_THROUGH) as u64;
+        assert!((PAT_LAYOUT >> (pat_index * 8)) & 0x7 == memory_type,
+            "{:?} selects PA{} with the wrong memory type", cache, pat_index);
+    }
+}
+
+// a zero-size allocated section maps nothing and doesn't underflow: a synthetic one at
+// the start of the empty P4[44] leaves it unused, and range_for_bytes is empty for size
+// 0 (the raw section is laid out as in the overlap test above)
+fn test_zero_size_section<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    static EMPTY_SECTION: [u64; 8] = [1 << 32, 0x2, 44 << 39, 0, 0, 0, 0x1000, 0];
+
+    let mut slot = TestSlot::take(44, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let section = unsafe { &*(&EMPTY_SECTION as *const _ as *const ElfSection) };
+    assert!(section.is_allocated() && section.start_address() == section.end_address());
+    let before = page_table.mapped_virtual_bytes();
+    map_section(page_table, section.start_address(), section.end_address(),
+        NO_EXECUTE, allocator);
+    assert!(page_table.p4()[44].is_unused() && page_table.mapped_virtual_bytes() == before,
+        "the empty section was mapped");
+    assert!(Frame::range_for_bytes(section.start_address(), 0).next().is_none(),
+        "range_for_bytes yields a frame for 0 bytes");
+    assert!(Frame::range_for_bytes(0xfff, 1).count() == 1 &&
+        Frame::range_for_bytes(0xfff, 2).count() == 2);
+}
+
+// ENTRIES_PER_TABLE ties the table layout together: a table of that many 8 byte
+// entries fills one frame, the recursive index is its last entry and the recursive
+// indices at every level give the address of the P4
+fn test_table_layout() {
+    assert!(ENTRIES_PER_TABLE == 512 && RECURSIVE_INDEX == ENTRIES_PER_TABLE - 1);
+    assert!(mem::size_of::<Table<Level1>>() == ENTRIES_PER_TABLE * 8 &&
+        mem::size_of::<Table<Level1>>() == PAGE_SIZE, "a table doesn't fill a frame");
+    assert!(Page::from_table_indices(RECURSIVE_INDEX, RECURSIVE_INDEX, RECURSIVE_INDEX,
+        RECURSIVE_INDEX).start_address() == P4 as usize, "the recursive index is off");
+}
+
+// prefault_range maps every page of the range read-only and zeroed before anything
+// touches it; a range running into a mapped page is refused with AlreadyMapped and the
+// pages prefaulted before it are unmapped and their frames returned
+fn test_prefault_range<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(45, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let pages = || Page::range_inclusive(Page::from_table_indices(45, 0, 0, 0),
+        Page::from_table_indices(45, 0, 0, 3));
+    page_table.prefault_range(pages(), NO_EXECUTE, true, allocator)
+        .expect("prefaulting failed");
+    for page in pages() {
+        assert!(page_table.translate_page(page).is_some(), "{:?} was not prefaulted", page);
+        assert!(!page_table.effective_writable(page.start_address()));
+    }
+    for page in pages() {
+        let words = unsafe {
+            slice::from_raw_parts(page.start_address() as *const u64, PAGE_SIZE / 8)
+        };
+        assert!(words.iter().all(|&word| word == 0), "{:?} was not zeroed", page);
+        let frame = page_table.translate_page(page).unwrap();
+        page_table.unmap(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+
+    let blocker = Page::from_table_indices(45, 0, 0, 3);
+    page_table.map(blocker, NO_EXECUTE, allocator);
+    let available = allocator.frames_available();
+    assert!(page_table.prefault_range(pages(), NO_EXECUTE, false, allocator) ==
+        Err(PagingError::AlreadyMapped(blocker)), "the mapped page was not refused");
+    for page in Page::range_inclusive(Page::from_table_indices(45, 0, 0, 0),
+        Page::from_table_indices(45, 0, 0, 2))
+    {
+        assert!(page_table.translate_page(page).is_none(), "{:?} is still mapped", page);
+    }
+    assert!(allocator.frames_available() == available, "the rollback leaked frames");
+    let frame = page_table.translate_page(blocker).unwrap();
+    page_table.unmap(blocker, allocator);
+    allocator.deallocate_frame(frame);
+}
+
+// validate_table_acyclic accepts a fresh table with one mapping and reports the P3
+// frame once a P2 entry of that table points back at it; the cycle is removed again
+// before free_all walks the table (the mapped frame lies above 4GiB, never accessed)
+fn test_validate_table_acyclic<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let mut table = {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        InactivePageTable::new(frame, page_table, &mut temporary_page)
+    };
+    page_table.with(&mut table, &mut temporary_page, |mapper| {
+        mapper.map_to(Page::from_table_indices(46, 0, 0, 0), Frame::from_number(
+            4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE), NO_EXECUTE, allocator);
+        assert!(mapper.validate_table_acyclic().is_ok(), "a fresh table has a cycle");
+
+        let p3_frame = mapper.p4()[46].pointed_frame().unwrap();
+        {
+            let p2 = mapper.p4_mut().next_table_mut(46).and_then(|p3| p3.next_table_mut(0))
+                .expect("the P2 of the mapping is missing");
+            p2[1].set(p3_frame.clone(), PRESENT | WRITABLE);
+        }
+        match mapper.validate_table_acyclic() {
+            Err(cycle) => assert!(cycle.frame == p3_frame && cycle.level == 1,
+                "wrong cycle report: {:?}", cycle),
+            Ok(()) => panic!("the P2 entry pointing at the P3 went unnoticed"),
+        }
+        mapper.p4_mut().next_table_mut(46).and_then(|p3| p3.next_table_mut(0))
+            .unwrap()[1].set_unused();
+    });
+    table.free_all(page_table, &mut temporary_page, allocator, true, false);
+    temporary_page.release(allocator);
+}
+
+// translate_batch gives the same results as translate for each address: several 4KiB
+// pages of one 2MiB region (sharing the cached P1), a 2MiB page, an unmapped page and
+// a return to the first region; the frames lie above 4GiB and are never accessed
+fn test_translate_batch<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(47, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let base = 4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE;
+    for i in 0..4 {
+        page_table.map_to(Page::from_table_indices(47, 0, 0, i),
+            Frame::from_number(base + 7 * i), NO_EXECUTE, allocator);
+    }
+    page_table.map_to_2mib(Page::from_table_indices(47, 0, 1, 0),
+        Frame::from_number(base + ENTRIES_PER_TABLE), NO_EXECUTE, allocator);
+    let at = |p2, p1, offset| {
+        Page::from_table_indices(47, 0, p2, p1).start_address() + offset
+    };
+    let addrs = [at(0, 0, 0x10), at(0, 1, 0), at(0, 3, 0xfff), at(0, 2, 0x800),
+        at(0, 4, 0), at(1, 0, 0x123), at(1, 511, 0x8), at(2, 0, 0), at(0, 0, 0x20)];
+    let mut out = [None; 9];
+    page_table.translate_batch(&addrs, &mut out);
+    for (address, result) in addrs.iter().zip(out.iter()) {
+        assert!(*result == page_table.translate(*address),
+            "translate_batch gave {:?} for {:#x}", result, address);
+    }
+    assert!(out[2] == Some(PhysAddr::new((base + 21) * PAGE_SIZE + 0xfff)) &&
+        out[4].is_none() && out[7].is_none(), "translate is wrong as well");
+}
+
+// TestFrameAllocator hands out its frames from the base upwards, a freed frame is the
+// next one handed out (the last freed first) and frames outside its range are never
+// handed out; the frames lie above 4GiB and are never accessed
+fn test_test_frame_allocator() {
+    use memory::TestFrameAllocator;
+
+    let base = 4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE;
+    let mut frames = TestFrameAllocator::new(Frame::from_number(base), 3);
+    let first = frames.allocate_frame().unwrap();
+    let second = frames.allocate_frame().unwrap();
+    assert!(first.number == base && second.number == base + 1, "wrong allocation order");
+    frames.deallocate_frame(first.clone());
+    frames.deallocate_frame(second.clone());
+    assert!(frames.available() == 3);
+    assert!(frames.allocate_frame() == Some(second) &&
+        frames.allocate_frame() == Some(first), "freed frames came back out of order");
+    assert!(frames.allocate_frame() == Some(Frame::from_number(base + 2)));
+    assert!(frames.allocate_frame().is_none() && frames.frames_available() == Some(0),
+        "the allocator handed out a frame outside of its range");
+}
+
+// entry_physical_address of every level of the mapping (48, 1, 2, 3) is the frame of
+// that level's table plus 8 * index, and the P1 entry read at that physical address is
+// the entry of the mapping; a missing P1 gives None (the frame lies above 4GiB)
+fn test_entry_physical_address<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(48, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let indices = [48, 1, 2, 3];
+    let page = Page::from_table_indices(48, 1, 2, 3);
+    page_table.map_to(page, Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE),
+        NO_EXECUTE, allocator);
+    let (frames, raw) = {
+        let p4 = page_table.p4();
+        let p3 = p4.next_table(48).unwrap();
+        let p2 = p3.next_table(1).unwrap();
+        let p4_frame = p4[RECURSIVE_INDEX].pointed_frame().unwrap();
+        ([p4_frame, p4[48].pointed_frame().unwrap(), p3[1].pointed_frame().unwrap(),
+            p2[2].pointed_frame().unwrap()], p2.next_table(2).unwrap()[3].raw())
+    };
+    for level in 1..5 {
+        let table = 4 - level as usize;
+        assert!(page_table.entry_physical_address(level, indices) ==
+            Some(frames[table].start_address() + indices[table] * 8),
+            "wrong address of the P{} entry", level);
+    }
+
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let address = page_table.entry_physical_address(1, indices).unwrap();
+    let seen = {
+        let table = temporary_page.map(Frame::containing_address(address), page_table);
+        unsafe { ptr::read_volatile((table + address % PAGE_SIZE) as *const u64) }
+    };
+    temporary_page.unmap(page_table);
+    temporary_page.release(allocator);
+    assert!(seen == raw, "the P1 entry is not at its physical address");
+    assert!(page_table.entry_physical_address(1, [48, 1, 3, 0]).is_none(),
+        "an entry of a missing P1 has an address");
+
+    page_table.unmap(page, allocator);
+}
+
+// every protection key round-trips through an entry without moving the frame or
+// touching the other flags, and map_to_with_pkey puts the key into the leaf entry
+// (CR4.PKE stays off, so the key has no effect; the frame lies above 4GiB)
+fn test_protection_keys<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(49, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE);
+    let flags = PRESENT | WRITABLE | USER_ACCESSIBLE | NO_EXECUTE;
+    let mut entry = Entry::from_raw(0);
+    entry.set(frame.clone(), flags);
+    for key in 0..16 {
+        entry.set_protection_key(key);
+        assert!(entry.get_protection_key() == key, "protection key {} was lost", key);
+        assert!(entry.pointed_frame() == Some(frame.clone()) &&
+            entry.flags().minus(PROTECTION_KEY) == flags,
+            "protection key {} disturbed the frame or the flags", key);
+    }
+
+    let page = Page::from_table_indices(49, 0, 0, 0);
+    page_table.map_to_with_pkey(page, frame, NO_EXECUTE, 5, allocator);
+    let mapped = page_table.translate_with_flags(page.start_address()).unwrap().1;
+    assert!(mapped.protection_key() == 5, "map_to_with_pkey dropped the key");
+}
+
+// a reserved range of a VirtualAddressSpace is skipped by allocate and stays
+// unmapped: the space covers 16 pages of the empty P4[50], pages 2-5 are reserved
+fn test_reserved_virtual_range<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(50, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = |p1| Page::from_table_indices(50, 0, 0, p1);
+    let mut space = VirtualAddressSpace::new(Page::range_inclusive(page(0), page(15)));
+    assert!(space.reserve(Page::range_inclusive(page(2), page(5))), "reserve failed");
+    assert!(!space.reserve(Page::range_inclusive(page(5), page(6))),
+        "a range overlapping the reservation was reserved again");
+    let first = space.allocate(2).expect("no room for 2 pages");
+    let second = space.allocate(3).expect("no room for 3 pages");
+    assert!(first.start == page(0) && first.end == page(1), "wrong first allocation");
+    assert!(second.start == page(6) && second.end == page(8),
+        "allocate handed out reserved pages");
+    for p1 in 2..6 {
+        assert!(space.is_used(page(p1)) && page_table.translate_page(page(p1)).is_none(),
+            "reserved page {} is free or mapped", p1);
+    }
+    assert!(page_table.p4()[50].is_unused(), "reserving created page table entries");
+}
+
+// a frame mapped to two pages (map_shared, then clone_mapping) survives unmapping the
+// first page and goes back to the allocator with the second
+fn test_shared_frame<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(51, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let (first, second) = (Page::from_table_indices(51, 0, 0, 0),
+        Page::from_table_indices(51, 0, 0, 1));
+    let frame = allocator.allocate_frame().expect("no more frames");
+    page_table.map_shared(first, frame.clone(), WRITABLE | NO_EXECUTE, allocator);
+    page_table.clone_mapping(first, second, allocator).expect("clone_mapping failed");
+    assert!(frame_refcount::count(&frame) == 2, "the frame is not counted twice");
+
+    let available = allocator.frames_available();
+    assert!(!page_table.unmap_shared(first, allocator), "the shared frame was freed");
+    assert!(page_table.translate_page(second) == Some(frame.clone()) &&
+        allocator.frames_available() == available, "the second mapping lost its frame");
+    assert!(page_table.unmap_shared(second, allocator), "the last unmap kept the frame");
+    assert!(frame_refcount::count(&frame) == 0 &&
+        allocator.frames_available() == available.map(|frames| frames + 1));
+}
+
+// the documented PRESENT semantics: map_to with empty flags gives a present, read-only
+// kernel page, while Entry::set stores exactly what it is given, so with empty flags the
+// entry keeps the frame but is not present (the frame lies above 4GiB)
+fn test_empty_flags<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(52, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(52, 0, 0, 0);
+    let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE);
+    page_table.map_to(page, frame.clone(), EntryFlags::empty(), allocator);
+    let flags = page_table.translate_with_flags(page.start_address())
+        .expect("map_to with empty flags gave a page that is not present").1;
+    assert!(flags.contains(PRESENT) && !flags.contains(WRITABLE) &&
+        !flags.contains(USER_ACCESSIBLE), "wrong flags of the mapped page: {:?}", flags);
+    page_table.free_subtree(52, false, allocator);
+
+    let mut entry = Entry::from_raw(0);
+    entry.set(frame.clone(), EntryFlags::empty());
+    assert!(!entry.is_unused() && entry.pointed_frame().is_none() &&
+        entry.raw() == frame.start_address() as u64, "Entry::set implied PRESENT");
+}
+
+// map_owned moves the frame into the mapping (using the moved binding again is a
+// compile error: "use of moved value"), unmap hands the same frame back by value
+fn test_map_owned<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(24, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(24, 0, 0, 0);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    let number = frame.number;
+    page_table.map_owned(page, frame, WRITABLE | NO_EXECUTE, allocator);
+    let owned: Frame = page_table.unmap(page, allocator).expect("unmap gave no frame");
+    assert!(owned.number == number, "unmap returned another frame");
+    assert!(page_table.unmap(page, allocator).is_none(), "the frame was handed out twice");
+    allocator.deallocate_frame(owned);
+}
+
+// the local APIC and the HPET (at its usual 0xfed00000) are mapped at their physical
+// addresses, uncacheable (PCD and PWT), writable and non-executable; the registers are
+// never accessed and the pages are unmapped again
+fn test_mmio_mappings<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    const HPET_BASE: PhysicalAddress = 0xfed0_0000;
+
+    assert!(page_table.translate(LOCAL_APIC_DEFAULT_BASE).is_none() &&
+        page_table.translate(HPET_BASE).is_none(), "the MMIO pages are mapped already");
+    let apic = map_local_apic(page_table, allocator);
+    let hpet = map_hpet(HPET_BASE, page_table, allocator);
+    assert!(apic == LOCAL_APIC_DEFAULT_BASE && hpet == HPET_BASE, "MMIO moved");
+    for &address in [apic, hpet].iter() {
+        let (phys, flags) = page_table.translate_with_flags(address)
+            .expect("the MMIO page is not mapped");
+        assert!(phys == address && flags.contains(NO_CACHE | WRITE_THROUGH) &&
+            flags.contains(WRITABLE | NO_EXECUTE), "wrong MMIO flags {:?}", flags);
+        page_table.unmap(Page::containing_address(address), allocator);
+    }
+}
+
+// mapped_slice hands out a slice inside one mapped page and refuses a misaligned one
+// and one reaching into the unmapped page after it, once that page is mapped as well
+// the slice spanning both pages is handed out
+fn test_mapped_slice<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(54, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let (first, second) = (Page::from_table_indices(54, 0, 0, 0),
+        Page::from_table_indices(54, 0, 0, 1));
+    page_table.map(first, WRITABLE | NO_EXECUTE, allocator);
+    let start = first.start_address();
+    for i in 0..PAGE_SIZE / 4 {
+        unsafe { *((start + i * 4) as *mut u32) = i as u32 };
+    }
+    {
+        let words = page_table.mapped_slice::<u32>(start, PAGE_SIZE / 4)
+            .expect("a slice inside the mapped page was refused");
+        assert!(words.iter().enumerate().all(|(i, &word)| word == i as u32));
+    }
+    assert!(page_table.mapped_slice::<u32>(start + 1, 4).is_none(),
+        "a misaligned slice was handed out");
+    let spanning = second.start_address() - 8;
+    assert!(page_table.mapped_slice::<u32>(spanning, 4).is_none(),
+        "a slice reaching into the unmapped page was handed out");
+    page_table.map(second, WRITABLE | NO_EXECUTE, allocator);
+    assert!(page_table.mapped_slice::<u32>(spanning, 4).map(|words| words[1]) ==
+        Some(PAGE_SIZE as u32 / 4 - 1), "the slice over both pages was refused");
+
+    for &page in [first, second].iter() {
+        let frame = page_table.translate_page(page).unwrap();
+        page_table.unmap(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+}
+
+// clear_mapped_range zeroes a region of two 4KiB pages followed by a 2MiB page: the
+// huge page is backed by the first 2MiB aligned block (from 16MiB on) whose 512 frames
+// are all free, taken frame by frame with allocate_specific_frame
+fn test_clear_mapped_range<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(55, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let block = (8..64).map(|block| block * ENTRIES_PER_TABLE).find(|&first| {
+        let taken = (first..first + ENTRIES_PER_TABLE).take_while(|&number| {
+            allocator.allocate_specific_frame(&Frame::from_number(number))
+        }).count();
+        if taken < ENTRIES_PER_TABLE {
+            for number in first..first + taken {
+                allocator.deallocate_frame(Frame::from_number(number));
+            }
+        }
+        taken == ENTRIES_PER_TABLE
+    }).expect("no free 2MiB block for the huge page");
+    page_table.map(Page::from_table_indices(55, 0, 0, 510), WRITABLE | NO_EXECUTE,
+        allocator);
+    page_table.map(Page::from_table_indices(55, 0, 0, 511), WRITABLE | NO_EXECUTE,
+        allocator);
+    page_table.map_to_2mib(Page::from_table_indices(55, 0, 1, 0), Frame::from_number(block),
+        WRITABLE | NO_EXECUTE, allocator);
+
+    let start = Page::from_table_indices(55, 0, 0, 510);
+    let last = Page::from_table_indices(55, 0, 1, ENTRIES_PER_TABLE - 1);
+    let size = 2 * PAGE_SIZE + HUGE_PAGE_SIZE_2MIB;
+    unsafe { ptr::write_bytes(start.start_address() as *mut u8, 0xff, size) };
+    page_table.clear_mapped_range(Page::range_inclusive(start, last));
+    let words = unsafe {
+        slice::from_raw_parts(start.start_address() as *const u64, size / 8)
+    };
+    assert!(words.iter().all(|&word| word == 0), "clear_mapped_range left bytes behind");
+
+    for p1 in 510..512 {
+        let page = Page::from_table_indices(55, 0, 0, p1);
+        let frame = page_table.translate_page(page).unwrap();
+        page_table.unmap(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+    page_table.free_subtree(55, false, allocator);
+    for number in block..block + ENTRIES_PER_TABLE {
+        allocator.deallocate_frame(Frame::from_number(number));
+    }
+}
+
+// largest_contiguous_free finds the longest free run of a 256 frame bitmap with the
+// runs 3-9, 60-140 (across a whole free word) and 200-201: 81 of the 90 free frames
+// are in that run, 10 percent fragmentation; with everything free the run is the bitmap
+fn test_largest_contiguous_free() {
+    use memory::BitmapFrameAllocator;
+    static mut FRAGMENT_BITMAP: [u64; 4] = [0; 4];
+
+    let mut bitmap = BitmapFrameAllocator::new(unsafe { &mut FRAGMENT_BITMAP });
+    for &(first, last) in [(3, 9), (60, 140), (200, 201)].iter() {
+        bitmap.mark_range_free(Frame::from_number(first), Frame::from_number(last));
+    }
+    assert!(bitmap.largest_contiguous_free() == 81,
+        "largest free run of {} frames instead of 81", bitmap.largest_contiguous_free());
+    assert!(bitmap.free_frames() == 90 && bitmap.fragmentation_percent() == 10);
+    bitmap.mark_range_free(Frame::from_number(0), Frame::from_number(255));
+    assert!(bitmap.largest_contiguous_free() == 256 && bitmap.fragmentation_percent() == 0,
+        "a completely free bitmap is fragmented");
+}
+
+// checksum_frame changes when a single byte of the frame changes and is the same
+// again once the byte is restored
+fn test_checksum_frame<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    fn write_byte(frame: &Frame, value: u8, table: &mut ActivePageTable,
+        temporary_page: &mut TemporaryPage)
+    {
+        let address = temporary_page.map(frame.clone(), table);
+        unsafe { ptr::write_volatile((address + 0x123) as *mut u8, value) };
+        temporary_page.unmap(table);
+    }
+
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    write_byte(&frame, 0, page_table, &mut temporary_page);
+    let before = checksum_frame(&frame, page_table, &mut temporary_page);
+    write_byte(&frame, 0x5a, page_table, &mut temporary_page);
+    let changed = checksum_frame(&frame, page_table, &mut temporary_page);
+    write_byte(&frame, 0, page_table, &mut temporary_page);
+    let restored = checksum_frame(&frame, page_table, &mut temporary_page);
+    assert!(changed != before, "the checksum missed a changed byte");
+    assert!(restored == before, "the checksum of the restored frame differs");
+    temporary_page.release(allocator);
+    allocator.deallocate_frame(frame);
+}
+
+// an entry written through the secondary recursive slot is there after switching to
+// the edited table: a fresh table gets the kernel P4 entries and, in P4[56], a page of
+// a frame holding a marker, all through the editor; after the switch the page reads
+// the marker, then the old table is switched back in
+fn test_secondary_editor<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    const MARKER: u64 = 0x5ec0_4da7_7ab1_e000;
+
+    let mut slot = TestSlot::take(56, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(56, 0, 0, 0);
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    {
+        let address = temporary_page.map(frame.clone(), page_table);
+        unsafe { ptr::write_volatile(address as *mut u64, MARKER) };
+    }
+    temporary_page.unmap(page_table);
+    let mut table = {
+        let p4_frame = allocator.allocate_frame().expect("no more frames");
+        InactivePageTable::new(p4_frame, page_table, &mut temporary_page)
+    };
+    let kernel_entries = page_table.kernel_p4_entries();
+    {
+        let mut editor = table.map_secondary_recursive(page_table);
+        for &(index, ref kernel_frame, flags) in &kernel_entries {
+            editor.p4_mut()[index].set(kernel_frame.clone(), flags);
+        }
+        editor.map_to(page, frame.clone(), WRITABLE | NO_EXECUTE, allocator);
+    }
+    assert!(page_table.translate_page(page).is_none(), "the edit reached the active table");
+
+    let old_table = match page_table.switch(table, &mut temporary_page) {
+        Ok(old_table) => old_table,
+        Err(_) => panic!("the edited table was refused"),
+    };
+    let seen = page_table.translate_page(page).map(|mapped| {
+        (mapped, unsafe { ptr::read_volatile(page.start_address() as *const u64) })
+    });
+    let mut table = match page_table.switch(old_table, &mut temporary_page) {
+        Ok(table) => table,
+        Err(_) => panic!("switching back to the old table failed"),
+    };
+    assert!(seen == Some((frame.clone(), MARKER)), "the edited entry is lost: {:?}", seen);
+
+    page_table.with(&mut table, &mut temporary_page, |mapper| {
+        mapper.free_subtree(56, false, allocator);
+    });
+    allocator.deallocate_frame(table.p4_frame);
+    allocator.deallocate_frame(frame);
+    temporary_page.release(allocator);
+}
+
+// map_region_best_fit on a range starting two pages before a 2MiB boundary: a 4KiB
+// prefix of two pages, one 2MiB page as the body and a 4KiB suffix of six pages, every
+// page backed by the frame frame_for gave (frames above 4GiB, never accessed)
+fn test_map_region_best_fit<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(57, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let first = Page::from_table_indices(57, 0, 0, 510);
+    let last = Page::from_table_indices(57, 0, 2, 5);
+    let base = 4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE + 510;
+    let frame_for = |page: Page| {
+        Some(Frame::from_number(base + page.number - first.number))
+    };
+    let huge_pages = page_table.map_region_best_fit(Page::range_inclusive(first, last),
+        &frame_for, NO_EXECUTE, allocator);
+    assert!(huge_pages == Ok(1), "best fit used {:?} huge pages instead of 1", huge_pages);
+    for page in Page::range_inclusive(first, last) {
+        let expected = if page.p2_index() == 1 { HUGE_PAGE_SIZE_2MIB } else { PAGE_SIZE };
+        assert!(page_table.mapping_page_size(page.start_address()) == Some(expected),
+            "{:?} is not mapped with a {:#x} byte page", page, expected);
+        assert!(page_table.translate_page(page) == frame_for(page), "{:?} moved", page);
+    }
+}
+
+// the Display output of every PagingError variant
+fn test_paging_error_display() {
+    let page = Page::containing_address(0x5000);
+    let errors = [
+        (PagingError::FrameAllocationFailed, "frame allocation failed"),
+        (PagingError::AlreadyMapped(page), "page 0x5000 is already mapped"),
+        (PagingError::NotMapped(page), "page 0x5000 is not mapped"),
+        (PagingError::InvalidAddress(0x8000_0000_0000),
+            "invalid virtual address 0x800000000000"),
+        (PagingError::Misaligned, "page or frame is misaligned"),
+        (PagingError::UnsupportedHugePage, "unsupported huge page mapping"),
+        (PagingError::Pinned(page), "the frame of page 0x5000 is pinned"),
+        (PagingError::StackOverflow(page), "stack overflow at page 0x5000"),
+        (PagingError::WrongFlags(page), "page 0x5000 has the wrong flags"),
+        (PagingError::BeyondPhysicalMemory(0x1_0000_0000),
+            "frame 0x100000000 is beyond physical memory"),
+        (PagingError::OutOfFrames(page), "out of frames while mapping page 0x5000"),
+        (PagingError::UnsupportedPagingMode,
+            "5-level paging is enabled, only 4 levels are supported"),
+        (PagingError::AlreadyRemapped, "kernel already remapped"),
+        (PagingError::UnknownFrameCount,
+            "the frame allocator can't tell how many frames it has"),
+        (PagingError::IncompleteKernelTable(0xb8000),
+            "the new kernel table does not map 0xb8000"),
+        (PagingError::MissingElfSections,
+            "the multiboot information has no ELF sections tag"),
+        (PagingError::WalkTooDeep(0x5000),
+            "page table walk deeper than 4 levels at table 0x5000"),
+        (PagingError::ActiveTableUnavailable, "the active page table is unavailable"),
+        (PagingError::RecursiveEntryInUse,
+            "the recursive P4 entry is in use by another mapping"),
+        (PagingError::ReservedPage(page), "page 0x5000 is reserved"),
+    ];
+    for &(error, expected) in errors.iter() {
+        let message = format!("{}", error);
+        assert!(message == expected, "{:?} is displayed as \"{}\"", error, message);
+    }
+}
+
+// free_frames_iter and free_ranges_iter of a 256 frame bitmap with the free runs 0-2,
+// 63-64 (across a word boundary), 130 and 250-255 yield exactly those frames and runs,
+// and reflect an allocation made after the first walk
+fn test_free_frame_iterators() {
+    use memory::BitmapFrameAllocator;
+    static mut FREE_RANGES_BITMAP: [u64; 4] = [0; 4];
+
+    let mut bitmap = BitmapFrameAllocator::new(unsafe { &mut FREE_RANGES_BITMAP });
+    for &(first, last) in [(0, 2), (63, 64), (130, 130), (250, 255)].iter() {
+        bitmap.mark_range_free(Frame::from_number(first), Frame::from_number(last));
+    }
+    {
+        let mut frames = bitmap.free_frames_iter();
+        for &number in [0, 1, 2, 63, 64, 130, 250, 251, 252, 253, 254, 255].iter() {
+            assert!(frames.next() == Some(Frame::from_number(number)),
+                "free frame {} is not yielded in order", number);
+        }
+        assert!(frames.next().is_none(), "a used frame is yielded as free");
+    }
+    {
+        let mut ranges = bitmap.free_ranges_iter();
+        for &(first, count) in [(0, 3), (63, 2), (130, 1), (250, 6)].iter() {
+            assert!(ranges.next() == Some((Frame::from_number(first), count)),
+                "the free run at frame {} is not yielded as {} frames", first, count);
+        }
+        assert!(ranges.next().is_none(), "a free run is yielded twice");
+    }
+    assert!(bitmap.allocate_frame() == Some(Frame::from_number(0)));
+    assert!(bitmap.free_ranges_iter().next() == Some((Frame::from_number(1), 2)),
+        "the free ranges don't reflect the allocated frame");
+}
+
+// map_to_exec_only drops NO_EXECUTE and WRITABLE; a kernel page always takes the
+// fallback and stays readable, a user page is only execute-only with PKU enabled
+fn test_exec_only<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(58, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let kernel_page = Page::from_table_indices(58, 0, 0, 0);
+    let user_page = Page::from_table_indices(58, 0, 0, 1);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    let user_frame = allocator.allocate_frame().expect("no more frames");
+
+    assert!(!page_table.map_to_exec_only(kernel_page, frame, WRITABLE | NO_EXECUTE,
+        allocator), "a kernel page is reported as execute-only");
+    let flags = page_table.translate_with_flags(kernel_page.start_address()).unwrap().1;
+    assert!(!flags.contains(NO_EXECUTE) && !flags.contains(WRITABLE),
+        "the exec only page is not executable or still writable");
+    assert!(page_table.probe_read(kernel_page.start_address()),
+        "the fallback mapping is not readable");
+
+    let exec_only = page_table.map_to_exec_only(user_page, user_frame,
+        USER_ACCESSIBLE | NO_EXECUTE, allocator);
+    assert!(exec_only == execute_only_supported());
+    let flags = page_table.translate_with_flags(user_page.start_address()).unwrap().1;
+    assert!(!flags.contains(NO_EXECUTE), "the exec only user page is not executable");
+    if exec_only {
+        assert!(flags.protection_key() == EXEC_ONLY_PKEY, "the exec only key is missing");
+    } else {
+        assert!(flags.protection_key() == 0, "the fallback user page has a key");
+    }
+    page_table.free_subtree(58, true, allocator);
+}
+
+// the Display output of EntryFlags lists the set flags in a fixed order, a protection
+// key as PK<n> and prints "-" without any flags
+fn test_entry_flags_display() {
+    let flags = NO_EXECUTE | USER_ACCESSIBLE | WRITABLE | PRESENT;
+    assert!(format!("{}", flags) == "P|W|U|NX", "flags are displayed as {}", flags);
+    let flags = PRESENT | HUGE_PAGE | GLOBAL | EntryFlags::from_protection_key(3);
+    assert!(format!("{}", flags) == "P|H|G|PK3", "flags are displayed as {}", flags);
+    assert!(format!("{}", EntryFlags::empty()) == "-", "empty flags aren't displayed as -");
+}
+
+// update_flags and unmap each flush the TLB exactly once, a flush_all counts once
+fn test_tlb_flush_counter<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(59, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(59, 0, 0, 0);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    page_table.map_to(page, frame.clone(), WRITABLE, allocator);
+
+    let before = tlb::flush_count();
+    page_table.update_flags(page, PRESENT).expect("update_flags failed");
+    assert!(tlb::flush_count() - before == 1, "update_flags flushed {} times",
+        tlb::flush_count() - before);
+    let before = tlb::flush_count();
+    page_table.unmap(page, allocator);
+    assert!(tlb::flush_count() - before == 1, "unmap flushed {} times",
+        tlb::flush_count() - before);
+    let before = tlb::flush_count();
+    tlb::flush_all();
+    assert!(tlb::flush_count() - before == 1, "flush_all counted more than once");
+
+    page_table.free_subtree(59, false, allocator);
+    allocator.deallocate_frame(frame);
+}
+
+// total_ram and largest_region of a synthetic memory map with the available areas
+// 0-0x9fc00 and 1MiB-128MiB and a reserved area below 4GiB that is skipped; a map
+// with an area up to the end of the address space saturates the total and cuts the
+// area at 2^64 - 1
+fn test_memory_map_summary(boot_info: &BootInformation) {
+    use memory::{total_ram, largest_region};
+
+    // total size, memory map tag (type 6, size 88, entry size 24), three areas
+    // (base, length, type), end tag (type 0, size 8)
+    static MEMORY_MAP: [u64; 13] = [104, 6 | 88 << 32, 24,
+        0, 0x9_fc00, 1, 0x10_0000, 0x7ee_0000, 1, 0xfffc_0000, 0x4_0000, 2, 8 << 32];
+    let boot_info = unsafe { multiboot2::load(&MEMORY_MAP as *const _ as usize) };
+    assert!(total_ram(boot_info) == 0x9_fc00 + 0x7ee_0000,
+        "total RAM of 0x{:x} bytes", total_ram(boot_info));
+    assert!(largest_region(boot_info) == (0x10_0000, 0x7ee_0000),
+        "the largest region is {:?}", largest_region(boot_info));
+
+    static HUGE_MEMORY_MAP: [u64; 10] = [80, 6 | 64 << 32, 24,
+        0x10_0000, 0x7ee_0000, 1, 1 << 32, !0, 1, 8 << 32];
+    let boot_info = unsafe { multiboot2::load(&HUGE_MEMORY_MAP as *const _ as usize) };
+    assert!(total_ram(boot_info) == usize::max_value(), "the total RAM overflowed");
+    assert!(largest_region(boot_info) == (1 << 32, (u64::max_value() - (1 << 32)) as usize),
+        "the area reaching past the address space isn't cut");
+}
+
+// diff of two snapshots of a fresh table reports exactly the one changed mapping,
+// making a page of P4[60] read-only (frames above 4GiB, never accessed)
+fn test_snapshot_diff<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let mut table = {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        InactivePageTable::new(frame, page_table, &mut temporary_page)
+    };
+    let page = |p1| Page::from_table_indices(60, 0, 0, p1);
+    let frame = |p1| Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE + p1);
+    page_table.with(&mut table, &mut temporary_page, |mapper| {
+        mapper.map_to(page(0), frame(0), WRITABLE, allocator);
+        mapper.map_to(page(1), frame(1), WRITABLE, allocator);
+        let before = mapper.snapshot_mappings();
+        assert!(before.leaves().len() == 2, "the snapshot has {} leaves",
+            before.leaves().len());
+        assert!(diff(&before, &before).is_empty(), "a snapshot differs from itself");
+
+        mapper.update_flags(page(1), PRESENT).expect("update_flags failed");
+        let diffs = diff(&before, &mapper.snapshot_mappings());
+        assert!(diffs.len() == 1, "{} differences for one change", diffs.len());
+        match diffs[0] {
+            MappingDiff::Changed { old, new } => assert!(old.page == page(1) &&
+                new.page == page(1) && old.frame_number == new.frame_number &&
+                new.flags == old.flags.minus(WRITABLE), "wrong change {:?}", diffs[0]),
+            other => panic!("the change is reported as {:?}", other),
+        }
+    });
+    table.free_all(page_table, &mut temporary_page, allocator, true, false);
+    temporary_page.release(allocator);
+}
+
+// the aliasing check of TemporaryPage::map (its panic can't be caught): while the
+// temporary page is mapped its own P1 is flagged, the P4 and the mapped frame are not
+fn test_temporary_page_aliasing<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    let page = Page::containing_address(temporary_page.map(frame.clone(), page_table));
+    let indices = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+    {
+        let table_frame = |level| Frame::containing_address(
+            page_table.entry_physical_address(level, indices).unwrap());
+        let (p1_frame, p4_frame) = (table_frame(1), table_frame(4));
+
+        assert!(temporary_page.is_own_table_frame(&p1_frame, page_table),
+            "the P1 of the temporary page is not detected");
+        assert!(!temporary_page.is_own_table_frame(&p4_frame, page_table) &&
+            !temporary_page.is_own_table_frame(&frame, page_table),
+            "the P4 or the mapped frame is taken for a table of the temporary page");
+    }
+    temporary_page.unmap(page_table);
+    temporary_page.release(allocator);
+    allocator.deallocate_frame(frame);
+}
+
+// a RecursiveRemapGuard points the recursive entry to another table while it lives
+// and restores it when a function returns early past it (the kernel aborts on panic,
+// so the early return stands in for an unwinding closure)
+fn test_recursive_remap_guard<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use x86_64::registers::control_regs;
+
+    fn return_early(active_table: &mut ActivePageTable, target: Frame,
+        temporary_page: &mut TemporaryPage) -> bool
+    {
+        let _guard = RecursiveRemapGuard::new(active_table, target.clone(), temporary_page);
+        if active_table.p4()[RECURSIVE_INDEX].pointed_frame() == Some(target) {
+            return true;
+        }
+        false
+    }
+
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let mut table = {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        InactivePageTable::new(frame, page_table, &mut temporary_page)
+    };
+    let p4_frame = Frame::containing_address(from_x86_phys(control_regs::cr3()));
+    assert!(return_early(page_table, table.p4_frame.clone(), &mut temporary_page),
+        "the guard didn't point the recursive entry to the other table");
+    assert!(page_table.p4()[RECURSIVE_INDEX].pointed_frame() == Some(p4_frame),
+        "the recursive entry wasn't restored on the early return");
+    temporary_page.unmap(page_table);
+    table.free_all(page_table, &mut temporary_page, allocator, true, false);
+    temporary_page.release(allocator);
+}
+
+// clear_p4_entry makes the whole 512GiB of P4[61] absent: freeing, it returns the 3
+// mapped frames and the 7 tables below the entry; without freeing only the entry is
+// cleared (the leaked tables of that half are handed back by hand afterwards)
+fn test_clear_p4_entry<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(61, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let start = Page::from_table_indices(61, 0, 0, 0).start_address();
+    let end = start + ENTRIES_PER_TABLE * HUGE_PAGE_SIZE_1GIB - 1;
+    let page = |p3, p2, p1| Page::from_table_indices(61, p3, p2, p1);
+    let pages = [page(0, 0, 0), page(200, 5, 9), page(511, 511, 511)];
+    for &page in pages.iter() {
+        page_table.map(page, WRITABLE, allocator);
+    }
+    assert!(page_table.clear_p4_entry(61, true, allocator) == 10,
+        "clear_p4_entry didn't free the frames and tables");
+    assert!(page_table.p4()[61].is_unused(), "the P4 entry is still present");
+    let addresses = [start, end, pages[1].start_address(), pages[2].start_address()];
+    for &address in addresses.iter() {
+        assert!(page_table.translate(address).is_none(), "0x{:x} is still mapped", address);
+    }
+
+    let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE);
+    page_table.map_to(pages[1], frame, EntryFlags::empty(), allocator);
+    let indices = [61, 200, 5, 9];
+    let tables: Vec<Frame> = (1..4).map(|level| Frame::containing_address(
+        page_table.entry_physical_address(level, indices).unwrap())).collect();
+    assert!(page_table.clear_p4_entry(61, false, allocator) == 0);
+    assert!(page_table.translate(pages[1].start_address()).is_none(),
+        "the page is still mapped after clearing the entry");
+    for table in tables {
+        allocator.deallocate_frame(table);
+    }
+}
+
+// effective_writable is false for a writable leaf below a P3 entry without WRITABLE,
+// true again once the entry allows writes
+fn test_effective_writable<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(62, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(62, 0, 0, 0);
+    let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE);
+    page_table.map_to(page, frame, WRITABLE, allocator);
+    assert!(page_table.effective_writable(page.start_address()),
+        "a writable page is not effectively writable");
+
+    let set_p3_flags = |page_table: &mut ActivePageTable, flags| {
+        let entry = &mut page_table.p4_mut().next_table_mut(62).unwrap()[0];
+        let p2_frame = entry.pointed_frame().unwrap();
+        entry.set(p2_frame, flags);
+        tlb::flush_all();
+    };
+    set_p3_flags(page_table, PRESENT);
+    assert!(page_table.p1_entry(page).unwrap().flags().contains(WRITABLE));
+    assert!(!page_table.effective_writable(page.start_address()),
+        "a page below a read-only P3 entry is effectively writable");
+    set_p3_flags(page_table, PRESENT | WRITABLE);
+    assert!(page_table.effective_writable(page.start_address()),
+        "the page is not writable again once the P3 entry allows it");
+}
+
+// a NO_EXECUTE P4 entry makes its executable leaf non-executable, and a user leaf
+// below the kernel only tables map_to creates is not user accessible
+fn test_effective_executable<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(63, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(63, 0, 0, 0);
+    let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE);
+    page_table.map_to(page, frame, USER_ACCESSIBLE, allocator);
+    let address = page.start_address();
+    assert!(page_table.p1_entry(page).unwrap().flags().contains(USER_ACCESSIBLE));
+    assert!(page_table.effective_executable(address) &&
+        !page_table.effective_user_accessible(address),
+        "a leaf below kernel only tables is user accessible or the page not executable");
+
+    let set_p4_flags = |page_table: &mut ActivePageTable, flags| {
+        let p3_frame = page_table.p4()[63].pointed_frame().unwrap();
+        page_table.p4_mut()[63].set(p3_frame, flags);
+        tlb::flush_all();
+    };
+    set_p4_flags(page_table, PRESENT | WRITABLE | NO_EXECUTE);
+    assert!(!page_table.p1_entry(page).unwrap().flags().contains(NO_EXECUTE));
+    assert!(!page_table.effective_executable(address),
+        "the NO_EXECUTE of the P4 entry is not applied to the leaf");
+    set_p4_flags(page_table, PRESENT | WRITABLE);
+    assert!(page_table.effective_executable(address),
+        "the page is not executable again without NO_EXECUTE on the P4 entry");
+}
+
+// a LoggingFrameAllocator around a TestFrameAllocator of 2 frames records every
+// allocation, the failed one and the free in order, 2 frames stay outstanding
+// (frames above 4GiB, never accessed)
+fn test_logging_allocator() {
+    use memory::{TestFrameAllocator, LoggingFrameAllocator, FrameEvent};
+
+    let base = 4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE;
+    let mut logging = LoggingFrameAllocator::new(
+        TestFrameAllocator::new(Frame::from_number(base), 2), false);
+    let first = logging.allocate_frame().expect("no first frame");
+    assert!(logging.allocate_frame() == Some(Frame::from_number(base + 1)));
+    assert!(logging.allocate_frame().is_none(), "a third frame was allocated");
+    logging.deallocate_frame(first);
+    assert!(logging.allocate_frame() == Some(Frame::from_number(base)));
+
+    let expected = [FrameEvent::Allocated(base), FrameEvent::Allocated(base + 1),
+        FrameEvent::Failed, FrameEvent::Freed(base), FrameEvent::Allocated(base)];
+    assert!(logging.log().count() == expected.len(), "{} events were logged",
+        logging.log().count());
+    for (sequence, (entry, &event)) in logging.log().zip(expected.iter()).enumerate() {
+        assert!(entry.sequence == sequence && entry.event == event,
+            "logged {:?} instead of event {} {:?}", entry, sequence, event);
+    }
+    assert!(logging.outstanding() == 2, "{} frames are outstanding", logging.outstanding());
+}
+
+// teardown with unmap_if_mapped leaves an installed guard page alone
+fn test_teardown_guard<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(25, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(25, 0, 0, 0);
+    page_table.install_guard_page(page, allocator);
+    assert!(page_table.unmap_if_mapped(page, allocator) == Ok(None),
+        "the guard page was unmapped");
+    assert!(page_table.is_guard_page(page.start_address()), "the guard page is gone");
+    page_table.remove_guard_page(page);
+}
+
+// after ensure_tables over pages of two P1 tables in P4[64] nothing is mapped, and
+// map_to into both tables takes no frame from its allocator (fake frames above 4GiB,
+// never accessed)
+fn test_ensure_tables<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use memory::TestFrameAllocator;
+
+    let mut slot = TestSlot::take(64, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let first = Page::from_table_indices(64, 0, 0, 500);
+    let last = Page::from_table_indices(64, 0, 1, 10);
+    page_table.ensure_tables(Page::range_inclusive(first, last), allocator);
+    assert!(page_table.translate(first.start_address()).is_none() &&
+        page_table.translate(last.start_address()).is_none(),
+        "ensure_tables mapped a page");
+
+    let frame = |n| Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE + n);
+    let mut no_tables = TestFrameAllocator::new(frame(2), 1);
+    page_table.map_to(first, frame(0), WRITABLE, &mut no_tables);
+    page_table.map_to(last, frame(1), WRITABLE, &mut no_tables);
+    assert!(no_tables.available() == 1, "map_to allocated a table after ensure_tables");
+    assert!(page_table.translate_page(last) == Some(frame(1)));
+}
+
+// to_x86_phys and from_x86_phys round-trip physical addresses up to 52 bits without
+// losing bits, and agree with the frame of the current CR3
+fn test_x86_phys_conversion() {
+    use x86_64::registers::control_regs;
+
+    for &address in [0, 0x1234_5000, 0x1_0000_0000, MAX_PHYSICAL_ADDRESS].iter() {
+        assert!(to_x86_phys(address).0 == address as u64 &&
+            from_x86_phys(to_x86_phys(address)) == address,
+            "0x{:x} doesn't survive the conversion", address);
+    }
+    let cr3 = control_regs::cr3();
+    assert!(to_x86_phys(from_x86_phys(cr3)).0 == cr3.0, "CR3 changed in the conversion");
+}
+
+// find_pages_for_frame finds both pages of P4[65] that map one frame, in address
+// order, and nothing for a frame mapped nowhere (frames above 4GiB, never accessed)
+fn test_find_pages_for_frame<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(65, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE);
+    let pages = [Page::from_table_indices(65, 0, 0, 3),
+        Page::from_table_indices(65, 7, 1, 2)];
+    for &page in pages.iter() {
+        page_table.map_to(page, frame.clone(), EntryFlags::empty(), allocator);
+    }
+    assert!(page_table.find_pages_for_frame(&frame) == pages.to_vec(),
+        "the reverse lookup found {:?}", page_table.find_pages_for_frame(&frame));
+    let unmapped = Frame::from_number(frame.number + 1);
+    assert!(page_table.find_pages_for_frame(&unmapped).is_empty(),
+        "a frame mapped nowhere has pages");
+}
+
+// the last canonical address is in the last page of P4[511] (all indices 511), and
+// range_for_bytes reaches it without start + size overflowing
+fn test_last_canonical_address() {
+    let page = Page::containing_address(0xffff_ffff_ffff_ffff);
+    assert!(page.start_address() == 0xffff_ffff_ffff_f000,
+        "the top page starts at 0x{:x}", page.start_address());
+    assert!(page.p4_index() == 511 && page.p3_index() == 511 && page.p2_index() == 511 &&
+        page.p1_index() == 511, "the top page has the wrong table indices");
+    assert!(Page::from_table_indices(511, 511, 511, 511) == page);
+
+    let mut pages = Page::range_for_bytes(page.start_address(), PAGE_SIZE);
+    assert!(pages.next() == Some(page) && pages.next().is_none(),
+        "range_for_bytes doesn't yield exactly the top page");
+    assert!(Page::range_for_bytes(page.start_address(), 0).next().is_none(),
+        "an empty range at the top has pages");
+}
+
+// a second harden_kernel_mappings pass over the active table keeps every frame and
+// leaves all kernel pages GLOBAL and the pages of the writable (data) sections NX
+fn test_harden_kernel_mappings(page_table: &mut ActivePageTable, boot_info: &BootInformation) {
+    let elf_sections_tag = boot_info.elf_sections_tag()
+        .expect("Elf sections tag required");
+    let sections = || elf_sections_tag.sections()
+        .filter(|s| s.is_allocated() && s.size > 0);
+    let section_pages = |section: &ElfSection|
+        Page::range_for_bytes(section.start_address(), section.size as usize);
+    let frames: Vec<Option<Frame>> = sections()
+        .map(|section| page_table.translate_page(section_pages(section).next().unwrap()))
+        .collect();
+
+    assert!(harden_kernel_mappings(page_table, boot_info) > 0, "nothing was hardened");
+    for (section, frame) in sections().zip(frames) {
+        let first = section_pages(section).next().unwrap();
+        assert!(page_table.translate_page(first) == frame,
+            "the hardening pass changed the frame of 0x{:x}", first.start_address());
+        let data = EntryFlags::from_elf_section_flags(section).contains(WRITABLE);
+        for page in section_pages(section) {
+            let flags = page_table.translate_with_flags(page.start_address()).unwrap().1;
+            assert!(flags.contains(GLOBAL), "kernel page 0x{:x} is not global",
+                page.start_address());
+            assert!(!data || flags.contains(NO_EXECUTE), "data page 0x{:x} is executable",
+                page.start_address());
+        }
+    }
+}
+
+// at the typed boundary a translation is a PhysAddr: it leads back to the mapped
+// frame through Frame::containing_phys, and the page comes from a VirtAddr
+// the mix-up itself, Page::containing_address(page_table.translate(..).unwrap()), is
+// a type error now; a compile-fail test needs a test harness this kernel doesn't have
+fn test_typed_translation(page_table: &mut ActivePageTable) {
+    let address = VirtAddr::new(0xb8123);
+    let physical = page_table.translate(address.as_usize()).expect("VGA buffer unmapped");
+    assert!(physical.as_usize() == 0xb8123, "the VGA buffer is not identity mapped");
+    assert!(Frame::containing_phys(physical) == Frame::containing_address(0xb8000));
+    assert!(Page::containing_virt(address) == Page::containing_address(0xb8000));
+}
+
+// identity_map_all_available over a synthetic memory map with an available area of
+// 2MiB + 3 frames at 320GiB (one 2MiB page and three 4KiB pages) and a reserved
+// frame after it: every available frame is identity mapped writable, the reserved one
+// isn't (memory that doesn't exist, never accessed)
+#[cfg(feature = "identity_map_all")]
+fn test_identity_map_all_available<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    // total size, memory map tag (type 6, size 64, entry size 24), two areas
+    // (base, length, type), end tag (type 0, size 8)
+    static MEMORY_MAP: [u64; 10] = [80, 6 | 64 << 32, 24,
+        0x50_0000_0000, 0x20_3000, 1, 0x50_0040_0000, 0x1000, 2, 8 << 32];
+    let memory_map = unsafe { multiboot2::load(&MEMORY_MAP as *const _ as usize) };
+    let start = 0x50_0000_0000;
+    assert!(page_table.p4().next_table(0).unwrap()[320].is_unused(),
+        "P3[320] of P4[0] is already in use");
+
+    let mapped = identity_map_all_available(page_table, memory_map, allocator);
+    assert!(mapped == ENTRIES_PER_TABLE + 3, "{} frames were mapped", mapped);
+    for number in 0..ENTRIES_PER_TABLE + 3 {
+        let address = start + number * PAGE_SIZE;
+        assert!(page_table.translate(address) == Some(PhysAddr::new(address)) &&
+            page_table.probe_write(address), "0x{:x} is not identity mapped", address);
+    }
+    assert!(page_table.translate(0x50_0040_0000).is_none(), "the reserved frame is mapped");
+
+    let (p2_frame, p1_frame) = {
+        let p3 = page_table.p4_mut().next_table_mut(0).unwrap();
+        let p2_frame = p3[320].pointed_frame().unwrap();
+        let p1_frame = p3.next_table(320).unwrap()[1].pointed_frame().unwrap();
+        p3[320].set_unused();
+        (p2_frame, p1_frame)
+    };
+    tlb::flush_all();
+    allocator.deallocate_frame(p1_frame);
+    allocator.deallocate_frame(p2_frame);
+}
+
+// mapping a mapped page of P4[66] again is refused with AlreadyMapped, for another
+// frame as well as for the same one, and the first mapping stays (frames above 4GiB,
+// never accessed)
+fn test_map_overwrite<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(66, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(66, 0, 0, 0);
+    let frame = |n| Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE + n);
+    page_table.map_to(page, frame(0), EntryFlags::empty(), allocator);
+    assert!(page_table.try_map_to(page, frame(1), WRITABLE, allocator) ==
+        Err(PagingError::AlreadyMapped(page)), "the mapping was overwritten");
+    assert!(page_table.try_map_to(page, frame(0), WRITABLE, allocator) ==
+        Err(PagingError::AlreadyMapped(page)), "map_to changed the flags of a mapping");
+    let (address, flags) = page_table.translate_with_flags(page.start_address()).unwrap();
+    assert!(address == frame(0).start_address() && !flags.contains(WRITABLE),
+        "the first mapping was changed");
+}
+
+// install_recursive_entry on a zeroed P4 at the temporary page (the boot table of a
+// bootloader without the recursive entry) points P4[511] to the P4 frame and leaves
+// an entry that is already correct alone
+fn test_install_recursive_entry<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    {
+        let p4 = temporary_page.map_table_frame(frame.clone(), page_table);
+        p4.zero();
+        let entry_frame = frame.clone();
+        assert!(install_recursive_entry(&mut p4[RECURSIVE_INDEX], entry_frame) == Ok(true),
+            "the recursive entry was not installed");
+        assert!(p4[RECURSIVE_INDEX].pointed_frame() == Some(frame.clone()) &&
+            p4[RECURSIVE_INDEX].flags() == PRESENT | WRITABLE,
+            "the recursive entry doesn't point to the P4");
+        let entry_frame = frame.clone();
+        assert!(install_recursive_entry(&mut p4[RECURSIVE_INDEX], entry_frame) == Ok(false),
+            "a correct recursive entry was installed again");
+        // an entry pointing elsewhere is refused and left alone
+        let other = Frame { number: frame.number + 1 };
+        p4[RECURSIVE_INDEX].set(other.clone(), PRESENT | WRITABLE);
+        assert!(recursive_entry_intact(&p4[RECURSIVE_INDEX], &frame) ==
+            Err(PagingError::RecursiveEntryInUse) &&
+            install_recursive_entry(&mut p4[RECURSIVE_INDEX], frame.clone()) ==
+            Err(PagingError::RecursiveEntryInUse), "a foreign entry was overwritten");
+        assert!(p4[RECURSIVE_INDEX].pointed_frame() == Some(other));
+    }
+    temporary_page.unmap(page_table);
+    temporary_page.release(allocator);
+    allocator.deallocate_frame(frame);
+}
+
+// frames_needed_for pages 510 to 512 of the empty P4[67] counts the 3 pages and the
+// P3, the P2 and both P1 tables; once page 510 is mapped only 2 pages and the second
+// P1 are left (fake frame above 4GiB, never accessed)
+fn test_frames_needed<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(67, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let first = Page::from_table_indices(67, 0, 0, 510);
+    let pages = || Page::range_inclusive(first, Page::from_table_indices(67, 0, 1, 0));
+    assert!(page_table.frames_needed_for(pages()) == 7,
+        "{} frames needed in an empty P4 entry", page_table.frames_needed_for(pages()));
+    let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE);
+    page_table.map_to(first, frame, EntryFlags::empty(), allocator);
+    assert!(page_table.frames_needed_for(pages()) == 3,
+        "{} frames needed next to existing tables", page_table.frames_needed_for(pages()));
+}
+
+// every RegionKind yields its documented flags (no kind is writable and executable),
+// and map_region_typed maps one page of P4[68] with the kind's flags and one with the
+// override (frames above 4GiB, never accessed)
+fn test_region_kinds<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(68, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let kinds = [
+        (RegionKind::KernelCode, EntryFlags::empty()),
+        (RegionKind::KernelData, WRITABLE | NO_EXECUTE),
+        (RegionKind::Heap, WRITABLE | NO_EXECUTE),
+        (RegionKind::Stack, WRITABLE | NO_EXECUTE),
+        (RegionKind::Mmio, WRITABLE | NO_EXECUTE | NO_CACHE | WRITE_THROUGH),
+        (RegionKind::UserCode, USER_ACCESSIBLE),
+        (RegionKind::UserData, USER_ACCESSIBLE | WRITABLE | NO_EXECUTE),
+    ];
+    for &(kind, flags) in kinds.iter() {
+        assert!(kind.flags() == flags, "{:?} has the flags {}", kind, kind.flags());
+        assert!(!flags.contains(WRITABLE) || flags.contains(NO_EXECUTE),
+            "{:?} is writable and executable", kind);
+    }
+
+    let page = |p1| Page::from_table_indices(68, 0, 0, p1);
+    let frame = |n| Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE + n);
+    let mut frames = (0..2).map(|n| frame(n));
+    page_table.map_region_typed(Page::range_inclusive(page(0), page(0)),
+        RegionKind::Mmio, None, || frames.next(), allocator).expect("Mmio mapping failed");
+    page_table.map_region_typed(Page::range_inclusive(page(1), page(1)),
+        RegionKind::Mmio, Some(NO_EXECUTE), || frames.next(), allocator)
+        .expect("overridden mapping failed");
+    {
+        let mapped = |p1| page_table.p1_entry(page(p1)).unwrap().flags();
+        assert!(mapped(0) == PRESENT | RegionKind::Mmio.flags(),
+            "the default Mmio mapping has the flags {}", mapped(0));
+        assert!(mapped(1) == PRESENT | NO_EXECUTE,
+            "the override was ignored: {}", mapped(1));
+    }
+}
+
+// ensure_recursive_mapping leaves an intact recursive entry alone and restores a
+// cleared one through the P4 alias that memory::init installed (nothing may use the
+// recursive addresses in between)
+fn test_ensure_recursive_mapping(page_table: &mut ActivePageTable) {
+    use x86_64::registers::control_regs;
+
+    let p4_frame = Frame::containing_address(from_x86_phys(control_regs::cr3()));
+    assert!(!page_table.ensure_recursive_mapping(), "an intact entry was repaired");
+    page_table.p4_mut()[RECURSIVE_INDEX].set_unused();
+    tlb::flush_all();
+    assert!(page_table.ensure_recursive_mapping(), "the cleared entry was not repaired");
+    assert!(page_table.p4()[RECURSIVE_INDEX].pointed_frame() == Some(p4_frame),
+        "the recursive entry doesn't point to the active P4 again");
+}
+
+// one mapping across a P4 boundary: the last page below 3 * 512GiB lies in P4[2],
+// the next one in P4[3], each needs its own P3
+fn test_p4_boundary<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let boundary = 3 * HUGE_PAGE_SIZE_1GIB * ENTRIES_PER_TABLE;
+    let first_page = Page::containing_address(boundary - PAGE_SIZE);
+    let second_page = Page::containing_address(boundary);
+    assert!(first_page.p4_index() == 2 && second_page.p4_index() == 3);
+    let mut first_slot = TestSlot::take(2, page_table, allocator);
+    let (page_table, allocator) = first_slot.parts();
+    let mut slot = TestSlot::take(3, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let first_frame = allocator.allocate_frame().expect("no more frames");
+    let second_frame = allocator.allocate_frame().expect("no more frames");
+    let expected = (Some(PhysAddr::new(first_frame.start_address())),
+                    Some(PhysAddr::new(second_frame.start_address())));
+    {
+        let mut frames = Some(first_frame).into_iter().chain(Some(second_frame));
+        page_table.map_region_with(Page::range_inclusive(first_page, second_page),
+            WRITABLE | NO_EXECUTE, || frames.next(), allocator).unwrap();
+    }
+    let p3_frames = (page_table.p4()[2].pointed_frame(), page_table.p4()[3].pointed_frame());
+    assert!(p3_frames.0.is_some() && p3_frames.1.is_some() && p3_frames.0 != p3_frames.1,
+        "pages across a P4 boundary need distinct P3 tables");
+    assert!(page_table.translate(first_page.start_address()) == expected.0 &&
+            page_table.translate(second_page.start_address()) == expected.1);
+    page_table.unmap(first_page, allocator);
+    page_table.unmap(second_page, allocator);
+}
+
+// for_each_page_in reports every page of a range, unmapped ones as None: five pages
+// in the empty P4[9] with the middle one left out
+fn test_for_each_page_in<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(9, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let base = Page::from_table_indices(9, 0, 0, 0).number;
+    for i in (0..5).filter(|&i| i != 2) {
+        page_table.map_to(Page { number: base + i }, Frame { number: 32 + i },
+            NO_EXECUTE, allocator);
+    }
+    let mut states = Vec::new();
+    page_table.for_each_page_in(
+        Page::range_inclusive(Page { number: base }, Page { number: base + 4 }),
+        |page, mapping| states.push((page, mapping)));
+    assert!(states.len() == 5, "expected five pages, got {}", states.len());
+    for (i, &(page, ref mapping)) in states.iter().enumerate() {
+        assert!(page.number == base + i);
+        match *mapping {
+            Some((ref frame, flags)) => assert!(i != 2 && frame.number == 32 + i &&
+                flags == PRESENT | NO_EXECUTE, "page {} is reported wrong", i),
+            None => assert!(i == 2, "mapped page {} is reported as unmapped", i),
+        }
+    }
+    for i in (0..5).filter(|&i| i != 2) {
+        page_table.unmap(Page { number: base + i }, allocator);
+    }
+}
+
+// a fault below a stack grows it up to its maximum size, past that it is an
+// overflow: a 2 page stack that may grow to 4 pages at the top of the empty P4[11]
+fn test_stack_growth<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use memory::stack_allocator::{Stack, grow_stack_on_fault};
+
+    let mut slot = TestSlot::take(11, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let top = Page::from_table_indices(11, 0, 0, 8).start_address();
+    for page in Page::range_for_bytes(top - 2 * PAGE_SIZE, 2 * PAGE_SIZE) {
+        page_table.map(page, WRITABLE | NO_EXECUTE, allocator);
+    }
+    let mut stack = Stack::new(top, top - 2 * PAGE_SIZE, 4);
+    let just_below = stack.bottom() - 8;
+    assert!(grow_stack_on_fault(&mut stack, just_below, page_table, allocator)
+        .is_ok());
+    assert!(stack.size() == 3 * PAGE_SIZE && page_table.translate(just_below).is_some());
+    let beyond = top - stack.max_size() - 8;
+    assert!(grow_stack_on_fault(&mut stack, beyond, page_table, allocator) ==
+        Err(PagingError::StackOverflow(Page::containing_address(beyond))));
+    assert!(stack.size() == 3 * PAGE_SIZE);
+    for page in Page::range_for_bytes(stack.bottom(), stack.size()) {
+        let frame = page_table.translate_page(page).unwrap();
+        page_table.unmap(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+}
+
+// stacks from a StackAllocator sit above an unmapped guard page each and are
+// refused once the guard page and the stack don't fit: 5 pages of the empty P4[23]
+// give a 2 page stack and a 1 page stack, but not a second 2 page one
+fn test_stack_allocator<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use memory::stack_allocator::StackAllocator;
+
+    let mut slot = TestSlot::take(23, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let first = Page::from_table_indices(23, 0, 0, 0);
+    let page = |offset: usize| Page { number: first.number + offset };
+    let mut stacks = StackAllocator::new(Page::range_inclusive(first, page(4)));
+    let stack = stacks.alloc_stack(page_table, allocator, 2)
+        .expect("no room for the first stack");
+    assert!(stack.bottom() == page(1).start_address() &&
+        stack.top() == page(3).start_address());
+    assert!(page_table.translate_page(page(0)).is_none(), "the guard page is mapped");
+    assert!(page_table.probe_write(stack.top() - 8));
+    assert!(stacks.alloc_stack(page_table, allocator, 2).is_none());
+    let small = stacks.alloc_stack(page_table, allocator, 1)
+        .expect("the refused stack used up the range");
+    assert!(small.bottom() == page(4).start_address() &&
+        page_table.translate_page(page(3)).is_none());
+    assert!(stacks.alloc_stack(page_table, allocator, 1).is_none());
+    for &offset in [1, 2, 4].iter() {
+        let frame = page_table.unmap(page(offset), allocator).unwrap();
+        allocator.deallocate_frame(frame);
+    }
+}
+
+// translate_extents merges physically contiguous pages: two pages on the frames
+// 64 and 65, a third on frame 80, in the empty P4[12]
+fn test_translate_extents<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(12, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let base = Page::from_table_indices(12, 0, 0, 0).number;
+    for (i, &number) in [64, 65, 80].iter().enumerate() {
+        page_table.map_to(Page { number: base + i }, Frame { number: number },
+            NO_EXECUTE, allocator);
+    }
+    let pages = Page::range_inclusive(Page { number: base }, Page { number: base + 2 });
+    let extents = page_table.translate_extents(pages);
+    let expected = vec![(64 * PAGE_SIZE, 2 * PAGE_SIZE), (80 * PAGE_SIZE, PAGE_SIZE)];
+    assert!(extents == Ok(expected), "wrong extents {:?}", extents);
+    for i in 0..3 {
+        page_table.unmap(Page { number: base + i }, allocator);
+    }
+}
+
+// the W^X scanner reports exactly the writable and executable page of the empty
+// P4[10], not the neighbours that are only one of the two
+fn test_wx_scanner<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(10, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let base = Page::from_table_indices(10, 0, 0, 0).number;
+    let in_test_slot = |pages: Vec<Page>| -> Vec<Page> {
+        pages.into_iter().filter(|page| page.p4_index() == 10).collect()
+    };
+    for (i, &flags) in [WRITABLE | NO_EXECUTE, EntryFlags::empty()].iter().enumerate() {
+        page_table.map_to(Page { number: base + i }, Frame { number: 48 + i }, flags,
+            allocator);
+    }
+    assert!(in_test_slot(page_table.scan_wx_violations()).is_empty(),
+        "a W^X clean mapping is reported");
+    page_table.map_to(Page { number: base + 2 }, Frame { number: 50 }, WRITABLE,
+        allocator);
+    assert!(in_test_slot(page_table.scan_wx_violations()) ==
+        [Page { number: base + 2 }], "the W+X page is not reported");
+    for i in 0..3 {
+        page_table.unmap(Page { number: base + i }, allocator);
+    }
+}
+
+// a free frame can be claimed by address, but only once (allocators that can't
+// claim frames by address refuse every claim)
+fn test_claim_frame<A>(allocator: &mut A)
+    where A: FrameAllocator
+{
+    let specific = allocator.allocate_frame().expect("no more frames");
+    allocator.deallocate_frame(specific.clone());
+    if allocator.allocate_specific_frame(&specific) {
+        assert!(!allocator.allocate_specific_frame(&specific),
+            "{:?} was claimed twice", specific);
+        allocator.deallocate_frame(specific);
+    }
+}
+
+// a pinned frame stays where it is, swap_frame refuses to move it
+fn test_pinned_frame<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let page = Page::from_table_indices(0, 42, 0, 0);
+    let pinned_frame = allocator.allocate_frame().expect("no more frames");
+    let target_frame = allocator.allocate_frame().expect("no more frames");
+    frame_refcount::pin_frame(&pinned_frame);
+    page_table.map_to(page, pinned_frame.clone(), WRITABLE, allocator);
+    {
+        let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+        assert!(swap_frame(page_table, page, target_frame, &mut temporary_page)
+            == Err(PagingError::Pinned(page)));
+    }
+    assert!(page_table.translate_page(page) == Some(pinned_frame.clone()));
+    frame_refcount::unpin_frame(&pinned_frame);
+    page_table.unmap(page, allocator);
+}
+
+// the linker symbol ranges are mapped with their flags; mocked symbols in the empty
+// P4[6], identity mapped like a low linked kernel (the frames are never accessed)
+fn test_linker_sections<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(6, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let base = Page::from_table_indices(6, 0, 0, 0).start_address();
+    let symbols = LinkerSymbols {
+        rodata: (base, base + 2 * PAGE_SIZE),
+        text: (base + 2 * PAGE_SIZE, base + 5 * PAGE_SIZE),
+        data: (base + 5 * PAGE_SIZE, base + 6 * PAGE_SIZE),
+        relro: (base + 6 * PAGE_SIZE, base + 6 * PAGE_SIZE),    // empty
+    };
+    for &(start, end, flags) in symbols.ranges().iter() {
+        map_section(page_table, start, end, flags, allocator);
+    }
+    for &(start, end, flags) in symbols.ranges().iter() {
+        for page in Page::range_for_bytes(start, end - start) {
+            let address = page.start_address();
+            assert!(page_table.translate_with_flags(address) ==
+                    Some((address, flags)),
+                    "linker range page {:#x} is mapped wrong", address);
+        }
+    }
+    assert!(page_table.translate(symbols.relro.1).is_none());
+    for page in Page::range_for_bytes(base, symbols.relro.1 - base) {
+        page_table.unmap(page, allocator);
+    }
+}
+
+// boot data sharing frames with a section keeps the section's mapping: a "section"
+// on pages 0-2 of the empty P4[7], "multiboot info" on frames 1-4 (identity mapped,
+// the frames are never accessed)
+fn test_boot_data_overlap<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(7, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let base = Page::from_table_indices(7, 0, 0, 0).start_address();
+    page_table.identity_map_phys_range(base, 3 * PAGE_SIZE, WRITABLE | NO_EXECUTE,
+        allocator);
+    let skipped = page_table.identity_map_phys_range_shared(base + PAGE_SIZE + 0x100,
+        3 * PAGE_SIZE, PRESENT, allocator);
+    assert!(skipped == Ok(2), "expected two shared frames, got {:?}", skipped);
+    for i in 0..5 {
+        let address = base + i * PAGE_SIZE;
+        let expected = if i < 3 { PRESENT | WRITABLE | NO_EXECUTE } else { PRESENT };
+        assert!(page_table.translate_with_flags(address) == Some((address, expected)),
+            "page {} of the overlap test has the wrong mapping", i);
+        page_table.unmap(Page::containing_address(address), allocator);
+    }
+}
+
+// flags survive a round trip through a raw entry value, the address is masked off
+fn test_raw_entry_round_trip() {
+    // the highest frame sets every address bit
+    let frame = Frame::containing_address(0x000f_ffff_ffff_f000);
+    let flags = PRESENT | WRITABLE | GLOBAL | NO_EXECUTE | COPY_ON_WRITE;
+    let raw = frame.start_address() as u64 | flags.bits();
+    assert!(EntryFlags::from_raw_entry(raw) == flags);
+    assert!((raw & ADDRESS_MASK) as usize == frame.start_address());
+}
+
+// the recursive entry shows up in p4_entries with the frame from CR3
+fn test_p4_entries(page_table: &mut ActivePageTable) {
+    use x86_64::registers::control_regs;
+
+    let p4_frame = Frame::containing_address(from_x86_phys(control_regs::cr3()));
+    let recursive = page_table.p4_entries().find(|&(index, _, _)| index == RECURSIVE_INDEX);
+    assert!(match recursive {
+        Some((_, flags, Some(ref frame))) =>
+            flags.contains(PRESENT | WRITABLE) && *frame == p4_frame,
+        _ => false,
+    }, "the recursive entry is missing from p4_entries: {:?}", recursive);
+    assert!(page_table.p4_entries().count() == ENTRIES_PER_TABLE);
+}
+
+// a range ending at the last page of the address space stops there
+fn test_range_at_address_space_end() {
+    let last = Page::containing_address(0xffff_ffff_ffff_f000);
+    let mut pages = Page::range_inclusive(Page { number: last.number - 1 }, last);
+    assert!(pages.next() == Some(Page { number: last.number - 1 }));
+    assert!(pages.next() == Some(last));
+    assert!(pages.next().is_none() && pages.next().is_none(),
+        "the range wrapped around after the last page");
+}
+
+// the bitmap storage goes into the first free gap of an available area: low memory
+// and the kernel are reserved, the multiboot info follows the kernel directly
+fn test_bitmap_placement() {
+    use memory::ReservationRegistry;
+    use memory::bitmap_frame_allocator::{bitmap_storage_size, find_bitmap_region};
+
+    let areas = [(0, 0x9_f000), (0x10_0000, 0x700_0000)];
+    let mut reservations = ReservationRegistry::new();
+    reservations.reserve_phys("low memory", 0, 0xa_0000);
+    reservations.reserve_phys("kernel", 0x10_0000, 0x30_0000);
+    reservations.reserve_phys("multiboot", 0x40_0000, 0x800);
+    let size = bitmap_storage_size(0x710_0000 / PAGE_SIZE);
+    assert!(size == 0xe20);
+    let start = find_bitmap_region(areas.iter().cloned(), size, &reservations,
+        IDENTITY_REGION_END);
+    assert!(start == Some(0x40_1000), "bitmap placed at {:?}", start);
+    assert!(reservations.phys_overlap(0x40_1000, 0x40_1000 + size - 1).is_none());
+    assert!(find_bitmap_region(areas.iter().cloned(), 0x800_0000, &reservations,
+        IDENTITY_REGION_END).is_none(), "a bitmap larger than every area was placed");
+}
+
+// corrupt section addresses are rejected before anything is mapped
+fn test_corrupt_sections() {
+    let physical_end = 16 * HUGE_PAGE_SIZE_1GIB;
+    assert!(check_section_range(0x10_0000, 0x2000, physical_end).is_ok());
+    assert!(check_section_range(KERNEL_VIRTUAL_BASE + 0x10_0000, 0x2000, physical_end).is_ok());
+    assert!(check_section_range(0x0000_9000_0000_0000, 0x1000, physical_end) ==
+            Err(PagingError::InvalidAddress(0x0000_9000_0000_0000)));
+    assert!(check_section_range(0x0000_7fff_ffff_f000, 0x2000, physical_end) ==
+            Err(PagingError::InvalidAddress(0x0000_8000_0000_0000)));
+    assert!(check_section_range(physical_end, 0x1000, physical_end) ==
+            Err(PagingError::InvalidAddress(physical_end)));
+}
+
+// a bulk map of a 2MiB region in the empty P4[5] takes its P3, P2 and P1 from the
+// pool, which is filled once; the allocator only gives the leaf frames and the refill
+fn test_bulk_map<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(5, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let bulk_pages = Page::range_inclusive(Page::from_table_indices(5, 0, 0, 0),
+        Page::from_table_indices(5, 0, 0, ENTRIES_PER_TABLE - 1));
+    let bulk_first = bulk_pages.start.number;
+    let available_before = allocator.frames_available();
+    let mut pool = TablePool::new();
+    page_table.map_region_bulk(bulk_pages, WRITABLE | NO_EXECUTE, &mut pool, allocator)
+        .unwrap();
+    assert!(pool.taken() == 3 && pool.refills() == 1,
+        "bulk map took {} tables in {} refills", pool.taken(), pool.refills());
+    if let (Some(before), Some(after)) = (available_before, allocator.frames_available()) {
+        assert!(before - after == ENTRIES_PER_TABLE + TABLE_POOL_SIZE);
+    }
+    pool.release(allocator);
+    for number in bulk_first..bulk_first + ENTRIES_PER_TABLE {
+        let page = Page { number: number };
+        let frame = page_table.translate_page(page).unwrap();
+        page_table.unmap(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+}
+
+// vm_regions merges pages that map consecutive frames with the same flags; two runs
+// in the empty P4[4]: three read-only pages and, after a gap, two writable ones
+// (aliases of low physical memory, nothing is written through them)
+fn test_vm_regions<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(4, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let base = Page::from_table_indices(4, 0, 0, 0).number;
+    let runs = [(base, 0, 3, NO_EXECUTE), (base + 4, 16, 2, WRITABLE | NO_EXECUTE)];
+    for &(first_page, first_frame, count, flags) in runs.iter() {
+        for i in 0..count {
+            page_table.map_to(Page { number: first_page + i },
+                Frame { number: first_frame + i }, flags, allocator);
+        }
+    }
+    {
+        let regions: Vec<VmRegion> = page_table.vm_regions().into_iter()
+            .filter(|region| region.start.p4_index() == 4).collect();
+        assert!(regions.len() == 2, "expected two regions, got {:?}", regions);
+        for (region, &(first_page, _, count, flags)) in regions.iter().zip(runs.iter()) {
+            assert!(region.start.number == first_page &&
+                    region.end.number == first_page + count - 1 &&
+                    region.flags == flags | PRESENT && region.size == count * PAGE_SIZE,
+                    "wrong region {:?}", region);
+        }
+    }
+    for &(first_page, _, count, _) in runs.iter() {
+        for i in 0..count {
+            page_table.unmap(Page { number: first_page + i }, allocator);
+        }
+    }
+}
+
+// the kernel tables below the copied P4 entries are shared: a page mapped in the
+// active table after the copy is visible through the second address space too
+// (P4[300] of the higher half, unused so far)
+fn test_kernel_table_sharing<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(300, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let first = Page::from_table_indices(300, 0, 0, 0);
+    let later = Page::from_table_indices(300, 1, 2, 3);   // needs new P2 and P1
+    page_table.map(first, WRITABLE | NO_EXECUTE, allocator);
+
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let mut other = {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        InactivePageTable::new(frame, page_table, &mut temporary_page)
+    };
+    let kernel_entries = page_table.kernel_p4_entries();
+    page_table.with(&mut other, &mut temporary_page, |mapper| {
+        copy_kernel_mappings(mapper, &kernel_entries);
+    });
+
+    page_table.map(later, WRITABLE | NO_EXECUTE, allocator);
+    let expected = page_table.translate(later.start_address());
+    let mut seen = None;
+    page_table.with(&mut other, &mut temporary_page, |mapper| {
+        seen = mapper.translate(later.start_address());
+    });
+    assert!(expected.is_some() && seen == expected,
+        "a kernel page mapped after the copy is not shared");
+
+    for &page in [first, later].iter() {
+        let frame = page_table.translate_page(page).unwrap();
+        page_table.unmap(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+    allocator.deallocate_frame(other.p4_frame);
+}
+
+// the trampoline page is present, executable, read-only and global, and its P4
+// entry is one of those every new address space gets
+fn test_trampoline_page<A>(page_table: &mut ActivePageTable, allocator: &mut A)
     where A: FrameAllocator
 {
-    let mut page_table = unsafe { ActivePageTable::new() };
+    if page_table.translate(TRAMPOLINE_ADDRESS).is_none() {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        install_trampoline_page(frame, TRAMPOLINE_ADDRESS, page_table, allocator);
+        let flags = page_table.translate_with_flags(TRAMPOLINE_ADDRESS).map(|(_, flags)| flags);
+        assert!(flags.map_or(false, |flags| flags.contains(PRESENT | GLOBAL) &&
+                !flags.contains(WRITABLE) && !flags.contains(NO_EXECUTE)),
+                "trampoline page has the wrong flags: {:?}", flags);
+        let p4_index = Page::containing_address(TRAMPOLINE_ADDRESS).p4_index();
+        assert!(page_table.kernel_p4_entries().iter().any(|&(index, _, _)| index == p4_index),
+            "the trampoline's P4 entry is not copied into new address spaces");
 
-    let addr = 42 * 512 * 512 * 4096; // 42th P3 entry
-    let page = Page::containing_address(addr);
+        let page = Page::containing_address(TRAMPOLINE_ADDRESS);
+        let frame = page_table.translate_page(page).unwrap();
+        page_table.unmap(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+}
+
+// a range mapped with its section's flags verifies, one with a writable page in a
+// read-only section or an executable page in a data section doesn't
+fn test_section_flags_check<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(8, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let base = Page::from_table_indices(8, 0, 0, 0);
+    let (start, end) = (base.start_address(), base.start_address() + 3 * PAGE_SIZE);
+    for i in 0..3 {
+        page_table.map_to(Page { number: base.number + i }, Frame { number: 56 + i },
+            PRESENT | NO_EXECUTE, allocator);
+    }
+    let rodata = PRESENT | NO_EXECUTE;
+    assert!(verify_kernel_range(page_table, start, end, rodata).is_ok(),
+        "a consistent section fails the check");
+    let data = PRESENT | WRITABLE | NO_EXECUTE;
+    assert!(verify_kernel_range(page_table, start, end, data) ==
+        Err(PagingError::WrongFlags(base)), "a read-only data section passes the check");
+
+    let last = Page { number: base.number + 2 };
+    page_table.update_flags(last, PRESENT).unwrap();
+    assert!(verify_kernel_range(page_table, start, end, rodata) ==
+        Err(PagingError::WrongFlags(last)), "an executable rodata page passes the check");
+    assert!(verify_kernel_range(page_table, start, end + PAGE_SIZE, PRESENT) ==
+        Err(PagingError::WrongFlags(base)), "a non-executable text page passes the check");
+    for i in 0..3 {
+        page_table.unmap(Page { number: base.number + i }, allocator);
+    }
+    assert!(verify_kernel_range(page_table, start, end, rodata) ==
+        Err(PagingError::NotMapped(base)), "an unmapped section passes the check");
+}
+
+// a stack pointer whose page or headroom below is unmapped fails the pre-switch check
+fn test_stack_check<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(13, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let lower = Page::from_table_indices(13, 0, 0, 0);
+    let upper = Page { number: lower.number + 1 };
+    for &(page, number) in [(lower, 60), (upper, 61)].iter() {
+        page_table.map_to(page, Frame { number: number }, WRITABLE | NO_EXECUTE,
+            allocator);
+    }
+    let near_bottom = upper.start_address() + 0x100;
+    assert!(check_stack_mapped(page_table, near_bottom).is_ok(),
+        "a mapped stack fails the check");
+
+    page_table.unmap(lower, allocator);
+    assert!(check_stack_mapped(page_table, near_bottom) ==
+        Err(PagingError::NotMapped(lower)), "unmapped headroom passes the check");
+    assert!(check_stack_mapped(page_table, upper.start_address() + 0x800).is_ok(),
+        "the headroom check reaches past STACK_HEADROOM");
+    page_table.update_flags(upper, NO_EXECUTE).unwrap();
+    assert!(check_stack_mapped(page_table, upper.start_address() + 0x800) ==
+        Err(PagingError::WrongFlags(upper)), "a read-only stack passes the check");
+    page_table.unmap(upper, allocator);
+    assert!(check_stack_mapped(page_table, upper.start_address() + 0x800) ==
+        Err(PagingError::NotMapped(upper)), "an unmapped stack passes the check");
+}
+
+// the recursive address of the P4 is the P4 constant, the one of a P1 is where the
+// table walk finds that P1
+fn test_recursive_address(page_table: &mut ActivePageTable) {
+    assert!(recursive_address(&[]) == 0xffff_ffff_ffff_f000, "wrong P4 address");
+    assert!(recursive_address(&[RECURSIVE_INDEX, RECURSIVE_INDEX, RECURSIVE_INDEX]) ==
+        0xffff_ffff_ffff_f000, "the P4 is not its own P1 through the recursive entry");
+    assert!(recursive_address(&[]) == P4 as usize);
+
+    // the stack check above left the tables of P4[13] behind
+    let page = Page::from_table_indices(13, 0, 0, 0);
+    let p1 = page_table.p4().next_table(page.p4_index())
+        .and_then(|p3| p3.next_table(page.p3_index()))
+        .and_then(|p2| p2.next_table(page.p2_index()))
+        .expect("the stack check page has no P1");
+    assert!(recursive_address(&[page.p4_index(), page.p3_index(), page.p2_index()]) ==
+        p1 as *const _ as usize, "wrong P1 address");
+}
+
+// with a bound set identity_map takes the frames up to it and refuses the ones above
+fn test_identity_map_bound<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let (below, max) = (Frame { number: 62 }, Frame { number: 63 });
+    let above = Frame { number: 64 };
+    let previous = set_max_physical_frame(Some(max.clone()));
+    assert!(page_table.try_identity_map(above, NO_EXECUTE, allocator) ==
+        Err(PagingError::BeyondPhysicalMemory(64 * PAGE_SIZE)),
+        "identity_map accepts a frame above the bound");
+    // the frames in range can only be mapped if nothing identity maps them yet
+    let pages = [Page::from_frame_identity(&below), Page::from_frame_identity(&max)];
+    if pages.iter().all(|&page| page_table.translate_page(page).is_none()) {
+        assert!(page_table.try_identity_map(below, NO_EXECUTE, allocator).is_ok());
+        assert!(page_table.try_identity_map(max, NO_EXECUTE, allocator).is_ok(),
+            "identity_map refuses the frame at the bound");
+        for &page in pages.iter() {
+            page_table.unmap(page, allocator);
+        }
+    }
+    set_max_physical_frame(previous);
+}
+
+// unmapping page by page flushes once per page, dropping the whole P4 entry with
+// free_subtree flushes once for everything below it
+fn test_unmap_flush_count<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(14, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let base = Page::from_table_indices(14, 0, 0, 0).number;
+    let pages = || Page::range_inclusive(Page { number: base }, Page { number: base + 9 });
+    for page in pages() {
+        page_table.map(page, WRITABLE | NO_EXECUTE, allocator);
+    }
+    let flushes = tlb::with_flush_count(|| {
+        for page in pages() {
+            let frame = page_table.unmap(page, allocator).unwrap();
+            allocator.deallocate_frame(frame);
+        }
+    });
+    assert!(flushes == 10, "unmapping 10 pages flushed {} times", flushes);
+
+    for page in pages() {
+        page_table.map(page, WRITABLE | NO_EXECUTE, allocator);
+    }
+    let flushes = tlb::with_flush_count(|| {
+        page_table.free_subtree(14, true, allocator);
+    });
+    assert!(flushes == 1, "freeing the subtree of 10 pages flushed {} times", flushes);
+    assert!(page_table.p4()[14].is_unused());
+}
+
+// with an inactive table in the secondary slot the same page and the recursive entry
+// are read from both tables at once
+fn test_side_by_side<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use x86_64::registers::control_regs;
+
+    let mut slot = TestSlot::take(15, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(15, 0, 0, 0);
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let mut other = {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        InactivePageTable::new(frame, page_table, &mut temporary_page)
+    };
+    page_table.map(page, WRITABLE | NO_EXECUTE, allocator);
+    let other_p4 = other.p4_frame.clone();
+    {
+        let mut editor = other.map_secondary_recursive(page_table);
+        editor.map(page, WRITABLE | NO_EXECUTE, allocator);
+
+        let inactive = editor.translate_page(page);
+        let active = editor.active().translate_page(page);
+        assert!(inactive.is_some() && active.is_some() && inactive != active,
+            "both windows show the same mapping: {:?}, {:?}", inactive, active);
+        let p4_frame = Frame::containing_address(from_x86_phys(control_regs::cr3()));
+        assert!(editor.p4()[RECURSIVE_INDEX].pointed_frame() == Some(other_p4));
+        assert!(editor.active().p4()[RECURSIVE_INDEX].pointed_frame() == Some(p4_frame));
+    }
+
+    page_table.with(&mut other, &mut temporary_page, |mapper| {
+        mapper.free_subtree(15, true, allocator);
+    });
+    page_table.free_subtree(15, true, allocator);
+    allocator.deallocate_frame(other.p4_frame);
+}
+
+// an allocator that has run dry makes the mapping fail with the page it was for,
+// whether the leaf frame or a table was missing
+fn test_allocator_exhausted(page_table: &mut ActivePageTable) {
+    let mut empty = TablePool::new();
+    let mut slot = TestSlot::take(16, page_table, &mut empty);
+    let (page_table, empty) = slot.parts();
+    let page = Page::from_table_indices(16, 0, 0, 0);
+    let result = page_table.try_map(page, WRITABLE | NO_EXECUTE, empty);
+    assert!(result == Err(PagingError::OutOfFrames(page)), "wrong error {:?}", result);
+    let result = page_table.try_map_to(page, Frame { number: 65 }, NO_EXECUTE, empty);
+    assert!(result == Err(PagingError::OutOfFrames(page)), "wrong error {:?}", result);
+    let message = format!("{}", result.unwrap_err());
+    assert!(message.contains(&format!("{:x}", page.start_address())),
+        "the message doesn't name the page: {}", message);
+    assert!(page_table.p4()[16].is_unused(), "a table was created without a frame");
+}
+
+// address ranges: adjacent ranges don't overlap, identical ones are their own
+// intersection, and a range may end at the last address
+fn test_address_ranges() {
+    let a = AddressRange::between(0x1000, 0x3000).unwrap();
+    let adjacent = AddressRange::between(0x3000, 0x4000).unwrap();
+    let inner = AddressRange::new(0x1800, 0x100).unwrap();
+    assert!(a.contains(0x1000) && a.contains(0x2fff) && !a.contains(0x3000));
+    assert!(!a.overlaps(&adjacent) && a.intersection(&adjacent).is_none(),
+        "adjacent ranges overlap");
+    assert!(a.overlaps(&a) && a.intersection(&a) == Some(a));
+    assert!(a.intersection(&inner) == Some(inner) && inner.intersection(&a) == Some(inner));
+    let crossing = AddressRange::between(0x2800, 0x3800).unwrap();
+    assert!(a.intersection(&crossing) == AddressRange::between(0x2800, 0x3000));
+    assert!(a.page_bounds().overlaps(&crossing.page_bounds()));
+    assert!(!a.page_bounds().overlaps(&adjacent.page_bounds()));
+    assert!(AddressRange::new(0x1000, 0).is_none(), "an empty range exists");
+    assert!(AddressRange::between(5, 5).is_none(), "an empty range exists");
+    assert!(inner.pages().count() == 1 && a.pages().count() == 2);
+
+    let size = 0usize.wrapping_sub(RECURSIVE_REGION_START);
+    let top = AddressRange::new(RECURSIVE_REGION_START, size).unwrap();
+    assert!(top.end == usize::max_value() && top.contains(usize::max_value()));
+    assert!(AddressRange::new(usize::max_value(), 2).is_none(), "a wrapping range exists");
+}
+
+// the temporary page is mapped while the scoped mapping lives and unmapped after
+fn test_scoped_temporary_mapping<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    let page = Page { number: temporary_page::TEMP_PAGE_NUMBER };
+    {
+        let mut bytes = temporary_page.map_scoped(frame.clone(), page_table);
+        bytes[0] = 0x42;
+        bytes[PAGE_SIZE - 1] = 0x24;
+        assert!(bytes.len() == PAGE_SIZE && bytes[0] == 0x42);
+    }
+    assert!(page_table.translate_page(page).is_none(),
+        "the temporary page is still mapped after the guard dropped");
+    let bytes = temporary_page.map_scoped(frame.clone(), page_table);
+    assert!(bytes[0] == 0x42 && bytes[PAGE_SIZE - 1] == 0x24,
+        "the write through the scoped mapping is lost");
+    drop(bytes);
+    assert!(page_table.translate_page(page).is_none());
+    allocator.deallocate_frame(frame);
+}
+
+// the 4-level guard trips when LA57 is reported enabled, and doesn't on this CPU
+fn test_la57_guard() {
+    assert!(check_four_level_paging(true) == Err(PagingError::UnsupportedPagingMode),
+        "the 4-level guard lets 5-level paging through");
+    assert!(check_four_level_paging(false).is_ok());
+    assert!(check_four_level_paging(la57_enabled()).is_ok(), "running with LA57");
+    println!("5-level paging supported: {}", la57_supported());
+}
+
+// a clobbered recursive entry is put back by restore_recursive_entry and the table
+// can be walked again
+fn test_restore_recursive_entry<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use x86_64::registers::control_regs;
+
+    if P4_ALIAS.try().is_none() {
+        page_table.install_p4_alias(allocator);
+    }
+    let expected = page_table.translate(0xb8000);
+    let saved = page_table.save_recursive_entry();
+    page_table.p4_mut()[RECURSIVE_INDEX].set_unused();
+    tlb::flush_all();
+    page_table.restore_recursive_entry(saved);
+
+    let p4_frame = Frame::containing_address(from_x86_phys(control_regs::cr3()));
+    assert!(page_table.p4()[RECURSIVE_INDEX].pointed_frame() == Some(p4_frame),
+        "the recursive entry was not restored");
+    assert!(expected.is_some() && page_table.translate(0xb8000) == expected,
+        "the table can't be walked after the restore");
+}
+
+// the testable memory of a synthetic map leaves out the first MiB, the kernel and
+// the multiboot info, and keeps the rest of the areas
+fn test_testable_memory() {
+    use memory::testable_ranges;
+
+    let areas = [(0, 0x9_f000), (0x10_0000, 0x700_0000), (0x800_0000, 0x1000)];
+    let kernel = AddressRange::between(0x10_0000, 0x40_0000).unwrap();
+    let multiboot = AddressRange::new(0x40_0000, 0x800).unwrap();
+    let ranges = testable_ranges(areas.iter().cloned(), &[kernel, multiboot]);
+    assert!(ranges == [AddressRange::between(0x40_0800, 0x710_0000).unwrap(),
+        AddressRange::between(0x800_0000, 0x800_1000).unwrap()],
+        "wrong testable ranges {:?}", ranges);
+    for range in ranges.iter() {
+        assert!(!range.overlaps(&kernel) && !range.overlaps(&multiboot) &&
+            range.start >= 0x10_0000, "{:?} contains an excluded region", range);
+    }
+}
+
+// a page mapped with DIRTY set is dirty before any write, one mapped clean isn't
+fn test_dirty_bit<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(17, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let (dirty, clean) = (Page::from_table_indices(17, 0, 0, 0),
+        Page::from_table_indices(17, 0, 0, 1));
+    page_table.map_to_with_state(dirty, Frame { number: 66 }, NO_EXECUTE, true, true,
+        allocator);
+    page_table.map_to_with_state(clean, Frame { number: 67 }, NO_EXECUTE | DIRTY, false,
+        false, allocator);
+    assert!(page_table.is_dirty(dirty.start_address()) &&
+        page_table.is_accessed(dirty.start_address()), "DIRTY was not pre-set");
+    assert!(!page_table.is_dirty(clean.start_address()) &&
+        !page_table.is_accessed(clean.start_address()), "the clean page is dirty");
+    for &page in [dirty, clean].iter() {
+        page_table.unmap(page, allocator);
+    }
+}
+
+// the one-shot guard of remap_the_kernel refuses the second claim; the kernel was
+// remapped before this test, so a second remap_the_kernel is refused as well
+fn test_remap_once_guard() {
+    let flag = AtomicBool::new(false);
+    assert!(claim_once(&flag), "the first claim is refused");
+    assert!(!claim_once(&flag), "the second claim goes through");
+    assert!(!claim_once(&KERNEL_REMAPPED), "remap_the_kernel could run again");
+}
+
+// a remap with too few frames, or with an allocator that can't tell how many it
+// has, fails before it allocates anything and the boot table stays active
+fn test_remap_frame_budget<A>(allocator: &mut A, boot_info: &BootInformation)
+    where A: FrameAllocator
+{
+    use memory::TestFrameAllocator;
+    use x86_64::registers::control_regs;
+
+    // knows no frame count, like the trait default
+    struct Uncounted;
+    impl FrameAllocator for Uncounted {
+        fn allocate_frame(&mut self) -> Option<Frame> {
+            None
+        }
+        fn deallocate_frame(&mut self, _frame: Frame) {
+            panic!("the uncounted allocator hands out no frames");
+        }
+    }
+
+    let cr3 = from_x86_phys(control_regs::cr3());
     let frame = allocator.allocate_frame().expect("no more frames");
+    {
+        let mut too_small = TestFrameAllocator::new(frame.clone(), 1);
+        assert!(remap_the_kernel(&mut too_small, boot_info).err() ==
+            Some(PagingError::FrameAllocationFailed));
+        assert!(too_small.available() == 1, "the failed remap took frames");
+    }
+    allocator.deallocate_frame(frame);
+    assert!(remap_the_kernel(&mut Uncounted, boot_info).err() ==
+        Some(PagingError::UnknownFrameCount));
+    assert!(from_x86_phys(control_regs::cr3()) == cr3, "the failed remap switched tables");
+}
 
-    println!("None = {:?}, map to {:?}", page_table.translate(addr),frame);
+// a bootloader without the ELF sections tag: the tag lookup reports it as an error
+// instead of panicking, the section check passes and the kernel range comes from the
+// linker symbols (boot information with nothing but the end tag)
+fn test_missing_elf_sections() {
+    use memory::kernel_physical_range;
 
-    page_table.map_to(page, frame, EntryFlags::empty(), allocator);
+    static NO_ELF_TAG: [u64; 2] = [16, 8 << 32];    // total size, end tag (type 0, size 8)
+    let no_elf_tag = unsafe { multiboot2::load(&NO_ELF_TAG as *const _ as usize) };
+    assert!(elf_sections_tag(no_elf_tag).err() == Some(PagingError::MissingElfSections));
+    assert!(check_kernel_sections(no_elf_tag) == Ok(()));
 
-    println!("Some = {:?}", page_table.translate(addr));
-    println!("next free frame: {:?}", allocator.allocate_frame());
+    let symbols = LinkerSymbols::from_linker();
+    let mut ranges = 0;
+    for_each_kernel_range(no_elf_tag, |start, end, flags| {
+        assert!(symbols.ranges().iter().any(|&range| range == (start, end, flags)),
+            "[{:#x}, {:#x}) is no linker symbol range", start, end);
+        ranges += 1;
+    });
+    assert!(ranges == symbols.ranges().iter().filter(|range| range.0 < range.1).count());
+    let load_address = |addr: usize| SectionMapping::detect(addr).physical_address(addr);
+    assert!(kernel_physical_range(no_elf_tag).0 ==
+        Frame::containing_address(load_address(symbols.rodata.0)));
+}
 
-    page_table.unmap(Page::containing_address(addr), allocator);
-    println!("None = {:?}", page_table.translate(addr));
+// remap_once run twice: a remap that fails its checks leaves the flag unclaimed,
+// once the flag is claimed the next remap is refused with the error
+// remap_the_kernel panics with; neither call gets to the page tables
+fn test_remap_once_retry<A>(allocator: &mut A, boot_info: &BootInformation)
+    where A: FrameAllocator
+{
+    use memory::TestFrameAllocator;
+
+    let flag = AtomicBool::new(false);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    {
+        let mut too_small = TestFrameAllocator::new(frame.clone(), 1);
+        assert!(remap_once(&flag, &mut too_small, boot_info).err() ==
+            Some(PagingError::FrameAllocationFailed));
+        assert!(too_small.available() == 1, "the failed remap took frames");
+    }
+    assert!(!flag.load(Ordering::SeqCst), "a failed check claimed the flag");
+    allocator.deallocate_frame(frame);
+
+    flag.store(true, Ordering::SeqCst);
+    assert!(remap_once(&flag, allocator, boot_info).err() ==
+        Some(PagingError::AlreadyRemapped), "the second remap was not refused");
+    assert!(format!("{}", PagingError::AlreadyRemapped) == "kernel already remapped");
+}
+
+// a read-only view shows the frame's contents through a mapping without WRITABLE
+// (the write fault itself can't be caught here, only that CR0.WP makes it fault)
+fn test_readonly_view<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use x86_64::registers::control_regs;
+
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    {
+        let mut bytes = temporary_page.map_scoped(frame.clone(), page_table);
+        bytes[7] = 0x5a;
+    }
+    {
+        let view = map_readonly_view(frame.clone(), page_table, &mut temporary_page);
+        assert!(view.len() == PAGE_SIZE && view[7] == 0x5a, "the view shows other data");
+    }
+    let page = Page { number: temporary_page::TEMP_PAGE_NUMBER };
+    let flags = page_table.translate_with_flags(page.start_address())
+        .map(|(_, flags)| flags);
+    assert!(flags.map_or(false, |flags| !flags.contains(WRITABLE)),
+        "the view is writable: {:?}", flags);
+    assert!(control_regs::cr0().contains(control_regs::Cr0::WRITE_PROTECT),
+        "CR0.WP is off, a write through the view wouldn't fault");
+    unmap_readonly_view(page_table, &mut temporary_page);
+    assert!(page_table.translate_page(page).is_none());
+    allocator.deallocate_frame(frame);
+}
+
+// the high-water mark follows the highest mapped page and stays there when that
+// page is unmapped again; a fresh mapper starts without one, page_table has
+// mapped far higher pages in the tests above
+fn test_high_water_mark<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(18, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let mut mapper = unsafe { Mapper::new() };
+    assert!(mapper.highest_mapped_page().is_none());
+    let base = Page::from_table_indices(18, 0, 0, 0).number;
+    for &offset in [3, 7, 5].iter() {
+        mapper.map_to(Page { number: base + offset }, Frame { number: 68 + offset },
+            NO_EXECUTE, allocator);
+    }
+    assert!(mapper.highest_mapped_page() == Some(Page { number: base + 7 }),
+        "wrong high-water mark {:?}", mapper.highest_mapped_page());
+    mapper.unmap(Page { number: base + 7 }, allocator);
+    assert!(mapper.highest_mapped_page() == Some(Page { number: base + 7 }),
+        "unmapping the top page lowered the high-water mark");
+    for &offset in [3, 5].iter() {
+        mapper.unmap(Page { number: base + offset }, allocator);
+    }
+}
+
+// only a switch to an edited table with global pages on flushes the global
+// translations; that flush counts once and leaves CR4.PGE as it was
+fn test_switch_global_flush() {
+    assert!(switch_needs_global_flush(true, true));
+    assert!(!switch_needs_global_flush(true, false));
+    assert!(!switch_needs_global_flush(false, true));
+    let global_pages = tlb::global_pages_enabled();
+    let flushes = tlb::with_flush_count(tlb::flush_global);
+    assert!(flushes == 1, "a global flush counted {} times", flushes);
+    assert!(tlb::global_pages_enabled() == global_pages, "flush_global changed CR4.PGE");
+}
+
+// a range with a 4KiB page before a 2MiB boundary, 2MiB pages up to a 1GiB boundary,
+// one whole GiB and a 4KiB page after it is mapped with exactly these pages
+// (the frames above 4GiB are never accessed, only the tables are walked)
+fn test_page_size_selection<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    const PAGES_PER_1GIB: usize = ENTRIES_PER_TABLE * ENTRIES_PER_TABLE;
+
+    let mut slot = TestSlot::take(19, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let base = Page::from_table_indices(19, 0, 0, 0).number;
+    let frame_base = 4 * PAGES_PER_1GIB;
+    let frame_for = |page: Page| Some(Frame { number: frame_base + page.number - base });
+    let pages = Page::range_inclusive(Page { number: base + 511 },
+        Page { number: base + 2 * PAGES_PER_1GIB });
+    let layout = page_table.map_optimal(pages, frame_for, NO_EXECUTE, allocator);
+    assert!(layout == Ok(MappingLayout { pages_4kib: 2, pages_2mib: 511, pages_1gib: 1 }),
+        "wrong layout {:?}", layout);
+    {
+        let size_at = |number: usize| {
+            page_table.mapping_page_size(Page { number: base + number }.start_address())
+        };
+        assert!(size_at(511) == Some(PAGE_SIZE));
+        assert!(size_at(512) == Some(HUGE_PAGE_SIZE_2MIB));
+        assert!(size_at(PAGES_PER_1GIB) == Some(HUGE_PAGE_SIZE_1GIB));
+        assert!(size_at(2 * PAGES_PER_1GIB) == Some(PAGE_SIZE) && size_at(0).is_none());
+    }
+    assert!(page_table.translate_page(Page { number: base + PAGES_PER_1GIB + 5 }) ==
+        Some(Frame { number: frame_base + PAGES_PER_1GIB + 5 }));
+    // only the tables go back, the frames were never allocated
+}
+
+// map_range maps and unmap_range frees every page of the range, an empty range is
+// a no-op for both
+fn test_map_range<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(20, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let first = Page::from_table_indices(20, 0, 0, 0);
+    let last = Page { number: first.number + 2 };
+    let available = allocator.frames_available();
+    let empty = || Page::range_inclusive(Page { number: first.number + 1 }, first);
+    assert!(page_table.map_range(empty(), WRITABLE, allocator) == 0);
+    assert!(page_table.map_range(Page::range_inclusive(first, last), WRITABLE,
+        allocator) == 3);
+    assert!(page_table.translate_page(first).is_some() &&
+        page_table.translate_page(last).is_some());
+    assert!(page_table.unmap_range(empty(), allocator) == 0);
+    assert!(page_table.unmap_range(Page::range_inclusive(first, last), allocator) == 3);
+    assert!(page_table.translate_page(first).is_none() &&
+        page_table.translate_page(last).is_none());
+    page_table.free_subtree(20, false, allocator);
+    assert!(allocator.frames_available() == available,
+        "map_range and unmap_range leaked frames");
+}
+
+// update_flags keeps the frame and takes no frames from the allocator, an unmapped
+// page is an error and stays unmapped
+fn test_update_flags<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(21, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(21, 0, 0, 0);
+    let unmapped = Page { number: page.number + 1 };
+    page_table.map(page, WRITABLE | NO_EXECUTE, allocator);
+    let frame = page_table.translate_page(page).unwrap();
+    let available = allocator.frames_available();
+    page_table.update_flags(page, NO_EXECUTE).unwrap();
+    assert!(page_table.translate_page(page) == Some(frame.clone()) &&
+        !page_table.probe_write(page.start_address()), "update_flags changed the frame");
+    assert!(page_table.update_flags(unmapped, NO_EXECUTE) ==
+        Err(PagingError::NotMapped(unmapped)));
+    assert!(page_table.translate_page(unmapped).is_none(),
+        "update_flags mapped an unmapped page");
+    assert!(allocator.frames_available() == available,
+        "update_flags used the frame allocator");
+    page_table.unmap(page, allocator);
+    allocator.deallocate_frame(frame);
+}
+
+// PageIter knows its length and runs from both ends, an empty range and a range
+// ending at page 0 are exhausted from either side without wrapping
+fn test_page_iter() {
+    let pages = || Page::range_inclusive(Page { number: 10 }, Page { number: 13 });
+    assert!(pages().len() == 4);
+    let reversed: Vec<usize> = pages().rev().map(|page| page.number).collect();
+    assert!(reversed == vec![13, 12, 11, 10], "wrong reverse order {:?}", reversed);
+    let mut both = pages();
+    assert!(both.next() == Some(Page { number: 10 }));
+    assert!(both.next_back() == Some(Page { number: 13 }) && both.len() == 2);
+    assert!(both.next_back() == Some(Page { number: 12 }));
+    assert!(both.next() == Some(Page { number: 11 }) && both.len() == 0);
+    assert!(both.next().is_none() && both.next_back().is_none());
+    let mut empty = Page::range_inclusive(Page { number: 1 }, Page { number: 0 });
+    assert!(empty.len() == 0 && empty.next_back().is_none() && empty.next().is_none());
+    let mut first = Page::range_inclusive(Page { number: 0 }, Page { number: 0 });
+    assert!(first.len() == 1 && first.next_back() == Some(Page { number: 0 }));
+    assert!(first.len() == 0 && first.next().is_none() && first.next_back().is_none());
+}
+
+// unmapping the temporary page frees the tables its mapping created, so mapping it
+// over and over takes no frames and release gives the tiny allocator's back
+fn test_temporary_page_table_leak<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(22, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let frame = allocator.allocate_frame().expect("no more frames");
+    let available = allocator.frames_available();
+    let mut temporary_page = TemporaryPage::new(Page::from_table_indices(22, 0, 0, 0),
+        allocator);
+    for _ in 0..4 {
+        temporary_page.map(frame.clone(), page_table);
+        temporary_page.unmap(page_table);
+        assert!(page_table.p4()[22].is_unused(), "the temporary page left tables behind");
+    }
+    temporary_page.release(allocator);
+    assert!(allocator.frames_available() == available,
+        "the temporary page leaked frames");
+    allocator.deallocate_frame(frame);
+}
+
+// zero_page clears its page and nothing past it
+fn test_zero_page<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let page = Page { number: temporary_page::TEMP_PAGE_NUMBER + 2 };
+    let next = Page { number: page.number + 1 };
+    assert!(page_table.translate_page(page).is_none() &&
+        page_table.translate_page(next).is_none(), "the zero_page test pages are in use");
+    for &test_page in [page, next].iter() {
+        page_table.map(test_page, WRITABLE | NO_EXECUTE, allocator);
+        unsafe {
+            ptr::write_bytes(test_page.start_address() as *mut u8, 0xa5, PAGE_SIZE);
+        }
+    }
+    zero_page(page.start_address());
+    let cleared = unsafe {
+        slice::from_raw_parts(page.start_address() as *const u8, PAGE_SIZE)
+    };
+    assert!(cleared.iter().all(|&byte| byte == 0), "zero_page left bytes set");
+    let neighbour = unsafe {
+        slice::from_raw_parts(next.start_address() as *const u8, PAGE_SIZE)
+    };
+    assert!(neighbour.iter().all(|&byte| byte == 0xa5), "zero_page wrote past its page");
+    for &test_page in [page, next].iter() {
+        let frame = page_table.unmap(test_page, allocator).unwrap();
+        allocator.deallocate_frame(frame);
+    }
+}
+
+// a frozen allocator hands out nothing and keeps freed frames, so its state stays
+// as it was (allocators that can't be frozen are skipped)
+fn test_frozen_allocator<A>(allocator: &mut A)
+    where A: FrameAllocator
+{
+    let frame = allocator.allocate_frame().expect("no more frames");
+    if allocator.freeze() {
+        let available = allocator.frames_available();
+        assert!(allocator.allocate_frame().is_none(), "frozen allocator gave a frame");
+        allocator.deallocate_frame(frame.clone());
+        assert!(allocator.frames_available() == available,
+            "the frozen allocator's state changed");
+        allocator.thaw();
+    }
+    allocator.deallocate_frame(frame);
+}
+
+// walking a table whose entries point back at its own frame (a corrupted
+// recursive-style entry, a loop a naive walk would follow forever) trips the depth
+// guard of both recursive walkers at every level instead of reading a data page as a
+// table; the table is mapped through the temporary page, which no recursive index
+// leads to; a real table walks on
+fn test_walk_depth_limit<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use self::table::{Level2, Level3};
+
+    let frame = allocator.allocate_frame().expect("no more frames");
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let crafted = {
+        let table = temporary_page.map_table_frame(frame.clone(), page_table);
+        for index in 0..ENTRIES_PER_TABLE {
+            table[index].set(frame.clone(), PRESENT | WRITABLE);
+        }
+        table as *const _ as usize
+    };
+    let too_deep = Err(PagingError::WalkTooDeep(crafted));
+    {
+        let p4 = unsafe { &*(crafted as *const Table<Level4>) };
+        let p3 = unsafe { &*(crafted as *const Table<Level3>) };
+        let p2 = unsafe { &*(crafted as *const Table<Level2>) };
+        assert!(p4.try_next_table_with(0, &RecursiveWalker).map(|_| ()) == too_deep &&
+            p3.try_next_table_with(1, &RecursiveWalker).map(|_| ()) == too_deep &&
+            p2.try_next_table_with(2, &RecursiveWalker).map(|_| ()) == too_deep,
+            "the crafted table passed the depth guard");
+        assert!(p4.try_next_table_with(0, &SecondaryRecursiveWalker).map(|_| ())
+                == too_deep &&
+            p2.try_next_table_with(2, &SecondaryRecursiveWalker).map(|_| ()) == too_deep,
+            "the crafted table passed the secondary depth guard");
+    }
+    temporary_page.unmap(page_table);
+    temporary_page.release(allocator);
+    allocator.deallocate_frame(frame);
+
+    let kernel_p3 = page_table.p4().try_next_table_with(0, &RecursiveWalker)
+        .expect("the real P4 was refused");
+    assert!(kernel_p3.map(|p3| p3 as *const _ as usize) == Some(recursive_address(&[0])));
+}
+
+// the checked last address of an empty or wrapping range is None, the debug check
+// of the range constructors flags an inverted range but not the empty `end + 1`
+// (a tripped debug_assert can't be caught in the kernel, so the check is tested)
+fn test_range_last_address() {
+    assert!(checked_last_address(0x1000, 0x1000) == Some(0x1fff));
+    assert!(checked_last_address(0x1000, 0).is_none() &&
+        checked_last_address(usize::max_value(), 2).is_none());
+    assert!(checked_last_address(usize::max_value(), 1) == Some(usize::max_value()));
+    assert!(is_inverted_range(5, 3) && is_inverted_range(usize::max_value(), 0));
+    assert!(!is_inverted_range(4, 3) && !is_inverted_range(3, 3) &&
+        !is_inverted_range(3, 5), "a valid range counts as inverted");
+}
+
+// the frames the early bump allocator handed out are marked used in the bitmap it is
+// turned into: a second bump allocator over the boot memory map hands three frames
+// out and turns into a bitmap of the first 64MiB
+fn test_bump_allocator_handoff(boot_info: &BootInformation) {
+    use memory::{BumpFrameAllocator, BitmapFrameAllocator, RESERVATIONS};
+    use memory::kernel_physical_range;
+    static mut TEST_BITMAP: [u64; 256] = [0; 256];
+
+    let (kernel_start, kernel_end) = kernel_physical_range(boot_info);
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+    let mut early = BumpFrameAllocator::new(kernel_start.start_address(),
+        kernel_end.start_address() + PAGE_SIZE, boot_info.start_address(),
+        boot_info.end_address(), memory_map_tag.memory_areas());
+    let early_frames: Vec<Frame> = (0..3)
+        .map(|_| early.allocate_frame().expect("no more frames")).collect();
+    let fresh = BitmapFrameAllocator::new(unsafe { &mut TEST_BITMAP });
+    let bitmap = early.into_bitmap_in(fresh, boot_info, &RESERVATIONS.lock());
+    assert!(early_frames.iter().all(|frame| bitmap.is_used(frame)),
+        "an early frame is free in the bitmap");
+    assert!(bitmap.free_frames() > 0, "the bitmap has no free frame at all");
+}
 
-    println!("{:#x}", unsafe {
-    *(Page::containing_address(addr).start_address() as *const u64)
+// map_to and map refuse pages of the recursive window (checked without the panic,
+// which can't be caught): every page of P4[RECURSIVE_INDEX] is refused, including the
+// P4 itself, the last page below the window is accepted
+fn test_recursive_window_refused() {
+    use self::mapper::outside_recursive_window;
+
+    for &(p3, p2, p1) in [(0, 0, 0), (0, 0, 42), (RECURSIVE_INDEX, 0, 0),
+        (RECURSIVE_INDEX, RECURSIVE_INDEX, RECURSIVE_INDEX)].iter()
+    {
+        let page = Page::from_table_indices(RECURSIVE_INDEX, p3, p2, p1);
+        assert!(!outside_recursive_window(page), "{:?} may be mapped", page);
+    }
+    assert!(outside_recursive_window(Page::containing_address(RECURSIVE_REGION_START - 1)),
+        "the last page below the recursive window is refused");
+    assert!(outside_recursive_window(Page::from_table_indices(0, 42, 0, 0)));
+}
+
+// a synthetic module tag (type 3, size 17, no name) over half of a fresh frame: its
+// frame is reserved and map_module hands out the bytes written to the frame before
+fn test_module_mapping<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use memory::{ReservationRegistry, map_module, reserve_module};
+    use multiboot2::ModuleTag;
+
+    let frame = allocator.allocate_frame().expect("no more frames");
+    let (start, size) = (frame.start_address(), PAGE_SIZE / 2);
+    let tag: [u32; 5] = [3, 17, start as u32, (start + size) as u32, 0];
+    let module = unsafe { &*(&tag as *const _ as *const ModuleTag) };
+
+    let mut reservations = ReservationRegistry::new();
+    reserve_module(&mut reservations, module);
+    assert!(reservations.contains_phys(&frame) &&
+        !reservations.contains_phys(&Frame::from_number(frame.number + 1)),
+        "the module's frames are not reserved");
+
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    {
+        let address = temporary_page.map(frame.clone(), page_table);
+        unsafe { ptr::write_bytes(address as *mut u8, 0xa5, PAGE_SIZE) };
+    }
+    temporary_page.unmap(page_table);
+    temporary_page.release(allocator);
+    let identity = Page::from_frame_identity(&frame);
+    let was_mapped = page_table.translate_page(identity).is_some();
+    {
+        let contents = map_module(module, allocator);
+        assert!(contents.len() == size && contents.iter().all(|&byte| byte == 0xa5),
+            "map_module doesn't show the module's bytes");
+    }
+    if !was_mapped {
+        page_table.unmap(identity, allocator);
+    }
+    allocator.deallocate_frame(frame);
+}
+
+// the temporary page tracks whether it is mapped, the state behind the double map and
+// spurious unmap checks (their panics can't be caught): a fresh page is unmapped, map
+// and map_readonly mark it mapped and unmap clears it again
+fn test_temporary_page_state<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let frame = allocator.allocate_frame().expect("no more frames");
+    assert!(!temporary_page.is_mapped(), "a fresh temporary page is mapped");
+    temporary_page.map(frame.clone(), page_table);
+    assert!(temporary_page.is_mapped(), "map didn't record the mapping");
+    temporary_page.unmap(page_table);
+    assert!(!temporary_page.is_mapped(), "unmap didn't record the unmapping");
+    temporary_page.map_readonly(frame.clone(), page_table);
+    assert!(temporary_page.is_mapped(), "map_readonly didn't record the mapping");
+    temporary_page.unmap(page_table);
+    assert!(!temporary_page.is_mapped());
+    temporary_page.release(allocator);
+    allocator.deallocate_frame(frame);
+}
+
+// the executable check before the kernel switch (its panic can't be caught): in a
+// fresh table a non-executable code page is refused, an executable one accepted and
+// an unmapped one reported as unmapped (frames above 4GiB, never accessed)
+fn test_executable_check<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+    let mut table = {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        InactivePageTable::new(frame, page_table, &mut temporary_page)
+    };
+    let page = |p1| Page::from_table_indices(53, 0, 0, p1);
+    let frame = |p1| Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE + p1);
+    page_table.with(&mut table, &mut temporary_page, |mapper| {
+        mapper.map_to(page(0), frame(0), NO_EXECUTE, allocator);
+        mapper.map_to(page(1), frame(1), EntryFlags::empty(), allocator);
+    });
+    {
+        let mut executable = |table: &mut InactivePageTable, p1| {
+            executable_in(page_table, table, &mut temporary_page,
+                page(p1).start_address() + 0x10)
+        };
+        assert!(executable(&mut table, 0) == Some(false), "a NO_EXECUTE code page passed");
+        assert!(executable(&mut table, 1) == Some(true), "an executable code page failed");
+        assert!(executable(&mut table, 2).is_none(), "an unmapped code page is mapped");
+    }
+    table.free_all(page_table, &mut temporary_page, allocator, true, false);
+    temporary_page.release(allocator);
+}
+
+// the kernel frames of synthetic sections: an identity linked section at 1MiB, a
+// higher half one loaded at 3MiB ending inside its second frame, plus a section that is
+// not allocated and an empty one, both below 1MiB and skipped; the raw sections are
+// laid out as in the overlap test above
+fn test_kernel_frames() {
+    use memory::extend_load_range;
+    static SECTIONS: [[u64; 8]; 4] = [
+        [1 << 32, 0x2, 0x10_0000, 0, 0x1000, 0, 0x1000, 0],
+        [1 << 32, 0x2, KERNEL_VIRTUAL_BASE as u64 + 0x30_0000, 0, 0x1800, 0, 0x1000, 0],
+        [1 << 32, 0, 0x1000, 0, 0x1000, 0, 0x1000, 0],
+        [1 << 32, 0x2, 0x8000, 0, 0, 0, 0x1000, 0],
+    ];
+    let sections = unsafe { &*(&SECTIONS as *const _ as *const [ElfSection; 4]) };
+    let mut range = None;
+    for_each_section_range(sections.iter(), |start, end, _| {
+        range = extend_load_range(range, start, end);
     });
+    let (start, end) = range.expect("no kernel range in the synthetic sections");
+    assert!(Frame::containing_address(start) == Frame::from_number(0x100) &&
+        Frame::containing_address(end) == Frame::from_number(0x301),
+        "wrong kernel frames {:#x} - {:#x}", start, end);
+}
+
+// free_subtree drops a GLOBAL translation too: a GLOBAL page in the empty P4[71] is
+// written (so it is cached), its subtree freed and the page mapped to a second frame;
+// a write through it goes to the second frame, not through the stale translation to
+// the first (only with CR4.PGE, without it GLOBAL has no effect)
+fn test_global_flush<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(71, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    if tlb::global_pages_enabled() {
+        let page = Page::from_table_indices(71, 0, 0, 0);
+        let first = allocator.allocate_frame().expect("no more frames");
+        let second = allocator.allocate_frame().expect("no more frames");
+        let word = page.start_address() as *mut u64;
+        page_table.map_to(page, first.clone(), WRITABLE | NO_EXECUTE | GLOBAL, allocator);
+        unsafe { *word = 1 };
+        page_table.free_subtree(71, false, allocator);
+        page_table.map_to(page, second.clone(), WRITABLE | NO_EXECUTE | GLOBAL, allocator);
+        unsafe { *word = 2 };
+        let mut temporary_page = TemporaryPage::new_default(page_table, allocator);
+        for &(ref frame, expected) in [(first.clone(), 1), (second.clone(), 2)].iter() {
+            let value = unsafe {
+                *(temporary_page.map(frame.clone(), page_table) as *const u64)
+            };
+            temporary_page.unmap(page_table);
+            assert!(value == expected, "{:?} holds {} instead of {}", frame, value, expected);
+        }
+        temporary_page.release(allocator);
+        page_table.free_subtree(71, false, allocator);
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(second);
+    }
+}
+
+// split_huge_page moves the PAT bit of a 2MiB page (bit 12) to bit 7 of the 4KiB
+// entries, none of them is huge and they keep the frames: a write-combining 2MiB page
+// in the empty P4[72] on frames above 4GiB (never accessed)
+fn test_split_pat_bit<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(72, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(72, 0, 0, 0);
+    let start = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE);
+    page_table.map_to_2mib(page, start.clone(), NO_EXECUTE, allocator);
+    {
+        let p2 = page_table.p4_mut().next_table_mut(72)
+            .and_then(|p3| p3.next_table_mut(0)).unwrap();
+        p2[0] = Entry::from_raw(p2[0].raw() | PAT_HUGE_PAGE);
+    }
+    page_table.split_huge_page(page, allocator).expect("the split failed");
+    for &index in [0, 1, ENTRIES_PER_TABLE - 1].iter() {
+        let entry = page_table.p1_entry(Page { number: page.number + index }).unwrap();
+        assert!(entry.flags() == PRESENT | NO_EXECUTE | PAT_4KIB,
+            "entry {} has the flags {}", index, entry.flags());
+        assert!(entry.pointed_frame() == Some(Frame::from_number(start.number + index)),
+            "entry {} points to the wrong frame", index);
+    }
+}
+
+// update_flags and protect_range replace the hardware flags only: COPY_ON_WRITE, the
+// other available bits, bit 52 and the protection key survive a protection change,
+// a new non-zero key replaces the old one (a page in the empty P4[73] on a frame
+// above 4GiB, never accessed)
+fn test_software_bits<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(73, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(73, 0, 0, 0);
+    let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE);
+    page_table.map_to(page, frame.clone(),
+        WRITABLE | COPY_ON_WRITE | EntryFlags::from_protection_key(3), allocator);
+    {
+        let entry = page_table.p1_entry_mut(page).unwrap();
+        entry.set_avl(0b111);
+        *entry = Entry::from_raw(entry.raw() | 1 << 52);
+    }
+    page_table.update_flags(page, NO_EXECUTE).expect("update_flags failed");
+    page_table.protect_range(Page::range_inclusive(page, page), NO_EXECUTE | GLOBAL)
+        .expect("protect_range failed");
+    {
+        let entry = page_table.p1_entry(page).unwrap();
+        assert!(entry.get_avl() == 0b111 && entry.raw() & 1 << 52 != 0 &&
+            entry.get_protection_key() == 3, "a software bit was dropped");
+        assert!(entry.flags().minus(COPY_ON_WRITE | PROTECTION_KEY) ==
+            PRESENT | NO_EXECUTE | GLOBAL, "wrong hardware flags {}", entry.flags());
+        assert!(entry.pointed_frame() == Some(frame), "the frame changed");
+    }
+    page_table.update_flags(page, NO_EXECUTE | EntryFlags::from_protection_key(5))
+        .expect("update_flags failed");
+    assert!(page_table.p1_entry(page).unwrap().get_protection_key() == 5,
+        "the new protection key was not set");
+}
+
+// a guard entry is not present and only carries GUARD_AVL_TAG, no address bit (a
+// reserved bit would depend on MAXPHYADDR); it still counts as used, maps nothing and
+// is told apart from an unmapped page (a guard page in the empty P4[74])
+fn test_guard_entry<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut slot = TestSlot::take(74, page_table, allocator);
+    let (page_table, allocator) = slot.parts();
+    let page = Page::from_table_indices(74, 0, 0, 0);
+    page_table.install_guard_page(page, allocator);
+    {
+        let entry = page_table.p1_entry(page).unwrap();
+        assert!(!entry.flags().contains(PRESENT) && entry.raw() & ADDRESS_MASK == 0,
+            "the guard entry is present or has address bits");
+        assert!(entry.get_avl() == GUARD_AVL_TAG && entry.is_guard() && !entry.is_unused());
+        assert!(entry.pointed_frame().is_none(), "the guard entry maps a frame");
+    }
+    assert!(page_table.is_guard_page(page.start_address()) &&
+        !page_table.is_guard_page(page.start_address() + PAGE_SIZE));
+    page_table.remove_guard_page(page);
+}
+
+// in debug builds mapping a reserved page (the lower heap guard page) is refused with
+// ReservedPage before any table is created, map_to would panic with that error
+#[cfg(debug_assertions)]
+fn test_reserved_page_refused<A>(page_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use memory::heap_guard_pages;
+
+    let (guard, _) = heap_guard_pages();
+    let frame = Frame::from_number(4 * ENTRIES_PER_TABLE * ENTRIES_PER_TABLE);
+    assert!(page_table.try_map_to(guard, frame, NO_EXECUTE, allocator) ==
+        Err(PagingError::ReservedPage(guard)), "a reserved page was mapped");
+    assert!(page_table.translate_page(guard).is_none(), "the heap guard page is mapped");
 }
@@ -0,0 +1,115 @@
+// a copy of all leaf mappings of a page table, to check that two tables (e.g. the
+// kernel table before and after a change to remap_the_kernel) map the same memory
+// ACCESSED and DIRTY are left out, the CPU sets them behind our back
+
+use super::Page;
+use super::entry::*;
+use memory::PAGE_SIZE;
+use alloc::vec::Vec;
+
+// one present leaf entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedLeaf {
+    pub page: Page,             // first page of the mapping
+    pub frame_number: usize,    // first frame of the mapping
+    pub flags: EntryFlags,
+    pub size: usize,            // bytes mapped by the entry
+}
+
+// leaf mappings sorted by page
+pub struct MappingSnapshot {
+    leaves: Vec<MappedLeaf>,
+}
+
+// a run of pages with the same flags that map consecutive frames, one line of a
+// vmmap listing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmRegion {
+    pub start: Page,            // first page of the run
+    pub end: Page,              // last page of the run
+    pub flags: EntryFlags,
+    pub size: usize,            // bytes mapped by the run
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingDiff {
+    Added(MappedLeaf),
+    Removed(MappedLeaf),
+    Changed { old: MappedLeaf, new: MappedLeaf },  // same first page, other frame/flags/size
+}
+
+impl MappingSnapshot {
+
+    pub fn new(mut leaves: Vec<MappedLeaf>) -> MappingSnapshot {
+        for leaf in leaves.iter_mut() {
+            leaf.flags = leaf.flags.minus(ACCESSED | DIRTY);
+        }
+        leaves.sort_by_key(|leaf| leaf.page);
+        MappingSnapshot { leaves: leaves }
+    }
+
+    pub fn leaves(&self) -> &[MappedLeaf] {
+        &self.leaves
+    }
+
+    // merge neighbouring leaves into regions: a leaf extends the region before it if it
+    // starts on the next page, maps the next frame and has the same flags
+    pub fn regions(&self) -> Vec<VmRegion> {
+        let mut regions: Vec<VmRegion> = Vec::new();
+        let mut next_frame = 0;     // frame the last region would continue with
+        for leaf in self.leaves.iter() {
+            let pages = leaf.size / PAGE_SIZE;
+            let extends = match regions.last() {
+                Some(region) => region.end.number + 1 == leaf.page.number &&
+                    next_frame == leaf.frame_number && region.flags == leaf.flags,
+                None => false,
+            };
+            if extends {
+                let region = regions.last_mut().unwrap();
+                region.end = Page { number: leaf.page.number + pages - 1 };
+                region.size += leaf.size;
+            } else {
+                regions.push(VmRegion {
+                    start: leaf.page,
+                    end: Page { number: leaf.page.number + pages - 1 },
+                    flags: leaf.flags,
+                    size: leaf.size,
+                });
+            }
+            next_frame = leaf.frame_number + pages;
+        }
+        regions
+    }
+}
+
+// every mapping that differs between the snapshots, in page order
+// empty if both map the same pages to the same frames with the same flags
+pub fn diff(old: &MappingSnapshot, new: &MappingSnapshot) -> Vec<MappingDiff> {
+    let mut diffs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.leaves.len() || j < new.leaves.len() {
+        match (old.leaves.get(i), new.leaves.get(j)) {
+            (Some(&o), Some(&n)) if o.page == n.page => {
+                if o != n {
+                    diffs.push(MappingDiff::Changed { old: o, new: n });
+                }
+                i += 1;
+                j += 1;
+            }
+            (Some(&o), Some(&n)) if o.page < n.page => {
+                diffs.push(MappingDiff::Removed(o));
+                i += 1;
+            }
+            (Some(&o), None) => {
+                diffs.push(MappingDiff::Removed(o));
+                i += 1;
+            }
+            (_, Some(&n)) => {
+                diffs.push(MappingDiff::Added(n));
+                j += 1;
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    diffs
+}
@@ -2,8 +2,9 @@
 
 use core::marker::PhantomData;      // needed since unused type parameters are not allowed in Rust
 use memory::paging::entry::*;
-use memory::paging::ENTRY_COUNT;
-use memory::FrameAllocator;
+use memory::paging::{ENTRIES_PER_TABLE, RECURSIVE_INDEX, SECONDARY_RECURSIVE_INDEX, zero_page};
+use memory::paging::{VirtualAddress, PagingError};
+use memory::{Frame, FrameAllocator};
 use core::ops::{Index, IndexMut};
 
 
@@ -13,8 +14,8 @@ pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
 pub struct Table<L: TableLevel> {
     // array of 512 entries
     // Entry - what it contains
-    // ENTRY_COUNT - size of array
-    entries: [Entry; ENTRY_COUNT],
+    // ENTRIES_PER_TABLE - size of array
+    entries: [Entry; ENTRIES_PER_TABLE],
     level: PhantomData<L>,          // indicate that your struct owns data of type L
 }
 
@@ -38,10 +39,14 @@ impl<L> Table<L> where L: TableLevel {
 
     // sets all entries to unused
     // needed when we create a new page table
+    // an unused entry is 0, so this clears the table's page in u64 writes
     pub fn zero(&mut self) {
-        for entry in self.entries.iter_mut() {
-            entry.set_unused();
-        }
+        zero_page(self as *mut _ as usize);
+    }
+
+    // no entry in use (guard entries count as used), the table's frame may be freed
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
     }
 }
 
@@ -52,59 +57,235 @@ impl<L> Table<L> where L: HierarchicalLevel {
     // convert addresses to Rust references through mut
     // return the table of the next level (P3 for P4, P1 for P2 and so on..)
     pub fn next_table(&self, index: usize) -> Option<&Table<L::NextLevel>> {
-        //if address at place index exists -> make it into a reference
-        self.next_table_address(index).map(|address| unsafe { &*(address as *const _) })
+        self.next_table_with(index, &RecursiveWalker)
     }
     pub fn next_table_mut(&mut self, index: usize) -> Option<&mut Table<L::NextLevel>> {
-        self.next_table_address(index).map(|address| unsafe { &mut *(address as *mut _) })
+        self.next_table_mut_with(index, &RecursiveWalker)
+    }
+
+    // the same through the given walker backend
+    // panics if the walk went deeper than 4 levels, see try_next_table_with
+    pub fn next_table_with<W>(&self, index: usize, walker: &W) -> Option<&Table<L::NextLevel>>
+        where W: PageTableWalker
+    {
+        //if address at place index exists -> make it into a reference
+        unwrap_walk(self.next_table_address(index, walker))
+            .map(|address| unsafe { &*(address as *const _) })
+    }
+    pub fn next_table_mut_with<W>(&mut self, index: usize, walker: &W)
+        -> Option<&mut Table<L::NextLevel>>
+        where W: PageTableWalker
+    {
+        unwrap_walk(self.next_table_address(index, walker))
+            .map(|address| unsafe { &mut *(address as *mut _) })
+    }
+
+    // WalkTooDeep instead of the panic if this table can't be of its level, e.g. because
+    // a corrupted entry points back at its own table
+    pub fn try_next_table_with<W>(&self, index: usize, walker: &W)
+        -> Result<Option<&Table<L::NextLevel>>, PagingError>
+        where W: PageTableWalker
+    {
+        self.next_table_address(index, walker)
+            .map(|address| address.map(|address| unsafe { &*(address as *const _) }))
     }
 
     // calculate the next page table address
-    fn next_table_address(&self, index: usize) -> Option<usize> {
+    fn next_table_address<W>(&self, index: usize, walker: &W)
+        -> Result<Option<usize>, PagingError>
+        where W: PageTableWalker
+    {
         let entry_flags = self[index].flags();
         // next table address is only valid if the corresponding entry is present and does not create a huge page
         if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
             let table_address = self as *const _ as usize;
-            // formula to calculate next address, the address of next page table
-            Some((table_address << 9) | (index << 12))
+            walker.next_table_address(table_address, L::level(), index, &self[index]).map(Some)
         } else {
-            None
+            Ok(None)
         }
     }
 
     // return next table if it exists or create a new one
     pub fn next_table_create<A>(&mut self, index: usize, allocator: &mut A) -> &mut Table<L::NextLevel>
     where A: FrameAllocator
+    {
+        self.next_table_create_with(index, allocator, &RecursiveWalker)
+    }
+
+    pub fn next_table_create_with<A, W>(&mut self, index: usize, allocator: &mut A, walker: &W)
+        -> &mut Table<L::NextLevel>
+        where A: FrameAllocator, W: PageTableWalker
+    {
+        match self.try_next_table_create_with(index, allocator, walker) {
+            Ok(table) => table,
+            Err(_) => panic!("no frames available"),
+        }
+    }
+
+    // FrameAllocationFailed instead of a panic if no frame is left for the new table
+    pub fn try_next_table_create_with<A, W>(&mut self, index: usize, allocator: &mut A,
+        walker: &W) -> Result<&mut Table<L::NextLevel>, PagingError>
+        where A: FrameAllocator, W: PageTableWalker
 {
     // if there does not exist a next table
-    if self.next_table(index).is_none() {
+    if self.next_table_with(index, walker).is_none() {
         assert!(!self.entries[index].flags().contains(HUGE_PAGE),
                 "mapping code does not support huge pages");
         // allocate frames
-        let frame = allocator.allocate_frame().expect("no frames available");
+        let frame = match allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return Err(PagingError::FrameAllocationFailed),
+        };
         // set the present and writeable bits
         self.entries[index].set(frame, PRESENT | WRITABLE);
         // set all entries to unused
-        self.next_table_mut(index).unwrap().zero();
+        self.next_table_mut_with(index, walker).unwrap().zero();
+    }
+    Ok(self.next_table_mut_with(index, walker).unwrap())
+    }
+}
+
+// backend that finds the virtual address of the table an entry points to
+pub trait PageTableWalker {
+    // `table_address` is the virtual address of a table of the given level
+    // WalkTooDeep if the table can't be of that level
+    fn next_table_address(&self, table_address: usize, level: usize, index: usize,
+        entry: &Entry) -> Result<usize, PagingError>;
+}
+
+// the default backend: tables are reached through the recursive entry P4[511]
+pub struct RecursiveWalker;
+
+impl PageTableWalker for RecursiveWalker {
+    fn next_table_address(&self, table_address: usize, level: usize, index: usize,
+        _entry: &Entry) -> Result<usize, PagingError>
+    {
+        check_walk_depth(recursive_depth(table_address), table_address, level)?;
+        // formula to calculate next address, the address of next page table
+        Ok((table_address << 9) | (index << 12))
+    }
+}
+
+// backend for editing an inactive table installed in the secondary recursive slot
+// P4[SECONDARY_RECURSIVE_INDEX]: its P4 is at 0o177777_777_777_777_776_0000, the
+// address arithmetic is the same as for the recursive walker
+pub struct SecondaryRecursiveWalker;
+
+impl PageTableWalker for SecondaryRecursiveWalker {
+    fn next_table_address(&self, table_address: usize, level: usize, index: usize,
+        _entry: &Entry) -> Result<usize, PagingError>
+    {
+        let depth = recursive_depth_through(table_address, SECONDARY_RECURSIVE_INDEX);
+        check_walk_depth(depth, table_address, level)?;
+        Ok((table_address << 9) | (index << 12))
     }
-    self.next_table_mut(index).unwrap()
+}
+
+// backend for a kernel that maps all physical memory at `offset`
+// a table in frame f is at offset + f.start_address()
+pub struct OffsetWalker {
+    pub offset: usize,
+}
+
+impl OffsetWalker {
+    // the P4 table stored in the given frame
+    pub fn p4(&self, p4_frame: &Frame) -> *mut Table<Level4> {
+        (self.offset + p4_frame.start_address()) as *mut _
+    }
+}
+
+impl PageTableWalker for OffsetWalker {
+    fn next_table_address(&self, _table_address: usize, _level: usize, _index: usize,
+        entry: &Entry) -> Result<usize, PagingError>
+    {
+        Ok(self.offset + entry.pointed_frame().unwrap().start_address())
+    }
+}
+
+// virtual address of a table through the recursive mapping, the table is identified by
+// the indexes leading to it: none for the P4, [p4] for a P3, [p4, p3] for a P2 and
+// [p4, p3, p2] for a P1
+// every missing level is taken by a recursive index in front, so the P4 itself is at
+// 0o177777_777_777_777_777_0000 = 0xffff_ffff_ffff_f000 (the P4 constant above)
+// the result is only a table if every entry on the way is present and not huge
+pub fn recursive_address(indices: &[usize]) -> VirtualAddress {
+    assert!(indices.len() < 4, "a table has at most 3 parent indexes: {:?}", indices);
+    assert!(indices.iter().all(|&index| index < ENTRIES_PER_TABLE),
+        "table index out of range: {:?}", indices);
+
+    let mut address = 0;
+    for _ in indices.len()..4 {
+        address = (address << 9) | RECURSIVE_INDEX;
+    }
+    for &index in indices {
+        address = (address << 9) | index;
+    }
+    address <<= 12;
+    // sign extension of bit 47, always set by the leading recursive index
+    if address & (1 << 47) != 0 {
+        address |= 0xffff_0000_0000_0000;
     }
+    address
 }
 
+// number of leading recursive (511) indexes in a table address
+// the P4 has 4, a P3 at least 3, a P2 at least 2 and a P1 at least 1
+fn recursive_depth(table_address: usize) -> usize {
+    table_indexes(table_address).iter()
+        .take_while(|&&index| index == RECURSIVE_INDEX).count()
+}
+
+// like recursive_depth for a table reached through another P4 slot: the leading
+// recursive indexes followed by `slot` count as well
+fn recursive_depth_through(table_address: usize, slot: usize) -> usize {
+    let indexes = table_indexes(table_address);
+    let depth = recursive_depth(table_address);
+    if depth < 4 && indexes[depth] == slot { depth + 1 } else { depth }
+}
+
+// P4 to P1 index of a table address
+fn table_indexes(table_address: usize) -> [usize; 4] {
+    [(table_address >> 39) & 0o777, (table_address >> 30) & 0o777,
+     (table_address >> 21) & 0o777, (table_address >> 12) & 0o777]
+}
+
+// guard against walking more than 4 levels deep
+// a table has to be reachable through at least `level` recursive indexes (`depth`),
+// otherwise the recursive entry is corrupted or a table reference was forged and the
+// next address would point at a data page instead of a table
+fn check_walk_depth(depth: usize, table_address: usize, level: usize)
+    -> Result<(), PagingError>
+{
+    if depth < level {
+        Err(PagingError::WalkTooDeep(table_address))
+    } else {
+        Ok(())
+    }
+}
+
+// the next table address of a walk that must not fail
+fn unwrap_walk(address: Result<Option<usize>, PagingError>) -> Option<usize> {
+    match address {
+        Ok(address) => address,
+        Err(error) => panic!("{}", error),
+    }
+}
 
 // model the different tables with traits and empty enums
 // empty enum has size 0 and disappears after compiling
-pub trait TableLevel {}
+pub trait TableLevel {
+    fn level() -> usize;    // 4 for the P4 down to 1 for the P1
+}
 
 pub enum Level4 {}
 pub enum Level3 {}
 pub enum Level2 {}
 pub enum Level1 {}
 
-impl TableLevel for Level4 {}
-impl TableLevel for Level3 {}
-impl TableLevel for Level2 {}
-impl TableLevel for Level1 {}
+impl TableLevel for Level4 { fn level() -> usize { 4 } }
+impl TableLevel for Level3 { fn level() -> usize { 3 } }
+impl TableLevel for Level2 { fn level() -> usize { 2 } }
+impl TableLevel for Level1 { fn level() -> usize { 1 } }
 
 // to differentiate the P1 table from the other tables, use HierarchicalLevel trait
 // we should only be able to use next_table methods on P4, P3 and P2
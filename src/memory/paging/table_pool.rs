@@ -0,0 +1,89 @@
+// frames for new page tables, taken from the frame allocator in batches
+// a bulk mapping creates a table every 512 pages; with the pool the allocator is only
+// called when the pool runs low instead of once per table
+// a pooled frame is zeroed when next_table_create installs it, the table can't be
+// reached through the recursive mapping any earlier
+
+use memory::{Frame, FrameAllocator};
+
+// frames the pool holds after a refill
+pub const TABLE_POOL_SIZE: usize = 16;
+
+pub struct TablePool {
+    frames: [Option<Frame>; TABLE_POOL_SIZE],
+    taken: usize,       // frames handed out as tables
+    refills: usize,     // batches taken from the allocator
+}
+
+impl TablePool {
+
+    pub fn new() -> TablePool {
+        TablePool {
+            frames: [None, None, None, None, None, None, None, None,
+                     None, None, None, None, None, None, None, None],
+            taken: 0,
+            refills: 0,
+        }
+    }
+
+    // frames currently in the pool
+    pub fn len(&self) -> usize {
+        self.frames.iter().filter(|frame| frame.is_some()).count()
+    }
+
+    pub fn taken(&self) -> usize {
+        self.taken
+    }
+
+    pub fn refills(&self) -> usize {
+        self.refills
+    }
+
+    // fill every empty slot from the allocator, stops early if it runs out
+    pub fn refill<A>(&mut self, allocator: &mut A) where A: FrameAllocator {
+        self.refills += 1;
+        for slot in self.frames.iter_mut().filter(|slot| slot.is_none()) {
+            match allocator.allocate_frame() {
+                Some(frame) => *slot = Some(frame),
+                None => return,
+            }
+        }
+    }
+
+    // hand the unused frames back to the allocator
+    pub fn release<A>(&mut self, allocator: &mut A) where A: FrameAllocator {
+        for slot in self.frames.iter_mut() {
+            if let Some(frame) = slot.take() {
+                allocator.deallocate_frame(frame);
+            }
+        }
+    }
+}
+
+// the pool is the allocator next_table_create sees during a bulk mapping
+impl FrameAllocator for TablePool {
+
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        for slot in self.frames.iter_mut() {
+            if slot.is_some() {
+                self.taken += 1;
+                return slot.take();
+            }
+        }
+        None
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        for slot in self.frames.iter_mut() {
+            if slot.is_none() {
+                *slot = Some(frame);
+                return;
+            }
+        }
+        panic!("table pool can hold only {} frames", TABLE_POOL_SIZE);
+    }
+
+    fn frames_available(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
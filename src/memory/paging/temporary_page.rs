@@ -2,14 +2,24 @@
 //we need to zero the P4 table since it can map garbage otherwise but we can't zero it right now because the p4_frame is not mapped to a virtual address
 
 
-use super::Page;
+use super::{Page, tlb, recursive_address};
 use super::{ActivePageTable, VirtualAddress};
-use super::table::{Table, Level1};
-use memory::Frame;
+use super::table::{Table, Level1, HierarchicalLevel};
+use super::entry::EntryFlags;
+use memory::{PAGE_SIZE, Frame};
+use core::ops::{Deref, DerefMut};
+use core::slice;
+
+// page number of the default temporary page: the first page of P4[508]
+// (0xffff_fe00_0000_0000), a 512GiB slot reserved for it so it can't collide with
+// the identity mapped kernel (P4[0]), user memory or the higher half kernel (P4[256])
+pub const TEMP_PAGE_NUMBER: usize = 0x000f_ffff_e000_0000;
 
 pub struct TemporaryPage {
     page: Page,
     allocator: TinyAllocator,
+    mapped: bool,   // map and unmap must alternate, checked in debug builds
+    created_tables: usize,  // tables the current mapping created, the lowest levels of its walk
 }
 
 impl TemporaryPage {
@@ -21,9 +31,21 @@ impl TemporaryPage {
         TemporaryPage {
             page: page,
             allocator: TinyAllocator::new(allocator),
+            mapped: false,
+            created_tables: 0,
         }
     }
 
+    // temporary page at TEMP_PAGE_NUMBER, panics if something is mapped there
+    pub fn new_default<A>(active_table: &ActivePageTable, allocator: &mut A) -> TemporaryPage
+        where A: FrameAllocator
+    {
+        let page = Page { number: TEMP_PAGE_NUMBER };
+        assert!(active_table.translate_page(page).is_none(),
+                "default temporary page {:#x} is already mapped", page.start_address());
+        TemporaryPage::new(page, allocator)
+    }
+
     /// Maps the temporary page to the given frame in the active table.
     /// Returns the start address of the temporary page.
     pub fn map(&mut self, frame: Frame, active_table: &mut ActivePageTable)
@@ -31,15 +53,110 @@ impl TemporaryPage {
     {
         use super::entry::WRITABLE;
 
+        self.map_with_flags(frame, WRITABLE, active_table)
+    }
+
+    // map without WRITABLE (and not executable): with CR0.WP a write through the page
+    // faults, even from ring 0
+    pub fn map_readonly(&mut self, frame: Frame, active_table: &mut ActivePageTable)
+        -> VirtualAddress
+    {
+        use super::entry::NO_EXECUTE;
+
+        self.map_with_flags(frame, NO_EXECUTE, active_table)
+    }
+
+    fn map_with_flags(&mut self, frame: Frame, flags: EntryFlags,
+        active_table: &mut ActivePageTable) -> VirtualAddress
+    {
+        debug_assert!(!self.is_mapped(), "temporary page mapped twice without unmap");
+        self.mapped = true;
         assert!(active_table.translate_page(self.page).is_none(),
                 "temporary page is already mapped");
-        active_table.map_to(self.page, frame, WRITABLE, &mut self.allocator);
+        debug_assert!(!self.is_own_table_frame(&frame, active_table),
+                "{:?} is a page table mapping the temporary page itself", frame);
+        let frames_before = self.allocator.len();
+        active_table.map_to(self.page, frame, flags, &mut self.allocator);
+        self.created_tables = frames_before - self.allocator.len();
         self.page.start_address()
     }
 
+    // is the frame the P3, P2 or P1 on the way to the temporary page?
+    // writing through the temporary page would then change its own mapping
+    // (the P4 is fine, `with` maps the active P4 on purpose)
+    // the condition of the debug check in map, which can't be triggered in a test
+    pub fn is_own_table_frame(&self, frame: &Frame, active_table: &ActivePageTable) -> bool {
+        let page = self.page;
+        let indices = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+        (1..4).filter_map(|level| active_table.entry_physical_address(level, indices))
+            .any(|address| Frame::containing_address(address) == *frame)
+    }
+
+    // is a frame mapped through the page right now? the state the debug checks of map,
+    // unmap and release test, a second map or an unmatched unmap panics there
+    pub fn is_mapped(&self) -> bool {
+        self.mapped
+    }
+
     /// Unmaps the temporary page in the active table.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        debug_assert!(self.is_mapped(), "temporary page unmapped while not mapped");
+        self.mapped = false;
+        active_table.unmap_strict(self.page);
+        self.free_created_tables(active_table);
+    }
+
+    // hand the tables the last map created back to the tiny allocator, P1 first, so
+    // they don't stay behind as empty tables holding the allocator's frames
+    // a table something else was mapped into meanwhile (e.g. the P4 alias next to the
+    // default temporary page) is kept, and so are the tables above it
+    fn free_created_tables(&mut self, active_table: &mut ActivePageTable) {
+        let page = self.page;
+        let (p4_index, p3_index, p2_index) =
+            (page.p4_index(), page.p3_index(), page.p2_index());
+        let created = self.created_tables;
+        self.created_tables = 0;
+
+        if created >= 1 {
+            let p2 = active_table.p4_mut().next_table_mut(p4_index)
+                .and_then(|p3| p3.next_table_mut(p3_index)).unwrap();
+            let address = recursive_address(&[p4_index, p3_index, p2_index]);
+            if !free_table_if_empty(p2, p2_index, address, &mut self.allocator) {
+                return;
+            }
+        }
+        if created >= 2 {
+            let p3 = active_table.p4_mut().next_table_mut(p4_index).unwrap();
+            let address = recursive_address(&[p4_index, p3_index]);
+            if !free_table_if_empty(p3, p3_index, address, &mut self.allocator) {
+                return;
+            }
+        }
+        if created >= 3 {
+            let address = recursive_address(&[p4_index]);
+            free_table_if_empty(active_table.p4_mut(), p4_index, address, &mut self.allocator);
+        }
+    }
+
+    // give the tiny allocator's frames back, e.g. after the kernel remap; the temporary
+    // page must not be mapped
+    pub fn release<A>(mut self, allocator: &mut A) where A: FrameAllocator {
+        debug_assert!(!self.is_mapped(), "releasing a mapped temporary page");
+        while let Some(frame) = self.allocator.allocate_frame() {
+            allocator.deallocate_frame(frame);
+        }
+    }
+
+    // map the frame for as long as the returned guard lives, dropping it unmaps the
+    // temporary page again, also on an early return
+    pub fn map_scoped<'a>(&'a mut self, frame: Frame, active_table: &'a mut ActivePageTable)
+        -> ScopedMap<'a>
+    {
+        self.map(frame, active_table);
+        ScopedMap {
+            temporary_page: self,
+            active_table: active_table,
+        }
     }
 
     /// Maps the temporary page to the given page table frame in the active
@@ -52,6 +169,50 @@ impl TemporaryPage {
     }
 }
 
+// clear the parent's entry of the table at `table_address` (its recursive address) and
+// free the table's frame, if no entry of the table is in use
+fn free_table_if_empty<L>(parent: &mut Table<L>, index: usize, table_address: VirtualAddress,
+    allocator: &mut TinyAllocator) -> bool
+    where L: HierarchicalLevel
+{
+    if !parent.next_table(index).map_or(false, |table| table.is_empty()) {
+        return false;
+    }
+    let frame = parent[index].pointed_frame().unwrap();
+    parent[index].set_unused();
+    tlb::flush_address(table_address);
+    allocator.deallocate_frame(frame);
+    true
+}
+
+// the bytes of a frame mapped through the temporary page, see map_scoped
+pub struct ScopedMap<'a> {
+    temporary_page: &'a mut TemporaryPage,
+    active_table: &'a mut ActivePageTable,
+}
+
+impl<'a> Deref for ScopedMap<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let address = self.temporary_page.page.start_address();
+        unsafe { slice::from_raw_parts(address as *const u8, PAGE_SIZE) }
+    }
+}
+
+impl<'a> DerefMut for ScopedMap<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let address = self.temporary_page.page.start_address();
+        unsafe { slice::from_raw_parts_mut(address as *mut u8, PAGE_SIZE) }
+    }
+}
+
+impl<'a> Drop for ScopedMap<'a> {
+    fn drop(&mut self) {
+        self.temporary_page.unmap(self.active_table);
+    }
+}
+
 //allocator only holds 3 frames, P3, P2 and P1
 //P4 is always mapped since we start from there when we need to access a page
 struct TinyAllocator([Option<Frame>; 3]);
@@ -65,6 +226,11 @@ impl TinyAllocator {
         let frames = [f(), f(), f()];
         TinyAllocator(frames)
     }
+
+    // frames not handed out as tables
+    fn len(&self) -> usize {
+        self.0.iter().filter(|frame| frame.is_some()).count()
+    }
 }
 
 use memory::FrameAllocator;
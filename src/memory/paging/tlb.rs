@@ -0,0 +1,66 @@
+// every TLB flush of the paging code goes through here
+// single core for now, on SMP these functions also have to send the shootdown IPIs
+// to the other cores, nothing outside this file should touch the TLB directly
+
+use super::{Page, VirtualAddress};
+use x86_64::instructions::tlb;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// number of flushes so far (a flush_all counts once), lets a test see how many flushes
+// an operation needs and catch redundant ones
+static FLUSHES: AtomicUsize = AtomicUsize::new(0);
+
+// drop the cached translation of the page
+pub fn flush_page(page: Page) {
+    FLUSHES.fetch_add(1, Ordering::Relaxed);
+    tlb::flush(::x86_64::VirtualAddress(page.start_address()));
+}
+
+// flush_page for the page containing the address
+pub fn flush_address(address: VirtualAddress) {
+    flush_page(Page::containing_address(address))
+}
+
+// drop all non global cached translations
+pub fn flush_all() {
+    FLUSHES.fetch_add(1, Ordering::Relaxed);
+    tlb::flush_all();
+}
+
+// CR4.PGE (bit 7): GLOBAL translations survive CR3 writes and flush_all
+pub fn global_pages_enabled() -> bool {
+    use x86_64::registers::control_regs::cr4;
+
+    cr4().bits() as u64 & CR4_PGE != 0
+}
+
+// drop all cached translations, the global ones too: clearing CR4.PGE flushes the
+// whole TLB, then it is set again
+// just a flush_all without global pages
+pub fn flush_global() {
+    use x86_64::registers::control_regs::cr4;
+
+    let cr4 = cr4().bits() as u64;
+    if cr4 & CR4_PGE == 0 {
+        return flush_all();
+    }
+    FLUSHES.fetch_add(1, Ordering::Relaxed);
+    unsafe {
+        asm!("mov $0, %cr4" :: "r"(cr4 & !CR4_PGE) : "memory" : "volatile");
+        asm!("mov $0, %cr4" :: "r"(cr4) : "memory" : "volatile");
+    }
+}
+
+const CR4_PGE: u64 = 1 << 7;
+
+pub fn flush_count() -> usize {
+    FLUSHES.load(Ordering::Relaxed)
+}
+
+// number of flushes f does, for tests that pin down how often an operation flushes
+// (e.g. that a bulk operation flushes once instead of once per page)
+pub fn with_flush_count<F>(f: F) -> usize where F: FnOnce() {
+    let before = flush_count();
+    f();
+    flush_count() - before
+}
@@ -0,0 +1,84 @@
+// allocator for ranges of virtual pages inside a fixed region
+// only bookkeeping: allocating or reserving pages creates no page table entries,
+// the caller maps what it needs explicitly
+
+use super::{Page, PageIter};
+use alloc::vec::Vec;
+
+pub struct VirtualAddressSpace {
+    start: usize,               // first page number of the region
+    end: usize,                 // last page number of the region
+    used: Vec<(usize, usize)>,  // sorted, disjoint [first, last] page numbers in use
+}
+
+impl VirtualAddressSpace {
+
+    // all pages of the range are free
+    pub fn new(pages: PageIter) -> VirtualAddressSpace {
+        VirtualAddressSpace {
+            start: pages.start.number,
+            end: pages.end.number,
+            used: Vec::new(),
+        }
+    }
+
+    // first fit: the lowest `count` consecutive pages that are neither allocated nor
+    // reserved, None if there is no gap that large
+    pub fn allocate(&mut self, count: usize) -> Option<PageIter> {
+        if count == 0 {
+            return None;
+        }
+        let mut candidate = self.start;
+        let mut index = 0;
+        loop {
+            let gap_end = match self.used.get(index) {
+                Some(&(first, _)) => first,
+                None => self.end + 1,
+            };
+            if gap_end - candidate >= count {
+                let last = candidate + count - 1;
+                self.used.insert(index, (candidate, last));
+                return Some(range(candidate, last));
+            }
+            match self.used.get(index) {
+                Some(&(_, last)) => candidate = last + 1,
+                None => return None,
+            }
+            index += 1;
+        }
+    }
+
+    // mark the pages as used without mapping them, e.g. for guard regions or areas that
+    // grow later; returns false (and records nothing) if a page is outside the region
+    // or already in use
+    pub fn reserve(&mut self, pages: PageIter) -> bool {
+        let (first, last) = (pages.start.number, pages.end.number);
+        if first > last || first < self.start || last > self.end {
+            return false;
+        }
+        let index = self.used.iter().position(|&(used_first, _)| used_first > last)
+            .unwrap_or(self.used.len());
+        if index > 0 && self.used[index - 1].1 >= first {
+            return false;   // overlaps the range before
+        }
+        self.used.insert(index, (first, last));
+        true
+    }
+
+    // give back a range returned by allocate or recorded by reserve
+    pub fn free(&mut self, pages: PageIter) {
+        let range = (pages.start.number, pages.end.number);
+        let index = self.used.iter().position(|&used| used == range)
+            .expect("freeing pages that were not allocated as one range");
+        self.used.remove(index);
+    }
+
+    // is the page allocated or reserved?
+    pub fn is_used(&self, page: Page) -> bool {
+        self.used.iter().any(|&(first, last)| first <= page.number && page.number <= last)
+    }
+}
+
+fn range(first: usize, last: usize) -> PageIter {
+    Page::range_inclusive(Page { number: first }, Page { number: last })
+}
@@ -0,0 +1,138 @@
+// registry of memory that must not be handed out or mapped over
+// physical ranges are never given out by the frame allocator, virtual ranges must not
+// be mapped by the mappers (checked in debug builds)
+// filled during boot before the heap exists, so the ranges live in fixed arrays
+
+use memory::{PAGE_SIZE, Frame, AddressRange};
+use memory::paging::{Page, PhysicalAddress, VirtualAddress};
+use spin::{Mutex, Once};
+
+// number of ranges each side of the registry can hold
+pub const MAX_RESERVATIONS: usize = 16;
+
+// a named range [start, end], end is the last reserved address
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub name: &'static str,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Reservation {
+    pub fn range(&self) -> AddressRange {
+        AddressRange { start: self.start, end: self.end }
+    }
+}
+
+#[derive(Clone)]
+pub struct ReservationRegistry {
+    phys: [Option<Reservation>; MAX_RESERVATIONS],
+    virt: [Option<Reservation>; MAX_RESERVATIONS],
+}
+
+pub static RESERVATIONS: Mutex<ReservationRegistry> = Mutex::new(ReservationRegistry::new());
+
+// the registry as it was at the end of boot, see freeze_reservations
+static FROZEN_RESERVATIONS: Once<ReservationRegistry> = Once::new();
+
+// take the snapshot the mappers and deallocate_frame check against, once boot made all
+// its reservations; they never lock RESERVATIONS themselves: they run with ACTIVE_TABLE
+// held, or called by code that holds RESERVATIONS, and would deadlock
+// reservations made after the freeze are not checked by them
+pub fn freeze_reservations() {
+    FROZEN_RESERVATIONS.call_once(|| RESERVATIONS.lock().clone());
+}
+
+// the snapshot of freeze_reservations, None before it
+pub fn frozen_reservations() -> Option<&'static ReservationRegistry> {
+    FROZEN_RESERVATIONS.try()
+}
+
+impl ReservationRegistry {
+
+    pub const fn new() -> ReservationRegistry {
+        ReservationRegistry {
+            phys: [None; MAX_RESERVATIONS],
+            virt: [None; MAX_RESERVATIONS],
+        }
+    }
+
+    // reserve the frames of [start, start + size), ranges may overlap
+    pub fn reserve_phys(&mut self, name: &'static str, start: PhysicalAddress, size: usize) {
+        insert(&mut self.phys, name, start, size);
+    }
+
+    // reserve the pages of [start, start + size), ranges may overlap
+    pub fn reserve_virt(&mut self, name: &'static str, start: VirtualAddress, size: usize) {
+        insert(&mut self.virt, name, start, size);
+    }
+
+    // is any part of the frame reserved?
+    pub fn contains_phys(&self, frame: &Frame) -> bool {
+        self.phys_reservation(frame).is_some()
+    }
+
+    // the first reservation touching the frame
+    pub fn phys_reservation(&self, frame: &Frame) -> Option<&Reservation> {
+        find(&self.phys, AddressRange { start: frame.start_address(),
+            end: frame.start_address() + PAGE_SIZE - 1 })
+    }
+
+    // the first reservation touching the physical range [start, end]
+    pub fn phys_overlap(&self, start: PhysicalAddress, end: PhysicalAddress)
+        -> Option<&Reservation>
+    {
+        find(&self.phys, AddressRange { start: start, end: end })
+    }
+
+    // is any part of the page reserved?
+    pub fn contains_virt(&self, page: Page) -> bool {
+        self.virt_reservation(page).is_some()
+    }
+
+    // the first reservation touching the page
+    pub fn virt_reservation(&self, page: Page) -> Option<&Reservation> {
+        let start = page.start_address();
+        find(&self.virt, AddressRange { start: start, end: start + PAGE_SIZE - 1 })
+    }
+
+    // all physical reservations, for the frame allocator
+    pub fn phys_ranges(&self) -> ReservationIter {
+        ReservationIter { slots: &self.phys, index: 0 }
+    }
+}
+
+fn insert(slots: &mut [Option<Reservation>], name: &'static str, start: usize, size: usize) {
+    let range = match AddressRange::new(start, size) {
+        Some(range) => range,
+        None => return,     // nothing to reserve
+    };
+    let slot = slots.iter_mut().find(|slot| slot.is_none())
+        .expect("reservation registry is full");
+    *slot = Some(Reservation { name: name, start: range.start, end: range.end });
+}
+
+// first reservation that intersects the range
+fn find(slots: &[Option<Reservation>], range: AddressRange) -> Option<&Reservation> {
+    slots.iter().filter_map(|slot| slot.as_ref()).find(|r| r.range().overlaps(&range))
+}
+
+pub struct ReservationIter<'a> {
+    slots: &'a [Option<Reservation>],
+    index: usize,
+}
+
+impl<'a> Iterator for ReservationIter<'a> {
+    type Item = &'a Reservation;
+
+    fn next(&mut self) -> Option<&'a Reservation> {
+        let slots = self.slots;
+        while self.index < slots.len() {
+            self.index += 1;
+            if let Some(ref reservation) = slots[self.index - 1] {
+                return Some(reservation);
+            }
+        }
+        None
+    }
+}
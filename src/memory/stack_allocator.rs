@@ -0,0 +1,110 @@
+// kernel stacks that grow on demand
+// a stack owns the pages [max_bottom, top): [bottom, top) is mapped, the pages below
+// bottom stay unmapped until a fault there grows the stack; the page below max_bottom
+// is never mapped and is the guard that catches a real overflow
+
+use memory::{PAGE_SIZE, FrameAllocator};
+use memory::paging::{ActivePageTable, Page, PageIter, PagingError, RegionKind, VirtualAddress};
+
+#[derive(Debug)]
+pub struct Stack {
+    top: VirtualAddress,        // first address above the stack, the initial rsp
+    bottom: VirtualAddress,     // lowest mapped address
+    max_bottom: VirtualAddress, // lowest address the stack may grow to
+}
+
+impl Stack {
+
+    // a stack whose pages [bottom, top) are mapped and that may grow to max_pages pages
+    pub fn new(top: VirtualAddress, bottom: VirtualAddress, max_pages: usize) -> Stack {
+        assert!(top % PAGE_SIZE == 0 && bottom % PAGE_SIZE == 0 && bottom < top,
+            "stack [{:#x}, {:#x}) is not a page aligned range", bottom, top);
+        assert!((top - bottom) / PAGE_SIZE <= max_pages,
+            "stack is larger than its maximum of {} pages", max_pages);
+        Stack {
+            top: top,
+            bottom: bottom,
+            max_bottom: top - max_pages * PAGE_SIZE,
+        }
+    }
+
+    pub fn top(&self) -> VirtualAddress {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtualAddress {
+        self.bottom
+    }
+
+    // bytes mapped right now
+    pub fn size(&self) -> usize {
+        self.top - self.bottom
+    }
+
+    // bytes the stack may grow to
+    pub fn max_size(&self) -> usize {
+        self.top - self.max_bottom
+    }
+}
+
+// hands out new kernel stacks from a range of unused pages, each one above its own
+// unmapped guard page; the pages are used once, freed stacks are not reused
+pub struct StackAllocator {
+    range: PageIter,    // pages no stack got yet
+}
+
+impl StackAllocator {
+
+    pub fn new(page_range: PageIter) -> StackAllocator {
+        StackAllocator { range: page_range }
+    }
+
+    // skip a guard page and map the `size_in_pages` pages above it, the stack can't grow
+    // (its maximum is its size); None without touching the range if the remaining pages
+    // don't fit the guard page and the stack
+    pub fn alloc_stack<A>(&mut self, active_table: &mut ActivePageTable, allocator: &mut A,
+        size_in_pages: usize) -> Option<Stack>
+        where A: FrameAllocator
+    {
+        if size_in_pages == 0 || self.range.len() < size_in_pages + 1 {
+            return None;
+        }
+
+        // left unmapped, an overflow faults there
+        self.range.next().unwrap();
+        let bottom = self.range.next().unwrap();
+        active_table.map(bottom, RegionKind::Stack.flags(), allocator);
+        let mut top_page = bottom;
+        for _ in 1..size_in_pages {
+            top_page = self.range.next().unwrap();
+            active_table.map(top_page, RegionKind::Stack.flags(), allocator);
+        }
+
+        // the stack grows down, so it starts just past its last byte
+        let top = top_page.start_address() + PAGE_SIZE;
+        Some(Stack::new(top, bottom.start_address(), size_in_pages))
+    }
+}
+
+// called by the page fault handler for a not present fault at `faulting_address`, with
+// the table from paging::fault_with_active_table (never with_active_table)
+// a fault in the growth room below the stack maps every page from the faulting one up
+// to the current bottom; StackOverflow below max_bottom (the stack may not grow
+// further), InvalidAddress if the address is not below this stack at all
+pub fn grow_stack_on_fault<A>(stack: &mut Stack, faulting_address: VirtualAddress,
+    active_table: &mut ActivePageTable, allocator: &mut A) -> Result<(), PagingError>
+    where A: FrameAllocator
+{
+    if faulting_address >= stack.bottom {
+        return Err(PagingError::InvalidAddress(faulting_address));
+    }
+    let page = Page::containing_address(faulting_address);
+    if faulting_address < stack.max_bottom {
+        return Err(PagingError::StackOverflow(page));
+    }
+
+    let pages = Page::range_inclusive(page, Page::containing_address(stack.bottom - 1));
+    active_table.prefault_range(pages, RegionKind::Stack.flags(), false, allocator)?;
+    stack.bottom = page.start_address();
+    Ok(())
+}
@@ -0,0 +1,58 @@
+// frame allocator with a fixed, documented order for reproducible mapping tests
+// frames are handed out from base upwards, freed frames come back first
+// (last freed, first reused), needs the heap for the free list
+
+use memory::{Frame, FrameAllocator};
+use alloc::vec::Vec;
+
+pub struct TestFrameAllocator {
+    start: usize,       // number of the first managed frame
+    next: usize,        // number of the next never allocated frame
+    end: usize,         // first frame number after the managed range
+    freed: Vec<Frame>,
+}
+
+impl TestFrameAllocator {
+
+    // manages the `count` frames starting at `base_frame`
+    // the frames must not be used by anyone else
+    pub fn new(base_frame: Frame, count: usize) -> TestFrameAllocator {
+        TestFrameAllocator {
+            start: base_frame.number,
+            next: base_frame.number,
+            end: base_frame.number + count,
+            freed: Vec::new(),
+        }
+    }
+
+    // frames that can still be allocated
+    pub fn available(&self) -> usize {
+        self.end - self.next + self.freed.len()
+    }
+}
+
+impl FrameAllocator for TestFrameAllocator {
+
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        if let Some(frame) = self.freed.pop() {
+            return Some(frame);
+        }
+        if self.next < self.end {
+            self.next += 1;
+            Some(Frame::from_number(self.next - 1))
+        } else {
+            None
+        }
+    }
+
+    fn frames_available(&self) -> Option<usize> {
+        Some(self.available())
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(self.start <= frame.number && frame.number < self.next &&
+            !self.freed.contains(&frame),
+            "{:?} was not allocated by this allocator", frame);
+        self.freed.push(frame);
+    }
+}
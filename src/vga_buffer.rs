@@ -4,7 +4,7 @@ use spin::Mutex;
 pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
     column_position: 0,
     color_code: ColorCode::new(Color::Pink, Color::Black),
-    buffer: unsafe { Unique::new_unchecked(0xb8000 as *mut _) },
+    buffer: unsafe { Unique::new_unchecked(VGA_BUFFER_PHYSICAL as *mut _) },
 });
 
 #[allow(dead_code)]         //Normally the compiler would issue a warning for each unused variant.
@@ -228,6 +228,16 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::io::_print(format_args!($($arg)*)));
 }*/
 
+//physical address of the VGA text buffer, identity mapped during early boot
+pub const VGA_BUFFER_PHYSICAL: usize = 0xb8000;
+
+//Point the writer at another virtual address of the same buffer, e.g. a higher half
+//mapping of the VGA frame, so the console no longer needs the identity mapping.
+//Unsafe because the address must map the VGA frame for the rest of the runtime.
+pub unsafe fn set_buffer_address(address: usize) {
+    WRITER.lock().buffer = Unique::new_unchecked(address as *mut _);
+}
+
 pub fn clear_screen() {
     for _ in 0..BUFFER_HEIGHT {
         println!("");